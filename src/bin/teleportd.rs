@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use teleport::{
+    config::{RpcConfig, RpcOptions},
+    daemon::DaemonConfig,
+    maker_protocol::MakerConfig,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "teleportd",
+    about = "Teleport daemon, keeps the wallet loaded and serves a local JSON-RPC API"
+)]
+struct Args {
+    /// Wallet file
+    #[structopt(default_value = "wallet.teleport", parse(from_os_str), long)]
+    wallet_file_name: PathBuf,
+
+    /// Config file, default is the TELEPORT_CONFIG environment variable or
+    /// "~/.teleport/config.toml" if it exists
+    #[structopt(parse(from_os_str), long)]
+    config_file: Option<PathBuf>,
+
+    /// Port to listen on for JSON-RPC requests, overrides the config file
+    #[structopt(long)]
+    port: Option<u16>,
+
+    /// Unix socket to also listen on, overrides the config file
+    #[structopt(parse(from_os_str), long)]
+    unix_socket: Option<PathBuf>,
+
+    /// Where to write the authentication cookie, default is the wallet file name with the
+    /// extension ".cookie"
+    #[structopt(parse(from_os_str), long)]
+    cookie_file: Option<PathBuf>,
+
+    /// Bitcoin Core RPC connection, overrides the config file and TELEPORT_RPC_* variables
+    #[structopt(flatten)]
+    rpc_options: RpcOptions,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    teleport::setup_logger();
+    let args = Args::from_args();
+    let config_file = args.config_file.as_deref();
+
+    let rpc_config = match RpcConfig::load(config_file, args.rpc_options) {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!(target: "main", "error loading config: {:?}", error);
+            return Err(error.into());
+        }
+    };
+    let mut daemon_config = match DaemonConfig::load(config_file) {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!(target: "main", "error loading daemon config: {:?}", error);
+            return Err(error.into());
+        }
+    };
+    let maker_config = match MakerConfig::load(config_file) {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!(target: "main", "error loading maker config: {:?}", error);
+            return Err(error.into());
+        }
+    };
+
+    if let Some(port) = args.port {
+        daemon_config.port = port;
+    }
+    if args.unix_socket.is_some() {
+        daemon_config.unix_socket = args.unix_socket;
+    }
+    if args.cookie_file.is_some() {
+        daemon_config.cookie_file = args.cookie_file;
+    }
+
+    teleport::run_daemon(
+        &rpc_config,
+        &args.wallet_file_name,
+        daemon_config,
+        maker_config,
+    );
+    Ok(())
+}
@@ -0,0 +1,474 @@
+//! The blockchain and wallet operations teleport needs from a bitcoin node.
+//!
+//! Everything which talks to the node goes through [BlockchainBackend], so other backends can be
+//! plugged in. Bitcoin Core over RPC is the default backend, implemented for
+//! [bitcoincore_rpc::Client]. Teleport uses the node's wallet as a watch-only wallet for its
+//! addresses and contracts, so a backend provides the wallet calls as well as the chain calls.
+//!
+//! Results are returned in types defined here, which the Core backend converts its RPC results
+//! into, so other backends don't need to produce Core's formats. The arguments of
+//! [BlockchainBackend::import_multi] and [BlockchainBackend::wallet_create_funded_psbt] are still
+//! the request types of `bitcoincore_rpc`, so a backend depends on that crate for them.
+
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bitcoin::{
+    consensus::encode::deserialize, util::psbt::PartiallySignedTransaction, Address, Amount, Block,
+    BlockHash, OutPoint, Script, Transaction, Txid,
+};
+use bitcoincore_rpc::{
+    json::{
+        CreateRawTransactionInput, ImportMultiOptions, ImportMultiRequest,
+        WalletCreateFundedPsbtOptions,
+    },
+    Client, RpcApi,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::TeleportError;
+
+/// Tx in the mempool, see [BlockchainBackend::get_mempool_entry].
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub vsize: u64,
+    pub fee: Amount,
+}
+
+/// Unspent output, see [BlockchainBackend::get_tx_out].
+#[derive(Debug, Clone)]
+pub struct TxOutInfo {
+    pub value: Amount,
+    pub script_pubkey: Script,
+    /// Zero if the output is created by a tx in the mempool.
+    pub confirmations: u32,
+}
+
+/// Descriptor to look for in the UTXO set, see [BlockchainBackend::scan_tx_out_set].
+#[derive(Debug, Clone)]
+pub struct ScanObject {
+    pub descriptor: String,
+    /// Last index scanned of a ranged descriptor, the backend's default if None.
+    pub range_end: Option<u32>,
+}
+
+/// Result of [BlockchainBackend::scan_tx_out_set].
+#[derive(Debug, Clone)]
+pub struct TxOutSetScan {
+    pub height: u64,
+    pub best_block: BlockHash,
+    /// Number of unspent outputs in the UTXO set.
+    pub txouts: u64,
+    pub unspents: Vec<ScannedUnspent>,
+    pub total_amount: Amount,
+}
+
+/// Unspent output matching a [ScanObject].
+#[derive(Debug, Clone)]
+pub struct ScannedUnspent {
+    pub txid: Txid,
+    pub vout: u32,
+    pub amount: Amount,
+    pub height: u64,
+    pub descriptor: String,
+}
+
+/// Wallet tx, see [BlockchainBackend::get_transaction].
+#[derive(Debug, Clone)]
+pub struct WalletTransaction {
+    pub txid: Txid,
+    /// Zero if the tx is in the mempool, negative if it conflicts with a tx confirmed that many
+    /// blocks ago.
+    pub confirmations: i32,
+    pub blockhash: Option<BlockHash>,
+    pub blocktime: Option<u64>,
+    pub transaction: Transaction,
+}
+
+/// Unspent output of the wallet, see [BlockchainBackend::list_unspent].
+#[derive(Debug, Clone)]
+pub struct ListUnspentEntry {
+    pub txid: Txid,
+    pub vout: u32,
+    pub address: Option<Address>,
+    pub label: Option<String>,
+    pub witness_script: Option<Script>,
+    pub script_pub_key: Script,
+    pub amount: Amount,
+    pub confirmations: u32,
+    /// Descriptor of the output if the wallet can sign for it, given the keys.
+    pub descriptor: Option<String>,
+}
+
+/// Result of one request given to [BlockchainBackend::import_multi].
+#[derive(Debug, Clone)]
+pub struct ImportResult {
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// See [BlockchainBackend::get_address_info].
+#[derive(Debug, Clone)]
+pub struct AddressInfo {
+    pub is_watchonly: bool,
+}
+
+/// Unsigned tx created by [BlockchainBackend::wallet_create_funded_psbt]. The psbt inputs have
+/// the outputs they spend, and the witness scripts and key origins the wallet knows.
+#[derive(Debug, Clone)]
+pub struct FundedPsbt {
+    pub psbt: PartiallySignedTransaction,
+    pub fee: Amount,
+    /// Position of the change output, -1 if there is none.
+    pub change_position: i32,
+}
+
+/// A bitcoin node with a watch-only wallet, see the [module docs](self). Takes the
+/// `bitcoincore_rpc` types [ImportMultiRequest], [CreateRawTransactionInput] and
+/// [WalletCreateFundedPsbtOptions] as arguments, which other backends have to accept too.
+pub trait BlockchainBackend: Send + Sync {
+    fn get_block_count(&self) -> Result<u64, TeleportError>;
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, TeleportError>;
+
+    fn get_best_block_hash(&self) -> Result<BlockHash, TeleportError>;
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, TeleportError>;
+
+    fn get_block_txids(&self, hash: &BlockHash) -> Result<Vec<Txid>, TeleportError>;
+
+    /// Timestamp in the header of the block.
+    fn get_block_time(&self, hash: &BlockHash) -> Result<u64, TeleportError>;
+
+    /// Median time past of the chain tip.
+    fn get_median_time(&self) -> Result<u64, TeleportError>;
+
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>, TeleportError>;
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<MempoolEntry, TeleportError>;
+
+    /// Unspent output, including outputs created by txes in the mempool. Returns None if the
+    /// output doesn't exist or is spent.
+    fn get_tx_out(&self, txid: &Txid, vout: u32) -> Result<Option<TxOutInfo>, TeleportError>;
+
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction, TeleportError>;
+
+    /// Merkle proof that the txes are in the block.
+    fn get_tx_out_proof(
+        &self,
+        txids: &[Txid],
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>, TeleportError>;
+
+    fn send_raw_transaction(&self, tx: &Transaction) -> Result<Txid, TeleportError>;
+
+    /// Scan the UTXO set for outputs matching the descriptors, aborting any scan already running.
+    fn scan_tx_out_set(&self, scan_objects: &[ScanObject]) -> Result<TxOutSetScan, TeleportError>;
+
+    /// Wallet tx, including watch-only txes.
+    fn get_transaction(&self, txid: &Txid) -> Result<WalletTransaction, TeleportError>;
+
+    /// Txids of the wallet txes, including watch-only txes. Skips the `skip` newest txes and
+    /// returns up to `count` of the rest, oldest first. A txid may be returned more than once.
+    fn list_transactions(&self, count: usize, skip: usize) -> Result<Vec<Txid>, TeleportError>;
+
+    /// Every unspent output of the wallet, confirmed or not.
+    fn list_unspent(&self) -> Result<Vec<ListUnspentEntry>, TeleportError>;
+
+    /// Stop the outputs from being selected by [BlockchainBackend::wallet_create_funded_psbt].
+    fn lock_unspent(&self, outpoints: &[OutPoint]) -> Result<(), TeleportError>;
+
+    fn unlock_unspent_all(&self) -> Result<(), TeleportError>;
+
+    /// Import into the wallet without rescanning the chain.
+    fn import_multi(
+        &self,
+        requests: &[ImportMultiRequest],
+    ) -> Result<Vec<ImportResult>, TeleportError>;
+
+    /// Import a confirmed tx into the wallet with its merkle proof, instead of a rescan.
+    fn import_pruned_funds(
+        &self,
+        tx: &Transaction,
+        merkleproof: &[u8],
+    ) -> Result<(), TeleportError>;
+
+    fn get_address_info(&self, address: &Address) -> Result<AddressInfo, TeleportError>;
+
+    fn derive_addresses(
+        &self,
+        descriptor: &str,
+        range: Option<[u32; 2]>,
+    ) -> Result<Vec<Address>, TeleportError>;
+
+    /// Returns the descriptor with its checksum added.
+    fn get_descriptor_with_checksum(&self, descriptor: &str) -> Result<String, TeleportError>;
+
+    fn wallet_create_funded_psbt(
+        &self,
+        inputs: &[CreateRawTransactionInput],
+        outputs: &HashMap<String, Amount>,
+        options: WalletCreateFundedPsbtOptions,
+    ) -> Result<FundedPsbt, TeleportError>;
+}
+
+//scantxoutset result, converted into a TxOutSetScan
+#[derive(Deserialize)]
+struct CoreTxOutSetScan {
+    success: bool,
+    txouts: u64,
+    height: u64,
+    bestblock: BlockHash,
+    unspents: Vec<CoreScannedUnspent>,
+    #[serde(with = "bitcoin::util::amount::serde::as_btc")]
+    total_amount: Amount,
+}
+
+#[derive(Deserialize)]
+struct CoreScannedUnspent {
+    txid: Txid,
+    vout: u32,
+    #[serde(with = "bitcoin::util::amount::serde::as_btc")]
+    amount: Amount,
+    height: u64,
+    desc: String,
+}
+
+impl BlockchainBackend for Client {
+    fn get_block_count(&self) -> Result<u64, TeleportError> {
+        Ok(RpcApi::get_block_count(self)?)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, TeleportError> {
+        Ok(RpcApi::get_block_hash(self, height)?)
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash, TeleportError> {
+        Ok(RpcApi::get_best_block_hash(self)?)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, TeleportError> {
+        Ok(RpcApi::get_block(self, hash)?)
+    }
+
+    fn get_block_txids(&self, hash: &BlockHash) -> Result<Vec<Txid>, TeleportError> {
+        Ok(self.get_block_info(hash)?.tx)
+    }
+
+    fn get_block_time(&self, hash: &BlockHash) -> Result<u64, TeleportError> {
+        Ok(self.get_block_header_info(hash)?.time as u64)
+    }
+
+    fn get_median_time(&self) -> Result<u64, TeleportError> {
+        Ok(self.get_blockchain_info()?.median_time)
+    }
+
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>, TeleportError> {
+        Ok(RpcApi::get_raw_mempool(self)?)
+    }
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<MempoolEntry, TeleportError> {
+        let entry = RpcApi::get_mempool_entry(self, txid)?;
+        Ok(MempoolEntry {
+            vsize: entry.vsize,
+            fee: entry.fees.base,
+        })
+    }
+
+    fn get_tx_out(&self, txid: &Txid, vout: u32) -> Result<Option<TxOutInfo>, TeleportError> {
+        Ok(
+            RpcApi::get_tx_out(self, txid, vout, Some(true))?.map(|txout| TxOutInfo {
+                value: txout.value,
+                script_pubkey: Script::from(txout.script_pub_key.hex),
+                confirmations: txout.confirmations,
+            }),
+        )
+    }
+
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction, TeleportError> {
+        Ok(RpcApi::get_raw_transaction(self, txid, block_hash)?)
+    }
+
+    fn get_tx_out_proof(
+        &self,
+        txids: &[Txid],
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>, TeleportError> {
+        Ok(RpcApi::get_tx_out_proof(self, txids, block_hash)?)
+    }
+
+    fn send_raw_transaction(&self, tx: &Transaction) -> Result<Txid, TeleportError> {
+        Ok(RpcApi::send_raw_transaction(self, tx)?)
+    }
+
+    fn scan_tx_out_set(&self, scan_objects: &[ScanObject]) -> Result<TxOutSetScan, TeleportError> {
+        let scan_objects = scan_objects
+            .iter()
+            .map(|scan_object| match scan_object.range_end {
+                Some(range_end) => json!({"desc": scan_object.descriptor, "range": range_end}),
+                None => json!({ "desc": scan_object.descriptor }),
+            })
+            .collect::<Vec<Value>>();
+        self.call::<Value>("scantxoutset", &[json!("abort")])?;
+        let scan =
+            self.call::<CoreTxOutSetScan>("scantxoutset", &[json!("start"), json!(scan_objects)])?;
+        if !scan.success {
+            return Err(TeleportError::Rpc(
+                bitcoincore_rpc::Error::UnexpectedStructure,
+            ));
+        }
+        Ok(TxOutSetScan {
+            height: scan.height,
+            best_block: scan.bestblock,
+            txouts: scan.txouts,
+            unspents: scan
+                .unspents
+                .into_iter()
+                .map(|unspent| ScannedUnspent {
+                    txid: unspent.txid,
+                    vout: unspent.vout,
+                    amount: unspent.amount,
+                    height: unspent.height,
+                    descriptor: unspent.desc,
+                })
+                .collect(),
+            total_amount: scan.total_amount,
+        })
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<WalletTransaction, TeleportError> {
+        let gettx = RpcApi::get_transaction(self, txid, Some(true))?;
+        Ok(WalletTransaction {
+            txid: gettx.info.txid,
+            confirmations: gettx.info.confirmations,
+            blockhash: gettx.info.blockhash,
+            blocktime: gettx.info.blocktime,
+            transaction: gettx.transaction().map_err(bitcoincore_rpc::Error::from)?,
+        })
+    }
+
+    fn list_transactions(&self, count: usize, skip: usize) -> Result<Vec<Txid>, TeleportError> {
+        Ok(
+            RpcApi::list_transactions(self, None, Some(count), Some(skip), Some(true))?
+                .into_iter()
+                .map(|tx| tx.info.txid)
+                .collect(),
+        )
+    }
+
+    fn list_unspent(&self) -> Result<Vec<ListUnspentEntry>, TeleportError> {
+        Ok(
+            RpcApi::list_unspent(self, Some(0), Some(9999999), None, None, None)?
+                .into_iter()
+                .map(|utxo| ListUnspentEntry {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    address: utxo.address,
+                    label: utxo.label,
+                    witness_script: utxo.witness_script,
+                    script_pub_key: utxo.script_pub_key,
+                    amount: utxo.amount,
+                    confirmations: utxo.confirmations,
+                    descriptor: utxo.descriptor,
+                })
+                .collect(),
+        )
+    }
+
+    fn lock_unspent(&self, outpoints: &[OutPoint]) -> Result<(), TeleportError> {
+        RpcApi::lock_unspent(self, outpoints)?;
+        Ok(())
+    }
+
+    fn unlock_unspent_all(&self) -> Result<(), TeleportError> {
+        //rust-bitcoincore-rpc's unlock_unspent(&[]) doesnt unlock everything
+        //https://github.com/rust-bitcoin/rust-bitcoincore-rpc/issues/148
+        self.call::<Value>("lockunspent", &[Value::Bool(true)])?;
+        Ok(())
+    }
+
+    fn import_multi(
+        &self,
+        requests: &[ImportMultiRequest],
+    ) -> Result<Vec<ImportResult>, TeleportError> {
+        Ok(RpcApi::import_multi(
+            self,
+            requests,
+            Some(&ImportMultiOptions {
+                rescan: Some(false),
+            }),
+        )?
+        .into_iter()
+        .map(|result| ImportResult {
+            success: result.success,
+            error: result.error.map(|e| e.message),
+        })
+        .collect())
+    }
+
+    fn import_pruned_funds(
+        &self,
+        tx: &Transaction,
+        merkleproof: &[u8],
+    ) -> Result<(), TeleportError> {
+        self.call::<Value>(
+            "importprunedfunds",
+            &[
+                json!(bitcoin::consensus::encode::serialize_hex(tx)),
+                json!(bitcoin::hashes::hex::ToHex::to_hex(merkleproof)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_address_info(&self, address: &Address) -> Result<AddressInfo, TeleportError> {
+        Ok(AddressInfo {
+            is_watchonly: RpcApi::get_address_info(self, address)?
+                .is_watchonly
+                .unwrap_or(false),
+        })
+    }
+
+    fn derive_addresses(
+        &self,
+        descriptor: &str,
+        range: Option<[u32; 2]>,
+    ) -> Result<Vec<Address>, TeleportError> {
+        Ok(RpcApi::derive_addresses(self, descriptor, range)?)
+    }
+
+    fn get_descriptor_with_checksum(&self, descriptor: &str) -> Result<String, TeleportError> {
+        Ok(self.get_descriptor_info(descriptor)?.descriptor)
+    }
+
+    fn wallet_create_funded_psbt(
+        &self,
+        inputs: &[CreateRawTransactionInput],
+        outputs: &HashMap<String, Amount>,
+        options: WalletCreateFundedPsbtOptions,
+    ) -> Result<FundedPsbt, TeleportError> {
+        let result =
+            RpcApi::wallet_create_funded_psbt(self, inputs, outputs, None, Some(options), None)?;
+        let psbt = BASE64
+            .decode(&result.psbt)
+            .ok()
+            .and_then(|psbt| deserialize::<PartiallySignedTransaction>(&psbt).ok())
+            .ok_or(TeleportError::Rpc(
+                bitcoincore_rpc::Error::UnexpectedStructure,
+            ))?;
+        Ok(FundedPsbt {
+            psbt,
+            fee: result.fee,
+            change_position: result.change_position,
+        })
+    }
+}
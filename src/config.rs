@@ -0,0 +1,464 @@
+//! Runtime configuration.
+//!
+//! Settings are read from a TOML config file, then overridden by environment variables, then
+//! overridden again by command line arguments. An example config file:
+//!
+//! ```toml
+//! [rpc]
+//! host = "localhost"
+//! port = 8332
+//! wallet = "teleport"
+//! network = "main"
+//! # either a username and password
+//! user = "rpcuser"
+//! password = "rpcpassword"
+//! # or the path of Bitcoin Core's cookie file
+//! cookie_file = "/home/user/.bitcoin/.cookie"
+//!
+//! [maker]
+//! port = 6102
+//! onion_addr = "myhiddenserviceaddress.onion:6102"
+//! absolute_fee_sat = 1000
+//! amount_relative_fee_ppb = 10000000
+//! min_size = 10000
+//!
+//! [daemon]
+//! port = 6104
+//! unix_socket = "/home/user/.teleport/teleportd.sock"
+//! ```
+//!
+//! The `[maker]` section only applies to `run-yield-generator` and makers started by
+//! `teleportd`, see [`MakerConfig`](crate::maker_protocol::MakerConfig) for all the keys. The
+//! `[daemon]` section only applies to `teleportd`, see
+//! [`DaemonConfig`](crate::daemon::DaemonConfig).
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bitcoin::Network;
+use bitcoincore_rpc::Auth;
+use dirs::home_dir;
+use structopt::StructOpt;
+
+use crate::error::TeleportError;
+
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "config.toml";
+pub const CONFIG_FILE_ENV_VAR: &str = "TELEPORT_CONFIG";
+
+const DEFAULT_RPC_HOST: &str = "localhost";
+const DEFAULT_RPC_WALLET: &str = "teleport";
+//credentials used when nothing else is configured, these match .github/bitcoin.conf
+const DEFAULT_REGTEST_RPC_CREDENTIALS: (&str, &str) = ("regtestrpcuser", "regtestrpcpass");
+
+/// Returns `~/.teleport/config.toml`
+pub fn default_config_file_path() -> Option<PathBuf> {
+    home_dir().map(|h| h.join(".teleport").join(DEFAULT_CONFIG_FILE_NAME))
+}
+
+/// Parses a network name, accepting both Bitcoin Core's names ("main", "test", "signet",
+/// "regtest") and rust-bitcoin's names ("bitcoin", "testnet", ..)
+pub fn parse_network(net_str: &str) -> Result<Network, TeleportError> {
+    Ok(match net_str {
+        "main" | "mainnet" | "bitcoin" => Network::Bitcoin,
+        "test" | "testnet" => Network::Testnet,
+        "signet" => Network::Signet,
+        "regtest" => Network::Regtest,
+        _ => return Err(config_error(format!("unknown network: {}", net_str))),
+    })
+}
+
+pub(crate) fn config_error(message: String) -> TeleportError {
+    TeleportError::Disk(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Bitcoin Core RPC settings from a single source (config file, environment or command line).
+/// Fields which are `None` leave the setting from the previous source unchanged. Arguments are
+/// named after their flags so they don't clash with the fields of the binaries flattening them.
+#[derive(Debug, Default, Clone, StructOpt, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpcOptions {
+    /// Bitcoin Core RPC host, default "localhost"
+    #[structopt(long = "rpc-host", name = "rpc-host")]
+    pub host: Option<String>,
+
+    /// Bitcoin Core RPC port, default is the standard port of the network
+    #[structopt(long = "rpc-port", name = "rpc-port")]
+    pub port: Option<u16>,
+
+    /// Bitcoin Core RPC username
+    #[structopt(long = "rpc-user", name = "rpc-user")]
+    pub user: Option<String>,
+
+    /// Bitcoin Core RPC password
+    #[structopt(long = "rpc-password", name = "rpc-password")]
+    pub password: Option<String>,
+
+    /// Path of Bitcoin Core's RPC cookie file, used instead of username and password
+    #[structopt(long = "rpc-cookie-file", name = "rpc-cookie-file", parse(from_os_str))]
+    pub cookie_file: Option<PathBuf>,
+
+    /// Bitcoin Core wallet used for watching addresses, default "teleport"
+    #[structopt(long = "rpc-wallet", name = "rpc-wallet")]
+    pub wallet: Option<String>,
+
+    /// Network the node is expected to be on, options are "main", "test", "signet", "regtest"
+    #[structopt(long = "rpc-network", name = "rpc-network")]
+    pub network: Option<String>,
+}
+
+impl RpcOptions {
+    /// Reads the `TELEPORT_RPC_*` and `TELEPORT_NETWORK` environment variables
+    pub fn from_env() -> Result<RpcOptions, TeleportError> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        Ok(RpcOptions {
+            host: var("TELEPORT_RPC_HOST"),
+            port: match var("TELEPORT_RPC_PORT") {
+                Some(p) => Some(p.parse::<u16>().map_err(|_| {
+                    config_error(format!("invalid TELEPORT_RPC_PORT value: {}", p))
+                })?),
+                None => None,
+            },
+            user: var("TELEPORT_RPC_USER"),
+            password: var("TELEPORT_RPC_PASSWORD"),
+            cookie_file: var("TELEPORT_RPC_COOKIE_FILE").map(PathBuf::from),
+            wallet: var("TELEPORT_RPC_WALLET"),
+            network: var("TELEPORT_NETWORK"),
+        })
+    }
+}
+
+/// The `[maker]` section of the config file, fields which are `None` keep their default value
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MakerOptions {
+    pub port: Option<u16>,
+    pub onion_addr: Option<String>,
+    pub heartbeat_interval_secs: Option<u64>,
+    pub rpc_ping_interval_secs: Option<u64>,
+    pub watchtower_ping_interval_secs: Option<u64>,
+    pub directory_servers_refresh_interval_secs: Option<u64>,
+    pub idle_connection_timeout: Option<u64>,
+    pub utxo_reservation_expiry_secs: Option<u64>,
+    pub swap_deadline_blocks: Option<u32>,
+    pub swap_deadline_check_interval_secs: Option<u64>,
+    pub admin_socket: Option<PathBuf>,
+    pub absolute_fee_sat: Option<u64>,
+    pub amount_relative_fee_ppb: Option<u64>,
+    pub time_relative_fee_ppb: Option<u64>,
+    pub required_confirms: Option<i32>,
+    pub minimum_locktime: Option<u16>,
+    pub min_size: Option<u64>,
+}
+
+/// The `[daemon]` section of the config file, fields which are `None` keep their default value
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DaemonOptions {
+    pub port: Option<u16>,
+    pub unix_socket: Option<PathBuf>,
+    pub cookie_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigFile {
+    #[serde(default)]
+    pub rpc: RpcOptions,
+    #[serde(default)]
+    pub maker: MakerOptions,
+    #[serde(default)]
+    pub daemon: DaemonOptions,
+}
+
+/// Reads the config file. If `config_file` is `None` then the path in the `TELEPORT_CONFIG`
+/// environment variable is used, and otherwise `~/.teleport/config.toml`. Only an explicitly
+/// given config file is required to exist.
+pub(crate) fn read_config_file(config_file: Option<&Path>) -> Result<ConfigFile, TeleportError> {
+    let (path, required) = match config_file {
+        Some(p) => (p.to_path_buf(), true),
+        None => match env::var_os(CONFIG_FILE_ENV_VAR) {
+            Some(p) => (PathBuf::from(p), true),
+            None => match default_config_file_path() {
+                Some(p) => (p, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        },
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+            return Ok(ConfigFile::default())
+        }
+        Err(e) => return Err(TeleportError::Disk(e)),
+    };
+    log::debug!(target: "config", "read config file {}", path.display());
+    toml::from_str::<ConfigFile>(&contents).map_err(|e| {
+        config_error(format!(
+            "unable to parse config file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Settings for connecting to Bitcoin Core's RPC server
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub cookie_file: Option<PathBuf>,
+    pub wallet: String,
+    pub network: Option<Network>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> RpcConfig {
+        RpcConfig {
+            host: DEFAULT_RPC_HOST.to_string(),
+            port: None,
+            user: None,
+            password: None,
+            cookie_file: None,
+            wallet: DEFAULT_RPC_WALLET.to_string(),
+            network: None,
+        }
+    }
+}
+
+impl RpcConfig {
+    /// Builds the RPC config from the config file, the environment and the given command line
+    /// options, with later sources taking priority
+    pub fn load(
+        config_file: Option<&Path>,
+        cli_options: RpcOptions,
+    ) -> Result<RpcConfig, TeleportError> {
+        let mut config = RpcConfig::default();
+        config.apply(read_config_file(config_file)?.rpc)?;
+        config.apply(RpcOptions::from_env()?)?;
+        config.apply(cli_options)?;
+        if config.user.is_some() != config.password.is_some() {
+            return Err(config_error(
+                "rpc user and password must be given together".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    fn apply(&mut self, options: RpcOptions) -> Result<(), TeleportError> {
+        if options.cookie_file.is_some() && (options.user.is_some() || options.password.is_some()) {
+            return Err(config_error(
+                "set either rpc user and password or rpc cookie file, not both".to_string(),
+            ));
+        }
+        if let Some(host) = options.host {
+            self.host = host;
+        }
+        if let Some(port) = options.port {
+            self.port = Some(port);
+        }
+        if options.user.is_some() || options.password.is_some() {
+            self.cookie_file = None;
+            if options.user.is_some() {
+                self.user = options.user;
+            }
+            if options.password.is_some() {
+                self.password = options.password;
+            }
+        }
+        if let Some(cookie_file) = options.cookie_file {
+            self.user = None;
+            self.password = None;
+            self.cookie_file = Some(cookie_file);
+        }
+        if let Some(wallet) = options.wallet {
+            self.wallet = wallet;
+        }
+        if let Some(net_str) = options.network {
+            self.network = Some(parse_network(&net_str)?);
+        }
+        Ok(())
+    }
+
+    /// Configured port, or the default RPC port of the configured network.
+    /// If no network is configured then regtest is assumed
+    pub fn get_port(&self) -> u16 {
+        self.port.unwrap_or(match self.network {
+            Some(Network::Bitcoin) => 8332,
+            Some(Network::Testnet) => 18332,
+            Some(Network::Signet) => 38332,
+            Some(Network::Regtest) | None => 18443,
+        })
+    }
+
+    pub fn get_auth(&self) -> Auth {
+        if let Some(cookie_file) = &self.cookie_file {
+            return Auth::CookieFile(cookie_file.clone());
+        }
+        if let (Some(user), Some(password)) = (&self.user, &self.password) {
+            return Auth::UserPass(user.clone(), password.clone());
+        }
+        match self.network {
+            Some(Network::Regtest) | None => Auth::UserPass(
+                DEFAULT_REGTEST_RPC_CREDENTIALS.0.to_string(),
+                DEFAULT_REGTEST_RPC_CREDENTIALS.1.to_string(),
+            ),
+            Some(network) => {
+                //TODO this is Bitcoin Core's default data directory on Linux only,
+                //     also support other OSes (Windows, MacOS...)
+                let data_dir = home_dir().unwrap().join(".bitcoin");
+                let network_dir = match network {
+                    Network::Bitcoin => data_dir,
+                    Network::Testnet => data_dir.join("testnet3"),
+                    Network::Signet => data_dir.join("signet"),
+                    Network::Regtest => data_dir.join("regtest"),
+                };
+                Auth::CookieFile(network_dir.join(".cookie"))
+            }
+        }
+    }
+
+    pub fn get_url(&self) -> String {
+        format!(
+            "http://{}:{}/wallet/{}",
+            self.host,
+            self.get_port(),
+            self.wallet
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_defaults_match_regtest_ci_node() {
+        let config = RpcConfig::default();
+        assert_eq!(config.get_url(), "http://localhost:18443/wallet/teleport");
+        match config.get_auth() {
+            Auth::UserPass(user, pass) => {
+                assert_eq!(user, "regtestrpcuser");
+                assert_eq!(pass, "regtestrpcpass");
+            }
+            _ => panic!("expected user and password auth"),
+        }
+    }
+
+    #[test]
+    fn test_later_sources_override_earlier() {
+        let file = toml::from_str::<ConfigFile>(
+            r#"
+            [rpc]
+            host = "10.0.0.2"
+            network = "main"
+            user = "fileuser"
+            password = "filepass"
+            wallet = "maker"
+            "#,
+        )
+        .unwrap();
+        let mut config = RpcConfig::default();
+        config.apply(file.rpc).unwrap();
+        assert_eq!(config.get_url(), "http://10.0.0.2:8332/wallet/maker");
+
+        config
+            .apply(RpcOptions {
+                port: Some(8000),
+                cookie_file: Some(PathBuf::from("/tmp/.cookie")),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(config.get_url(), "http://10.0.0.2:8000/wallet/maker");
+        assert!(config.user.is_none() && config.password.is_none());
+        match config.get_auth() {
+            Auth::CookieFile(path) => assert_eq!(path, PathBuf::from("/tmp/.cookie")),
+            _ => panic!("expected cookie file auth"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_options_rejected() {
+        let mut config = RpcConfig::default();
+        assert!(config
+            .apply(RpcOptions {
+                user: Some("u".to_string()),
+                cookie_file: Some(PathBuf::from("/tmp/.cookie")),
+                ..Default::default()
+            })
+            .is_err());
+        assert!(config
+            .apply(RpcOptions {
+                network: Some("mainnet2".to_string()),
+                ..Default::default()
+            })
+            .is_err());
+        assert!(toml::from_str::<ConfigFile>("[rpc]\nhots = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn test_maker_section() {
+        let file = toml::from_str::<ConfigFile>(
+            r#"
+            [maker]
+            port = 16102
+            absolute_fee_sat = 2000
+            min_size = 50000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.maker.port, Some(16102));
+        assert_eq!(file.maker.absolute_fee_sat, Some(2000));
+        assert_eq!(file.maker.min_size, Some(50000));
+        assert!(file.maker.onion_addr.is_none());
+        assert!(toml::from_str::<ConfigFile>("[maker]\nabsolute_fee = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_daemon_section() {
+        let file = toml::from_str::<ConfigFile>(
+            r#"
+            [daemon]
+            port = 16104
+            unix_socket = "/tmp/teleportd.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.daemon.port, Some(16104));
+        assert_eq!(
+            file.daemon.unix_socket,
+            Some(PathBuf::from("/tmp/teleportd.sock"))
+        );
+        assert!(file.daemon.cookie_file.is_none());
+        assert!(toml::from_str::<ConfigFile>("[daemon]\nbind = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_flattened_with_same_field_names() {
+        // like teleportd, which has its own port and cookie file
+        #[derive(StructOpt)]
+        struct Args {
+            #[structopt(long)]
+            port: Option<u16>,
+            #[structopt(parse(from_os_str), long)]
+            cookie_file: Option<PathBuf>,
+            #[structopt(flatten)]
+            rpc_options: RpcOptions,
+        }
+        let args = Args::from_iter_safe(&[
+            "teleportd",
+            "--port=1",
+            "--rpc-port=2",
+            "--cookie-file=a",
+            "--rpc-cookie-file=b",
+        ])
+        .unwrap();
+        assert_eq!(args.port, Some(1));
+        assert_eq!(args.rpc_options.port, Some(2));
+        assert_eq!(args.cookie_file, Some(PathBuf::from("a")));
+        assert_eq!(args.rpc_options.cookie_file, Some(PathBuf::from("b")));
+    }
+}
@@ -17,9 +17,8 @@ use bitcoin::{
     OutPoint, SigHashType, Transaction, TxIn, TxOut,
 };
 
-use bitcoincore_rpc::{Client, RpcApi};
-
 use crate::{
+    blockchain_backend::BlockchainBackend,
     error::TeleportError,
     messages::FundingTxInfo,
     wallet_sync::{create_multisig_redeemscript, IncomingSwapCoin, OutgoingSwapCoin, Wallet},
@@ -42,7 +41,7 @@ pub const MAKER_FUNDING_TX_VBYTE_SIZE: u64 = 372;
 
 //like the Incoming/OutgoingSwapCoin structs but no privkey or signature information
 //used by the taker to monitor coinswaps between two makers
-#[derive(Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct WatchOnlySwapCoin {
     pub sender_pubkey: PublicKey,
     pub receiver_pubkey: PublicKey,
@@ -399,7 +398,7 @@ pub fn find_funding_output<'a>(
 //or None if the proof is invalid for some reason
 //or an error if the RPC connection fails
 pub fn verify_proof_of_funding(
-    rpc: Arc<Client>,
+    rpc: Arc<dyn BlockchainBackend>,
     wallet: &mut Wallet,
     funding_info: &FundingTxInfo,
     funding_output_index: u32,
@@ -408,9 +407,7 @@ pub fn verify_proof_of_funding(
     //returns my_multisig_privkey, other_multisig_pubkey, my_hashlock_privkey
 ) -> Result<(SecretKey, PublicKey, SecretKey), TeleportError> {
     //check the funding_tx exists and was really confirmed
-    if let Some(txout) =
-        rpc.get_tx_out(&funding_info.funding_tx.txid(), funding_output_index, None)?
-    {
+    if let Some(txout) = rpc.get_tx_out(&funding_info.funding_tx.txid(), funding_output_index)? {
         if txout.confirmations < 1 {
             return Err(TeleportError::Protocol("funding tx not confirmed"));
         }
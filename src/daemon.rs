@@ -0,0 +1,939 @@
+//! `teleportd`, a daemon which keeps the wallet loaded and is controlled through a local
+//! JSON-RPC API.
+//!
+//! JSON-RPC 2.0 requests are sent as HTTP POSTs to a port on localhost, and optionally to a unix
+//! socket which only the user running the daemon can connect to. Like with Bitcoin Core,
+//! clients authenticate with HTTP basic auth using the `__cookie__:<secret>` credentials which
+//! the daemon writes to its cookie file at startup. For example
+//!
+//! ```text
+//! curl --user "$(cat wallet.cookie)" --data '{"jsonrpc":"2.0","id":1,"method":"getbalance"}' \
+//!     http://127.0.0.1:6104/
+//! ```
+//!
+//! Params are passed by name and all amounts are in satoshis. The methods are
+//! `getinfo`, `getbalance`, `listunspent`, `getnewaddress`, `getfidelitybondaddress`,
+//! `directsend`, `startmaker`, `stopmaker`, `startcoinswap`, `resumecoinswap`, `recover`,
+//! `getswapstatus`, `listoffers` and `stop`.
+//!
+//! Coinswaps, resuming a coinswap and recovery run in the background, one at a time, and their
+//! outcome is read with `getswapstatus`, along with the [SwapEvent]s of a coinswap so far. They
+//! keep the wallet busy until they end, so the other wallet methods fail in the meantime, and
+//! they can't run while the maker is running as both would be spending from the same wallet.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+    select,
+    sync::Notify,
+    time::timeout,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bitcoin::{
+    hashes::{
+        hash160::Hash as Hash160,
+        hex::{FromHex, ToHex},
+    },
+    secp256k1::rand::{rngs::OsRng, RngCore},
+    Amount, Network,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    blockchain_backend::{BlockchainBackend, ListUnspentEntry},
+    config::read_config_file,
+    direct_send::{CoinToSpend, Destination, SendAmount},
+    error::TeleportError,
+    fidelity_bonds::YearAndMonth,
+    json_rpc::{
+        self, parse_params, unix_time, NoParams, RpcError, RPC_INVALID_PARAMS,
+        RPC_METHOD_NOT_FOUND, RPC_MISC_ERROR, RPC_WALLET_ERROR,
+    },
+    maker_protocol::{Maker, MakerConfig, MakerHandle},
+    offerbook_sync::{
+        get_advertised_maker_addresses, sync_offerbook_with_addresses, verify_fidelity_bonds,
+        MakerAddress,
+    },
+    recovery,
+    swap_events::SwapEvent,
+    taker_protocol::{SwapParams, Taker, TakerConfig},
+    wallet_sync::{UTXOSpendInfo, Wallet},
+};
+
+pub const DEFAULT_DAEMON_PORT: u16 = 6104;
+
+const COOKIE_USERNAME: &str = "__cookie__";
+const MAX_REQUEST_HEADER_SIZE: u64 = 8 * 1024;
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+//same default as the command line
+const DEFAULT_FEE_RATE: u64 = 1000;
+
+/// Where `teleportd` serves its JSON-RPC API.
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// Port on localhost
+    pub port: u16,
+    /// Unix socket to also listen on
+    pub unix_socket: Option<PathBuf>,
+    /// Where the authentication cookie is written, `None` means the wallet file name with the
+    /// extension `.cookie`
+    pub cookie_file: Option<PathBuf>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            port: DEFAULT_DAEMON_PORT,
+            unix_socket: None,
+            cookie_file: None,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Reads the `[daemon]` section of the config file, see
+    /// [`read_config_file`](crate::config::read_config_file) for how the file is found
+    pub fn load(config_file: Option<&Path>) -> Result<DaemonConfig, TeleportError> {
+        let options = read_config_file(config_file)?.daemon;
+        let default = DaemonConfig::default();
+        Ok(DaemonConfig {
+            port: options.port.unwrap_or(default.port),
+            unix_socket: options.unix_socket,
+            cookie_file: options.cookie_file,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobKind {
+    Coinswap,
+    ResumeCoinswap,
+    Recover,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+//a background task which needs the wallet for as long as it runs
+#[derive(Debug, Clone, Serialize)]
+struct JobStatus {
+    kind: JobKind,
+    state: JobState,
+    started_at: u64,
+    finished_at: Option<u64>,
+    error: Option<String>,
+    events: Vec<SwapEvent>,
+}
+
+struct RunningMaker {
+    port: u16,
+    started_at: u64,
+    handle: MakerHandle,
+    thread: thread::JoinHandle<()>,
+}
+
+struct Daemon {
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    network: Network,
+    wallet_file_name: PathBuf,
+    maker_config: MakerConfig,
+    maker: Mutex<Option<RunningMaker>>,
+    job: Arc<Mutex<Option<JobStatus>>>,
+    cookie: String,
+    stop_requested: AtomicBool,
+    shutdown: Notify,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FidelityBondAddressParams {
+    year_and_month: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SendAmountParam {
+    Sats(u64),
+    Max(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DirectSendParams {
+    send_amount: SendAmountParam,
+    destination: String,
+    #[serde(default)]
+    coins_to_spend: Vec<String>,
+    fee_rate: Option<u64>,
+    broadcast: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartMakerParams {
+    port: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartCoinswapParams {
+    send_amount: u64,
+    maker_count: Option<u16>,
+    tx_count: Option<u32>,
+    min_fidelity_bond_value: Option<f64>,
+    max_total_fee: Option<u64>,
+    fee_rate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecoverParams {
+    hashvalue: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListOffersParams {
+    maker_address: Option<String>,
+}
+
+fn invalid_params<E: std::fmt::Debug>(what: &str) -> impl FnOnce(E) -> RpcError + '_ {
+    move |e| RpcError::new(RPC_INVALID_PARAMS, format!("invalid {}: {:?}", what, e))
+}
+
+impl Daemon {
+    fn is_authorized(&self, authorization: Option<&String>) -> bool {
+        let credentials = match authorization
+            .and_then(|a| a.strip_prefix("Basic "))
+            .and_then(|c| BASE64.decode(c.trim()).ok())
+        {
+            Some(c) => c,
+            None => return false,
+        };
+        //compare in constant time so the cookie cant be guessed byte by byte
+        credentials.len() == self.cookie.len()
+            && credentials
+                .iter()
+                .zip(self.cookie.as_bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    fn maker_status(&self) -> Value {
+        match &*self.maker.lock().unwrap() {
+            Some(running) if !running.thread.is_finished() => json!({
+                "running": true,
+                "port": running.port,
+                "started_at": running.started_at,
+            }),
+            _ => json!({ "running": false }),
+        }
+    }
+
+    fn is_maker_running(&self) -> bool {
+        self.maker
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|running| !running.thread.is_finished())
+    }
+
+    fn check_wallet_not_busy(job: &Option<JobStatus>) -> Result<(), RpcError> {
+        match job {
+            Some(status) if status.state == JobState::Running => Err(RpcError::new(
+                RPC_WALLET_ERROR,
+                format!(
+                    "wallet is busy with {}, check getswapstatus",
+                    json!(status.kind).as_str().unwrap()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn wallet_not_busy(&self) -> Result<(), RpcError> {
+        Daemon::check_wallet_not_busy(&self.job.lock().unwrap())
+    }
+
+    //runs a blocking json-rpc method, listoffers and stop are handled in handle_rpc_call
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "getinfo" => {
+                parse_params::<NoParams>(params)?;
+                self.get_info()
+            }
+            "getbalance" => {
+                parse_params::<NoParams>(params)?;
+                self.get_balance()
+            }
+            "listunspent" => {
+                parse_params::<NoParams>(params)?;
+                self.list_unspent()
+            }
+            "getnewaddress" => {
+                parse_params::<NoParams>(params)?;
+                self.wallet_not_busy()?;
+                let address = self
+                    .wallet
+                    .write()
+                    .unwrap()
+                    .get_next_external_address(self.rpc.as_ref())?;
+                Ok(json!(address.to_string()))
+            }
+            "getfidelitybondaddress" => {
+                let params = parse_params::<FidelityBondAddressParams>(params)?;
+                let year_and_month = YearAndMonth::from_str(&params.year_and_month)
+                    .map_err(invalid_params("year_and_month"))?;
+                self.wallet_not_busy()?;
+                let (address, locktime) = self
+                    .wallet
+                    .read()
+                    .unwrap()
+                    .get_timelocked_address(&year_and_month);
+                Ok(json!({ "address": address.to_string(), "locktime": locktime }))
+            }
+            "directsend" => self.direct_send(parse_params(params)?),
+            "startmaker" => self.start_maker(parse_params(params)?),
+            "stopmaker" => {
+                parse_params::<NoParams>(params)?;
+                self.stop_maker()
+            }
+            "startcoinswap" => self.start_coinswap(parse_params(params)?),
+            "resumecoinswap" => {
+                parse_params::<NoParams>(params)?;
+                let offerbook_file_path = self.wallet_file_name.with_extension("offerbook");
+                let swap_journal_file_path = self.wallet_file_name.with_extension("swap");
+                self.start_job(JobKind::ResumeCoinswap, move |rpc, wallet, job_status| {
+                    let taker = Taker::new(
+                        rpc,
+                        wallet,
+                        TakerConfig::default(),
+                        &offerbook_file_path,
+                        &swap_journal_file_path,
+                    )?;
+                    run_taker_job(taker, None, job_status)
+                })
+            }
+            "recover" => {
+                let params = parse_params::<RecoverParams>(params)?;
+                let hashvalue = params
+                    .hashvalue
+                    .map(|h| Hash160::from_hex(&h))
+                    .transpose()
+                    .map_err(invalid_params("hashvalue"))?;
+                let recovery_file_path = self.wallet_file_name.with_extension("recovery");
+                self.start_job(JobKind::Recover, move |rpc, wallet, _job_status| {
+                    recovery::run_recovery(
+                        rpc.as_ref(),
+                        &mut wallet.write().unwrap(),
+                        hashvalue,
+                        &recovery_file_path,
+                    )
+                })
+            }
+            "getswapstatus" => {
+                parse_params::<NoParams>(params)?;
+                Ok(json!(*self.job.lock().unwrap()))
+            }
+            _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+
+    fn get_info(&self) -> Result<Value, RpcError> {
+        Ok(json!({
+            "network": self.network.to_string(),
+            "wallet_file": self.wallet_file_name.to_string_lossy(),
+            "block_height": self.rpc.get_block_count()?,
+            "maker": self.maker_status(),
+            "swap": *self.job.lock().unwrap(),
+        }))
+    }
+
+    fn get_balance(&self) -> Result<Value, RpcError> {
+        self.wallet_not_busy()?;
+        let wallet = self.wallet.read().unwrap();
+        let utxos = wallet.list_unspent_from_wallet(self.rpc.as_ref(), false, true)?;
+        let (fidelity_bond_utxos, utxos): (Vec<_>, Vec<_>) = utxos
+            .iter()
+            .partition(|(_, usi)| matches!(usi, UTXOSpendInfo::FidelityBondCoin { .. }));
+        let sum = |utxos: &[&(ListUnspentEntry, UTXOSpendInfo)]| {
+            utxos.iter().map(|(u, _)| u.amount.as_sat()).sum::<u64>()
+        };
+        let incomplete_coinswaps = wallet
+            .find_incomplete_coinswaps(self.rpc.as_ref())?
+            .iter()
+            .map(|(hashvalue, (incoming, outgoing))| {
+                json!({
+                    "hashvalue": hashvalue.to_hex(),
+                    "incoming": incoming.iter().map(|(u, _)| u.amount.as_sat()).sum::<u64>(),
+                    "outgoing": outgoing.iter().map(|(u, _)| u.amount.as_sat()).sum::<u64>(),
+                })
+            })
+            .collect::<Vec<Value>>();
+        Ok(json!({
+            "balance": sum(&utxos),
+            "coin_count": utxos.len(),
+            "fidelity_bond_balance": sum(&fidelity_bond_utxos),
+            "incomplete_coinswaps": incomplete_coinswaps,
+        }))
+    }
+
+    fn list_unspent(&self) -> Result<Value, RpcError> {
+        self.wallet_not_busy()?;
+        let wallet = self.wallet.read().unwrap();
+        let utxos = wallet.list_unspent_from_wallet(self.rpc.as_ref(), true, true)?;
+        Ok(utxos
+            .iter()
+            .map(|(utxo, usi)| {
+                json!({
+                    "coin": format!("{}:{}", utxo.txid, utxo.vout),
+                    "address": utxo.address.as_ref().map(|a| a.to_string()),
+                    "type": match usi {
+                        UTXOSpendInfo::SeedCoin { .. } => "seed",
+                        UTXOSpendInfo::SwapCoin { .. } => "swapcoin",
+                        UTXOSpendInfo::TimelockContract { .. } => "timelockcontract",
+                        UTXOSpendInfo::HashlockContract { .. } => "hashlockcontract",
+                        UTXOSpendInfo::FidelityBondCoin { .. } => "fidelitybond",
+                    },
+                    "confirmations": utxo.confirmations,
+                    "amount": utxo.amount.as_sat(),
+                })
+            })
+            .collect())
+    }
+
+    fn direct_send(&self, params: DirectSendParams) -> Result<Value, RpcError> {
+        let send_amount = match params.send_amount {
+            SendAmountParam::Sats(sats) => SendAmount::Amount(Amount::from_sat(sats)),
+            SendAmountParam::Max(s) if s == "max" => SendAmount::Max,
+            SendAmountParam::Max(s) => return Err(invalid_params("send_amount")(s)),
+        };
+        let destination =
+            Destination::from_str(&params.destination).map_err(invalid_params("destination"))?;
+        let coins_to_spend = params
+            .coins_to_spend
+            .iter()
+            .map(|c| CoinToSpend::from_str(c))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_params("coins_to_spend"))?;
+        self.wallet_not_busy()?;
+        let tx = self.wallet.write().unwrap().create_direct_send(
+            self.rpc.as_ref(),
+            params.fee_rate.unwrap_or(DEFAULT_FEE_RATE),
+            send_amount,
+            destination,
+            &coins_to_spend,
+        )?;
+        let broadcast = params.broadcast.unwrap_or(true);
+        if broadcast {
+            self.rpc.send_raw_transaction(&tx)?;
+            log::info!("Direct send broadcasted {}", tx.txid());
+        }
+        Ok(json!({
+            "txid": tx.txid().to_string(),
+            "hex": bitcoin::consensus::encode::serialize_hex(&tx),
+            "broadcast": broadcast,
+        }))
+    }
+
+    fn start_maker(&self, params: StartMakerParams) -> Result<Value, RpcError> {
+        //lock order is always job then maker
+        let job = self.job.lock().unwrap();
+        Daemon::check_wallet_not_busy(&job)?;
+        let mut running = self.maker.lock().unwrap();
+        if running.as_ref().is_some_and(|r| !r.thread.is_finished()) {
+            return Err(RpcError::new(RPC_MISC_ERROR, "maker is already running"));
+        }
+        let config = MakerConfig {
+            port: params.port.unwrap_or(self.maker_config.port),
+            kill_flag: Arc::new(RwLock::new(false)),
+            ..self.maker_config.clone()
+        };
+        let port = config.port;
+        let maker = Maker::new(Arc::clone(&self.rpc), Arc::clone(&self.wallet), config)?;
+        *running = Some(RunningMaker {
+            port,
+            started_at: unix_time(),
+            handle: maker.handle(),
+            thread: thread::spawn(move || {
+                let result = tokio::runtime::Runtime::new()
+                    .map_err(TeleportError::from)
+                    .and_then(|runtime| runtime.block_on(maker.run()));
+                match result {
+                    Ok(_o) => log::info!("maker ended without error"),
+                    Err(e) => log::error!("maker ended with err: {:?}", e),
+                }
+            }),
+        });
+        log::info!("Started maker on port {}", port);
+        Ok(json!({ "port": port }))
+    }
+
+    fn stop_maker(&self) -> Result<Value, RpcError> {
+        let running = self
+            .maker
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| RpcError::new(RPC_MISC_ERROR, "maker is not running"))?;
+        running.handle.shutdown();
+        if running.thread.join().is_err() {
+            return Err(RpcError::new(RPC_MISC_ERROR, "maker thread panicked"));
+        }
+        log::info!("Stopped maker");
+        Ok(Value::Null)
+    }
+
+    fn start_coinswap(&self, params: StartCoinswapParams) -> Result<Value, RpcError> {
+        let swap_params = SwapParams {
+            send_amount: params.send_amount,
+            maker_count: params.maker_count.unwrap_or(2),
+            tx_count: params.tx_count.unwrap_or(3),
+            min_fidelity_bond_value: params.min_fidelity_bond_value.unwrap_or(0.0),
+            max_total_fee: params.max_total_fee,
+            required_confirms: 1,
+            fee_rate: params.fee_rate.unwrap_or(DEFAULT_FEE_RATE),
+        };
+        let offerbook_file_path = self.wallet_file_name.with_extension("offerbook");
+        let swap_journal_file_path = self.wallet_file_name.with_extension("swap");
+        self.start_job(JobKind::Coinswap, move |rpc, wallet, job_status| {
+            let taker = Taker::new(
+                rpc,
+                wallet,
+                TakerConfig::default(),
+                &offerbook_file_path,
+                &swap_journal_file_path,
+            )?;
+            run_taker_job(taker, Some(swap_params), job_status)
+        })
+    }
+
+    //runs the job on its own thread, as the recovery code blocks
+    //the wallet isnt locked for the whole job, the other wallet methods check for a running job
+    fn start_job<F>(&self, kind: JobKind, job: F) -> Result<Value, RpcError>
+    where
+        F: FnOnce(
+                Arc<dyn BlockchainBackend>,
+                Arc<RwLock<Wallet>>,
+                Arc<Mutex<Option<JobStatus>>>,
+            ) -> Result<(), TeleportError>
+            + Send
+            + 'static,
+    {
+        let mut status = self.job.lock().unwrap();
+        Daemon::check_wallet_not_busy(&status)?;
+        if self.is_maker_running() {
+            return Err(RpcError::new(
+                RPC_WALLET_ERROR,
+                "wallet is busy with the maker, stop it first",
+            ));
+        }
+        *status = Some(JobStatus {
+            kind,
+            state: JobState::Running,
+            started_at: unix_time(),
+            finished_at: None,
+            error: None,
+            events: Vec::new(),
+        });
+        let rpc = Arc::clone(&self.rpc);
+        let wallet = Arc::clone(&self.wallet);
+        let job_status = Arc::clone(&self.job);
+        thread::spawn(move || {
+            let result = job(rpc, wallet, Arc::clone(&job_status));
+            if let Err(e) = &result {
+                log::error!("{} failed: {:?}", json!(kind).as_str().unwrap(), e);
+            }
+            let mut status = job_status.lock().unwrap();
+            let status = status.as_mut().unwrap();
+            status.finished_at = Some(unix_time());
+            status.state = if result.is_ok() {
+                JobState::Succeeded
+            } else {
+                JobState::Failed
+            };
+            status.error = result.err().map(|e| format!("{:?}", e));
+        });
+        Ok(json!(*status))
+    }
+}
+
+//sends a new coinswap, or resumes the one in the swap journal if there are no swap params
+//the swap events are recorded in the job status as they happen
+fn run_taker_job(
+    mut taker: Taker,
+    swap_params: Option<SwapParams>,
+    job_status: Arc<Mutex<Option<JobStatus>>>,
+) -> Result<(), TeleportError> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut events = taker.subscribe();
+    let recorder = runtime.spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Some(status) = job_status.lock().unwrap().as_mut() {
+                status.events.push(event);
+            }
+        }
+    });
+    let result = runtime.block_on(async {
+        match swap_params {
+            Some(swap_params) => taker.send_coinswap(swap_params).await,
+            None => taker.resume_coinswap().await,
+        }
+    });
+    //the recorder ends after the last event once the taker is dropped
+    drop(taker);
+    let _ = runtime.block_on(recorder);
+    result
+}
+
+async fn list_offers(daemon: Arc<Daemon>, params: ListOffersParams) -> Result<Value, RpcError> {
+    let maker_addresses = match params.maker_address {
+        Some(address) => vec![MakerAddress::from_host_and_port(address)],
+        None => get_advertised_maker_addresses(daemon.network)
+            .await
+            .map_err(|e| {
+                RpcError::new(
+                    RPC_MISC_ERROR,
+                    format!("unable to sync maker addresses: {:?}", e),
+                )
+            })?,
+    };
+    let offers_addresses = sync_offerbook_with_addresses(maker_addresses).await;
+    let offers_addresses = tokio::task::spawn_blocking(move || {
+        verify_fidelity_bonds(daemon.rpc.as_ref(), offers_addresses)
+    })
+    .await
+    .unwrap()?;
+    Ok(offers_addresses
+        .iter()
+        .map(|(offer_address, fidelity_bond_value)| {
+            let o = &offer_address.offer;
+            json!({
+                "address": offer_address.address.to_string(),
+                "max_size": o.max_size,
+                "min_size": o.min_size,
+                "absolute_fee_sat": o.absolute_fee_sat,
+                "amount_relative_fee_ppb": o.amount_relative_fee_ppb,
+                "time_relative_fee_ppb": o.time_relative_fee_ppb,
+                "required_confirms": o.required_confirms,
+                "minimum_locktime": o.minimum_locktime,
+                "fidelity_bond_value": fidelity_bond_value,
+            })
+        })
+        .collect())
+}
+
+async fn handle_rpc_call(
+    daemon: Arc<Daemon>,
+    method: String,
+    params: Value,
+) -> Result<Value, RpcError> {
+    match method.as_str() {
+        "listoffers" => list_offers(daemon, parse_params(params)?).await,
+        "stop" => {
+            parse_params::<NoParams>(params)?;
+            //the shutdown happens once the response is sent
+            daemon.stop_requested.store(true, Ordering::SeqCst);
+            Ok(json!("teleportd stopping"))
+        }
+        _ => tokio::task::spawn_blocking(move || daemon.call(&method, params))
+            .await
+            .unwrap_or_else(|e| Err(RpcError::new(RPC_MISC_ERROR, e.to_string()))),
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    //header names are lowercase
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+//returns the http status code and reason to respond with if the request is invalid
+async fn read_http_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<HttpRequest, (u16, &'static str)> {
+    let mut lines = Vec::<String>::new();
+    {
+        let mut header_reader = (&mut *reader).take(MAX_REQUEST_HEADER_SIZE);
+        loop {
+            let mut line = String::new();
+            let n = header_reader
+                .read_line(&mut line)
+                .await
+                .map_err(|_| (400, "Bad Request"))?;
+            if n == 0 {
+                return Err(if header_reader.limit() == 0 {
+                    (431, "Request Header Fields Too Large")
+                } else {
+                    (400, "Bad Request")
+                });
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+    }
+    let method = lines
+        .first()
+        .and_then(|request_line| request_line.split(' ').next())
+        .ok_or((400, "Bad Request"))?
+        .to_string();
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect::<HashMap<String, String>>();
+    if headers.contains_key("transfer-encoding") {
+        return Err((411, "Length Required"));
+    }
+    let content_length = match headers.get("content-length") {
+        Some(l) => l.parse::<usize>().map_err(|_| (400, "Bad Request"))?,
+        None => 0,
+    };
+    if content_length > MAX_REQUEST_BODY_SIZE {
+        return Err((413, "Payload Too Large"));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|_| (400, "Bad Request"))?;
+    Ok(HttpRequest {
+        method,
+        headers,
+        body,
+    })
+}
+
+async fn write_http_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    (status, reason): (u16, &str),
+    extra_headers: &str,
+    body: &[u8],
+) -> Result<(), TeleportError> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n{}\r\n",
+        status,
+        reason,
+        body.len(),
+        extra_headers
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    daemon: Arc<Daemon>,
+    stream: S,
+) -> Result<(), TeleportError> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let request = match timeout(
+        Duration::from_secs(REQUEST_TIMEOUT_SECS),
+        read_http_request(&mut reader),
+    )
+    .await
+    {
+        Ok(Ok(request)) => request,
+        Ok(Err(status)) => return write_http_response(&mut writer, status, "", &[]).await,
+        Err(_) => return Ok(()),
+    };
+    if !daemon.is_authorized(request.headers.get("authorization")) {
+        return write_http_response(
+            &mut writer,
+            (401, "Unauthorized"),
+            "WWW-Authenticate: Basic realm=\"teleportd\"\r\n",
+            &[],
+        )
+        .await;
+    }
+    if request.method != "POST" {
+        return write_http_response(&mut writer, (405, "Method Not Allowed"), "", &[]).await;
+    }
+    let response = json_rpc::handle_request(&request.body, |method, params| {
+        handle_rpc_call(Arc::clone(&daemon), method, params)
+    })
+    .await;
+    write_http_response(
+        &mut writer,
+        (200, "OK"),
+        "",
+        &serde_json::to_vec(&response).unwrap(),
+    )
+    .await?;
+    if daemon.stop_requested.load(Ordering::SeqCst) {
+        daemon.shutdown.notify_one();
+    }
+    Ok(())
+}
+
+//the cookie is random for every run and only readable by the user running the daemon
+fn write_cookie_file(path: &Path) -> Result<String, TeleportError> {
+    let mut secret = [0u8; 32];
+    OsRng::new().unwrap().fill_bytes(&mut secret);
+    let cookie = format!("{}:{}", COOKIE_USERNAME, secret.to_hex());
+    //delete any old cookie file, as permissions are only set when creating the file
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(cookie.as_bytes())?;
+    Ok(cookie)
+}
+
+#[cfg(unix)]
+fn spawn_unix_socket_server(daemon: Arc<Daemon>, path: &Path) -> Result<(), TeleportError> {
+    let listener = json_rpc::bind_private_unix_socket(path)?;
+    log::info!("Listening for RPC On Unix Socket {}", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let daemon = Arc::clone(&daemon);
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(daemon, stream).await {
+                            log::debug!("RPC connection error: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Error accepting RPC connection: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_unix_socket_server(_daemon: Arc<Daemon>, _path: &Path) -> Result<(), TeleportError> {
+    Err(TeleportError::Protocol(
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+#[tokio::main]
+pub async fn start_daemon(
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Wallet,
+    wallet_file_name: &Path,
+    config: DaemonConfig,
+    maker_config: MakerConfig,
+) {
+    match run(rpc, wallet, wallet_file_name, config, maker_config).await {
+        Ok(_o) => log::info!("daemon ended without error"),
+        Err(e) => log::error!("daemon ended with err: {:?}", e),
+    };
+}
+
+async fn run(
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Wallet,
+    wallet_file_name: &Path,
+    config: DaemonConfig,
+    maker_config: MakerConfig,
+) -> Result<(), TeleportError> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.port)).await?;
+    let cookie_file = config
+        .cookie_file
+        .clone()
+        .unwrap_or_else(|| wallet_file_name.with_extension("cookie"));
+    let cookie = write_cookie_file(&cookie_file)?;
+
+    let daemon = Arc::new(Daemon {
+        rpc,
+        network: wallet.network,
+        wallet: Arc::new(RwLock::new(wallet)),
+        wallet_file_name: wallet_file_name.to_path_buf(),
+        maker_config,
+        maker: Mutex::new(None),
+        job: Arc::new(Mutex::new(None)),
+        cookie,
+        stop_requested: AtomicBool::new(false),
+        shutdown: Notify::new(),
+    });
+    if let Some(path) = &config.unix_socket {
+        spawn_unix_socket_server(Arc::clone(&daemon), path)?;
+    }
+    log::info!(
+        "Listening for RPC On Port {}, cookie file {}",
+        config.port,
+        cookie_file.display()
+    );
+
+    loop {
+        select! {
+            new_client = listener.accept() => {
+                let (stream, _addr) = new_client?;
+                let daemon = Arc::clone(&daemon);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(daemon, stream).await {
+                        log::debug!("RPC connection error: {:?}", e);
+                    }
+                });
+            },
+            _ = daemon.shutdown.notified() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    log::info!("Shutting down daemon");
+    if daemon.is_maker_running() {
+        let stopping_daemon = Arc::clone(&daemon);
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || stopping_daemon.stop_maker()).await
+        {
+            log::warn!("Error stopping maker: {:?}", e);
+        }
+    }
+    if let Some(status) = &*daemon.job.lock().unwrap() {
+        if status.state == JobState::Running {
+            log::warn!(
+                "Shutting down during {}, continue it with resumecoinswap or recover",
+                json!(status.kind).as_str().unwrap()
+            );
+        }
+    }
+    fs::remove_file(&cookie_file)?;
+    if let Some(path) = &config.unix_socket {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -2,9 +2,8 @@ use std::{num::ParseIntError, str::FromStr};
 
 use bitcoin::{Address, Amount, Network, OutPoint, Script, Transaction, TxIn, TxOut};
 
-use bitcoincore_rpc::{json::ListUnspentResultEntry, Client};
-
 use crate::{
+    blockchain_backend::{BlockchainBackend, ListUnspentEntry},
     contracts::SwapCoin,
     error::TeleportError,
     fidelity_bonds::get_locktime_from_index,
@@ -101,14 +100,14 @@ impl FromStr for CoinToSpend {
 impl Wallet {
     pub fn create_direct_send(
         &mut self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         fee_rate: u64,
         send_amount: SendAmount,
         destination: Destination,
         coins_to_spend: &[CoinToSpend],
     ) -> Result<Transaction, TeleportError> {
         let mut tx_inputs = Vec::<TxIn>::new();
-        let mut unspent_inputs = Vec::<(ListUnspentResultEntry, UTXOSpendInfo)>::new();
+        let mut unspent_inputs = Vec::<(ListUnspentEntry, UTXOSpendInfo)>::new();
         //TODO this search within a search could get very slow
         let list_unspent_result = self.list_unspent_from_wallet(rpc, true, true)?;
         for (list_unspent_entry, spend_info) in list_unspent_result {
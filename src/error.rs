@@ -1,4 +1,9 @@
-use std::{error, io};
+use std::{error, fmt, io};
+
+use crate::{
+    directory_servers::DirectoryServerError,
+    messages::{ProtocolError, ProtocolErrorCode},
+};
 
 // error enum for the whole project
 // try to make functions return this
@@ -9,8 +14,28 @@ pub enum TeleportError {
     Protocol(&'static str),
     Rpc(bitcoincore_rpc::Error),
     Socks(tokio_socks::Error),
+    Database(rusqlite::Error),
+    /// A taker or maker was stopped through its handle
+    Cancelled,
+    /// The peer rejected our message with an error message
+    Peer(ProtocolError),
+    /// We rejected the peer's message, it is told why with an error message
+    Rejected(ProtocolErrorCode, &'static str),
+}
+
+/// A file we can't read, because it's corrupted or was written by a newer version of teleport
+pub(crate) fn file_format_error(message: &str) -> TeleportError {
+    TeleportError::Disk(io::Error::new(io::ErrorKind::InvalidData, message))
 }
 
+impl fmt::Display for TeleportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for TeleportError {}
+
 impl From<Box<dyn error::Error + Send>> for TeleportError {
     fn from(e: Box<dyn error::Error + Send>) -> TeleportError {
         TeleportError::Network(e)
@@ -34,3 +59,18 @@ impl From<tokio_socks::Error> for TeleportError {
         TeleportError::Socks(e)
     }
 }
+
+impl From<rusqlite::Error> for TeleportError {
+    fn from(e: rusqlite::Error) -> TeleportError {
+        TeleportError::Database(e)
+    }
+}
+
+impl From<DirectoryServerError> for TeleportError {
+    fn from(e: DirectoryServerError) -> TeleportError {
+        match e {
+            DirectoryServerError::Reqwest(e) => TeleportError::Network(Box::new(e)),
+            DirectoryServerError::Other(e) => TeleportError::Protocol(e),
+        }
+    }
+}
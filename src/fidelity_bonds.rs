@@ -28,12 +28,8 @@ use bitcoin::{
     Address, OutPoint,
 };
 
-use bitcoincore_rpc::{
-    json::{GetTxOutResult, ListUnspentResultEntry},
-    Client, RpcApi,
-};
-
 use crate::{
+    blockchain_backend::{BlockchainBackend, ListUnspentEntry, TxOutInfo},
     contracts::redeemscript_to_scriptpubkey,
     error::TeleportError,
     messages::FidelityBondProof,
@@ -114,6 +110,16 @@ fn create_cert_msg_hash(cert_pubkey: &PublicKey, cert_expiry: u16) -> Message {
     Message::from_slice(&sha256d::Hash::hash(&btc_signed_msg)).unwrap()
 }
 
+//makers which encrypt sign their noise static key along with the hostname, so the key cant be
+//removed from the proof to make the taker accept an unencrypted connection
+fn create_onion_msg_hash(onion_hostname: &str, noise_static_key: Option<&[u8; 32]>) -> Message {
+    let mut onion_msg = onion_hostname.as_bytes().to_vec();
+    if let Some(key) = noise_static_key {
+        onion_msg.extend_from_slice(key);
+    }
+    Message::from_slice(&sha256d::Hash::hash(&onion_msg)).unwrap()
+}
+
 pub struct HotWalletFidelityBond {
     pub utxo: OutPoint,
     utxo_key: PublicKey,
@@ -122,7 +128,7 @@ pub struct HotWalletFidelityBond {
 }
 
 impl HotWalletFidelityBond {
-    pub fn new(wallet: &Wallet, utxo: &ListUnspentResultEntry, spend_info: &UTXOSpendInfo) -> Self {
+    pub fn new(wallet: &Wallet, utxo: &ListUnspentEntry, spend_info: &UTXOSpendInfo) -> Self {
         let index = if let UTXOSpendInfo::FidelityBondCoin {
             index,
             input_value: _,
@@ -144,10 +150,13 @@ impl HotWalletFidelityBond {
         }
     }
 
+    /// The proof for a maker at `onion_hostname`, committing to its `noise_static_key` if it
+    /// encrypts clearnet connections.
     pub fn create_proof(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         onion_hostname: &str,
+        noise_static_key: Option<[u8; 32]>,
     ) -> Result<FidelityBondProof, TeleportError> {
         const BLOCK_COUNT_SAFETY: u64 = 2;
         const RETARGET_INTERVAL: u64 = 2016;
@@ -164,8 +173,7 @@ impl HotWalletFidelityBond {
         let cert_msg_hash = create_cert_msg_hash(&cert_pubkey, cert_expiry);
         let cert_sig = secp.sign(&cert_msg_hash, &self.utxo_privkey);
 
-        let onion_msg_hash =
-            Message::from_slice(&sha256d::Hash::hash(onion_hostname.as_bytes())).unwrap();
+        let onion_msg_hash = create_onion_msg_hash(onion_hostname, noise_static_key.as_ref());
         let onion_sig = secp.sign(&onion_msg_hash, &cert_privkey);
 
         Ok(FidelityBondProof {
@@ -176,6 +184,7 @@ impl HotWalletFidelityBond {
             cert_expiry,
             cert_pubkey,
             onion_sig,
+            noise_static_key,
         })
     }
 }
@@ -183,14 +192,13 @@ impl HotWalletFidelityBond {
 impl FidelityBondProof {
     pub fn verify_and_get_txo(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         block_count: u64,
         onion_hostname: &str,
-    ) -> Result<GetTxOutResult, TeleportError> {
+    ) -> Result<TxOutInfo, TeleportError> {
         let secp = Secp256k1::new();
 
-        let onion_msg_hash =
-            Message::from_slice(&sha256d::Hash::hash(onion_hostname.as_bytes())).unwrap();
+        let onion_msg_hash = create_onion_msg_hash(onion_hostname, self.noise_static_key.as_ref());
         secp.verify(&onion_msg_hash, &self.onion_sig, &self.cert_pubkey.key)
             .map_err(|_| TeleportError::Protocol("onion sig does not verify"))?;
 
@@ -199,7 +207,7 @@ impl FidelityBondProof {
             .map_err(|_| TeleportError::Protocol("cert sig does not verify"))?;
 
         let txo_data = rpc
-            .get_tx_out(&self.utxo.txid, self.utxo.vout, None)?
+            .get_tx_out(&self.utxo.txid, self.utxo.vout)?
             .ok_or(TeleportError::Protocol("fidelity bond UTXO doesnt exist"))?;
 
         const RETARGET_INTERVAL: u64 = 2016;
@@ -211,7 +219,7 @@ impl FidelityBondProof {
             self.locktime,
             &self.utxo_key,
         ));
-        if txo_data.script_pub_key.hex != implied_spk.into_bytes() {
+        if txo_data.script_pubkey != implied_spk {
             return Err(TeleportError::Protocol(
                 "UTXO script doesnt match given script",
             ));
@@ -224,18 +232,33 @@ impl FidelityBondProof {
         Ok(txo_data)
     }
 
+    pub fn verify_and_get_value(
+        &self,
+        rpc: &dyn BlockchainBackend,
+        block_count: u64,
+        mediantime: u64,
+        onion_hostname: &str,
+    ) -> Result<f64, TeleportError> {
+        let txo_data = self.verify_and_get_txo(rpc, block_count, onion_hostname)?;
+        //the bond value is calculated from the time it confirmed
+        if txo_data.confirmations == 0 {
+            return Err(TeleportError::Protocol("fidelity bond UTXO is unconfirmed"));
+        }
+        self.calculate_fidelity_bond_value(rpc, block_count, &txo_data, mediantime)
+    }
+
     pub fn calculate_fidelity_bond_value(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         block_count: u64,
-        txo_data: &GetTxOutResult,
+        txo_data: &TxOutInfo,
         mediantime: u64,
     ) -> Result<f64, TeleportError> {
         let blockhash = rpc.get_block_hash(block_count - txo_data.confirmations as u64 + 1)?;
         Ok(calculate_timelocked_fidelity_bond_value(
             txo_data.value.as_sat(),
             self.locktime,
-            rpc.get_block_header_info(&blockhash)?.time as i64,
+            rpc.get_block_time(&blockhash)? as i64,
             mediantime,
         ))
     }
@@ -264,9 +287,9 @@ fn calculate_timelocked_fidelity_bond_value(
 }
 
 fn calculate_timelocked_fidelity_bond_value_from_utxo(
-    utxo: &ListUnspentResultEntry,
+    utxo: &ListUnspentEntry,
     usi: &UTXOSpendInfo,
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
 ) -> Result<f64, TeleportError> {
     Ok(calculate_timelocked_fidelity_bond_value(
         utxo.amount.as_sat(),
@@ -281,11 +304,8 @@ fn calculate_timelocked_fidelity_bond_value_from_utxo(
                 panic!("bug, should be fidelity bond coin")
             },
         ),
-        rpc.get_transaction(&utxo.txid, Some(true))?
-            .info
-            .blocktime
-            .unwrap() as i64,
-        rpc.get_blockchain_info()?.median_time,
+        rpc.get_transaction(&utxo.txid)?.blocktime.unwrap() as i64,
+        rpc.get_median_time()?,
     ))
 }
 
@@ -395,9 +415,9 @@ impl Wallet {
     //returns Ok(None) if no fidelity bonds in wallet
     pub fn find_most_valuable_fidelity_bond(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
     ) -> Result<Option<HotWalletFidelityBond>, TeleportError> {
-        let list_unspent_result = self.list_unspent_from_wallet(&rpc, false, true)?;
+        let list_unspent_result = self.list_unspent_from_wallet(rpc, false, true)?;
         let fidelity_bond_utxos = list_unspent_result
             .iter()
             .filter(|(utxo, _)| utxo.confirmations > 0)
@@ -408,7 +428,7 @@ impl Wallet {
                 } => true,
                 _ => false,
             })
-            .collect::<Vec<&(ListUnspentResultEntry, UTXOSpendInfo)>>();
+            .collect::<Vec<&(ListUnspentEntry, UTXOSpendInfo)>>();
         let fidelity_bond_values = fidelity_bond_utxos
             .iter()
             .map(|(utxo, usi)| calculate_timelocked_fidelity_bond_value_from_utxo(utxo, usi, rpc))
@@ -426,11 +446,131 @@ impl Wallet {
                 )
             }))
     }
+
+    //returns Ok(None) if no fidelity bonds in wallet
+    pub fn create_most_valuable_fidelity_bond_proof(
+        &self,
+        rpc: &dyn BlockchainBackend,
+        onion_hostname: &str,
+        noise_static_key: Option<[u8; 32]>,
+    ) -> Result<Option<FidelityBondProof>, TeleportError> {
+        self.find_most_valuable_fidelity_bond(rpc)?
+            .map(|bond| bond.create_proof(rpc, onion_hostname, noise_static_key))
+            .transpose()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        messages::Offer,
+        offerbook_sync::{verify_fidelity_bonds, MakerAddress, OfferAndAddress},
+        simulated_blockchain::SimulatedBlockchain,
+    };
+    use bitcoin::{Amount, Network};
+
+    fn create_bond(rpc: &SimulatedBlockchain) -> HotWalletFidelityBond {
+        let (utxo_key, utxo_privkey) = generate_keypair();
+        let locktime = 1_900_000_000;
+        let address = Address::p2wsh(
+            &create_timelocked_redeemscript(locktime, &utxo_key),
+            Network::Regtest,
+        );
+        let txid = rpc
+            .send_to_address(&address, Amount::from_sat(1_000_000))
+            .unwrap();
+        rpc.mine_blocks(1);
+        let vout = rpc
+            .get_raw_transaction(&txid, None)
+            .unwrap()
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == address.script_pubkey())
+            .unwrap() as u32;
+        HotWalletFidelityBond {
+            utxo: OutPoint { txid, vout },
+            utxo_key,
+            locktime,
+            utxo_privkey,
+        }
+    }
+
+    #[test]
+    fn test_copied_fidelity_bond_proof() {
+        let rpc = SimulatedBlockchain::new();
+        let bond = create_bond(&rpc);
+        let utxo_key = bond.utxo_key;
+
+        let honest = MakerAddress::Tor {
+            address: "honest.onion:6102".to_string(),
+        };
+        let copier = MakerAddress::Tor {
+            address: "copier.onion:6102".to_string(),
+        };
+        let offer = |address: &MakerAddress, proof: &FidelityBondProof| OfferAndAddress {
+            offer: Offer {
+                absolute_fee_sat: 1000,
+                amount_relative_fee_ppb: 10_000_000,
+                time_relative_fee_ppb: 100_000,
+                required_confirms: 1,
+                minimum_locktime: 48,
+                max_size: 1_000_000,
+                min_size: 10_000,
+                tweakable_point: utxo_key,
+                fidelity_bond_proof: Some(proof.clone()),
+                noise_static_key: None,
+            },
+            address: address.clone(),
+        };
+
+        //a copied proof doesnt verify for the copier's hostname so the honest maker is kept
+        let proof = bond
+            .create_proof(&rpc, honest.get_fidelity_bond_hostname(), None)
+            .unwrap();
+        let verified =
+            verify_fidelity_bonds(&rpc, vec![offer(&honest, &proof), offer(&copier, &proof)])
+                .unwrap();
+        assert_eq!(verified.len(), 1);
+        assert_eq!(verified[0].0.address, honest);
+        assert!(verified[0].1.unwrap() > 0.0);
+
+        //a bond which verifies for two makers backs neither of them
+        let copier_proof = bond
+            .create_proof(&rpc, copier.get_fidelity_bond_hostname(), None)
+            .unwrap();
+        let verified = verify_fidelity_bonds(
+            &rpc,
+            vec![offer(&honest, &proof), offer(&copier, &copier_proof)],
+        )
+        .unwrap();
+        assert!(verified.is_empty());
+    }
+
+    #[test]
+    fn test_proof_commits_to_noise_static_key() {
+        let rpc = SimulatedBlockchain::new();
+        let bond = create_bond(&rpc);
+        let block_count = rpc.get_block_count().unwrap();
+        let hostname = "maker.onion";
+
+        let proof = bond.create_proof(&rpc, hostname, Some([7; 32])).unwrap();
+        assert_eq!(proof.noise_static_key, Some([7; 32]));
+        assert!(proof
+            .verify_and_get_txo(&rpc, block_count, hostname)
+            .is_ok());
+
+        //neither removing nor replacing the key keeps the proof valid
+        for noise_static_key in [None, Some([8; 32])] {
+            let changed = FidelityBondProof {
+                noise_static_key,
+                ..proof.clone()
+            };
+            assert!(changed
+                .verify_and_get_txo(&rpc, block_count, hostname)
+                .is_err());
+        }
+    }
 
     #[test]
     fn test_fidelity_bond_value_function_behavior() {
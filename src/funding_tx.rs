@@ -2,21 +2,13 @@
 
 use std::collections::HashMap;
 
-use bitcoin::{hashes::hex::FromHex, Address, Amount, OutPoint, Transaction, Txid};
+use bitcoin::{Address, Amount, OutPoint, Transaction, Txid};
 
-use bitcoincore_rpc::{
-    json::{CreateRawTransactionInput, WalletCreateFundedPsbtOptions},
-    Client, RpcApi,
-};
-
-use serde_json::Value;
+use bitcoincore_rpc::json::{CreateRawTransactionInput, WalletCreateFundedPsbtOptions};
 
 use bitcoin::secp256k1::rand::{rngs::OsRng, RngCore};
 
-use crate::{
-    error::TeleportError,
-    wallet_sync::{convert_json_rpc_bitcoin_to_satoshis, Wallet},
-};
+use crate::{blockchain_backend::BlockchainBackend, error::TeleportError, wallet_sync::Wallet};
 
 pub struct CreateFundingTxesResult {
     pub funding_txes: Vec<Transaction>,
@@ -27,7 +19,7 @@ pub struct CreateFundingTxesResult {
 impl Wallet {
     pub fn create_funding_txes(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         coinswap_amount: u64,
         destinations: &[Address],
         fee_rate: u64,
@@ -130,7 +122,7 @@ impl Wallet {
 
     fn create_funding_txes_random_amounts(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         coinswap_amount: u64,
         destinations: &[Address],
         fee_rate: u64,
@@ -162,19 +154,17 @@ impl Wallet {
             let wcfp_result = rpc.wallet_create_funded_psbt(
                 &[],
                 &outputs,
-                None,
-                Some(WalletCreateFundedPsbtOptions {
+                WalletCreateFundedPsbtOptions {
                     include_watching: Some(true),
                     change_address: Some(change_address.clone()),
                     fee_rate: Some(Amount::from_sat(fee_rate)),
                     ..Default::default()
-                }),
-                None,
+                },
             )?;
             total_miner_fee += wcfp_result.fee.as_sat();
             log::debug!(target: "wallet", "created funding tx, miner fee={}", wcfp_result.fee);
 
-            let funding_tx = self.from_walletcreatefundedpsbt_to_tx(rpc, &wcfp_result.psbt)?;
+            let funding_tx = self.from_walletcreatefundedpsbt_to_tx(&wcfp_result.psbt)?;
 
             rpc.lock_unspent(
                 &funding_tx
@@ -204,7 +194,7 @@ impl Wallet {
 
     fn create_mostly_sweep_txes_with_one_tx_having_change(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         coinswap_amount: u64,
         destinations: &[Address],
         fee_rate: u64,
@@ -236,16 +226,14 @@ impl Wallet {
                     sequence: None,
                 }],
                 &outputs,
-                None,
-                Some(WalletCreateFundedPsbtOptions {
+                WalletCreateFundedPsbtOptions {
                     add_inputs: Some(false),
                     subtract_fee_from_outputs: vec![0],
                     fee_rate: Some(Amount::from_sat(fee_rate)),
                     ..Default::default()
-                }),
-                None,
+                },
             )?;
-            let funding_tx = self.from_walletcreatefundedpsbt_to_tx(rpc, &wcfp_result.psbt)?;
+            let funding_tx = self.from_walletcreatefundedpsbt_to_tx(&wcfp_result.psbt)?;
             leftover_coinswap_amount -= funding_tx.output[0].value;
 
             total_miner_fee += wcfp_result.fee.as_sat();
@@ -275,16 +263,14 @@ impl Wallet {
         let wcfp_result = rpc.wallet_create_funded_psbt(
             &leftover_inputs,
             &outputs,
-            None,
-            Some(WalletCreateFundedPsbtOptions {
+            WalletCreateFundedPsbtOptions {
                 add_inputs: Some(false),
                 subtract_fee_from_outputs: vec![0],
                 fee_rate: Some(Amount::from_sat(fee_rate)),
                 ..Default::default()
-            }),
-            None,
+            },
         )?;
-        let funding_tx = self.from_walletcreatefundedpsbt_to_tx(rpc, &wcfp_result.psbt)?;
+        let funding_tx = self.from_walletcreatefundedpsbt_to_tx(&wcfp_result.psbt)?;
         leftover_coinswap_amount -= funding_tx.output[0].value;
 
         total_miner_fee += wcfp_result.fee.as_sat();
@@ -306,16 +292,14 @@ impl Wallet {
                 sequence: None,
             }],
             &outputs,
-            None,
-            Some(WalletCreateFundedPsbtOptions {
+            WalletCreateFundedPsbtOptions {
                 add_inputs: Some(false),
                 change_address: Some(change_address.clone()),
                 fee_rate: Some(Amount::from_sat(fee_rate)),
                 ..Default::default()
-            }),
-            None,
+            },
         )?;
-        let funding_tx = self.from_walletcreatefundedpsbt_to_tx(rpc, &wcfp_result.psbt)?;
+        let funding_tx = self.from_walletcreatefundedpsbt_to_tx(&wcfp_result.psbt)?;
 
         total_miner_fee += wcfp_result.fee.as_sat();
         log::debug!(target: "wallet", "created funding tx, miner fee={}", wcfp_result.fee);
@@ -336,7 +320,7 @@ impl Wallet {
 
     fn create_funding_txes_utxo_max_sends(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         coinswap_amount: u64,
         destinations: &[Address],
         fee_rate: u64,
@@ -357,20 +341,17 @@ impl Wallet {
         let wcfp_result = rpc.wallet_create_funded_psbt(
             &[],
             &outputs,
-            None,
-            Some(WalletCreateFundedPsbtOptions {
+            WalletCreateFundedPsbtOptions {
                 include_watching: Some(true),
                 change_address: Some(change_address.clone()),
                 fee_rate: Some(Amount::from_sat(fee_rate)),
                 ..Default::default()
-            }),
-            None,
+            },
         )?;
-        //TODO rust-bitcoin handles psbt, use those functions instead
-        let decoded_psbt = rpc.call::<Value>("decodepsbt", &[Value::String(wcfp_result.psbt)])?;
-        log::debug!(target: "wallet", "total tx decoded_psbt = {:?}", decoded_psbt);
+        let psbt = &wcfp_result.psbt;
+        log::debug!(target: "wallet", "total tx psbt = {:?}", psbt);
 
-        let total_tx_inputs_len = decoded_psbt["inputs"].as_array().unwrap().len();
+        let total_tx_inputs_len = psbt.inputs.len();
         log::debug!(target: "wallet", "total tx inputs.len = {}", total_tx_inputs_len);
         if total_tx_inputs_len < destinations.len() {
             return Err(TeleportError::Protocol(
@@ -378,20 +359,22 @@ impl Wallet {
             ));
         }
 
-        let mut total_tx_inputs = decoded_psbt["tx"]["vin"]
-            .as_array()
-            .unwrap()
+        let mut total_tx_inputs = psbt
+            .global
+            .unsigned_tx
+            .input
             .iter()
-            .zip(decoded_psbt["inputs"].as_array().unwrap().iter())
-            .collect::<Vec<(&Value, &Value)>>();
-
-        total_tx_inputs.sort_by(|(_, a), (_, b)| {
-            b["witness_utxo"]["amount"]
-                .as_f64()
-                .unwrap()
-                .partial_cmp(&a["witness_utxo"]["amount"].as_f64().unwrap())
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
+            .zip(psbt.inputs.iter())
+            .map(|(vin, input_info)| {
+                input_info
+                    .witness_utxo
+                    .as_ref()
+                    .map(|utxo| (vin.previous_output, utxo.value))
+                    .ok_or(TeleportError::Protocol("psbt input without witness utxo"))
+            })
+            .collect::<Result<Vec<(OutPoint, u64)>, TeleportError>>()?;
+
+        total_tx_inputs.sort_by(|(_, a), (_, b)| b.cmp(a));
 
         self.create_mostly_sweep_txes_with_one_tx_having_change(
             rpc,
@@ -399,19 +382,15 @@ impl Wallet {
             destinations,
             fee_rate,
             &change_address,
-            &mut total_tx_inputs.iter().map(|(vin, input_info)| {
-                (
-                    Txid::from_hex(vin["txid"].as_str().unwrap()).unwrap(),
-                    vin["vout"].as_u64().unwrap() as u32,
-                    convert_json_rpc_bitcoin_to_satoshis(&input_info["witness_utxo"]["amount"]),
-                )
-            }),
+            &mut total_tx_inputs
+                .iter()
+                .map(|(outpoint, value)| (outpoint.txid, outpoint.vout, *value)),
         )
     }
 
     fn create_funding_txes_use_biggest_utxos(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         coinswap_amount: u64,
         destinations: &[Address],
         fee_rate: u64,
@@ -419,7 +398,17 @@ impl Wallet {
         //this function will pick the top most valuable UTXOs and use them
         //to create funding transactions
 
-        let mut list_unspent_result = self.list_unspent_from_wallet(rpc, false, false)?;
+        let reserved_utxos = self.get_reserved_utxos()?;
+        let mut list_unspent_result = self
+            .list_unspent_from_wallet(rpc, false, false)?
+            .into_iter()
+            .filter(|(u, _)| {
+                !reserved_utxos.contains(&OutPoint {
+                    txid: u.txid,
+                    vout: u.vout,
+                })
+            })
+            .collect::<Vec<_>>();
         if list_unspent_result.len() < destinations.len() {
             return Err(TeleportError::Protocol(
                 "Not enough UTXOs to create this many funding txes",
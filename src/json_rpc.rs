@@ -0,0 +1,161 @@
+//! JSON-RPC 2.0 handling shared by the [maker admin socket](crate::maker_admin) and
+//! [teleportd](crate::daemon).
+//!
+//! Params are passed by name, requests without params may leave them out or send null or an
+//! empty array. Error codes are the ones of the JSON-RPC spec, and bitcoind's for the others.
+
+use std::{
+    future::Future,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::error::TeleportError;
+
+pub(crate) const RPC_PARSE_ERROR: i64 = -32700;
+pub(crate) const RPC_INVALID_REQUEST: i64 = -32600;
+pub(crate) const RPC_METHOD_NOT_FOUND: i64 = -32601;
+pub(crate) const RPC_INVALID_PARAMS: i64 = -32602;
+pub(crate) const RPC_MISC_ERROR: i64 = -1;
+pub(crate) const RPC_WALLET_ERROR: i64 = -4;
+
+#[derive(Debug)]
+pub(crate) struct RpcError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+}
+
+impl RpcError {
+    pub(crate) fn new<S: Into<String>>(code: i64, message: S) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<TeleportError> for RpcError {
+    fn from(e: TeleportError) -> RpcError {
+        RpcError::new(RPC_MISC_ERROR, format!("{:?}", e))
+    }
+}
+
+/// Params of a method which takes none.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NoParams {}
+
+pub(crate) fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => json!({}),
+        Value::Array(a) if a.is_empty() => json!({}),
+        p => p,
+    };
+    serde_json::from_value(params).map_err(|e| RpcError::new(RPC_INVALID_PARAMS, e.to_string()))
+}
+
+/// Seconds since the unix epoch, which is how times are given in responses.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_secs()
+}
+
+/// Parses a request and answers it with `call`, which is given the method and params.
+pub(crate) async fn handle_request<F, R>(request: &[u8], call: F) -> Value
+where
+    F: FnOnce(String, Value) -> R,
+    R: Future<Output = Result<Value, RpcError>>,
+{
+    let error_response = |id: Value, e: RpcError| {
+        json!({
+            "jsonrpc": "2.0",
+            "error": { "code": e.code, "message": e.message },
+            "id": id,
+        })
+    };
+    let request = match serde_json::from_slice::<Value>(request) {
+        Ok(r) => r,
+        Err(e) => {
+            return error_response(Value::Null, RpcError::new(RPC_PARSE_ERROR, e.to_string()))
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(m) => m.to_string(),
+        None => {
+            return error_response(
+                id,
+                RpcError::new(
+                    RPC_INVALID_REQUEST,
+                    "request must be an object with a method",
+                ),
+            )
+        }
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    log::debug!("RPC call {} {}", method, params);
+    match call(method, params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    }
+}
+
+/// Listen on a unix socket which only the user running us can connect to. The socket is
+/// created in a directory nobody else can enter and moved into place once its permissions are
+/// restricted, so there is no moment in which others could connect.
+#[cfg(unix)]
+pub(crate) fn bind_private_unix_socket(
+    path: &Path,
+) -> Result<tokio::net::UnixListener, TeleportError> {
+    use std::{
+        fs,
+        os::unix::fs::{DirBuilderExt, PermissionsExt},
+    };
+
+    //a socket file left behind by a process which was killed
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let mut private_dir = path.as_os_str().to_owned();
+    private_dir.push(".tmp");
+    let private_dir = Path::new(&private_dir);
+    if private_dir.exists() {
+        fs::remove_dir_all(private_dir)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(private_dir)?;
+    let private_path = private_dir.join("socket");
+    let listener = tokio::net::UnixListener::bind(&private_path)
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&private_path, path)?;
+            Ok(listener)
+        })
+        .map_err(TeleportError::from);
+    fs::remove_dir_all(private_dir)?;
+    listener
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[tokio::test]
+    async fn test_bind_private_unix_socket() {
+        let path =
+            std::env::temp_dir().join(format!("teleport-test-rpc-{}.sock", std::process::id()));
+        // A stale socket file is replaced.
+        fs::write(&path, "").unwrap();
+        let _listener = bind_private_unix_socket(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("sock.tmp").exists());
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
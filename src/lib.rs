@@ -1,32 +1,32 @@
-const RPC_CREDENTIALS: Option<(&str, &str)> = Some(("regtestrpcuser", "regtestrpcpass"));
-//None; // use Bitcoin Core cookie-based authentication
-
-const RPC_WALLET: &str = "teleport";
-const RPC_HOSTPORT: &str = "localhost:18443";
-//default ports: mainnet=8332, testnet=18332, regtest=18443, signet=38332
-
 extern crate bitcoin;
 extern crate bitcoin_wallet;
 extern crate bitcoincore_rpc;
 
-use dirs::home_dir;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryInto,
     io,
     iter::repeat,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Once, RwLock},
 };
 
 use bitcoin::{
     hashes::{hash160::Hash as Hash160, hex::ToHex},
-    Amount, Network,
+    Amount, Network, SignedAmount,
 };
 use bitcoin_wallet::mnemonic;
-use bitcoincore_rpc::{Auth, Client, Error, RpcApi};
+use bitcoincore_rpc::{Client, Error, RpcApi};
 
 use chrono::NaiveDateTime;
+use serde_json::Value;
+
+pub mod blockchain_backend;
+#[cfg(any(test, feature = "simulated-blockchain"))]
+pub mod simulated_blockchain;
+
+pub mod config;
+use config::RpcConfig;
 
 pub mod wallet_sync;
 use wallet_sync::{
@@ -40,13 +40,25 @@ pub mod contracts;
 use contracts::{read_locktime_from_contract, SwapCoin};
 
 pub mod maker_protocol;
-use maker_protocol::MakerBehavior;
+use maker_protocol::MakerConfig;
+
+pub mod maker_admin;
+pub mod maker_ledger;
+use maker_ledger::{ReportFormat, ReportPeriod};
 
 pub mod taker_protocol;
 use taker_protocol::SwapParams;
 
+pub mod daemon;
+use daemon::DaemonConfig;
+
+pub mod swap_events;
+
 pub mod offerbook_sync;
-use offerbook_sync::{get_advertised_maker_addresses, sync_offerbook_with_addresses, MakerAddress};
+use offerbook_sync::{
+    get_advertised_maker_addresses, sync_offerbook_with_addresses, verify_fidelity_bonds,
+    MakerAddress,
+};
 
 pub mod fidelity_bonds;
 use fidelity_bonds::{get_locktime_from_index, YearAndMonth};
@@ -54,8 +66,14 @@ use fidelity_bonds::{get_locktime_from_index, YearAndMonth};
 mod directory_servers;
 mod error;
 mod funding_tx;
+mod json_rpc;
 mod messages;
+mod noise;
+mod recovery;
+mod transport;
 mod util;
+mod wallet_storage;
+use wallet_storage::WalletStorage;
 pub mod watchtower_client;
 pub mod watchtower_protocol;
 
@@ -71,21 +89,20 @@ fn str_to_bitcoin_network(net_str: &str) -> Network {
     }
 }
 
-pub fn get_bitcoin_rpc() -> Result<(Client, Network), Error> {
-    let auth = match RPC_CREDENTIALS {
-        Some((user, pass)) => Auth::UserPass(user.to_string(), pass.to_string()),
-        None => {
-            //TODO this currently only works for Linux and regtest,
-            //     also support other OSes (Windows, MacOS...) and networks
-            let data_dir = home_dir().unwrap().join(".bitcoin");
-            Auth::CookieFile(data_dir.join("regtest").join(".cookie"))
-        }
-    };
-    let rpc = Client::new(
-        format!("http://{}/wallet/{}", RPC_HOSTPORT, RPC_WALLET),
-        auth,
-    )?;
+pub fn get_bitcoin_rpc(rpc_config: &RpcConfig) -> Result<(Client, Network), Error> {
+    let rpc = Client::new(rpc_config.get_url(), rpc_config.get_auth())?;
     let network = str_to_bitcoin_network(rpc.get_blockchain_info()?.chain.as_str());
+    if let Some(configured_network) = rpc_config.network {
+        if configured_network != network {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "bitcoin node is on network {} but configured network is {}",
+                    network, configured_network
+                ),
+            )));
+        }
+    }
     Ok((rpc, network))
 }
 
@@ -101,8 +118,24 @@ pub fn setup_logger() {
     });
 }
 
-pub fn generate_wallet(wallet_file_name: &PathBuf) -> std::io::Result<()> {
-    let (rpc, network) = match get_bitcoin_rpc() {
+/// Ask for the passphrase to encrypt a wallet with, returns None if left blank.
+fn read_new_wallet_passphrase() -> io::Result<Option<String>> {
+    loop {
+        let passphrase = rpassword::prompt_password(
+            "input passphrase to encrypt the wallet file with (or leave blank for none): ",
+        )?;
+        if passphrase.is_empty() {
+            return Ok(None);
+        }
+        if rpassword::prompt_password("repeat passphrase: ")? == passphrase {
+            return Ok(Some(passphrase));
+        }
+        println!("passphrases do not match, try again");
+    }
+}
+
+pub fn generate_wallet(rpc_config: &RpcConfig, wallet_file_name: &PathBuf) -> std::io::Result<()> {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -117,11 +150,19 @@ pub fn generate_wallet(wallet_file_name: &PathBuf) -> std::io::Result<()> {
         mnemonic::Mnemonic::new_random(bitcoin_wallet::account::MasterKeyEntropy::Sufficient)
             .unwrap();
 
-    Wallet::save_new_wallet_file(&wallet_file_name, mnemonic.to_string(), extension.clone())
-        .unwrap();
+    let passphrase = read_new_wallet_passphrase()?;
+
+    Wallet::save_new_wallet_file(
+        wallet_file_name,
+        mnemonic.to_string(),
+        extension.clone(),
+        passphrase.clone(),
+    )
+    .unwrap();
 
-    let w = match Wallet::load_wallet_from_file(
-        &wallet_file_name,
+    let w = match Wallet::load_wallet_from_file_with_passphrase(
+        wallet_file_name,
+        passphrase.as_deref(),
         network,
         WalletSyncAddressAmount::Normal,
     ) {
@@ -149,7 +190,10 @@ pub fn generate_wallet(wallet_file_name: &PathBuf) -> std::io::Result<()> {
     }
     println!(
         "\nThis seed phrase is NOT enough to backup all coins in your wallet\n\
-        The teleport wallet file is needed to backup swapcoins"
+        The keys of swapcoins are derived from it, but most swapcoins can't be rebuilt from\n\
+        the seed phrase alone. The contracts and the keys received from the other side of each\n\
+        coinswap only appear on the blockchain once the coins are spent, so the teleport\n\
+        wallet file is needed to backup swapcoins"
     );
     println!("\nSaved to file `{}`", wallet_file_name.to_string_lossy());
 
@@ -172,13 +216,102 @@ pub fn recover_wallet(wallet_file_name: &PathBuf) -> std::io::Result<()> {
     io::stdin().read_line(&mut extension)?;
     extension = extension.trim().to_string();
 
-    Wallet::save_new_wallet_file(&wallet_file_name, seed_phrase, extension).unwrap();
+    let passphrase = read_new_wallet_passphrase()?;
+
+    Wallet::save_new_wallet_file(wallet_file_name, seed_phrase, extension, passphrase).unwrap();
     println!("\nSaved to file `{}`", wallet_file_name.to_string_lossy());
     Ok(())
 }
 
-pub fn display_wallet_balance(wallet_file_name: &PathBuf, long_form: Option<bool>) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+pub fn change_wallet_passphrase(wallet_file_name: &Path) -> std::io::Result<()> {
+    let mut storage = match WalletStorage::open(wallet_file_name, None) {
+        Ok(storage) => storage,
+        Err(error) => {
+            log::error!(target: "main", "error loading wallet file: {:?}", error);
+            return Ok(());
+        }
+    };
+    let passphrase = read_new_wallet_passphrase()?;
+    if let Err(error) = taker_protocol::change_wallet_passphrase(
+        &mut storage,
+        &wallet_file_name.with_extension("swap"),
+        passphrase.as_deref(),
+    ) {
+        log::error!(target: "main", "error changing wallet passphrase: {:?}", error);
+        return Ok(());
+    }
+    if storage.is_encrypted() {
+        println!(
+            "Wallet file `{}` encrypted",
+            wallet_file_name.to_string_lossy()
+        );
+    } else {
+        println!(
+            "Wallet file `{}` is no longer encrypted",
+            wallet_file_name.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+pub fn scan_swapcoin_keys(rpc_config: &RpcConfig, wallet_file_name: &PathBuf, start_height: u64) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
+        Ok(rpc) => rpc,
+        Err(error) => {
+            log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
+            return;
+        }
+    };
+    let mut wallet = match Wallet::load_wallet_from_file(
+        wallet_file_name,
+        network,
+        WalletSyncAddressAmount::Normal,
+    ) {
+        Ok(w) => w,
+        Err(error) => {
+            log::error!(target: "main", "error loading wallet file: {:?}", error);
+            return;
+        }
+    };
+
+    println!("Scanning blocks from height {}. . .", start_height);
+    let scan = match wallet.scan_for_used_swapcoin_keys(&rpc, start_height) {
+        Ok(s) => s,
+        Err(error) => {
+            log::error!(target: "main", "error scanning blockchain: {:?}", error);
+            return;
+        }
+    };
+    println!(
+        "next swapcoin key index = {}",
+        wallet.get_swapcoin_key_index()
+    );
+    if !scan.rebuilt_contract_outpoints.is_empty() {
+        println!(
+            "\nThese outgoing swapcoins were rebuilt, run `recover` to get their coins back \
+            once the timelock matures:"
+        );
+        for outpoint in scan.rebuilt_contract_outpoints {
+            println!("{}", outpoint);
+        }
+    }
+    if !scan.locked_contract_outpoints.is_empty() {
+        println!(
+            "\nThese contract outputs of past coinswaps are unspent, they can't be \
+            recovered without the wallet file:"
+        );
+        for outpoint in scan.locked_contract_outpoints {
+            println!("{}", outpoint);
+        }
+    }
+}
+
+pub fn display_wallet_balance(
+    rpc_config: &RpcConfig,
+    wallet_file_name: &PathBuf,
+    long_form: Option<bool>,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -412,15 +545,18 @@ pub fn display_wallet_balance(wallet_file_name: &PathBuf, long_form: Option<bool
 }
 
 pub fn display_wallet_addresses(
+    rpc_config: &RpcConfig,
     wallet_file_name: &PathBuf,
     types: DisplayAddressType,
     network: Option<String>,
 ) {
-    let network = match get_bitcoin_rpc() {
+    let network = match get_bitcoin_rpc(rpc_config) {
         Ok((_rpc, network)) => network,
         Err(error) => {
             if let Some(net_str) = network {
                 str_to_bitcoin_network(net_str.as_str())
+            } else if let Some(configured_network) = rpc_config.network {
+                configured_network
             } else {
                 panic!(
                     "network string not provided, and error connecting to bitcoin node: {:?}",
@@ -443,8 +579,8 @@ pub fn display_wallet_addresses(
     wallet.display_addresses(types);
 }
 
-pub fn print_receive_invoice(wallet_file_name: &PathBuf) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+pub fn print_receive_invoice(rpc_config: &RpcConfig, wallet_file_name: &PathBuf) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -474,8 +610,12 @@ pub fn print_receive_invoice(wallet_file_name: &PathBuf) {
     println!("{}", addr);
 }
 
-pub fn print_fidelity_bond_address(wallet_file_name: &PathBuf, locktime: &YearAndMonth) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+pub fn print_fidelity_bond_address(
+    rpc_config: &RpcConfig,
+    wallet_file_name: &PathBuf,
+    locktime: &YearAndMonth,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -518,13 +658,12 @@ pub fn print_fidelity_bond_address(wallet_file_name: &PathBuf, locktime: &YearAn
 }
 
 pub fn run_maker(
+    rpc_config: &RpcConfig,
     wallet_file_name: &PathBuf,
     sync_amount: WalletSyncAddressAmount,
-    port: u16,
-    maker_behavior: MakerBehavior,
-    kill_flag: Option<Arc<RwLock<bool>>>,
+    config: MakerConfig,
 ) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -542,31 +681,119 @@ pub fn run_maker(
 
     let rpc_ptr = Arc::new(rpc);
     let wallet_ptr = Arc::new(RwLock::new(wallet));
-    let config = maker_protocol::MakerConfig {
-        port,
-        rpc_ping_interval_secs: 60,
-        watchtower_ping_interval_secs: 300,
-        directory_servers_refresh_interval_secs: 60 * 60 * 12, //12 hours
-        maker_behavior,
-        kill_flag: if kill_flag.is_none() {
-            Arc::new(RwLock::new(false))
-        } else {
-            kill_flag.unwrap().clone()
-        },
-        idle_connection_timeout: 300,
-    };
     maker_protocol::start_maker(rpc_ptr, wallet_ptr, config);
 }
 
+#[tokio::main]
+pub async fn send_maker_admin_request(socket_path: &Path, method: &str, params: Value) {
+    let response = match maker_admin::send_request(socket_path, method, params).await {
+        Ok(r) => r,
+        Err(error) => {
+            log::error!(target: "main", "error connecting to maker admin socket {}: {:?}",
+                socket_path.display(), error);
+            return;
+        }
+    };
+    match response.get("error") {
+        Some(error) => log::error!(target: "main", "maker admin request failed: {}", error),
+        None => println!(
+            "{}",
+            serde_json::to_string_pretty(&response["result"]).unwrap()
+        ),
+    }
+}
+
+pub fn print_maker_report(
+    wallet_file_name: &Path,
+    period: ReportPeriod,
+    format: ReportFormat,
+    list_swaps: bool,
+) {
+    let entries = match WalletStorage::open(wallet_file_name, None)
+        .and_then(|storage| storage.load_swap_ledger())
+    {
+        Ok(entries) => entries,
+        Err(error) => {
+            log::error!(target: "main", "error loading swap ledger: {:?}", error);
+            return;
+        }
+    };
+    match (format, list_swaps) {
+        (ReportFormat::Table, true) => {
+            println!(
+                "{:<19} {:<40} {:<12} {:<12} {:<9} {:<8} {:<8} {:<11}",
+                "finished",
+                "hashvalue",
+                "incoming",
+                "outgoing",
+                "miner fee",
+                "earned",
+                "locktime",
+                "outcome"
+            );
+            for entry in &entries {
+                println!(
+                    "{:<19} {:<40} {:<12} {:<12} {:<9} {:<8} {:<8} {:<11}",
+                    entry
+                        .finished_at
+                        .map(|t| maker_ledger::format_unix_time(t, "%Y-%m-%d %H:%M:%S"))
+                        .unwrap_or_default(),
+                    entry.hashvalue.to_hex(),
+                    entry.incoming_amount,
+                    entry.outgoing_amount,
+                    entry.miner_fee,
+                    entry.earned(),
+                    format!("{}/{}", entry.incoming_locktime, entry.outgoing_locktime),
+                    entry.outcome.as_str(),
+                );
+            }
+        }
+        (ReportFormat::Table, false) => {
+            println!(
+                "{:<10} {:<9} {:<9} {:<11} {:<12} {:<13} {:<10} {:<6}",
+                "period",
+                "completed",
+                "timed out",
+                "in progress",
+                "volume",
+                "coinswap fees",
+                "miner fees",
+                "earned"
+            );
+            let summaries = maker_ledger::summarize(&entries, period);
+            for s in &summaries {
+                println!(
+                    "{:<10} {:<9} {:<9} {:<11} {:<12} {:<13} {:<10} {}",
+                    s.period,
+                    s.completed,
+                    s.timed_out,
+                    s.in_progress,
+                    s.volume,
+                    s.coinswap_fees,
+                    s.miner_fees,
+                    s.earned
+                );
+            }
+            println!(
+                "total earned = {}",
+                SignedAmount::from_sat(summaries.iter().map(|s| s.earned).sum())
+            );
+        }
+        (format, true) => print!("{}", maker_ledger::export_entries(&entries, format)),
+        (format, false) => print!(
+            "{}",
+            maker_ledger::export_summaries(&maker_ledger::summarize(&entries, period), format)
+        ),
+    }
+}
+
 pub fn run_taker(
+    rpc_config: &RpcConfig,
     wallet_file_name: &PathBuf,
     sync_amount: WalletSyncAddressAmount,
-    fee_rate: u64,
-    send_amount: u64,
-    maker_count: u16,
-    tx_count: u32,
+    swap_params: SwapParams,
 ) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -582,24 +809,83 @@ pub fn run_taker(
     };
     wallet.startup_sync(&rpc).unwrap();
     taker_protocol::start_taker(
-        &rpc,
-        &mut wallet,
-        SwapParams {
-            send_amount,
-            maker_count,
-            tx_count,
-            required_confirms: 1,
-            fee_rate,
-        },
+        Arc::new(rpc),
+        Arc::new(RwLock::new(wallet)),
+        swap_params,
+        &wallet_file_name.with_extension("offerbook"),
+        &wallet_file_name.with_extension("swap"),
+    );
+}
+
+pub fn run_daemon(
+    rpc_config: &RpcConfig,
+    wallet_file_name: &PathBuf,
+    config: DaemonConfig,
+    maker_config: MakerConfig,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
+        Ok(rpc) => rpc,
+        Err(error) => {
+            log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
+            return;
+        }
+    };
+    let mut wallet = match Wallet::load_wallet_from_file(
+        wallet_file_name,
+        network,
+        WalletSyncAddressAmount::Normal,
+    ) {
+        Ok(w) => w,
+        Err(error) => {
+            log::error!(target: "main", "error loading wallet file: {:?}", error);
+            return;
+        }
+    };
+    wallet.startup_sync(&rpc).unwrap();
+    daemon::start_daemon(
+        Arc::new(rpc),
+        wallet,
+        wallet_file_name,
+        config,
+        maker_config,
+    );
+}
+
+pub fn resume_coinswap(
+    rpc_config: &RpcConfig,
+    wallet_file_name: &PathBuf,
+    sync_amount: WalletSyncAddressAmount,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
+        Ok(rpc) => rpc,
+        Err(error) => {
+            log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
+            return;
+        }
+    };
+    let mut wallet = match Wallet::load_wallet_from_file(wallet_file_name, network, sync_amount) {
+        Ok(w) => w,
+        Err(error) => {
+            log::error!(target: "main", "error loading wallet file: {:?}", error);
+            return;
+        }
+    };
+    wallet.startup_sync(&rpc).unwrap();
+    taker_protocol::resume_taker(
+        Arc::new(rpc),
+        Arc::new(RwLock::new(wallet)),
+        &wallet_file_name.with_extension("offerbook"),
+        &wallet_file_name.with_extension("swap"),
     );
 }
 
 pub fn recover_from_incomplete_coinswap(
+    rpc_config: &RpcConfig,
     wallet_file_name: &PathBuf,
     hashvalue: Hash160,
     dont_broadcast: bool,
 ) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -665,21 +951,58 @@ pub fn recover_from_incomplete_coinswap(
     }
 }
 
+pub fn recover_coinswaps(
+    rpc_config: &RpcConfig,
+    wallet_file_name: &PathBuf,
+    hashvalue: Option<Hash160>,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
+        Ok(rpc) => rpc,
+        Err(error) => {
+            log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
+            return;
+        }
+    };
+    let mut wallet = match Wallet::load_wallet_from_file(
+        wallet_file_name,
+        network,
+        WalletSyncAddressAmount::Normal,
+    ) {
+        Ok(w) => w,
+        Err(error) => {
+            log::error!(target: "main", "error loading wallet file: {:?}", error);
+            return;
+        }
+    };
+    wallet.startup_sync(&rpc).unwrap();
+
+    if let Err(error) = recovery::run_recovery(
+        &rpc,
+        &mut wallet,
+        hashvalue,
+        &wallet_file_name.with_extension("recovery"),
+    ) {
+        log::error!(target: "main", "error recovering coinswaps: {:?}", error);
+    }
+}
+
 #[tokio::main]
 pub async fn download_and_display_offers(
+    rpc_config: &RpcConfig,
     network_str: Option<String>,
     maker_address: Option<String>,
 ) {
+    let rpc_network = get_bitcoin_rpc(rpc_config);
     let maker_addresses = if let Some(maker_addr) = maker_address {
-        vec![MakerAddress::Tor {
-            address: maker_addr,
-        }]
+        vec![MakerAddress::from_host_and_port(maker_addr)]
     } else {
-        let network = match get_bitcoin_rpc() {
-            Ok((_rpc, network)) => network,
+        let network = match &rpc_network {
+            Ok((_rpc, network)) => *network,
             Err(error) => {
                 if let Some(net_str) = network_str {
                     str_to_bitcoin_network(net_str.as_str())
+                } else if let Some(configured_network) = rpc_config.network {
+                    configured_network
                 } else {
                     panic!(
                         "network string not provided, and error connecting to bitcoin node: {:?}",
@@ -693,13 +1016,29 @@ pub async fn download_and_display_offers(
             .expect("unable to sync maker addresses from directory servers")
     };
     let offers_addresses = sync_offerbook_with_addresses(maker_addresses.clone()).await;
+    let downloaded_addresses = offers_addresses
+        .iter()
+        .map(|offer_address| offer_address.address.clone())
+        .collect::<HashSet<_>>();
+    let offers_addresses = match &rpc_network {
+        Ok((rpc, _network)) => match verify_fidelity_bonds(rpc, offers_addresses) {
+            Ok(verified_offers) => verified_offers,
+            Err(error) => {
+                log::error!(target: "main", "error verifying fidelity bonds: {:?}", error);
+                return;
+            }
+        },
+        Err(_) => {
+            log::warn!(target: "main", "no connection to bitcoin node, cant verify fidelity bonds");
+            offers_addresses
+                .into_iter()
+                .map(|offer_address| (offer_address, None))
+                .collect()
+        }
+    };
     let mut addresses_offers_map = HashMap::new();
-    for offer_address in offers_addresses.iter() {
-        let address_str = match &offer_address.address {
-            MakerAddress::Clearnet { address } => address,
-            MakerAddress::Tor { address } => address,
-        };
-        addresses_offers_map.insert(address_str, offer_address);
+    for (offer_address, fidelity_bond_value) in offers_addresses.iter() {
+        addresses_offers_map.insert(&offer_address.address, (offer_address, fidelity_bond_value));
     }
 
     println!(
@@ -720,11 +1059,11 @@ pub async fn download_and_display_offers(
             MakerAddress::Clearnet { address } => address,
             MakerAddress::Tor { address } => address,
         };
-        if let Some(offer_address) = addresses_offers_map.get(&address_str) {
+        if let Some((offer_address, fidelity_bond_value)) = addresses_offers_map.get(&address) {
             let o = &offer_address.offer;
 
             println!(
-                "{:<3} {:<70} {:<12} {:<12} {:<12} {:<12} {:<12} {:<12}",
+                "{:<3} {:<70} {:<12} {:<12} {:<12} {:<12} {:<12} {:<12} {:<19}",
                 ii,
                 address_str,
                 o.max_size,
@@ -733,7 +1072,12 @@ pub async fn download_and_display_offers(
                 o.amount_relative_fee_ppb,
                 o.time_relative_fee_ppb,
                 o.minimum_locktime,
+                fidelity_bond_value
+                    .map(|v| format!("{:.0}", v))
+                    .unwrap_or_default(),
             );
+        } else if downloaded_addresses.contains(address) {
+            println!("{:<3} {:<70} REJECTED FIDELITY BOND", ii, address_str);
         } else {
             println!("{:<3} {:<70} UNREACHABLE", ii, address_str);
         }
@@ -741,6 +1085,7 @@ pub async fn download_and_display_offers(
 }
 
 pub fn direct_send(
+    rpc_config: &RpcConfig,
     wallet_file_name: &PathBuf,
     fee_rate: u64,
     send_amount: SendAmount,
@@ -748,7 +1093,7 @@ pub fn direct_send(
     coins_to_spend: &[CoinToSpend],
     dont_broadcast: bool,
 ) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
@@ -797,8 +1142,12 @@ pub fn direct_send(
     }
 }
 
-pub fn run_watchtower(data_file_path: &PathBuf, kill_flag: Option<Arc<RwLock<bool>>>) {
-    let (rpc, network) = match get_bitcoin_rpc() {
+pub fn run_watchtower(
+    rpc_config: &RpcConfig,
+    data_file_path: &PathBuf,
+    kill_flag: Option<Arc<RwLock<bool>>>,
+) {
+    let (rpc, network) = match get_bitcoin_rpc(rpc_config) {
         Ok(rpc) => rpc,
         Err(error) => {
             log::error!(target: "main", "error connecting to bitcoin node: {:?}", error);
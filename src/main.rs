@@ -9,9 +9,12 @@ use structopt::StructOpt;
 
 use teleport::{
     self,
+    config::{RpcConfig, RpcOptions},
     direct_send::{CoinToSpend, Destination, SendAmount},
     fidelity_bonds::YearAndMonth,
-    maker_protocol::MakerBehavior,
+    maker_ledger::{ReportFormat, ReportPeriod},
+    maker_protocol::{MakerBehavior, MakerConfig},
+    taker_protocol::SwapParams,
     wallet_sync::{DisplayAddressType, WalletSyncAddressAmount},
     watchtower_protocol::{ContractTransaction, ContractsInfo},
 };
@@ -32,6 +35,15 @@ struct ArgsWithWalletFile {
     #[structopt(default_value = "1000", short = "f", long)]
     fee_rate: u64,
 
+    /// Config file, default is the TELEPORT_CONFIG environment variable or
+    /// "~/.teleport/config.toml" if it exists
+    #[structopt(parse(from_os_str), long)]
+    config_file: Option<PathBuf>,
+
+    /// Bitcoin Core RPC connection, overrides the config file and TELEPORT_RPC_* variables
+    #[structopt(flatten)]
+    rpc_options: RpcOptions,
+
     /// Subcommand
     #[structopt(flatten)]
     subcommand: Subcommand,
@@ -46,6 +58,18 @@ enum Subcommand {
     /// Recovers a wallet file from an existing seed phrase
     RecoverWallet,
 
+    /// Encrypts the wallet file with a new passphrase, or decrypts it if left blank
+    ChangeWalletPassphrase,
+
+    /// Scan the blockchain for swapcoin keys used before a wallet was recovered from its seed
+    /// phrase, so that they aren't used again. Outgoing swapcoins of the first hop of a coinswap
+    /// are rebuilt once their contract transaction is on the blockchain, other swapcoins need
+    /// the wallet file
+    ScanSwapcoinKeys {
+        /// Block height to start scanning from, e.g. when the wallet was first created
+        start_height: u64,
+    },
+
     /// Prints current wallet balance.
     WalletBalance {
         /// Whether to print entire TXIDs and addresses
@@ -68,12 +92,35 @@ enum Subcommand {
 
     /// Runs yield generator aiming to produce an income
     RunYieldGenerator {
-        /// Port to listen on, default is 6102
+        /// Port to listen on, overrides the config file, default is 6102
         port: Option<u16>,
         /// Special behavior used for testing e.g. "closeonsignsenderscontracttx"
         special_behavior: Option<String>,
     },
 
+    /// Sends a request to the admin socket of a running yield generator. Methods are
+    /// "listconnections", "pause", "resume", "getofferconfig", "setofferconfig", "drain" and
+    /// "getliquidity"
+    MakerAdmin {
+        /// Method to call
+        method: String,
+        /// Params as a JSON object, for example '{"min_size":20000}' for setofferconfig
+        params: Option<String>,
+    },
+
+    /// Prints the earnings of the yield generator per day or month, or exports them
+    MakerReport {
+        /// "day" or "month"
+        #[structopt(default_value = "month", long)]
+        period: ReportPeriod,
+        /// "table", or "csv" and "json" for exporting
+        #[structopt(default_value = "table", long)]
+        format: ReportFormat,
+        /// List every coinswap instead of summing them up per period
+        #[structopt(long)]
+        swaps: bool,
+    },
+
     /// Prints a fidelity bond timelocked address
     GetFidelityBondAddress {
         /// Locktime value of timelocked address as yyyy-mm year and month, for example "2025-03"
@@ -88,8 +135,19 @@ enum Subcommand {
         maker_count: Option<u16>,
         /// How many transactions per hop, default 3
         tx_count: Option<u32>,
+        /// Only route through makers with at least this fidelity bond value, default 0 which
+        /// also allows makers without a fidelity bond
+        #[structopt(long)]
+        min_fidelity_bond_value: Option<f64>,
+        /// Maximum total coinswap fee in sats paid to all makers, not counting miner fees
+        #[structopt(long)]
+        max_total_fee: Option<u64>,
     },
 
+    /// Continue a coinswap which was interrupted, for example by teleport being closed. If the
+    /// coinswap cant be continued, the contract transactions are broadcast to recover the coins
+    ResumeCoinswap,
+
     /// Broadcast contract transactions for incomplete coinswap. Locked up bitcoins are
     /// returned to your wallet after the timeout
     RecoverFromIncompleteCoinswap {
@@ -97,6 +155,13 @@ enum Subcommand {
         hashvalue: Hash160,
     },
 
+    /// Recover the coins of incomplete coinswaps by broadcasting the contract transactions and
+    /// spending them back to the wallet once possible. Keeps running until all coins are back
+    Recover {
+        /// Hashvalue as hex string of the only coinswap to recover, otherwise recover all
+        hashvalue: Option<Hash160>,
+    },
+
     /// Download all offers from all makers out there. If bitcoin node not configured then
     /// provide the network as an argument, can also optionally download from one given maker
     DownloadOffers {
@@ -134,72 +199,146 @@ enum Subcommand {
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     teleport::setup_logger();
     let args = ArgsWithWalletFile::from_args();
+    let rpc_config = match RpcConfig::load(args.config_file.as_deref(), args.rpc_options) {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!(target: "main", "error loading config: {:?}", error);
+            return Err(error.into());
+        }
+    };
 
     match args.subcommand {
         Subcommand::GenerateWallet => {
-            teleport::generate_wallet(&args.wallet_file_name)?;
+            teleport::generate_wallet(&rpc_config, &args.wallet_file_name)?;
         }
         Subcommand::RecoverWallet => {
             teleport::recover_wallet(&args.wallet_file_name)?;
         }
+        Subcommand::ChangeWalletPassphrase => {
+            teleport::change_wallet_passphrase(&args.wallet_file_name)?;
+        }
+        Subcommand::ScanSwapcoinKeys { start_height } => {
+            teleport::scan_swapcoin_keys(&rpc_config, &args.wallet_file_name, start_height);
+        }
         Subcommand::WalletBalance { long_form } => {
-            teleport::display_wallet_balance(&args.wallet_file_name, long_form);
+            teleport::display_wallet_balance(&rpc_config, &args.wallet_file_name, long_form);
         }
         Subcommand::DisplayWalletAddresses { types, network } => {
             teleport::display_wallet_addresses(
+                &rpc_config,
                 &args.wallet_file_name,
                 types.unwrap_or(DisplayAddressType::All),
                 network,
             );
         }
         Subcommand::GetReceiveInvoice => {
-            teleport::print_receive_invoice(&args.wallet_file_name);
+            teleport::print_receive_invoice(&rpc_config, &args.wallet_file_name);
         }
         Subcommand::RunYieldGenerator {
             port,
             special_behavior,
         } => {
-            let maker_special_behavior = match special_behavior.unwrap_or(String::new()).as_str() {
+            let mut maker_config = match MakerConfig::load(args.config_file.as_deref()) {
+                Ok(c) => c,
+                Err(error) => {
+                    log::error!(target: "main", "error loading maker config: {:?}", error);
+                    return Err(error.into());
+                }
+            };
+            if let Some(port) = port {
+                maker_config.port = port;
+            }
+            maker_config.maker_behavior = match special_behavior.unwrap_or(String::new()).as_str() {
                 "closeonsignsenderscontracttx" => MakerBehavior::CloseOnSignSendersContractTx,
                 _ => MakerBehavior::Normal,
             };
             teleport::run_maker(
+                &rpc_config,
                 &args.wallet_file_name,
                 WalletSyncAddressAmount::Normal,
-                port.unwrap_or(6102),
-                maker_special_behavior,
-                None,
+                maker_config,
             );
         }
+        Subcommand::MakerAdmin { method, params } => {
+            let maker_config = match MakerConfig::load(args.config_file.as_deref()) {
+                Ok(c) => c,
+                Err(error) => {
+                    log::error!(target: "main", "error loading maker config: {:?}", error);
+                    return Err(error.into());
+                }
+            };
+            let params = match params.as_deref().map(serde_json::from_str).transpose() {
+                Ok(p) => p.unwrap_or(serde_json::Value::Null),
+                Err(error) => {
+                    log::error!(target: "main", "params aren't valid JSON: {}", error);
+                    return Err(error.into());
+                }
+            };
+            teleport::send_maker_admin_request(
+                &maker_config.admin_socket_path(&args.wallet_file_name),
+                &method,
+                params,
+            );
+        }
+        Subcommand::MakerReport {
+            period,
+            format,
+            swaps,
+        } => {
+            teleport::print_maker_report(&args.wallet_file_name, period, format, swaps);
+        }
         Subcommand::GetFidelityBondAddress { year_and_month } => {
-            teleport::print_fidelity_bond_address(&args.wallet_file_name, &year_and_month);
+            teleport::print_fidelity_bond_address(
+                &rpc_config,
+                &args.wallet_file_name,
+                &year_and_month,
+            );
         }
         Subcommand::DoCoinswap {
             send_amount,
             maker_count,
             tx_count,
+            min_fidelity_bond_value,
+            max_total_fee,
         } => {
             teleport::run_taker(
+                &rpc_config,
+                &args.wallet_file_name,
+                WalletSyncAddressAmount::Normal,
+                SwapParams {
+                    send_amount,
+                    maker_count: maker_count.unwrap_or(2),
+                    tx_count: tx_count.unwrap_or(3),
+                    min_fidelity_bond_value: min_fidelity_bond_value.unwrap_or(0.0),
+                    max_total_fee,
+                    required_confirms: 1,
+                    fee_rate: args.fee_rate,
+                },
+            );
+        }
+        Subcommand::ResumeCoinswap => {
+            teleport::resume_coinswap(
+                &rpc_config,
                 &args.wallet_file_name,
                 WalletSyncAddressAmount::Normal,
-                args.fee_rate,
-                send_amount,
-                maker_count.unwrap_or(2),
-                tx_count.unwrap_or(3),
             );
         }
         Subcommand::RecoverFromIncompleteCoinswap { hashvalue } => {
             teleport::recover_from_incomplete_coinswap(
+                &rpc_config,
                 &args.wallet_file_name,
                 hashvalue,
                 args.dont_broadcast,
             );
         }
+        Subcommand::Recover { hashvalue } => {
+            teleport::recover_coinswaps(&rpc_config, &args.wallet_file_name, hashvalue);
+        }
         Subcommand::DownloadOffers {
             network,
             maker_address,
         } => {
-            teleport::download_and_display_offers(network, maker_address);
+            teleport::download_and_display_offers(&rpc_config, network, maker_address);
         }
         Subcommand::DirectSend {
             send_amount,
@@ -207,6 +346,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             coins_to_spend,
         } => {
             teleport::direct_send(
+                &rpc_config,
                 &args.wallet_file_name,
                 args.fee_rate,
                 send_amount,
@@ -217,6 +357,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         Subcommand::RunWatchtower { data_file_path } => {
             teleport::run_watchtower(
+                &rpc_config,
                 &data_file_path.unwrap_or(Path::new("watchtower.dat").to_path_buf()),
                 None,
             );
@@ -0,0 +1,408 @@
+//! Local admin interface of a running maker.
+//!
+//! The maker listens on a unix socket which only the user running it can connect to, by
+//! default the wallet file name with the extension `.sock`. Every line sent to it is a JSON-RPC
+//! 2.0 request, answered by one line holding the response. For example
+//!
+//! ```text
+//! echo '{"jsonrpc":"2.0","id":1,"method":"listconnections"}' | socat - UNIX-CONNECT:maker.sock
+//! ```
+//!
+//! or `teleport maker-admin listconnections`. Params are passed by name and all amounts are in
+//! satoshis. The methods are
+//!
+//! * `listconnections`, the connected takers and which message the maker expects from each next
+//! * `pause` and `resume`, stop and start again taking on new coinswaps. Takers in the middle of
+//!   a coinswap can still finish it
+//! * `getofferconfig` and `setofferconfig`, the terms offered to takers which connect from now
+//!   on. Changes aren't written to the config file, so they last until the next `SIGHUP` or
+//!   restart
+//! * `drain`, stop taking on new coinswaps and shut down once none are in progress, coinswaps
+//!   being recovered after their deadline passed aren't waited for
+//! * `getliquidity`, the wallet's coins and how much of them can be offered
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use tokio::task::JoinHandle;
+
+use bitcoin::{
+    hashes::{hash160::Hash as Hash160, hex::ToHex},
+    OutPoint,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    blockchain_backend::BlockchainBackend,
+    error::TeleportError,
+    json_rpc::{
+        self, parse_params, unix_time, NoParams, RpcError, RPC_INVALID_PARAMS,
+        RPC_METHOD_NOT_FOUND, RPC_MISC_ERROR,
+    },
+    maker_protocol::{ExpectedMessage, OfferConfig},
+    wallet_sync::{UTXOSpendInfo, Wallet},
+};
+
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+//fields which are left out keep their current value
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetOfferConfigParams {
+    absolute_fee_sat: Option<u64>,
+    amount_relative_fee_ppb: Option<u64>,
+    time_relative_fee_ppb: Option<u64>,
+    required_confirms: Option<i32>,
+    minimum_locktime: Option<u16>,
+    min_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ConnectionInfo {
+    addr: SocketAddr,
+    connected_at: u64,
+    expected_message: ExpectedMessage,
+    hashvalue: Option<Hash160>,
+}
+
+/// State of a running maker shared between its connections and the admin socket.
+pub(crate) struct MakerAdmin {
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    offer_config: Arc<RwLock<OfferConfig>>,
+    swap_deadline_blocks: u32,
+    connections: Mutex<HashMap<u64, ConnectionInfo>>,
+    next_connection_id: AtomicU64,
+    paused: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl MakerAdmin {
+    pub(crate) fn new(
+        rpc: Arc<dyn BlockchainBackend>,
+        wallet: Arc<RwLock<Wallet>>,
+        offer_config: Arc<RwLock<OfferConfig>>,
+        swap_deadline_blocks: u32,
+    ) -> MakerAdmin {
+        MakerAdmin {
+            rpc,
+            wallet,
+            offer_config,
+            swap_deadline_blocks,
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Start listing a newly connected taker, returns the id to update it with.
+    pub(crate) fn add_connection(&self, addr: SocketAddr) -> u64 {
+        let id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        self.connections.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                addr,
+                connected_at: unix_time(),
+                expected_message: ExpectedMessage::TakerHello,
+                hashvalue: None,
+            },
+        );
+        id
+    }
+
+    pub(crate) fn update_connection(
+        &self,
+        id: u64,
+        expected_message: ExpectedMessage,
+        hashvalue: Option<Hash160>,
+    ) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.expected_message = expected_message;
+            connection.hashvalue = hashvalue;
+        }
+    }
+
+    pub(crate) fn remove_connection(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Whether takers may start new coinswaps, false while paused or draining.
+    pub(crate) fn is_taking_new_coinswaps(&self) -> bool {
+        !self.paused.load(Ordering::SeqCst) && !self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// The coinswaps a draining maker waits for, those of connected takers and those whose
+    /// private keys weren't handed over yet, apart from the ones being recovered.
+    pub(crate) fn coinswaps_in_progress(
+        &self,
+        recovering_hashvalues: &HashSet<Hash160>,
+    ) -> Result<HashSet<Hash160>, TeleportError> {
+        let mut in_progress = self
+            .wallet
+            .read()
+            .unwrap()
+            .find_incomplete_coinswaps(self.rpc.as_ref())?
+            .into_keys()
+            .collect::<HashSet<Hash160>>();
+        in_progress.extend(
+            self.connections
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(|connection| connection.hashvalue),
+        );
+        Ok(in_progress
+            .difference(recovering_hashvalues)
+            .cloned()
+            .collect())
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "listconnections" => {
+                parse_params::<NoParams>(params)?;
+                Ok(self.list_connections())
+            }
+            "pause" => {
+                parse_params::<NoParams>(params)?;
+                if !self.paused.swap(true, Ordering::SeqCst) {
+                    log::info!("Paused, not taking new coinswaps");
+                }
+                Ok(json!("maker paused"))
+            }
+            "resume" => {
+                parse_params::<NoParams>(params)?;
+                if self.is_draining() {
+                    return Err(RpcError::new(RPC_MISC_ERROR, "maker is shutting down"));
+                }
+                if self.paused.swap(false, Ordering::SeqCst) {
+                    log::info!("Resumed, taking new coinswaps");
+                }
+                Ok(json!("maker resumed"))
+            }
+            "getofferconfig" => {
+                parse_params::<NoParams>(params)?;
+                Ok(json!(*self.offer_config.read().unwrap()))
+            }
+            "setofferconfig" => self.set_offer_config(parse_params(params)?),
+            "drain" => {
+                parse_params::<NoParams>(params)?;
+                if !self.draining.swap(true, Ordering::SeqCst) {
+                    log::info!("Draining, shutting down once no coinswap is in progress");
+                }
+                Ok(json!("maker draining"))
+            }
+            "getliquidity" => {
+                parse_params::<NoParams>(params)?;
+                self.get_liquidity()
+            }
+            _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+
+    fn list_connections(&self) -> Value {
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|c| c.connected_at);
+        json!({
+            "paused": self.paused.load(Ordering::SeqCst),
+            "draining": self.is_draining(),
+            "connections": connections,
+        })
+    }
+
+    fn set_offer_config(&self, params: SetOfferConfigParams) -> Result<Value, RpcError> {
+        let mut offer_config = self.offer_config.write().unwrap();
+        let mut new_offer_config = offer_config.clone();
+        macro_rules! set_if_some {
+            ($field:ident) => {
+                if let Some(v) = params.$field {
+                    new_offer_config.$field = v;
+                }
+            };
+        }
+        set_if_some!(absolute_fee_sat);
+        set_if_some!(amount_relative_fee_ppb);
+        set_if_some!(time_relative_fee_ppb);
+        set_if_some!(required_confirms);
+        set_if_some!(minimum_locktime);
+        set_if_some!(min_size);
+        new_offer_config
+            .validate(self.swap_deadline_blocks)
+            .map_err(|e| RpcError::new(RPC_INVALID_PARAMS, format!("{:?}", e)))?;
+        log::info!("Offer config changed = {:?}", new_offer_config);
+        *offer_config = new_offer_config;
+        Ok(json!(*offer_config))
+    }
+
+    fn get_liquidity(&self) -> Result<Value, RpcError> {
+        let wallet = self.wallet.read().unwrap();
+        let reserved_utxos = wallet.get_reserved_utxos()?;
+        let utxos = wallet.list_unspent_from_wallet(self.rpc.as_ref(), false, true)?;
+        let mut balance = 0;
+        let mut reserved_balance = 0;
+        let mut fidelity_bond_balance = 0;
+        for (utxo, spend_info) in &utxos {
+            let amount = utxo.amount.as_sat();
+            if let UTXOSpendInfo::FidelityBondCoin { .. } = spend_info {
+                fidelity_bond_balance += amount;
+                continue;
+            }
+            balance += amount;
+            if reserved_utxos.contains(&OutPoint {
+                txid: utxo.txid,
+                vout: utxo.vout,
+            }) {
+                reserved_balance += amount;
+            }
+        }
+        let incomplete_coinswaps = wallet
+            .find_incomplete_coinswaps(self.rpc.as_ref())?
+            .iter()
+            .map(|(hashvalue, (incoming, outgoing))| {
+                json!({
+                    "hashvalue": hashvalue.to_hex(),
+                    "incoming": incoming.iter().map(|(u, _)| u.amount.as_sat()).sum::<u64>(),
+                    "outgoing": outgoing.iter().map(|(u, _)| u.amount.as_sat()).sum::<u64>(),
+                })
+            })
+            .collect::<Vec<Value>>();
+        Ok(json!({
+            "balance": balance,
+            "reserved_balance": reserved_balance,
+            "offer_max_size": wallet.get_offer_maxsize_cache(),
+            "fidelity_bond_balance": fidelity_bond_balance,
+            "incomplete_coinswaps": incomplete_coinswaps,
+        }))
+    }
+}
+
+async fn handle_json_rpc(admin: Arc<MakerAdmin>, line: &str) -> Value {
+    json_rpc::handle_request(line.as_bytes(), |method, params| async move {
+        //the wallet methods block on the bitcoin node
+        tokio::task::spawn_blocking(move || admin.call(&method, params))
+            .await
+            .unwrap_or_else(|e| Err(RpcError::new(RPC_MISC_ERROR, e.to_string())))
+    })
+    .await
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    admin: Arc<MakerAdmin>,
+    stream: tokio::net::UnixStream,
+) -> Result<(), TeleportError> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = String::new();
+        let len = (&mut reader)
+            .take(MAX_REQUEST_SIZE)
+            .read_line(&mut line)
+            .await?;
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && len as u64 == MAX_REQUEST_SIZE {
+            return Err(TeleportError::Protocol("admin request too large"));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_json_rpc(Arc::clone(&admin), &line).await;
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await?;
+    }
+}
+
+/// Listen for admin requests until the returned task is aborted. The socket is only accessible
+/// to the user running the maker.
+#[cfg(unix)]
+pub(crate) fn spawn_admin_server(
+    admin: Arc<MakerAdmin>,
+    path: &Path,
+) -> Result<JoinHandle<()>, TeleportError> {
+    let listener = json_rpc::bind_private_unix_socket(path)?;
+    log::info!(
+        "Listening for admin requests On Unix Socket {}",
+        path.display()
+    );
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let admin = Arc::clone(&admin);
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(admin, stream).await {
+                            log::debug!("Admin connection error: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Error accepting admin connection: {:?}", e),
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_admin_server(
+    _admin: Arc<MakerAdmin>,
+    _path: &Path,
+) -> Result<JoinHandle<()>, TeleportError> {
+    log::warn!("Unix sockets are not supported on this platform, no admin socket");
+    Ok(tokio::spawn(async {}))
+}
+
+/// Send one request to the admin socket of a running maker, returns the whole JSON-RPC
+/// response.
+#[cfg(unix)]
+pub async fn send_request(
+    socket_path: &Path,
+    method: &str,
+    params: Value,
+) -> Result<Value, TeleportError> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(socket_path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    serde_json::from_str(&line)
+        .map_err(|_| TeleportError::Protocol("invalid response from maker admin socket"))
+}
+
+#[cfg(not(unix))]
+pub async fn send_request(
+    _socket_path: &Path,
+    _method: &str,
+    _params: Value,
+) -> Result<Value, TeleportError> {
+    Err(TeleportError::Protocol(
+        "unix sockets are not supported on this platform",
+    ))
+}
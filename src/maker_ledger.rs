@@ -0,0 +1,344 @@
+//! Ledger of the coinswaps a maker funded, and the earnings reports made from it.
+//!
+//! An entry is saved in the wallet file when the maker broadcasts its funding transactions, and
+//! is finished once the taker hands over the private keys or once the maker gives up on the
+//! coinswap after `swap_deadline_blocks`. A coinswap earns the difference between the incoming
+//! and outgoing amounts, minus the miner fee of the maker's funding transactions. Completed
+//! coinswaps count towards the amounts and earnings of a report. A timed out coinswap still cost
+//! the miner fee of its funding transactions, plus the miner fees of the contract transactions
+//! and their spends which recovery broadcasts, which are booked as a loss. Ones in progress are
+//! only counted.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use bitcoin::hashes::{hash160::Hash as Hash160, hex::ToHex};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapOutcome {
+    /// Waiting for the taker to hand over the private keys
+    InProgress,
+    /// The taker handed over the private keys
+    Completed,
+    /// The private keys weren't handed over in time, the maker recovers its coins with the
+    /// contract transactions
+    TimedOut,
+}
+
+impl SwapOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SwapOutcome::InProgress => "in_progress",
+            SwapOutcome::Completed => "completed",
+            SwapOutcome::TimedOut => "timed_out",
+        }
+    }
+}
+
+impl FromStr for SwapOutcome {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "in_progress" => SwapOutcome::InProgress,
+            "completed" => SwapOutcome::Completed,
+            "timed_out" => SwapOutcome::TimedOut,
+            _ => Err("unknown swap outcome")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwapLedgerEntry {
+    pub hashvalue: Hash160,
+    /// Unix time the maker accepted the proof of funding
+    pub started_at: u64,
+    /// Unix time the coinswap completed or timed out
+    pub finished_at: Option<u64>,
+    /// Sum of the funding outputs paid to the maker
+    pub incoming_amount: u64,
+    /// Sum of the maker's own funding outputs
+    pub outgoing_amount: u64,
+    pub coinswap_fee: u64,
+    /// Miner fee of the maker's funding transactions
+    pub miner_fee: u64,
+    /// Miner fee of the contract transactions and their spends, when the maker recovers its
+    /// coins
+    #[serde(default)]
+    pub recovery_fee: u64,
+    pub incoming_locktime: u16,
+    pub outgoing_locktime: u16,
+    pub outcome: SwapOutcome,
+}
+
+impl SwapLedgerEntry {
+    /// What the coinswap earned, negative if the miner fee was more than the taker paid for. A
+    /// timed out coinswap only lost its miner fees.
+    pub fn earned(&self) -> i64 {
+        match self.outcome {
+            SwapOutcome::TimedOut => -((self.miner_fee + self.recovery_fee) as i64),
+            _ => self.incoming_amount as i64 - self.outgoing_amount as i64 - self.miner_fee as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportPeriod {
+    Day,
+    Month,
+}
+
+impl FromStr for ReportPeriod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "day" => ReportPeriod::Day,
+            "month" => ReportPeriod::Month,
+            _ => Err("unknown period")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "table" => ReportFormat::Table,
+            "csv" => ReportFormat::Csv,
+            "json" => ReportFormat::Json,
+            _ => Err("unknown format")?,
+        })
+    }
+}
+
+/// Totals of the coinswaps which finished, or started if they haven't finished, in one day or
+/// month. Days and months are in UTC.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PeriodSummary {
+    pub period: String,
+    pub completed: u32,
+    pub timed_out: u32,
+    pub in_progress: u32,
+    /// Incoming amount of the completed coinswaps
+    pub volume: u64,
+    pub coinswap_fees: u64,
+    pub miner_fees: u64,
+    pub earned: i64,
+}
+
+pub(crate) fn format_unix_time(time: u64, format: &str) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .map(|t| t.format(format).to_string())
+        .unwrap_or_default()
+}
+
+/// Sum up the ledger per day or month, oldest first.
+pub fn summarize(entries: &[SwapLedgerEntry], period: ReportPeriod) -> Vec<PeriodSummary> {
+    let format = match period {
+        ReportPeriod::Day => "%Y-%m-%d",
+        ReportPeriod::Month => "%Y-%m",
+    };
+    let mut summaries = BTreeMap::<String, PeriodSummary>::new();
+    for entry in entries {
+        //earnings are booked when the coinswap completes
+        let key = format_unix_time(entry.finished_at.unwrap_or(entry.started_at), format);
+        let summary = summaries
+            .entry(key.clone())
+            .or_insert_with(|| PeriodSummary {
+                period: key,
+                ..PeriodSummary::default()
+            });
+        match entry.outcome {
+            SwapOutcome::InProgress => summary.in_progress += 1,
+            SwapOutcome::TimedOut => {
+                summary.timed_out += 1;
+                summary.miner_fees += entry.miner_fee + entry.recovery_fee;
+                summary.earned += entry.earned();
+            }
+            SwapOutcome::Completed => {
+                summary.completed += 1;
+                summary.volume += entry.incoming_amount;
+                summary.coinswap_fees += entry.coinswap_fee;
+                summary.miner_fees += entry.miner_fee;
+                summary.earned += entry.earned();
+            }
+        }
+    }
+    summaries.into_values().collect()
+}
+
+/// Export the summaries as CSV with a header line, or as a JSON array.
+pub fn export_summaries(summaries: &[PeriodSummary], format: ReportFormat) -> String {
+    if format == ReportFormat::Json {
+        return serde_json::to_string_pretty(summaries).unwrap() + "\n";
+    }
+    let mut csv = String::from(
+        "period,completed,timed_out,in_progress,volume,coinswap_fees,miner_fees,earned\n",
+    );
+    for s in summaries {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{}\n",
+            s.period,
+            s.completed,
+            s.timed_out,
+            s.in_progress,
+            s.volume,
+            s.coinswap_fees,
+            s.miner_fees,
+            s.earned
+        );
+    }
+    csv
+}
+
+/// Export every coinswap as CSV with a header line, or as a JSON array. Times are unix
+/// timestamps in JSON and UTC dates in CSV.
+pub fn export_entries(entries: &[SwapLedgerEntry], format: ReportFormat) -> String {
+    if format == ReportFormat::Json {
+        return serde_json::to_string_pretty(entries).unwrap() + "\n";
+    }
+    let mut csv = String::from(concat!(
+        "hashvalue,started_at,finished_at,incoming_amount,outgoing_amount,coinswap_fee,",
+        "miner_fee,recovery_fee,earned,incoming_locktime,outgoing_locktime,outcome\n"
+    ));
+    let datetime = "%Y-%m-%d %H:%M:%S";
+    for e in entries {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            e.hashvalue.to_hex(),
+            format_unix_time(e.started_at, datetime),
+            e.finished_at
+                .map(|t| format_unix_time(t, datetime))
+                .unwrap_or_default(),
+            e.incoming_amount,
+            e.outgoing_amount,
+            e.coinswap_fee,
+            e.miner_fee,
+            e.recovery_fee,
+            e.earned(),
+            e.incoming_locktime,
+            e.outgoing_locktime,
+            e.outcome.as_str()
+        );
+    }
+    csv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn entry(n: u8, finished_at: Option<u64>, outcome: SwapOutcome) -> SwapLedgerEntry {
+        SwapLedgerEntry {
+            hashvalue: Hash160::from_slice(&[n; 20]).unwrap(),
+            started_at: 1_700_000_000,
+            finished_at,
+            incoming_amount: 500_000,
+            outgoing_amount: 494_000,
+            coinswap_fee: 5_000,
+            miner_fee: 400,
+            recovery_fee: 0,
+            incoming_locktime: 50,
+            outgoing_locktime: 40,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        // 2023-11-14 22:13:20 UTC, then a day and a month later
+        let entries = vec![
+            entry(1, Some(1_700_000_100), SwapOutcome::Completed),
+            entry(2, Some(1_700_086_400), SwapOutcome::Completed),
+            entry(3, Some(1_702_592_000), SwapOutcome::TimedOut),
+            entry(4, None, SwapOutcome::InProgress),
+        ];
+        assert_eq!(entries[0].earned(), 5_600);
+        assert_eq!(entries[2].earned(), -400);
+
+        let days = summarize(&entries, ReportPeriod::Day);
+        assert_eq!(
+            days.iter().map(|s| s.period.as_str()).collect::<Vec<_>>(),
+            vec!["2023-11-14", "2023-11-15", "2023-12-14"]
+        );
+        assert_eq!(days[0].completed, 1);
+        assert_eq!(days[0].in_progress, 1);
+        assert_eq!(days[0].earned, 5_600);
+
+        let months = summarize(&entries, ReportPeriod::Month);
+        assert_eq!(months.len(), 2);
+        assert_eq!(
+            months[0],
+            PeriodSummary {
+                period: "2023-11".to_string(),
+                completed: 2,
+                timed_out: 0,
+                in_progress: 1,
+                volume: 1_000_000,
+                coinswap_fees: 10_000,
+                miner_fees: 800,
+                earned: 11_200,
+            }
+        );
+        assert_eq!(months[1].timed_out, 1);
+        assert_eq!(months[1].volume, 0);
+        assert_eq!(months[1].miner_fees, 400);
+        assert_eq!(months[1].earned, -400);
+
+        assert_eq!(
+            export_summaries(&months, ReportFormat::Csv).lines().nth(1),
+            Some("2023-11,2,0,1,1000000,10000,800,11200")
+        );
+        let csv = export_entries(&entries[3..], ReportFormat::Csv);
+        assert!(csv.lines().nth(1).unwrap().starts_with(&format!(
+            "{},2023-11-14 22:13:20,,500000",
+            entries[3].hashvalue.to_hex()
+        )));
+        let json = serde_json::from_str::<Vec<SwapLedgerEntry>>(&export_entries(
+            &entries,
+            ReportFormat::Json,
+        ))
+        .unwrap();
+        assert_eq!(json, entries);
+    }
+
+    #[test]
+    fn test_summarize_mixed_outcomes() {
+        // All finished or started on 2023-11-14
+        let mut entries = vec![
+            entry(1, Some(1_700_000_100), SwapOutcome::Completed),
+            entry(2, Some(1_700_000_200), SwapOutcome::TimedOut),
+            entry(3, Some(1_700_000_300), SwapOutcome::TimedOut),
+            entry(4, None, SwapOutcome::InProgress),
+        ];
+        // One timed out coinswap was recovered, the other is still waiting for its timelock
+        entries[1].recovery_fee = 300;
+        assert_eq!(entries[1].earned(), -700);
+        let days = summarize(&entries, ReportPeriod::Day);
+        assert_eq!(
+            days,
+            vec![PeriodSummary {
+                period: "2023-11-14".to_string(),
+                completed: 1,
+                timed_out: 2,
+                in_progress: 1,
+                volume: 500_000,
+                coinswap_fees: 5_000,
+                miner_fees: 1_500,
+                earned: 5_600 - 700 - 400,
+            }]
+        );
+    }
+}
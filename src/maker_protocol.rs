@@ -1,26 +1,18 @@
-//put your onion address and port here
-const MAKER_ONION_ADDR: &str = "myhiddenserviceaddress.onion:6102";
-const ABSOLUTE_FEE_SAT: u64 = 1000;
-const AMOUNT_RELATIVE_FEE_PPB: u64 = 10_000_000;
-const TIME_RELATIVE_FEE_PPB: u64 = 100_000;
-const REQUIRED_CONFIRMS: i32 = 1;
-const MINIMUM_LOCKTIME: u16 = 48;
-const MIN_SIZE: u64 = 10000;
-
-//TODO this goes in the config file
-
 use std::{
+    collections::HashSet,
+    fs, io,
     net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::{tcp::WriteHalf, TcpListener},
     select,
-    sync::mpsc,
-    time::sleep,
+    sync::{mpsc, mpsc::UnboundedReceiver, watch, Notify},
+    task::JoinHandle,
+    time::{interval, sleep},
 };
 
 use bitcoin::{
@@ -28,9 +20,10 @@ use bitcoin::{
     secp256k1::{SecretKey, Signature},
     Amount, Network, OutPoint, PublicKey, Transaction, TxOut, Txid,
 };
-use bitcoincore_rpc::{Client, RpcApi};
 
 use crate::{
+    blockchain_backend::BlockchainBackend,
+    config::{config_error, read_config_file},
     contracts,
     contracts::{
         calculate_coinswap_fee, find_funding_output, read_hashvalue_from_contract,
@@ -39,18 +32,29 @@ use crate::{
     },
     directory_servers::post_maker_address_to_directory_servers,
     error::TeleportError,
+    fidelity_bonds::REGTEST_DUMMY_ONION_HOSTNAME,
+    json_rpc::unix_time,
+    maker_admin::{spawn_admin_server, MakerAdmin},
+    maker_ledger::{SwapLedgerEntry, SwapOutcome},
     messages::{
-        ContractSigsAsRecvrAndSender, ContractSigsForRecvr, ContractSigsForRecvrAndSender,
-        ContractSigsForSender, HashPreimage, MakerHello, MakerToTakerMessage, MultisigPrivkey,
-        Offer, PrivKeyHandover, ProofOfFunding, ReqContractSigsForRecvr, ReqContractSigsForSender,
-        SenderContractTxInfo, TakerToMakerMessage,
+        negotiate_protocol_version, Capabilities, ContractSigsAsRecvrAndSender,
+        ContractSigsForRecvr, ContractSigsForRecvrAndSender, ContractSigsForSender,
+        FidelityBondProof, HashPreimage, MakerHello, MakerToTakerMessage, MultisigPrivkey,
+        NoiseStaticKey, Offer, PrivKeyHandover, ProofOfFunding, ProtocolError, ProtocolErrorCode,
+        ReqContractSigsForRecvr, ReqContractSigsForSender, SenderContractTxInfo,
+        TakerToMakerMessage, PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN,
     },
+    noise::{self, NoiseKeypair},
+    recovery,
+    swap_events::{SwapEvent, SwapEventSender},
+    transport::{self, Frame, Framing, MessageReader, MessageWriter},
     wallet_sync::{IncomingSwapCoin, OutgoingSwapCoin, Wallet, WalletSwapCoin},
     watchtower_client::{ping_watchtowers, register_coinswap_with_watchtowers},
     watchtower_protocol::{ContractTransaction, ContractsInfo},
 };
 
-const MAKER_HEARTBEAT_INTERVAL_SECS: u64 = 3;
+//placeholder which must be replaced in the config file when not on regtest
+const DEFAULT_MAKER_ONION_ADDR: &str = "myhiddenserviceaddress.onion:6102";
 
 //used to configure the maker do weird things for testing
 #[derive(Debug, Clone, Copy)]
@@ -59,20 +63,310 @@ pub enum MakerBehavior {
     CloseOnSignSendersContractTx,
 }
 
+/// Terms of the offer advertised to takers. Unlike the rest of [`MakerConfig`] these can be
+/// changed without restarting the maker, by editing the config file and sending SIGHUP
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct OfferConfig {
+    pub absolute_fee_sat: u64,
+    pub amount_relative_fee_ppb: u64,
+    pub time_relative_fee_ppb: u64,
+    pub required_confirms: i32,
+    pub minimum_locktime: u16,
+    pub min_size: u64,
+}
+
+impl Default for OfferConfig {
+    fn default() -> OfferConfig {
+        OfferConfig {
+            absolute_fee_sat: 1000,
+            amount_relative_fee_ppb: 10_000_000,
+            time_relative_fee_ppb: 100_000,
+            required_confirms: 1,
+            minimum_locktime: 48,
+            min_size: 10000,
+        }
+    }
+}
+
+impl OfferConfig {
+    /// The deadline of the maker running with this offer is checked too, the contracts must not
+    /// be able to time out before the maker gives up on the taker.
+    pub(crate) fn validate(&self, swap_deadline_blocks: u32) -> Result<(), TeleportError> {
+        if self.amount_relative_fee_ppb >= 1_000_000_000 {
+            return Err(config_error(
+                "amount_relative_fee_ppb must be less than 1000000000 (100%)".to_string(),
+            ));
+        }
+        if self.required_confirms < 1 {
+            return Err(config_error(
+                "required_confirms must be at least 1".to_string(),
+            ));
+        }
+        if self.minimum_locktime == 0 {
+            return Err(config_error(
+                "minimum_locktime must be at least 1".to_string(),
+            ));
+        }
+        if self.min_size == 0 {
+            return Err(config_error("min_size must be at least 1".to_string()));
+        }
+        if swap_deadline_blocks >= u32::from(self.minimum_locktime) {
+            return Err(config_error(format!(
+                "swap_deadline_blocks ({}) must be less than minimum_locktime ({})",
+                swap_deadline_blocks, self.minimum_locktime
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MakerConfig {
     pub port: u16,
+    pub onion_addr: String,
+    pub heartbeat_interval_secs: u64,
     pub rpc_ping_interval_secs: u64,
     pub watchtower_ping_interval_secs: u64,
     pub directory_servers_refresh_interval_secs: u64,
+    pub offer_config: OfferConfig,
     pub maker_behavior: MakerBehavior,
     pub kill_flag: Arc<RwLock<bool>>,
     pub idle_connection_timeout: u64,
+    /// How long the coins of an unbroadcast funding transaction are kept from other takers
+    pub utxo_reservation_expiry_secs: u64,
+    /// Blocks after the funding txes of a coinswap confirmed within which the taker must hand
+    /// over the private keys, after that the maker broadcasts the contract txes and recovers its
+    /// coins
+    pub swap_deadline_blocks: u32,
+    /// How often coinswaps are checked for having passed `swap_deadline_blocks`
+    pub swap_deadline_check_interval_secs: u64,
+    /// Config file re-read on SIGHUP, `None` means the default location
+    pub config_file: Option<PathBuf>,
+    /// Unix socket of the [admin interface](crate::maker_admin), `None` means the wallet file
+    /// name with the extension `.sock`
+    pub admin_socket: Option<PathBuf>,
+}
+
+impl Default for MakerConfig {
+    fn default() -> MakerConfig {
+        MakerConfig {
+            port: 6102,
+            onion_addr: DEFAULT_MAKER_ONION_ADDR.to_string(),
+            heartbeat_interval_secs: 3,
+            rpc_ping_interval_secs: 60,
+            watchtower_ping_interval_secs: 300,
+            directory_servers_refresh_interval_secs: 60 * 60 * 12, //12 hours
+            offer_config: OfferConfig::default(),
+            maker_behavior: MakerBehavior::Normal,
+            kill_flag: Arc::new(RwLock::new(false)),
+            idle_connection_timeout: 300,
+            utxo_reservation_expiry_secs: 60 * 60,
+            swap_deadline_blocks: 24,
+            swap_deadline_check_interval_secs: 60,
+            config_file: None,
+            admin_socket: None,
+        }
+    }
+}
+
+impl MakerConfig {
+    /// Reads the `[maker]` section of the config file, see
+    /// [`read_config_file`](crate::config::read_config_file) for how the file is found
+    pub fn load(config_file: Option<&Path>) -> Result<MakerConfig, TeleportError> {
+        let options = read_config_file(config_file)?.maker;
+        let mut config = MakerConfig::default();
+        let mut offer_config = OfferConfig::default();
+        macro_rules! set_if_some {
+            ($target:ident . $field:ident) => {
+                if let Some(v) = options.$field.clone() {
+                    $target.$field = v;
+                }
+            };
+        }
+        set_if_some!(config.port);
+        set_if_some!(config.onion_addr);
+        set_if_some!(config.heartbeat_interval_secs);
+        set_if_some!(config.rpc_ping_interval_secs);
+        set_if_some!(config.watchtower_ping_interval_secs);
+        set_if_some!(config.directory_servers_refresh_interval_secs);
+        set_if_some!(config.idle_connection_timeout);
+        set_if_some!(config.utxo_reservation_expiry_secs);
+        set_if_some!(config.swap_deadline_blocks);
+        set_if_some!(config.swap_deadline_check_interval_secs);
+        set_if_some!(offer_config.absolute_fee_sat);
+        set_if_some!(offer_config.amount_relative_fee_ppb);
+        set_if_some!(offer_config.time_relative_fee_ppb);
+        set_if_some!(offer_config.required_confirms);
+        set_if_some!(offer_config.minimum_locktime);
+        set_if_some!(offer_config.min_size);
+        config.offer_config = offer_config;
+        config.admin_socket = options.admin_socket;
+        config.config_file = config_file.map(|p| p.to_path_buf());
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), TeleportError> {
+        if self.heartbeat_interval_secs == 0
+            || self.rpc_ping_interval_secs == 0
+            || self.watchtower_ping_interval_secs == 0
+            || self.directory_servers_refresh_interval_secs == 0
+            || self.idle_connection_timeout == 0
+            || self.utxo_reservation_expiry_secs == 0
+            || self.swap_deadline_check_interval_secs == 0
+        {
+            return Err(config_error(
+                "maker intervals and timeouts must be at least 1 second".to_string(),
+            ));
+        }
+        if self.swap_deadline_blocks == 0 {
+            return Err(config_error(
+                "swap_deadline_blocks must be at least 1".to_string(),
+            ));
+        }
+        if !self.onion_addr.contains(':') {
+            return Err(config_error(format!(
+                "onion_addr must be in the form host:port, got {}",
+                self.onion_addr
+            )));
+        }
+        self.offer_config.validate(self.swap_deadline_blocks)
+    }
+
+    /// Where the maker using this wallet file listens for admin requests
+    pub fn admin_socket_path(&self, wallet_file_name: &Path) -> PathBuf {
+        self.admin_socket
+            .clone()
+            .unwrap_or_else(|| wallet_file_name.with_extension("sock"))
+    }
+}
+
+//re-reads the offer terms from the config file whenever the maker gets SIGHUP
+//everything else in the config file needs a restart to take effect
+#[cfg(unix)]
+fn spawn_offer_config_reloader(
+    config_file: Option<PathBuf>,
+    offer_config: Arc<RwLock<OfferConfig>>,
+    swap_deadline_blocks: u32,
+) -> Result<(), TeleportError> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            //only the offer is reloaded, so it has to fit the deadline the maker runs with
+            let new_config = MakerConfig::load(config_file.as_deref()).and_then(|c| {
+                c.offer_config.validate(swap_deadline_blocks)?;
+                Ok(c)
+            });
+            match new_config {
+                Ok(new_config) => {
+                    log::info!("Reloaded offer config = {:?}", new_config.offer_config);
+                    *offer_config.write().unwrap() = new_config.offer_config;
+                }
+                Err(e) => log::warn!("Not reloading offer config, invalid config file: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_offer_config_reloader(
+    _config_file: Option<PathBuf>,
+    _offer_config: Arc<RwLock<OfferConfig>>,
+    _swap_deadline_blocks: u32,
+) -> Result<(), TeleportError> {
+    Ok(())
+}
+
+/// Handle to shut down a running [Maker] from another task or thread.
+#[derive(Debug, Clone)]
+pub struct MakerHandle {
+    kill_flag: Arc<RwLock<bool>>,
+    shutdown: Arc<Notify>,
+}
+
+impl MakerHandle {
+    /// Makes [Maker::run] return. It stops accepting takers, and closes the connections of
+    /// ongoing coinswaps once they're done with the message they are handling. The takers of
+    /// those coinswaps will recover their coins via the timelock.
+    pub fn shutdown(&self) {
+        *self.kill_flag.write().unwrap() = true;
+        self.shutdown.notify_one();
+    }
+}
+
+/// A maker which runs on the caller's tokio runtime, so it can be embedded in an application
+/// which already has one. The wallet is shared with the application, but shouldn't be spent
+/// from while the maker is running.
+pub struct Maker {
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    config: MakerConfig,
+    shutdown: Arc<Notify>,
+    events: SwapEventSender,
+}
+
+impl Maker {
+    pub fn new(
+        rpc: Arc<dyn BlockchainBackend>,
+        wallet: Arc<RwLock<Wallet>>,
+        config: MakerConfig,
+    ) -> Result<Maker, TeleportError> {
+        config.validate()?;
+        Ok(Maker {
+            rpc,
+            wallet,
+            config,
+            shutdown: Arc::new(Notify::new()),
+            events: SwapEventSender::default(),
+        })
+    }
+
+    /// Get a [MakerHandle] to shut down the maker. Setting the `kill_flag` of the
+    /// [MakerConfig] also works, but only takes effect at the next heartbeat.
+    pub fn handle(&self) -> MakerHandle {
+        MakerHandle {
+            kill_flag: Arc::clone(&self.config.kill_flag),
+            shutdown: Arc::clone(&self.shutdown),
+        }
+    }
+
+    /// Receive [SwapEvent]s about the coinswaps of takers. Only the latest subscriber gets the
+    /// events.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<SwapEvent> {
+        self.events.subscribe()
+    }
+
+    /// The wallet used by the maker.
+    pub fn wallet(&self) -> &Arc<RwLock<Wallet>> {
+        &self.wallet
+    }
+
+    /// Serve takers until the maker is shut down, or until an error it can't recover from.
+    pub async fn run(&self) -> Result<(), TeleportError> {
+        run(
+            Arc::clone(&self.rpc),
+            Arc::clone(&self.wallet),
+            self.config.clone(),
+            Arc::clone(&self.shutdown),
+            self.events.clone(),
+        )
+        .await
+    }
 }
 
 #[tokio::main]
-pub async fn start_maker(rpc: Arc<Client>, wallet: Arc<RwLock<Wallet>>, config: MakerConfig) {
-    match run(rpc, wallet, config).await {
+pub async fn start_maker(
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    config: MakerConfig,
+) {
+    let result = match Maker::new(rpc, wallet, config) {
+        Ok(maker) => maker.run().await,
+        Err(e) => Err(e),
+    };
+    match result {
         Ok(_o) => log::info!("maker ended without error"),
         Err(e) => log::info!("maker ended with err: {:?}", e),
     };
@@ -83,8 +377,8 @@ pub async fn start_maker(rpc: Arc<Client>, wallet: Arc<RwLock<Wallet>>, config:
 //
 // If the recieved message doesn't match expected method,
 // protocol error will be returned.
-#[derive(Debug)]
-enum ExpectedMessage {
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(crate) enum ExpectedMessage {
     TakerHello,
     NewlyConnectedTaker,
     SignSendersContractTx,
@@ -97,15 +391,172 @@ enum ExpectedMessage {
 
 struct ConnectionState {
     allowed_message: ExpectedMessage,
+    //offer terms at the time the taker connected, so a reload doesnt change them mid-swap
+    offer_config: OfferConfig,
+    fidelity_bond_proof: Option<FidelityBondProof>,
+    noise_static_key: NoiseStaticKey,
+    incoming_swapcoins: Option<Vec<IncomingSwapCoin>>,
+    outgoing_swapcoins: Option<Vec<OutgoingSwapCoin>>,
+    //the coins spent by these are reserved in the wallet until they are broadcast
+    pending_funding_txes: Option<Vec<Transaction>>,
+    //added to the ledger once the funding txes are broadcast
+    ledger_entry: Option<SwapLedgerEntry>,
+    utxo_reservation_expiry: Duration,
+    //known once the taker sent a message about a particular coinswap
+    hashvalue: Option<Hash160>,
+    //none until the taker said hello, takers from before capabilities existed send none at all
+    taker_capabilities: Capabilities,
+    //how replies are written, agreed on in the hellos
+    framing: Framing,
+    //row in the wallet file, saved once the taker said which coinswap the connection is for
+    persisted_id: Option<i64>,
+}
+
+/// What is saved of a [ConnectionState] after every message, so a swap which was in progress
+/// when the maker crashed can be dealt with on the next startup.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PersistedConnectionState {
+    allowed_message: ExpectedMessage,
+    hashvalue: Hash160,
     incoming_swapcoins: Option<Vec<IncomingSwapCoin>>,
     outgoing_swapcoins: Option<Vec<OutgoingSwapCoin>>,
     pending_funding_txes: Option<Vec<Transaction>>,
+    #[serde(default)]
+    ledger_entry: Option<SwapLedgerEntry>,
+}
+
+fn persist_connection_state(
+    wallet: &Wallet,
+    connection_state: &mut ConnectionState,
+) -> Result<(), TeleportError> {
+    //nothing to recover before that
+    let hashvalue = match connection_state.hashvalue {
+        Some(hashvalue) => hashvalue,
+        None => return Ok(()),
+    };
+    let state = serde_json::to_string(&PersistedConnectionState {
+        allowed_message: connection_state.allowed_message,
+        hashvalue,
+        incoming_swapcoins: connection_state.incoming_swapcoins.clone(),
+        outgoing_swapcoins: connection_state.outgoing_swapcoins.clone(),
+        pending_funding_txes: connection_state.pending_funding_txes.clone(),
+        ledger_entry: connection_state.ledger_entry.clone(),
+    })
+    .map_err(io::Error::from)?;
+    connection_state.persisted_id =
+        Some(wallet.save_maker_connection(connection_state.persisted_id, &state)?);
+    Ok(())
+}
+
+/// Deal with the connections which were in the middle of a swap when the maker last stopped.
+/// Swaps whose funding txes were never broadcast are dropped, the others are handed to the
+/// watchtower and left in the wallet for `recover-coinswaps`.
+async fn reconcile_persisted_connections(
+    rpc: &Arc<dyn BlockchainBackend>,
+    wallet: &Arc<RwLock<Wallet>>,
+) -> Result<(), TeleportError> {
+    let persisted_connections = wallet.read().unwrap().load_maker_connections()?;
+    for (id, state) in persisted_connections {
+        match reconcile_persisted_connection(rpc, wallet, &state).await {
+            Ok(()) => wallet.read().unwrap().delete_maker_connection(id)?,
+            //one bad row shouldnt stop the maker, it's kept to be tried again on the next start
+            Err(e) => log::error!(
+                "Failed to reconcile persisted connection id={}, keeping it: {:?}",
+                id,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+async fn reconcile_persisted_connection(
+    rpc: &Arc<dyn BlockchainBackend>,
+    wallet: &Arc<RwLock<Wallet>>,
+    state: &str,
+) -> Result<(), TeleportError> {
+    let state = serde_json::from_str::<PersistedConnectionState>(state).map_err(io::Error::from)?;
+    let contracts = {
+        let mut w = wallet.write().unwrap();
+        let funding_broadcasted = state
+            .pending_funding_txes
+            .iter()
+            .flatten()
+            .any(|tx| rpc.get_transaction(&tx.txid()).is_ok());
+        if funding_broadcasted {
+            //the swapcoins are normally saved before broadcasting, this is a fallback
+            let incoming_swapcoins = state
+                .incoming_swapcoins
+                .iter()
+                .flatten()
+                .filter(|swapcoin| {
+                    swapcoin.others_contract_sig.is_some()
+                        && w.find_incoming_swapcoin(&swapcoin.get_multisig_redeemscript())
+                            .is_none()
+                })
+                .cloned()
+                .collect();
+            let outgoing_swapcoins = state
+                .outgoing_swapcoins
+                .iter()
+                .flatten()
+                .filter(|swapcoin| {
+                    swapcoin.others_contract_sig.is_some()
+                        && w.find_outgoing_swapcoin(&swapcoin.get_multisig_redeemscript())
+                            .is_none()
+                })
+                .cloned()
+                .collect();
+            w.add_swapcoins(incoming_swapcoins, outgoing_swapcoins)?;
+            if let Some(entry) = &state.ledger_entry {
+                w.add_swap_ledger_entry(entry)?;
+            }
+        } else if let Some(funding_txes) = &state.pending_funding_txes {
+            release_funding_tx_inputs(&mut w, Arc::clone(rpc), funding_txes)?;
+        }
+        let incomplete_coinswaps = w.find_incomplete_coinswaps(rpc.as_ref())?;
+        match incomplete_coinswaps.get(&state.hashvalue) {
+            Some((incoming_swapcoins, outgoing_swapcoins)) => Some(contracts_to_watch(
+                &w,
+                rpc.as_ref(),
+                &incoming_swapcoins
+                    .iter()
+                    .map(|(_, swapcoin)| (*swapcoin).clone())
+                    .collect::<Vec<_>>(),
+                &outgoing_swapcoins
+                    .iter()
+                    .map(|(_, swapcoin)| (*swapcoin).clone())
+                    .collect::<Vec<_>>(),
+            )?),
+            None => None,
+        }
+    };
+    match contracts {
+        Some(contracts) => {
+            register_coinswap_with_watchtowers(contracts).await?;
+            log::warn!(
+                "Coinswap hashvalue={} was in progress when the maker stopped (expecting {:?}), \
+                its contracts are now watched, run recover-coinswaps if the taker doesnt \
+                finish it",
+                state.hashvalue,
+                state.allowed_message
+            );
+        }
+        None => log::info!(
+            "Dropping coinswap hashvalue={} which was never funded (expecting {:?})",
+            state.hashvalue,
+            state.allowed_message
+        ),
+    }
+    Ok(())
 }
 
 async fn run(
-    rpc: Arc<Client>,
+    rpc: Arc<dyn BlockchainBackend>,
     wallet: Arc<RwLock<Wallet>>,
     config: MakerConfig,
+    shutdown: Arc<Notify>,
+    events: SwapEventSender,
 ) -> Result<(), TeleportError> {
     log::debug!(
         "Running maker with special behavior = {:?}",
@@ -116,34 +567,94 @@ async fn run(
         .unwrap()
         .refresh_offer_maxsize_cache(Arc::clone(&rpc))?;
 
+    let network = wallet.read().unwrap().network;
+    let fidelity_bond_hostname = if network == Network::Regtest {
+        REGTEST_DUMMY_ONION_HOSTNAME
+    } else {
+        config.onion_addr.split(':').next().unwrap()
+    };
+    let tweakable_privkey = wallet.read().unwrap().get_tweakable_keypair().0;
+    let noise_keypair = Arc::new(NoiseKeypair::from_tweakable_privkey(&tweakable_privkey));
+    let noise_static_key = noise_keypair.sign_with_tweakable_privkey(&tweakable_privkey);
+
+    let fidelity_bond_proof = wallet
+        .read()
+        .unwrap()
+        .create_most_valuable_fidelity_bond_proof(
+            rpc.as_ref(),
+            fidelity_bond_hostname,
+            Some(noise_keypair.public_key()),
+        )?;
+    match &fidelity_bond_proof {
+        Some(proof) => log::info!("Announcing fidelity bond {}", proof.utxo),
+        None => log::info!("No confirmed fidelity bond in wallet, not announcing one"),
+    }
+    let fidelity_bond_proof = Arc::new(RwLock::new(fidelity_bond_proof));
+
     log::info!("Pinging watchtowers. . .");
     ping_watchtowers().await?;
 
-    if wallet.read().unwrap().network != Network::Regtest {
-        if MAKER_ONION_ADDR == "myhiddenserviceaddress.onion:6102" {
-            panic!("You must set config variable MAKER_ONION_ADDR in file src/maker_protocol.rs");
+    reconcile_persisted_connections(&rpc, &wallet).await?;
+
+    if network != Network::Regtest {
+        if config.onion_addr == DEFAULT_MAKER_ONION_ADDR {
+            return Err(config_error(
+                "you must set onion_addr in the [maker] section of the config file".to_string(),
+            ));
         }
         log::info!(
             "Adding my address ({}) to the directory servers. . .",
-            MAKER_ONION_ADDR
+            config.onion_addr
         );
-        post_maker_address_to_directory_servers(wallet.read().unwrap().network, MAKER_ONION_ADDR)
-            .await
-            .expect("unable to add my address to the directory servers, is tor reachable?");
+        if let Err(e) = post_maker_address_to_directory_servers(network, &config.onion_addr).await {
+            log::error!(
+                "unable to add my address to the directory servers, is tor reachable? error={:?}",
+                e
+            );
+            return Err(e.into());
+        }
     }
 
+    let offer_config = Arc::new(RwLock::new(config.offer_config.clone()));
+    spawn_offer_config_reloader(
+        config.config_file.clone(),
+        Arc::clone(&offer_config),
+        config.swap_deadline_blocks,
+    )?;
+
+    let admin = Arc::new(MakerAdmin::new(
+        Arc::clone(&rpc),
+        Arc::clone(&wallet),
+        Arc::clone(&offer_config),
+        config.swap_deadline_blocks,
+    ));
+    let admin_socket_path = config.admin_socket_path(wallet.read().unwrap().get_wallet_file_path());
+
     let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.port)).await?;
     log::info!("Listening On Port {}", config.port);
+    let admin_server = spawn_admin_server(Arc::clone(&admin), &admin_socket_path)?;
 
     let (server_loop_comms_tx, mut server_loop_comms_rx) = mpsc::channel::<TeleportError>(100);
     let mut accepting_clients = true;
     let mut last_rpc_ping = Instant::now();
     let mut last_watchtowers_ping = Instant::now();
     let mut last_directory_servers_refresh = Instant::now();
+    let recovery_file_path = wallet
+        .read()
+        .unwrap()
+        .get_wallet_file_path()
+        .with_extension("recovery");
+    let mut recovering_hashvalues = HashSet::<Hash160>::new();
+    //unlike the heartbeat this isnt delayed by takers connecting all the time
+    let mut swap_deadline_check = interval(Duration::from_secs(
+        config.swap_deadline_check_interval_secs,
+    ));
 
     let my_kill_flag = config.kill_flag.clone();
+    let mut connections = Vec::<JoinHandle<()>>::new();
+    let (connections_shutdown_tx, connections_shutdown_rx) = watch::channel(());
 
-    loop {
+    let result = loop {
         let (mut socket, addr) = select! {
             new_client = listener.accept() => new_client?,
             client_err = server_loop_comms_rx.recv() => {
@@ -164,7 +675,24 @@ async fn run(
                 }
                 break Err(client_err.unwrap());
             },
-            _ = sleep(Duration::from_secs(MAKER_HEARTBEAT_INTERVAL_SECS)) => {
+            _ = shutdown.notified() => {
+                log::info!("Shutting down maker");
+                break Ok(());
+            },
+            _ = swap_deadline_check.tick() => {
+                if let Err(e) = check_swap_deadlines(
+                    rpc.as_ref(),
+                    &mut wallet.write().unwrap(),
+                    config.swap_deadline_blocks,
+                    &recovery_file_path,
+                    &mut recovering_hashvalues,
+                    &events,
+                ) {
+                    log::warn!("unable to check coinswap deadlines: {:?}", e);
+                }
+                continue;
+            },
+            _ = sleep(Duration::from_secs(config.heartbeat_interval_secs)) => {
                 let mut rpc_ping_success = true;
                 let mut watchtowers_ping_success = true;
 
@@ -177,6 +705,19 @@ async fn run(
                         .refresh_offer_maxsize_cache(Arc::clone(&rpc))
                         .is_ok();
                     log::debug!("rpc_ping_success = {}", rpc_ping_success);
+                    //the proof certificate expires, so it is recreated as the chain moves on
+                    let new_proof = wallet
+                        .read()
+                        .unwrap()
+                        .create_most_valuable_fidelity_bond_proof(
+                            rpc.as_ref(),
+                            fidelity_bond_hostname,
+                            Some(noise_keypair.public_key()),
+                        );
+                    match new_proof {
+                        Ok(proof) => *fidelity_bond_proof.write().unwrap() = proof,
+                        Err(e) => log::warn!("unable to refresh fidelity bond proof: {:?}", e),
+                    }
                 }
                 let watchtowers_ping_interval
                     = Duration::from_secs(config.watchtower_ping_interval_secs);
@@ -193,19 +734,33 @@ async fn run(
                 }
 
                 if *my_kill_flag.read().unwrap() {
-                    break Err(TeleportError::Protocol("kill flag is true"));
+                    log::info!("Kill flag set, shutting down maker");
+                    break Ok(());
+                }
+
+                if admin.is_draining() {
+                    match admin.coinswaps_in_progress(&recovering_hashvalues) {
+                        Ok(in_progress) if in_progress.is_empty() => {
+                            log::info!("No coinswaps in progress, shutting down maker");
+                            break Ok(());
+                        }
+                        Ok(in_progress) => log::debug!(
+                            "Draining, waiting for {} coinswaps", in_progress.len()
+                        ),
+                        Err(e) => log::warn!("unable to find coinswaps in progress: {:?}", e),
+                    }
                 }
 
                 let directory_servers_refresh_interval = Duration::from_secs(
                     config.directory_servers_refresh_interval_secs
                 );
-                if wallet.read().unwrap().network != Network::Regtest
+                if network != Network::Regtest
                         && Instant::now().saturating_duration_since(last_directory_servers_refresh)
                         > directory_servers_refresh_interval {
                     last_directory_servers_refresh = Instant::now();
                     let result_expiry_time = post_maker_address_to_directory_servers(
-                        wallet.read().unwrap().network,
-                        MAKER_ONION_ADDR
+                        network,
+                        &config.onion_addr
                     ).await;
                     log::info!("Refreshing my address at the directory servers = {:?}",
                         result_expiry_time);
@@ -229,23 +784,44 @@ async fn run(
         let server_loop_comms_tx = server_loop_comms_tx.clone();
         let maker_behavior = config.maker_behavior;
         let idle_connection_timeout = config.idle_connection_timeout;
+        let utxo_reservation_expiry = Duration::from_secs(config.utxo_reservation_expiry_secs);
+        let connection_offer_config = offer_config.read().unwrap().clone();
+        let connection_fidelity_bond_proof = fidelity_bond_proof.read().unwrap().clone();
+        let connection_noise_static_key = noise_static_key.clone();
+        let connection_noise_keypair = Arc::clone(&noise_keypair);
+        let connection_events = events.clone();
+        let connection_admin = Arc::clone(&admin);
+
+        let mut connection_shutdown = connections_shutdown_rx.clone();
 
-        tokio::spawn(async move {
-            let (socket_reader, mut socket_writer) = socket.split();
-            let mut reader = BufReader::new(socket_reader);
+        connections.retain(|connection| !connection.is_finished());
+        connections.push(tokio::spawn(async move {
+            let (socket_reader, socket_writer) = socket.split();
+            let mut reader = MessageReader::new(socket_reader);
+            let mut socket_writer = MessageWriter::new(socket_writer);
 
             let mut connection_state = ConnectionState {
                 allowed_message: ExpectedMessage::TakerHello,
+                offer_config: connection_offer_config,
+                fidelity_bond_proof: connection_fidelity_bond_proof,
+                noise_static_key: connection_noise_static_key,
                 incoming_swapcoins: None,
                 outgoing_swapcoins: None,
                 pending_funding_txes: None,
+                ledger_entry: None,
+                utxo_reservation_expiry,
+                hashvalue: None,
+                taker_capabilities: Capabilities::default(),
+                framing: Framing::Lines,
+                persisted_id: None,
             };
 
             if let Err(e) = send_message(
                 &mut socket_writer,
                 &MakerToTakerMessage::MakerHello(MakerHello {
-                    protocol_version_min: 0,
-                    protocol_version_max: 0,
+                    protocol_version_min: PROTOCOL_VERSION_MIN,
+                    protocol_version_max: PROTOCOL_VERSION_MAX,
+                    capabilities: Capabilities::ours(),
                 }),
             )
             .await
@@ -254,39 +830,113 @@ async fn run(
                 return;
             }
             log::info!("[{}] <=== MakerHello", addr.port());
+            let connection_id = connection_admin.add_connection(addr);
+            //a handshake is only accepted as the first frame after the hellos
+            let mut handshake_allowed = false;
 
             loop {
-                let mut line = String::new();
-                select! {
-                    readline_ret = reader.read_line(&mut line) => {
-                        match readline_ret {
-                            Ok(n) if n == 0 => {
-                                log::info!("[{}] Connection closed by peer", addr.port());
-                                break;
+                let read_ret = select! {
+                    //a message which already arrived is handled before shutting down
+                    biased;
+                    read_ret = reader.read_frame() => read_ret,
+                    _ = sleep(Duration::from_secs(idle_connection_timeout)) => {
+                        log::info!("[{}] Idle connection closed", addr.port());
+                        break;
+                    },
+                    _ = connection_shutdown.changed() => {
+                        log::info!("[{}] Connection closed, maker shutting down", addr.port());
+                        break;
+                    },
+                };
+
+                let expecting_hello = matches!(
+                    connection_state.allowed_message,
+                    ExpectedMessage::TakerHello
+                );
+                let message_result = match read_ret {
+                    Ok(Some(Frame::Message(payload))) => {
+                        match transport::decode_message(&payload) {
+                            //takers in the middle of a coinswap can still finish it
+                            Ok(TakerToMakerMessage::ReqGiveOffer(_))
+                            | Ok(TakerToMakerMessage::ReqContractSigsForSender(_))
+                                if !connection_admin.is_taking_new_coinswaps() =>
+                            {
+                                Err(TeleportError::Rejected(
+                                    ProtocolErrorCode::Internal,
+                                    "maker is not taking new coinswaps",
+                                ))
+                            }
+                            Ok(request) => {
+                                handle_message(
+                                    request,
+                                    &mut connection_state,
+                                    Arc::clone(&client_rpc),
+                                    Arc::clone(&client_wallet),
+                                    addr,
+                                    maker_behavior,
+                                    &connection_events,
+                                )
+                                .await
+                            }
+                            Err(e) => Err(rejected(ProtocolErrorCode::UnexpectedMessage)(e)),
+                        }
+                    }
+                    Ok(Some(Frame::Handshake(message))) if handshake_allowed => {
+                        handshake_allowed = false;
+                        match noise::respond(
+                            &message,
+                            &mut reader,
+                            &mut socket_writer,
+                            &connection_noise_keypair,
+                        )
+                        .await
+                        {
+                            Ok(_) => {
+                                log::info!("[{}] Connection encrypted", addr.port());
+                                continue;
                             }
-                            Ok(_n) => (),
                             Err(e) => {
-                                log::error!("error reading from socket: {:?}", e);
+                                log::error!("[{}] noise handshake failed: {:?}", addr.port(), e);
                                 break;
                             }
                         }
-                    },
-                    _ = sleep(Duration::from_secs(idle_connection_timeout)) => {
-                        log::info!("[{}] Idle connection closed", addr.port());
+                    }
+                    Ok(Some(Frame::Handshake(_))) => Err(TeleportError::Rejected(
+                        ProtocolErrorCode::UnexpectedMessage,
+                        "unexpected handshake",
+                    )),
+                    Ok(None) => {
+                        log::info!("[{}] Connection closed by peer", addr.port());
                         break;
-                    },
+                    }
+                    //unparsable or oversized messages are answered with an error message
+                    Err(TeleportError::Protocol(e)) => Err(TeleportError::Rejected(
+                        ProtocolErrorCode::UnexpectedMessage,
+                        e,
+                    )),
+                    Err(e) => {
+                        log::error!("error reading from socket: {:?}", e);
+                        break;
+                    }
                 };
-
-                line = line.trim_end().to_string();
-                let message_result = handle_message(
-                    line,
-                    &mut connection_state,
-                    Arc::clone(&client_rpc),
-                    Arc::clone(&client_wallet),
-                    addr,
-                    maker_behavior,
-                )
-                .await;
+                handshake_allowed = expecting_hello
+                    && !matches!(
+                        connection_state.allowed_message,
+                        ExpectedMessage::TakerHello
+                    );
+                let message_result = message_result.and_then(|reply| {
+                    persist_connection_state(
+                        &client_wallet.read().unwrap(),
+                        &mut connection_state,
+                    )?;
+                    Ok(reply)
+                });
+                connection_admin.update_connection(
+                    connection_id,
+                    connection_state.allowed_message,
+                    connection_state.hashvalue,
+                );
+                socket_writer.set_framing(connection_state.framing);
                 match message_result {
                     Ok(reply) => {
                         if let Some(message) = reply {
@@ -299,9 +949,31 @@ async fn run(
                     }
                     Err(err) => {
                         log::error!("error handling client request: {:?}", err);
+                        if let Some(error) = protocol_error_reply(&err).filter(|_| {
+                            connection_state
+                                .taker_capabilities
+                                .contains(Capabilities::ERROR_MESSAGES)
+                        }) {
+                            //the connection is closed anyway, so a failure to send is ignored
+                            log::info!("[{}] <=== Error {:?}", addr.port(), error.code);
+                            let _ = send_message(
+                                &mut socket_writer,
+                                &MakerToTakerMessage::Error(error),
+                            )
+                            .await;
+                        }
+                        if let Some(hashvalue) = connection_state.hashvalue {
+                            connection_events.send(SwapEvent::Failed {
+                                hashvalue,
+                                error: format!("{:?}", err),
+                            });
+                        }
                         match err {
                             TeleportError::Network(_e) => (),
                             TeleportError::Protocol(_e) => (),
+                            TeleportError::Cancelled => (),
+                            TeleportError::Peer(_e) => (),
+                            TeleportError::Rejected(_c, _e) => (),
                             TeleportError::Disk(e) => server_loop_comms_tx
                                 .send(TeleportError::Disk(e))
                                 .await
@@ -314,39 +986,144 @@ async fn run(
                                 .send(TeleportError::Socks(e))
                                 .await
                                 .unwrap(),
+                            TeleportError::Database(e) => server_loop_comms_tx
+                                .send(TeleportError::Database(e))
+                                .await
+                                .unwrap(),
                         };
                         break;
                     }
                 };
             }
+            connection_admin.remove_connection(connection_id);
+            //the funding txes will never be broadcast, so their coins can go to other takers
+            if let Some(funding_txes) = connection_state.pending_funding_txes.take() {
+                if let Err(e) = release_funding_tx_inputs(
+                    &mut client_wallet.write().unwrap(),
+                    Arc::clone(&client_rpc),
+                    &funding_txes,
+                ) {
+                    log::error!("unable to release reserved utxos: {:?}", e);
+                }
+            }
+            //while the maker is running the wallet alone is enough to finish or recover the swap
+            if let Some(id) = connection_state.persisted_id {
+                if let Err(e) = client_wallet.read().unwrap().delete_maker_connection(id) {
+                    log::error!("unable to delete connection state: {:?}", e);
+                }
+            }
+        }));
+    };
+
+    //connections end once they are done with the message they are handling
+    let _ = connections_shutdown_tx.send(());
+    for connection in connections {
+        let _ = connection.await;
+    }
+    admin_server.abort();
+    if admin_socket_path.exists() {
+        if let Err(e) = fs::remove_file(&admin_socket_path) {
+            log::warn!("unable to remove admin socket: {:?}", e);
+        }
+    }
+    result
+}
+
+//hands the coinswaps whose private keys didnt arrive in time to the recovery code, and moves on
+// the recoveries already started
+fn check_swap_deadlines(
+    rpc: &dyn BlockchainBackend,
+    wallet: &mut Wallet,
+    swap_deadline_blocks: u32,
+    recovery_file_path: &Path,
+    recovering_hashvalues: &mut HashSet<Hash160>,
+    events: &SwapEventSender,
+) -> Result<(), TeleportError> {
+    let expired_hashvalues = wallet
+        .find_incomplete_coinswaps(rpc)?
+        .iter()
+        .filter(|(hashvalue, (incoming, outgoing))| {
+            let confirmations = incoming
+                .iter()
+                .map(|(utxo, _)| utxo.confirmations)
+                .chain(outgoing.iter().map(|(utxo, _)| utxo.confirmations))
+                .max()
+                .unwrap_or(0);
+            !recovering_hashvalues.contains(*hashvalue) && confirmations >= swap_deadline_blocks
+        })
+        .map(|(hashvalue, _)| *hashvalue)
+        .collect::<Vec<Hash160>>();
+    for hashvalue in expired_hashvalues {
+        log::warn!(
+            "Private keys of coinswap hashvalue={} not handed over within {} blocks, \
+            broadcasting contract txes",
+            hashvalue,
+            swap_deadline_blocks
+        );
+        recovery::add_to_recovery(rpc, wallet, Some(hashvalue), recovery_file_path)?;
+        recovering_hashvalues.insert(hashvalue);
+        wallet.finish_swap_ledger_entry(&hashvalue, SwapOutcome::TimedOut)?;
+        events.send(SwapEvent::Failed {
+            hashvalue,
+            error: format!(
+                "private keys not handed over within {} blocks",
+                swap_deadline_blocks
+            ),
         });
     }
+    if recovery::poll_recovery(rpc, wallet, recovery_file_path)?
+        && !recovering_hashvalues.is_empty()
+    {
+        log::info!("All coins of expired coinswaps recovered");
+        recovering_hashvalues.clear();
+    }
+    Ok(())
 }
 
 async fn send_message(
-    socket_writer: &mut WriteHalf<'_>,
-    first_message: &MakerToTakerMessage,
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
+    message: &MakerToTakerMessage,
 ) -> Result<(), TeleportError> {
-    let mut message_bytes =
-        serde_json::to_vec(first_message).map_err(|e| std::io::Error::from(e))?;
-    message_bytes.push(b'\n');
-    socket_writer.write_all(&message_bytes).await?;
-    Ok(())
+    socket_writer.send(message).await
+}
+
+//errors from checking the taker's message are sent back to it with this code
+fn rejected(code: ProtocolErrorCode) -> impl FnOnce(TeleportError) -> TeleportError {
+    move |e| match e {
+        TeleportError::Protocol(message) => TeleportError::Rejected(code, message),
+        e => e,
+    }
+}
+
+//the error message telling the taker why its message was rejected, if it should be told
+fn protocol_error_reply(err: &TeleportError) -> Option<ProtocolError> {
+    let (code, message) = match err {
+        TeleportError::Rejected(code, message) => (*code, *message),
+        TeleportError::Protocol(message) => (ProtocolErrorCode::Other, *message),
+        //details of our own problems are none of the taker's business
+        TeleportError::Disk(_)
+        | TeleportError::Rpc(_)
+        | TeleportError::Socks(_)
+        | TeleportError::Database(_) => (ProtocolErrorCode::Internal, "internal error"),
+        TeleportError::Network(_) | TeleportError::Cancelled | TeleportError::Peer(_) => {
+            return None
+        }
+    };
+    Some(ProtocolError {
+        code,
+        message: message.to_string(),
+    })
 }
 
 async fn handle_message(
-    line: String,
+    request: TakerToMakerMessage,
     connection_state: &mut ConnectionState,
-    rpc: Arc<Client>,
+    rpc: Arc<dyn BlockchainBackend>,
     wallet: Arc<RwLock<Wallet>>,
     from_addrs: SocketAddr,
     maker_behavior: MakerBehavior,
+    events: &SwapEventSender,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
-    let request: TakerToMakerMessage = match serde_json::from_str(&line) {
-        Ok(r) => r,
-        Err(_e) => return Err(TeleportError::Protocol("message parsing error")),
-    };
-
     log::info!(
         "[{}] ===> {} ",
         from_addrs.port(),
@@ -360,17 +1137,59 @@ async fn handle_message(
             TakerToMakerMessage::ReqContractSigsForRecvr(_) => "SignReceiversContractTx",
             TakerToMakerMessage::RespHashPreimage(_) => "HashPreimage",
             TakerToMakerMessage::RespPrivKeyHandover(_) => "PrivateKeyHandover",
+            TakerToMakerMessage::Error(_) => "Error",
         }
     );
     log::debug!("{:#?}", request);
 
+    if let TakerToMakerMessage::Error(error) = request {
+        log::warn!(
+            "[{}] Taker rejected our message: {}",
+            from_addrs.port(),
+            error.message
+        );
+        return Err(TeleportError::Peer(error));
+    }
+
     let outgoing_message = match connection_state.allowed_message {
         ExpectedMessage::TakerHello => {
-            if let TakerToMakerMessage::TakerHello(_) = request {
+            if let TakerToMakerMessage::TakerHello(hello) = request {
+                connection_state.taker_capabilities = hello.capabilities;
+                let protocol_version = match negotiate_protocol_version(
+                    hello.protocol_version_min,
+                    hello.protocol_version_max,
+                ) {
+                    Some(v) => v,
+                    None => {
+                        log::warn!(
+                            "[{}] Taker speaks protocol versions {} to {}, we speak {} to {}",
+                            from_addrs.port(),
+                            hello.protocol_version_min,
+                            hello.protocol_version_max,
+                            PROTOCOL_VERSION_MIN,
+                            PROTOCOL_VERSION_MAX
+                        );
+                        return Err(TeleportError::Rejected(
+                            ProtocolErrorCode::UnsupportedVersion,
+                            "no protocol version in common with maker",
+                        ));
+                    }
+                };
+                log::debug!(
+                    "[{}] protocol version={} capabilities={:?}",
+                    from_addrs.port(),
+                    protocol_version,
+                    hello.capabilities
+                );
+                connection_state.framing =
+                    Framing::negotiated(protocol_version, hello.capabilities);
                 connection_state.allowed_message = ExpectedMessage::NewlyConnectedTaker;
                 None
             } else {
-                return Err(TeleportError::Protocol("Expected Taker Hello Message"));
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
+                    "Expected Taker Hello Message",
+                ));
             }
         }
         ExpectedMessage::NewlyConnectedTaker => match request {
@@ -378,36 +1197,50 @@ async fn handle_message(
                 let max_size = wallet.read().unwrap().get_offer_maxsize_cache();
                 let tweakable_point = wallet.read().unwrap().get_tweakable_keypair().1;
                 connection_state.allowed_message = ExpectedMessage::SignSendersContractTx;
+                let offer_config = &connection_state.offer_config;
                 Some(MakerToTakerMessage::RespOffer(Offer {
-                    absolute_fee_sat: ABSOLUTE_FEE_SAT,
-                    amount_relative_fee_ppb: AMOUNT_RELATIVE_FEE_PPB,
-                    time_relative_fee_ppb: TIME_RELATIVE_FEE_PPB,
-                    required_confirms: REQUIRED_CONFIRMS,
-                    minimum_locktime: MINIMUM_LOCKTIME,
+                    absolute_fee_sat: offer_config.absolute_fee_sat,
+                    amount_relative_fee_ppb: offer_config.amount_relative_fee_ppb,
+                    time_relative_fee_ppb: offer_config.time_relative_fee_ppb,
+                    required_confirms: offer_config.required_confirms,
+                    minimum_locktime: offer_config.minimum_locktime,
                     max_size,
-                    min_size: MIN_SIZE,
+                    min_size: offer_config.min_size,
                     tweakable_point,
+                    fidelity_bond_proof: connection_state.fidelity_bond_proof.clone(),
+                    noise_static_key: Some(connection_state.noise_static_key.clone()),
                 }))
             }
             TakerToMakerMessage::ReqContractSigsForSender(message) => {
                 connection_state.allowed_message = ExpectedMessage::ProofOfFunding;
-                handle_sign_senders_contract_tx(wallet, message, maker_behavior)?
+                connection_state.hashvalue = Some(message.hashvalue);
+                handle_sign_senders_contract_tx(
+                    wallet,
+                    message,
+                    &connection_state.offer_config,
+                    maker_behavior,
+                )
+                .map_err(rejected(ProtocolErrorCode::InvalidContract))?
             }
             TakerToMakerMessage::RespProofOfFunding(proof) => {
                 connection_state.allowed_message =
                     ExpectedMessage::ProofOfFundingORSendersAndReceiversContractSigs;
-                handle_proof_of_funding(connection_state, rpc, wallet, &proof)?
+                handle_proof_of_funding(connection_state, rpc, wallet, &proof)
+                    .map_err(rejected(ProtocolErrorCode::InvalidFunding))?
             }
             TakerToMakerMessage::ReqContractSigsForRecvr(message) => {
                 connection_state.allowed_message = ExpectedMessage::HashPreimage;
-                handle_sign_receivers_contract_tx(wallet, message)?
+                handle_sign_receivers_contract_tx(wallet, message)
+                    .map_err(rejected(ProtocolErrorCode::InvalidContract))?
             }
             TakerToMakerMessage::RespHashPreimage(message) => {
                 connection_state.allowed_message = ExpectedMessage::PrivateKeyHandover;
-                handle_hash_preimage(wallet, message)?
+                handle_hash_preimage(connection_state, wallet, message, events)
+                    .map_err(rejected(ProtocolErrorCode::InvalidPreimage))?
             }
             _ => {
-                return Err(TeleportError::Protocol(
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
                     "Unexpected Newly Connected Taker message",
                 ));
             }
@@ -415,9 +1248,17 @@ async fn handle_message(
         ExpectedMessage::SignSendersContractTx => {
             if let TakerToMakerMessage::ReqContractSigsForSender(message) = request {
                 connection_state.allowed_message = ExpectedMessage::ProofOfFunding;
-                handle_sign_senders_contract_tx(wallet, message, maker_behavior)?
+                connection_state.hashvalue = Some(message.hashvalue);
+                handle_sign_senders_contract_tx(
+                    wallet,
+                    message,
+                    &connection_state.offer_config,
+                    maker_behavior,
+                )
+                .map_err(rejected(ProtocolErrorCode::InvalidContract))?
             } else {
-                return Err(TeleportError::Protocol(
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
                     "Expected Sign sender's contract transaction message",
                 ));
             }
@@ -426,9 +1267,13 @@ async fn handle_message(
             if let TakerToMakerMessage::RespProofOfFunding(proof) = request {
                 connection_state.allowed_message =
                     ExpectedMessage::ProofOfFundingORSendersAndReceiversContractSigs;
-                handle_proof_of_funding(connection_state, rpc, wallet, &proof)?
+                handle_proof_of_funding(connection_state, rpc, wallet, &proof)
+                    .map_err(rejected(ProtocolErrorCode::InvalidFunding))?
             } else {
-                return Err(TeleportError::Protocol("Expected proof of funding message"));
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
+                    "Expected proof of funding message",
+                ));
             }
         }
         ExpectedMessage::ProofOfFundingORSendersAndReceiversContractSigs => {
@@ -436,7 +1281,8 @@ async fn handle_message(
                 TakerToMakerMessage::RespProofOfFunding(proof) => {
                     connection_state.allowed_message =
                         ExpectedMessage::ProofOfFundingORSendersAndReceiversContractSigs;
-                    handle_proof_of_funding(connection_state, rpc, wallet, &proof)?
+                    handle_proof_of_funding(connection_state, rpc, wallet, &proof)
+                        .map_err(rejected(ProtocolErrorCode::InvalidFunding))?
                 }
                 TakerToMakerMessage::RespContractSigsForRecvrAndSender(message) => {
                     // Nothing to send. Maker now creates and broadcasts his funding Txs
@@ -446,11 +1292,14 @@ async fn handle_message(
                         rpc,
                         wallet,
                         message,
+                        events,
                     )
-                    .await?
+                    .await
+                    .map_err(rejected(ProtocolErrorCode::InvalidContract))?
                 }
                 _ => {
-                    return Err(TeleportError::Protocol(
+                    return Err(TeleportError::Rejected(
+                        ProtocolErrorCode::UnexpectedMessage,
                         "Expected proof of funding or sender's and reciever's contract signatures",
                     ));
                 }
@@ -459,9 +1308,11 @@ async fn handle_message(
         ExpectedMessage::SignReceiversContractTx => {
             if let TakerToMakerMessage::ReqContractSigsForRecvr(message) = request {
                 connection_state.allowed_message = ExpectedMessage::HashPreimage;
-                handle_sign_receivers_contract_tx(wallet, message)?
+                handle_sign_receivers_contract_tx(wallet, message)
+                    .map_err(rejected(ProtocolErrorCode::InvalidContract))?
             } else {
-                return Err(TeleportError::Protocol(
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
                     "Expected reciever's contract transaction",
                 ));
             }
@@ -469,17 +1320,25 @@ async fn handle_message(
         ExpectedMessage::HashPreimage => {
             if let TakerToMakerMessage::RespHashPreimage(message) = request {
                 connection_state.allowed_message = ExpectedMessage::PrivateKeyHandover;
-                handle_hash_preimage(wallet, message)?
+                handle_hash_preimage(connection_state, wallet, message, events)
+                    .map_err(rejected(ProtocolErrorCode::InvalidPreimage))?
             } else {
-                return Err(TeleportError::Protocol("Expected hash preimgae"));
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
+                    "Expected hash preimgae",
+                ));
             }
         }
         ExpectedMessage::PrivateKeyHandover => {
             if let TakerToMakerMessage::RespPrivKeyHandover(message) = request {
                 // Nothing to send. Succesfully completed swap
-                handle_private_key_handover(wallet, message)?
+                handle_private_key_handover(connection_state, wallet, message, events)
+                    .map_err(rejected(ProtocolErrorCode::InvalidPrivateKey))?
             } else {
-                return Err(TeleportError::Protocol("expected privatekey handover"));
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnexpectedMessage,
+                    "expected privatekey handover",
+                ));
             }
         }
     };
@@ -497,6 +1356,7 @@ async fn handle_message(
                         "SignSendersAndReceiversContractTxes",
                     MakerToTakerMessage::RespContractSigsForRecvr(_) => "ReceiversContractSig",
                     MakerToTakerMessage::RespPrivKeyHandover(_) => "PrivateKeyHandover",
+                    MakerToTakerMessage::Error(_) => "Error",
                 }
             );
             log::debug!("{:#?}", reply_message);
@@ -509,11 +1369,20 @@ async fn handle_message(
 fn handle_sign_senders_contract_tx(
     wallet: Arc<RwLock<Wallet>>,
     message: ReqContractSigsForSender,
+    offer_config: &OfferConfig,
     maker_behavior: MakerBehavior,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
     if let MakerBehavior::CloseOnSignSendersContractTx = maker_behavior {
-        return Err(TeleportError::Protocol(
+        //like a crash, the taker isnt told why
+        return Err(TeleportError::Network(Box::new(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
             "closing connection early due to special maker behavior",
+        ))));
+    }
+    if message.locktime < offer_config.minimum_locktime {
+        return Err(TeleportError::Rejected(
+            ProtocolErrorCode::LocktimeTooShort,
+            "locktime too short",
         ));
     }
     let tweakable_privkey = wallet.read().unwrap().get_tweakable_keypair().0;
@@ -532,7 +1401,7 @@ fn handle_sign_senders_contract_tx(
             txinfo.funding_input_value,
             message.hashvalue,
             message.locktime,
-            MINIMUM_LOCKTIME,
+            offer_config.minimum_locktime,
             &tweakable_privkey,
             &mut wallet.write().unwrap(),
         )?;
@@ -540,7 +1409,9 @@ fn handle_sign_senders_contract_tx(
         funding_txids.push(txinfo.senders_contract_tx.input[0].previous_output.txid);
         total_amount += txinfo.funding_input_value;
     }
-    if total_amount >= MIN_SIZE && total_amount < wallet.read().unwrap().get_offer_maxsize_cache() {
+    if total_amount >= offer_config.min_size
+        && total_amount < wallet.read().unwrap().get_offer_maxsize_cache()
+    {
         log::info!(
             "requested contracts amount={}, for funding txids = {:?}",
             Amount::from_sat(total_amount),
@@ -554,13 +1425,16 @@ fn handle_sign_senders_contract_tx(
             "rejecting contracts for amount={} because not enough funds",
             Amount::from_sat(total_amount)
         );
-        Err(TeleportError::Protocol("not enough funds"))
+        Err(TeleportError::Rejected(
+            ProtocolErrorCode::AmountOutOfRange,
+            "not enough funds",
+        ))
     }
 }
 
 fn handle_proof_of_funding(
     connection_state: &mut ConnectionState,
-    rpc: Arc<Client>,
+    rpc: Arc<dyn BlockchainBackend>,
     wallet: Arc<RwLock<Wallet>>,
     proof: &ProofOfFunding,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
@@ -592,7 +1466,7 @@ fn handle_proof_of_funding(
             &funding_info,
             funding_output_index,
             proof.next_locktime,
-            MINIMUM_LOCKTIME,
+            connection_state.offer_config.minimum_locktime,
         )?;
         incoming_swapcoin_keys.push(verify_result);
     }
@@ -617,6 +1491,7 @@ fn handle_proof_of_funding(
             ));
         }
     }
+    connection_state.hashvalue = Some(hashvalue);
 
     log::debug!("proof of funding valid, creating own funding txes");
 
@@ -631,19 +1506,20 @@ fn handle_proof_of_funding(
         let (pubkey1, pubkey2) =
             read_pubkeys_from_multisig_redeemscript(&funding_info.multisig_redeemscript)
                 .ok_or(TeleportError::Protocol("invalid multisig redeemscript"))?;
-        wallet
-            .read()
-            .unwrap()
-            .import_wallet_multisig_redeemscript(&rpc, &pubkey1, &pubkey2)?;
+        wallet.read().unwrap().import_wallet_multisig_redeemscript(
+            rpc.as_ref(),
+            &pubkey1,
+            &pubkey2,
+        )?;
         wallet.read().unwrap().import_tx_with_merkleproof(
-            &rpc,
+            rpc.as_ref(),
             &funding_info.funding_tx,
             funding_info.funding_tx_merkleproof.clone(),
         )?;
-        wallet
-            .read()
-            .unwrap()
-            .import_wallet_contract_redeemscript(&rpc, &funding_info.contract_redeemscript)?;
+        wallet.read().unwrap().import_wallet_contract_redeemscript(
+            rpc.as_ref(),
+            &funding_info.contract_redeemscript,
+        )?;
         let my_receivers_contract_tx = contracts::create_receivers_contract_tx(
             OutPoint {
                 txid: funding_info.funding_tx.txid(),
@@ -675,9 +1551,9 @@ fn handle_proof_of_funding(
     //set up the next coinswap in the route
     let incoming_amount = funding_outputs.iter().map(|o| o.value).sum::<u64>();
     let coinswap_fees = calculate_coinswap_fee(
-        ABSOLUTE_FEE_SAT,
-        AMOUNT_RELATIVE_FEE_PPB,
-        TIME_RELATIVE_FEE_PPB,
+        connection_state.offer_config.absolute_fee_sat,
+        connection_state.offer_config.amount_relative_fee_ppb,
+        connection_state.offer_config.time_relative_fee_ppb,
         incoming_amount,
         1, //time_in_blocks just 1 for now
     );
@@ -686,24 +1562,35 @@ fn handle_proof_of_funding(
             / 1000;
     let outgoing_amount = incoming_amount - coinswap_fees - miner_fees_paid_by_taker;
 
-    let (my_funding_txes, outgoing_swapcoins, total_miner_fee) =
-        wallet.write().unwrap().initalize_coinswap(
-            &rpc,
-            outgoing_amount,
-            &proof
-                .next_coinswap_info
-                .iter()
-                .map(|nci| nci.next_multisig_pubkey)
-                .collect::<Vec<PublicKey>>(),
-            &proof
-                .next_coinswap_info
-                .iter()
-                .map(|nci| nci.next_hashlock_pubkey)
-                .collect::<Vec<PublicKey>>(),
-            hashvalue,
-            proof.next_locktime,
-            proof.next_fee_rate,
-        )?;
+    //a repeated proof of funding replaces the funding txes created for the previous one
+    let mut wallet_ref = wallet.write().unwrap();
+    if let Some(funding_txes) = connection_state.pending_funding_txes.take() {
+        release_funding_tx_inputs(&mut wallet_ref, Arc::clone(&rpc), &funding_txes)?;
+    }
+    //reserved while still holding the lock, otherwise another taker could be given the same coins
+    let (my_funding_txes, outgoing_swapcoins, total_miner_fee) = wallet_ref.initalize_coinswap(
+        rpc.as_ref(),
+        outgoing_amount,
+        &proof
+            .next_coinswap_info
+            .iter()
+            .map(|nci| nci.next_multisig_pubkey)
+            .collect::<Vec<PublicKey>>(),
+        &proof
+            .next_coinswap_info
+            .iter()
+            .map(|nci| nci.next_hashlock_pubkey)
+            .collect::<Vec<PublicKey>>(),
+        hashvalue,
+        proof.next_locktime,
+        proof.next_fee_rate,
+    )?;
+    wallet_ref.reserve_utxos(
+        &funding_tx_inputs(&my_funding_txes),
+        connection_state.utxo_reservation_expiry,
+    )?;
+    wallet_ref.refresh_offer_maxsize_cache(Arc::clone(&rpc))?;
+    drop(wallet_ref);
 
     log::info!(
         "Proof of funding valid. Incoming funding txes, txids = {:?}",
@@ -734,6 +1621,22 @@ fn handle_proof_of_funding(
         Amount::from_sat(incoming_amount - outgoing_amount - total_miner_fee)
     );
 
+    connection_state.ledger_entry = Some(SwapLedgerEntry {
+        hashvalue,
+        started_at: unix_time(),
+        finished_at: None,
+        incoming_amount,
+        outgoing_amount,
+        coinswap_fee: coinswap_fees,
+        miner_fee: total_miner_fee,
+        recovery_fee: 0,
+        incoming_locktime: read_locktime_from_contract(
+            &proof.confirmed_funding_txes[0].contract_redeemscript,
+        )
+        .unwrap(),
+        outgoing_locktime: proof.next_locktime,
+        outcome: SwapOutcome::InProgress,
+    });
     connection_state.pending_funding_txes = Some(my_funding_txes);
     connection_state.outgoing_swapcoins = Some(outgoing_swapcoins);
     log::debug!(
@@ -768,9 +1671,10 @@ fn handle_proof_of_funding(
 
 async fn handle_senders_and_receivers_contract_sigs(
     connection_state: &mut ConnectionState,
-    rpc: Arc<Client>,
+    rpc: Arc<dyn BlockchainBackend>,
     wallet: Arc<RwLock<Wallet>>,
     sigs: ContractSigsForRecvrAndSender,
+    events: &SwapEventSender,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
     //if incoming/outgoing_swapcoin are None then the app should crash because
     //its a logic error, so no error handling, just use unwrap()
@@ -810,12 +1714,71 @@ async fn handle_senders_and_receivers_contract_sigs(
             outgoing_swapcoin.others_contract_sig = Some(senders_sig)
         });
 
-    let wallet_label = wallet.read().unwrap().get_core_wallet_label();
-    let internal_addresses = wallet
-        .read()
-        .unwrap()
-        .get_next_internal_addresses(&rpc, incoming_swapcoins.len() as u32)?;
-    register_coinswap_with_watchtowers(ContractsInfo {
+    let contracts = contracts_to_watch(
+        &wallet.read().unwrap(),
+        rpc.as_ref(),
+        incoming_swapcoins,
+        outgoing_swapcoins,
+    )?;
+    register_coinswap_with_watchtowers(contracts).await?;
+
+    let mut w = wallet.write().unwrap();
+    w.add_swapcoins(incoming_swapcoins.clone(), outgoing_swapcoins.clone())?;
+
+    let hashvalue = connection_state
+        .hashvalue
+        .expect("hashvalue known after proof of funding");
+    events.send(SwapEvent::HopEstablished {
+        hashvalue,
+        hop: None,
+        funding_txids: incoming_swapcoins
+            .iter()
+            .map(|isc| isc.contract_tx.input[0].previous_output.txid)
+            .collect(),
+    });
+
+    //a crash while broadcasting is reconciled on the next startup
+    persist_connection_state(&w, connection_state)?;
+
+    let mut my_funding_txids = Vec::<Txid>::new();
+    for my_funding_tx in connection_state.pending_funding_txes.as_ref().unwrap() {
+        log::debug!("Broadcasting My Funding Tx : {:#?}", my_funding_tx);
+        let txid = rpc.send_raw_transaction(my_funding_tx)?;
+        assert_eq!(txid, my_funding_tx.txid());
+        my_funding_txids.push(txid);
+    }
+    log::info!("Broadcasted My Funding Txes: {:?}", my_funding_txids);
+    w.release_utxos(&funding_tx_inputs(
+        connection_state.pending_funding_txes.as_ref().unwrap(),
+    ))?;
+    //the funding txes are out, so failing to record them mustnt fail the swap
+    if let Err(e) = w.add_swap_ledger_entry(connection_state.ledger_entry.as_ref().unwrap()) {
+        log::error!("unable to add coinswap to the ledger: {:?}", e);
+    }
+    events.send(SwapEvent::FundingBroadcast {
+        hashvalue,
+        txids: my_funding_txids,
+    });
+
+    //set these to None which might be helpful in picking up logic errors later
+    connection_state.incoming_swapcoins = None;
+    connection_state.outgoing_swapcoins = None;
+    connection_state.pending_funding_txes = None;
+    connection_state.ledger_entry = None;
+
+    Ok(None)
+}
+
+fn contracts_to_watch(
+    wallet: &Wallet,
+    rpc: &dyn BlockchainBackend,
+    incoming_swapcoins: &[IncomingSwapCoin],
+    outgoing_swapcoins: &[OutgoingSwapCoin],
+) -> Result<ContractsInfo, TeleportError> {
+    let wallet_label = wallet.get_core_wallet_label();
+    let internal_addresses =
+        wallet.get_next_internal_addresses(rpc, incoming_swapcoins.len() as u32)?;
+    Ok(ContractsInfo {
         contract_txes: incoming_swapcoins
             .iter()
             .zip(internal_addresses.iter())
@@ -843,32 +1806,22 @@ async fn handle_senders_and_receivers_contract_sigs(
             .collect::<Vec<ContractTransaction>>(),
         wallet_label,
     })
-    .await?;
+}
 
-    let mut w = wallet.write().unwrap();
-    incoming_swapcoins
-        .iter()
-        .for_each(|incoming_swapcoin| w.add_incoming_swapcoin(incoming_swapcoin.clone()));
-    outgoing_swapcoins
+fn funding_tx_inputs(funding_txes: &[Transaction]) -> Vec<OutPoint> {
+    funding_txes
         .iter()
-        .for_each(|outgoing_swapcoin| w.add_outgoing_swapcoin(outgoing_swapcoin.clone()));
-    w.save_to_disk()?;
-
-    let mut my_funding_txids = Vec::<Txid>::new();
-    for my_funding_tx in connection_state.pending_funding_txes.as_ref().unwrap() {
-        log::debug!("Broadcasting My Funding Tx : {:#?}", my_funding_tx);
-        let txid = rpc.send_raw_transaction(my_funding_tx)?;
-        assert_eq!(txid, my_funding_tx.txid());
-        my_funding_txids.push(txid);
-    }
-    log::info!("Broadcasted My Funding Txes: {:?}", my_funding_txids);
-
-    //set these to None which might be helpful in picking up logic errors later
-    connection_state.incoming_swapcoins = None;
-    connection_state.outgoing_swapcoins = None;
-    connection_state.pending_funding_txes = None;
+        .flat_map(|tx| tx.input.iter().map(|input| input.previous_output))
+        .collect()
+}
 
-    Ok(None)
+fn release_funding_tx_inputs(
+    wallet: &mut Wallet,
+    rpc: Arc<dyn BlockchainBackend>,
+    funding_txes: &[Transaction],
+) -> Result<(), TeleportError> {
+    wallet.release_utxos(&funding_tx_inputs(funding_txes))?;
+    wallet.refresh_offer_maxsize_cache(rpc)
 }
 
 fn handle_sign_receivers_contract_tx(
@@ -895,10 +1848,13 @@ fn handle_sign_receivers_contract_tx(
 }
 
 fn handle_hash_preimage(
+    connection_state: &mut ConnectionState,
     wallet: Arc<RwLock<Wallet>>,
     message: HashPreimage,
+    events: &SwapEventSender,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
     let hashvalue = Hash160::hash(&message.preimage);
+    connection_state.hashvalue = Some(hashvalue);
     {
         let mut wallet_mref = wallet.write().unwrap();
         for multisig_redeemscript in message.senders_multisig_redeemscripts {
@@ -914,10 +1870,12 @@ fn handle_hash_preimage(
                 return Err(TeleportError::Protocol("not correct hash preimage"));
             }
             incoming_swapcoin.hash_preimage = Some(message.preimage);
+            wallet_mref.save_incoming_swapcoin(&multisig_redeemscript)?;
         }
         //TODO tell preimage to watchtowers
     }
     log::info!("received preimage for hashvalue={}", hashvalue);
+    events.send(SwapEvent::PreimageRevealed { hashvalue });
     let wallet_ref = wallet.read().unwrap();
     let mut swapcoin_private_keys = Vec::<MultisigPrivkey>::new();
     for multisig_redeemscript in message.receivers_multisig_redeemscripts {
@@ -938,7 +1896,6 @@ fn handle_hash_preimage(
         });
     }
 
-    wallet_ref.save_to_disk()?;
     Ok(Some(MakerToTakerMessage::RespPrivKeyHandover(
         PrivKeyHandover {
             multisig_privkeys: swapcoin_private_keys,
@@ -947,17 +1904,129 @@ fn handle_hash_preimage(
 }
 
 fn handle_private_key_handover(
+    connection_state: &ConnectionState,
     wallet: Arc<RwLock<Wallet>>,
     message: PrivKeyHandover,
+    events: &SwapEventSender,
 ) -> Result<Option<MakerToTakerMessage>, TeleportError> {
     let mut wallet_ref = wallet.write().unwrap();
     for swapcoin_private_key in message.multisig_privkeys {
         wallet_ref
             .find_incoming_swapcoin_mut(&swapcoin_private_key.multisig_redeemscript)
             .ok_or(TeleportError::Protocol("multisig_redeemscript not found"))?
-            .apply_privkey(swapcoin_private_key.key)?
+            .apply_privkey(swapcoin_private_key.key)?;
+        wallet_ref.save_incoming_swapcoin(&swapcoin_private_key.multisig_redeemscript)?;
     }
-    wallet_ref.save_to_disk()?;
     log::info!("Successfully Completed Coinswap");
+    if let Some(hashvalue) = connection_state.hashvalue {
+        if let Err(e) = wallet_ref.finish_swap_ledger_entry(&hashvalue, SwapOutcome::Completed) {
+            log::error!("unable to update the coinswap ledger: {:?}", e);
+        }
+        events.send(SwapEvent::KeysHandedOver { hashvalue });
+        events.send(SwapEvent::Completed { hashvalue });
+    }
     Ok(None)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{simulated_blockchain::SimulatedBlockchain, wallet_sync::create_temp_wallet};
+    use std::fs;
+
+    fn connection_state(
+        hashvalue: Hash160,
+        funding_tx: Transaction,
+        ledger_entry: &SwapLedgerEntry,
+    ) -> String {
+        serde_json::to_string(&PersistedConnectionState {
+            allowed_message: ExpectedMessage::SignReceiversContractTx,
+            hashvalue,
+            incoming_swapcoins: None,
+            outgoing_swapcoins: None,
+            pending_funding_txes: Some(vec![funding_tx]),
+            ledger_entry: Some(SwapLedgerEntry {
+                hashvalue,
+                ..ledger_entry.clone()
+            }),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_swap_deadline_before_minimum_locktime() {
+        let mut config = MakerConfig::default();
+        assert!(config.validate().is_ok());
+        config.swap_deadline_blocks = u32::from(config.offer_config.minimum_locktime);
+        assert!(config.validate().is_err());
+        config.swap_deadline_blocks -= 1;
+        config.offer_config.minimum_locktime -= 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_persisted_connections() {
+        let mut wallet = create_temp_wallet("reconcile");
+        let wallet_path = wallet.get_wallet_file_path().to_path_buf();
+        let sim = Arc::new(SimulatedBlockchain::new());
+        wallet
+            .import_initial_addresses(
+                sim.as_ref(),
+                &wallet
+                    .get_hd_wallet_descriptors(sim.as_ref())
+                    .unwrap()
+                    .iter()
+                    .collect::<Vec<&String>>(),
+                &Vec::<_>::new(),
+                &Vec::<_>::new(),
+            )
+            .unwrap();
+
+        // A swap whose funding tx was broadcast, and one whose wasn't.
+        let address = wallet.get_next_external_address(sim.as_ref()).unwrap();
+        let funding_txid = sim
+            .send_to_address(&address, Amount::from_sat(100_000))
+            .unwrap();
+        let funding_tx = sim.get_raw_transaction(&funding_txid, None).unwrap();
+        let mut unbroadcast_tx = funding_tx.clone();
+        unbroadcast_tx.lock_time += 1;
+        let entry = SwapLedgerEntry {
+            hashvalue: Hash160::from_slice(&[0; 20]).unwrap(),
+            started_at: 100,
+            finished_at: None,
+            incoming_amount: 500_000,
+            outgoing_amount: 494_000,
+            coinswap_fee: 5_000,
+            miner_fee: 400,
+            recovery_fee: 0,
+            incoming_locktime: 50,
+            outgoing_locktime: 40,
+            outcome: SwapOutcome::InProgress,
+        };
+        let funded = Hash160::from_slice(&[1; 20]).unwrap();
+        let unfunded = Hash160::from_slice(&[2; 20]).unwrap();
+        wallet
+            .save_maker_connection(None, &connection_state(funded, funding_tx, &entry))
+            .unwrap();
+        let bad_row = wallet.save_maker_connection(None, "not json").unwrap();
+        wallet
+            .save_maker_connection(None, &connection_state(unfunded, unbroadcast_tx, &entry))
+            .unwrap();
+
+        let rpc: Arc<dyn BlockchainBackend> = sim;
+        let wallet = Arc::new(RwLock::new(wallet));
+        reconcile_persisted_connections(&rpc, &wallet)
+            .await
+            .unwrap();
+
+        // Only the funded swap is in the ledger, and the row which failed is kept for next time.
+        let wallet = wallet.read().unwrap();
+        let ledger = wallet.load_swap_ledger().unwrap();
+        assert_eq!(ledger.len(), 1);
+        assert_eq!(ledger[0].hashvalue, funded);
+        let connections = wallet.load_maker_connections().unwrap();
+        assert_eq!(connections, vec![(bad_row, "not json".to_string())]);
+
+        fs::remove_file(&wallet_path).unwrap();
+    }
+}
@@ -58,6 +58,14 @@
 //! Taker -> Maker2: [TakerToMakerMessage::RespPrivKeyHandover] (For Maker1-Maker2 funding multisig, received from Maker1 in Step 16)
 //! Taker -> Maker2: [`TakerToMakerMessage::RespHashPreimage`] (for Maker2-Taker HTLC).
 //! Maker2 -> Taker: [`MakerToTakerMessage::RespPrivKeyHandover`] (For Maker2-Taker funding multisig).
+//!
+//! Either side may send a [ProtocolError] instead of the message expected from it, telling the
+//! other side why it rejected the last message before closing the connection.
+
+use std::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+};
 
 use bitcoin::{
     secp256k1::{SecretKey, Signature},
@@ -68,13 +76,70 @@ use serde::{Deserialize, Serialize};
 
 use bitcoin::hashes::hash160::Hash as Hash160;
 
+use crate::transport::{WireMessage, MAX_MESSAGE_SIZE, SMALL_MESSAGE_SIZE};
+
 pub const PREIMAGE_LEN: usize = 32;
 pub type Preimage = [u8; PREIMAGE_LEN];
 
+/// Oldest protocol version this implementation speaks, with takers, makers and watchtowers.
+pub const PROTOCOL_VERSION_MIN: u32 = 0;
+/// Newest protocol version this implementation speaks. A change which older peers can't just
+/// ignore needs a new version, other features are announced with [Capabilities].
+///
+/// Version 1 sends the messages after the hellos as length-prefixed frames.
+pub const PROTOCOL_VERSION_MAX: u32 = 1;
+
+/// The highest protocol version in common with a peer speaking versions `min` to `max`, or
+/// None if there is none.
+//clippy sees through PROTOCOL_VERSION_MIN, which is 0
+#[allow(clippy::unnecessary_min_or_max)]
+pub fn negotiate_protocol_version(min: u32, max: u32) -> Option<u32> {
+    let version = max.min(PROTOCOL_VERSION_MAX);
+    if version >= min.max(PROTOCOL_VERSION_MIN) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Optional features a peer supports, sent in the hello messages as a bitfield. Unknown bits
+/// are ignored, and peers from before capabilities existed send none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Offers may carry a [FidelityBondProof].
+    pub const FIDELITY_BONDS: Capabilities = Capabilities(1 << 0);
+    /// Rejected messages are answered with a [ProtocolError].
+    pub const ERROR_MESSAGES: Capabilities = Capabilities(1 << 1);
+    /// Frames may be deflate compressed.
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+    /// Connections may be encrypted with a noise handshake after the hellos.
+    pub const NOISE: Capabilities = Capabilities(1 << 3);
+
+    /// Everything this implementation supports.
+    pub fn ours() -> Capabilities {
+        Capabilities(
+            Self::FIDELITY_BONDS.0 | Self::ERROR_MESSAGES.0 | Self::COMPRESSION.0 | Self::NOISE.0,
+        )
+    }
+
+    pub fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TakerHello {
     pub protocol_version_min: u32,
     pub protocol_version_max: u32,
+    #[serde(default)]
+    pub capabilities: Capabilities,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -197,15 +262,96 @@ pub enum TakerToMakerMessage {
     RespHashPreimage(HashPreimage),
     /// Respond by handing over the Private Keys of coinswap multisig. This denotes the completion of the whole swap.
     RespPrivKeyHandover(PrivKeyHandover),
+    /// The Maker's last message was rejected, the connection is closed after this.
+    Error(ProtocolError),
+}
+
+impl WireMessage for TakerToMakerMessage {
+    fn max_size(method: &str) -> usize {
+        match method {
+            "takerhello" | "reqgiveoffer" | "error" => SMALL_MESSAGE_SIZE,
+            _ => MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+/// Why a message was rejected, sent in [TakerToMakerMessage::Error] and
+/// [MakerToTakerMessage::Error]. The numbers are part of the protocol and never change, codes
+/// added in later versions are read as [ProtocolErrorCode::Other].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ProtocolErrorCode {
+    /// Any other reason.
+    Other = 0,
+    /// The message isn't the one expected at this point of the protocol, or couldn't be parsed.
+    UnexpectedMessage = 1,
+    /// The amount is below the offer's minimum size or above what the maker can swap.
+    AmountOutOfRange = 2,
+    /// The locktime is shorter than the offer's minimum locktime.
+    LocktimeTooShort = 3,
+    /// A contract transaction, redeemscript or signature is invalid.
+    InvalidContract = 4,
+    /// The funding transactions aren't valid or not confirmed enough.
+    InvalidFunding = 5,
+    /// The hash preimage doesn't match the hashvalue of the contracts.
+    InvalidPreimage = 6,
+    /// A handed over private key doesn't match the multisig.
+    InvalidPrivateKey = 7,
+    /// The peer had a problem of its own, such as with its node or wallet. Trying again later
+    /// may work.
+    Internal = 8,
+    /// There is no protocol version both sides speak.
+    UnsupportedVersion = 9,
+}
+
+impl ProtocolErrorCode {
+    /// Whether repeating the rejected request could succeed, every other code means the peer
+    /// will reject it again.
+    pub fn is_retryable(&self) -> bool {
+        *self == ProtocolErrorCode::Internal
+    }
+}
+
+impl From<u16> for ProtocolErrorCode {
+    fn from(code: u16) -> ProtocolErrorCode {
+        match code {
+            1 => ProtocolErrorCode::UnexpectedMessage,
+            2 => ProtocolErrorCode::AmountOutOfRange,
+            3 => ProtocolErrorCode::LocktimeTooShort,
+            4 => ProtocolErrorCode::InvalidContract,
+            5 => ProtocolErrorCode::InvalidFunding,
+            6 => ProtocolErrorCode::InvalidPreimage,
+            7 => ProtocolErrorCode::InvalidPrivateKey,
+            8 => ProtocolErrorCode::Internal,
+            9 => ProtocolErrorCode::UnsupportedVersion,
+            _ => ProtocolErrorCode::Other,
+        }
+    }
+}
+
+impl From<ProtocolErrorCode> for u16 {
+    fn from(code: ProtocolErrorCode) -> u16 {
+        code as u16
+    }
+}
+
+/// Sent instead of a reply when a message is rejected. The message is for humans, programs
+/// should only look at the code.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub code: ProtocolErrorCode,
+    pub message: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MakerHello {
     pub protocol_version_min: u32,
     pub protocol_version_max: u32,
+    #[serde(default)]
+    pub capabilities: Capabilities,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct FidelityBondProof {
     pub utxo: OutPoint,
     pub utxo_key: PublicKey,
@@ -214,6 +360,81 @@ pub struct FidelityBondProof {
     pub cert_expiry: u16,
     pub cert_pubkey: PublicKey,
     pub onion_sig: Signature,
+    /// The maker's noise static key, which the onion signature covers as well. None from
+    /// makers which don't encrypt.
+    #[serde(default)]
+    pub noise_static_key: Option<[u8; 32]>,
+}
+
+type ProofOrderingKey = (
+    OutPoint,
+    PublicKey,
+    i64,
+    [u8; 64],
+    u16,
+    PublicKey,
+    [u8; 64],
+    Option<[u8; 32]>,
+);
+
+//signatures dont implement Ord or Hash, so those are done by hand on the compact serialization
+//this lets offers carrying a proof still be kept in the taker's BTreeSet offerbook
+impl FidelityBondProof {
+    fn ordering_key(&self) -> ProofOrderingKey {
+        (
+            self.utxo,
+            self.utxo_key,
+            self.locktime,
+            self.cert_sig.serialize_compact(),
+            self.cert_expiry,
+            self.cert_pubkey,
+            self.onion_sig.serialize_compact(),
+            self.noise_static_key,
+        )
+    }
+}
+
+impl PartialOrd for FidelityBondProof {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FidelityBondProof {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ordering_key().cmp(&other.ordering_key())
+    }
+}
+
+impl Hash for FidelityBondProof {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ordering_key().hash(state);
+    }
+}
+
+/// A maker's static key for noise handshakes, signed by its tweakable point.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NoiseStaticKey {
+    pub key: [u8; 32],
+    pub sig: Signature,
+}
+
+impl PartialOrd for NoiseStaticKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NoiseStaticKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.key, self.sig.serialize_compact()).cmp(&(other.key, other.sig.serialize_compact()))
+    }
+}
+
+impl Hash for NoiseStaticKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.key, self.sig.serialize_compact()).hash(state);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -226,6 +447,10 @@ pub struct Offer {
     pub max_size: u64,
     pub min_size: u64,
     pub tweakable_point: PublicKey,
+    /// Proof of the maker's most valuable fidelity bond, if it has one.
+    pub fidelity_bond_proof: Option<FidelityBondProof>,
+    /// Key the maker encrypts clearnet connections with, None from makers which don't.
+    pub noise_static_key: Option<NoiseStaticKey>,
 }
 
 /// Contract Tx signatures provided by a Sender of a Coinswap.
@@ -274,4 +499,101 @@ pub enum MakerToTakerMessage {
     RespContractSigsForRecvr(ContractSigsForRecvr),
     /// Send the multisig private keys of the swap, declaring completion of the contract.
     RespPrivKeyHandover(PrivKeyHandover),
+    /// The Taker's last message was rejected, the connection is closed after this.
+    Error(ProtocolError),
+}
+
+impl WireMessage for MakerToTakerMessage {
+    fn max_size(method: &str) -> usize {
+        match method {
+            "makerhello" | "respoffer" | "error" => SMALL_MESSAGE_SIZE,
+            _ => MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protocol_error_wire_format() {
+        let message = MakerToTakerMessage::Error(ProtocolError {
+            code: ProtocolErrorCode::LocktimeTooShort,
+            message: "locktime too short".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"method":"error","code":3,"message":"locktime too short"}"#
+        );
+
+        // codes from a newer version are still understood as an error
+        let message: TakerToMakerMessage =
+            serde_json::from_str(r#"{"method":"error","code":999,"message":"?"}"#).unwrap();
+        match message {
+            TakerToMakerMessage::Error(error) => {
+                assert_eq!(error.code, ProtocolErrorCode::Other);
+                assert!(!error.code.is_retryable());
+            }
+            _ => panic!("expected error message"),
+        }
+    }
+
+    #[test]
+    fn test_message_size_limits() {
+        // the limits are looked up by the method tag, which has to match the serialized one
+        let hello = TakerToMakerMessage::TakerHello(TakerHello {
+            protocol_version_min: PROTOCOL_VERSION_MIN,
+            protocol_version_max: PROTOCOL_VERSION_MAX,
+            capabilities: Capabilities::ours(),
+        });
+        let give_offer = TakerToMakerMessage::ReqGiveOffer(GiveOffer);
+        for message in [hello, give_offer].iter() {
+            let json = serde_json::to_value(message).unwrap();
+            let method = json["method"].as_str().unwrap();
+            assert_eq!(TakerToMakerMessage::max_size(method), SMALL_MESSAGE_SIZE);
+        }
+        let hello = MakerToTakerMessage::MakerHello(MakerHello {
+            protocol_version_min: PROTOCOL_VERSION_MIN,
+            protocol_version_max: PROTOCOL_VERSION_MAX,
+            capabilities: Capabilities::ours(),
+        });
+        let json = serde_json::to_value(&hello).unwrap();
+        let method = json["method"].as_str().unwrap();
+        assert_eq!(MakerToTakerMessage::max_size(method), SMALL_MESSAGE_SIZE);
+        assert_eq!(
+            MakerToTakerMessage::max_size("respcontractsigsforsender"),
+            MAX_MESSAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_protocol_version_negotiation() {
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX),
+            Some(PROTOCOL_VERSION_MAX)
+        );
+        // a newer peer which still speaks our version
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX + 5),
+            Some(PROTOCOL_VERSION_MAX)
+        );
+        // a peer which dropped our version
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION_MAX + 1, PROTOCOL_VERSION_MAX + 5),
+            None
+        );
+        // a nonsense range
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION_MAX + 1, PROTOCOL_VERSION_MIN),
+            None
+        );
+
+        // peers from before capabilities existed
+        let hello: MakerHello =
+            serde_json::from_str(r#"{"protocol_version_min":0,"protocol_version_max":0}"#).unwrap();
+        assert_eq!(hello.capabilities, Capabilities::default());
+        assert!(!hello.capabilities.contains(Capabilities::ERROR_MESSAGES));
+        assert!(Capabilities::ours().contains(Capabilities::ERROR_MESSAGES));
+    }
 }
@@ -0,0 +1,449 @@
+//! Encryption of clearnet connections with the Noise protocol framework, using the `XX`
+//! handshake.
+//!
+//! Tor encrypts and authenticates connections to onion addresses, but a connection to a
+//! clearnet address can be read and changed by anyone on the path, and swap messages carry
+//! key nonces and multisig private keys. When both hellos announce [Capabilities::NOISE] the
+//! connecting side starts a handshake right after them, and every later frame is encrypted.
+//!
+//! A maker derives its static key from the private key of its tweakable point, and its offer
+//! carries the key signed by the tweakable point. So a taker which has the offer knows it is
+//! talking to whoever can sign contracts with that point, and refuses to talk to the maker
+//! unencrypted. The maker's fidelity bond proof commits to the key as well, and a taker refuses
+//! a clearnet offer which didn't come over a connection encrypted with the key its proof
+//! commits to, so someone on the path can't strip [Capabilities::NOISE] from the hellos to
+//! swap the offer's key. Both hellos are part of the handshake's prologue, so changing them
+//! makes the handshake fail. Takers use a new static key for every connection. Watchtowers
+//! have nothing to bind a key to and use a new one each time they start, which only protects
+//! against eavesdroppers.
+//!
+//! [Capabilities::NOISE]: crate::messages::Capabilities::NOISE
+
+use bitcoin::{
+    hashes::{sha256, sha256d, Hash},
+    secp256k1::{Message, Secp256k1, SecretKey},
+    PublicKey,
+};
+use snow::{
+    params::DHChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+    Builder, HandshakeState, StatelessTransportState,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::sync::Arc;
+
+use crate::{
+    error::TeleportError,
+    messages::NoiseStaticKey,
+    transport::{Frame, MessageReader, MessageWriter, MAX_MESSAGE_SIZE},
+};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"teleport-transactions";
+//noise messages are limited to 64 KiB, larger payloads are encrypted in chunks
+const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const CHUNK_LEN: usize = NOISE_MAX_MESSAGE_LEN - TAG_LEN;
+
+/// Most bytes encryption adds to a payload of up to [MAX_MESSAGE_SIZE].
+pub(crate) const MAX_CIPHERTEXT_OVERHEAD: usize = (MAX_MESSAGE_SIZE / CHUNK_LEN + 1) * TAG_LEN;
+
+/// A static Curve25519 keypair.
+pub struct NoiseKeypair {
+    private: [u8; 32],
+    public: [u8; 32],
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> NoiseKeypair {
+        let keypair = builder()
+            .generate_keypair()
+            .expect("curve25519 is supported");
+        NoiseKeypair {
+            private: to_key(&keypair.private),
+            public: to_key(&keypair.public),
+        }
+    }
+
+    /// The maker's keypair, which is the same every time the maker starts.
+    pub fn from_tweakable_privkey(tweakable_privkey: &SecretKey) -> NoiseKeypair {
+        let private = sha256::Hash::hash(&[PROLOGUE, &tweakable_privkey[..]].concat()).into_inner();
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("curve25519 is supported");
+        dh.set(&private);
+        NoiseKeypair {
+            private,
+            public: to_key(dh.pubkey()),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// The public key signed with the tweakable point's private key, for the maker's offer.
+    pub fn sign_with_tweakable_privkey(&self, tweakable_privkey: &SecretKey) -> NoiseStaticKey {
+        let secp = Secp256k1::new();
+        NoiseStaticKey {
+            key: self.public,
+            sig: secp.sign(&static_key_message(&self.public), tweakable_privkey),
+        }
+    }
+}
+
+/// Checks that the key in an offer was signed by the offer's tweakable point.
+pub fn verify_static_key(static_key: &NoiseStaticKey, tweakable_point: &PublicKey) -> bool {
+    let secp = Secp256k1::new();
+    secp.verify(
+        &static_key_message(&static_key.key),
+        &static_key.sig,
+        &tweakable_point.key,
+    )
+    .is_ok()
+}
+
+fn static_key_message(key: &[u8; 32]) -> Message {
+    Message::from_slice(&sha256d::Hash::hash(&[PROLOGUE, &key[..]].concat())).unwrap()
+}
+
+fn builder<'a>() -> Builder<'a> {
+    Builder::new(NOISE_PARAMS.parse().expect("valid noise params"))
+}
+
+//the hello lines end with a newline, so the concatenation is unambiguous
+fn prologue(responder_hello: &[u8], initiator_hello: &[u8]) -> Vec<u8> {
+    [PROLOGUE, responder_hello, initiator_hello].concat()
+}
+
+fn to_key(bytes: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    key.copy_from_slice(bytes);
+    key
+}
+
+fn handshake_error(_e: snow::Error) -> TeleportError {
+    TeleportError::Protocol("noise handshake failed")
+}
+
+/// Encrypts and decrypts frames after the handshake. Each direction has its own key, and the
+/// reader and writer halves of a connection each count their own nonces.
+pub(crate) struct Cipher(StatelessTransportState);
+
+impl Cipher {
+    pub(crate) fn encrypt(
+        &self,
+        nonce: &mut u64,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, TeleportError> {
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + MAX_CIPHERTEXT_OVERHEAD);
+        let mut buf = vec![0; NOISE_MAX_MESSAGE_LEN];
+        for chunk in plaintext.chunks(CHUNK_LEN) {
+            let len = self
+                .0
+                .write_message(*nonce, chunk, &mut buf)
+                .map_err(|_| TeleportError::Protocol("encryption failed"))?;
+            *nonce += 1;
+            ciphertext.extend_from_slice(&buf[..len]);
+        }
+        Ok(ciphertext)
+    }
+
+    pub(crate) fn decrypt(
+        &self,
+        nonce: &mut u64,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, TeleportError> {
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        let mut buf = vec![0; NOISE_MAX_MESSAGE_LEN];
+        for chunk in ciphertext.chunks(NOISE_MAX_MESSAGE_LEN) {
+            let len = self
+                .0
+                .read_message(*nonce, chunk, &mut buf)
+                .map_err(|_| TeleportError::Protocol("decryption failed"))?;
+            *nonce += 1;
+            plaintext.extend_from_slice(&buf[..len]);
+        }
+        Ok(plaintext)
+    }
+}
+
+/// Runs the handshake as the side which connected, and returns the other side's static key.
+pub(crate) async fn initiate<R, W>(
+    reader: &mut MessageReader<R>,
+    writer: &mut MessageWriter<W>,
+) -> Result<[u8; 32], TeleportError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let keypair = NoiseKeypair::generate();
+    let prologue = prologue(reader.hello(), writer.hello());
+    let mut handshake = builder()
+        .prologue(&prologue)
+        .local_private_key(&keypair.private)
+        .build_initiator()
+        .map_err(handshake_error)?;
+    let mut buf = vec![0; NOISE_MAX_MESSAGE_LEN];
+    // -> e
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(handshake_error)?;
+    writer.send_handshake(&buf[..len]).await?;
+    // <- e, ee, s, es
+    let message = read_handshake_message(reader).await?;
+    handshake
+        .read_message(&message, &mut buf)
+        .map_err(handshake_error)?;
+    // -> s, se
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(handshake_error)?;
+    writer.send_handshake(&buf[..len]).await?;
+    finish_handshake(handshake, reader, writer)
+}
+
+/// Runs the handshake as the side which was connected to, starting from the first handshake
+/// message it read, and returns the other side's static key.
+pub(crate) async fn respond<R, W>(
+    first_message: &[u8],
+    reader: &mut MessageReader<R>,
+    writer: &mut MessageWriter<W>,
+    keypair: &NoiseKeypair,
+) -> Result<[u8; 32], TeleportError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let prologue = prologue(writer.hello(), reader.hello());
+    let mut handshake = builder()
+        .prologue(&prologue)
+        .local_private_key(&keypair.private)
+        .build_responder()
+        .map_err(handshake_error)?;
+    let mut buf = vec![0; NOISE_MAX_MESSAGE_LEN];
+    handshake
+        .read_message(first_message, &mut buf)
+        .map_err(handshake_error)?;
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(handshake_error)?;
+    writer.send_handshake(&buf[..len]).await?;
+    let message = read_handshake_message(reader).await?;
+    handshake
+        .read_message(&message, &mut buf)
+        .map_err(handshake_error)?;
+    finish_handshake(handshake, reader, writer)
+}
+
+async fn read_handshake_message<R: AsyncRead + Unpin>(
+    reader: &mut MessageReader<R>,
+) -> Result<Vec<u8>, TeleportError> {
+    match reader.read_frame().await? {
+        Some(Frame::Handshake(message)) => Ok(message),
+        Some(Frame::Message(_)) => Err(TeleportError::Protocol("expected handshake")),
+        None => Err(TeleportError::Protocol("eof during handshake")),
+    }
+}
+
+fn finish_handshake<R, W>(
+    handshake: HandshakeState,
+    reader: &mut MessageReader<R>,
+    writer: &mut MessageWriter<W>,
+) -> Result<[u8; 32], TeleportError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let remote_static_key = to_key(
+        handshake
+            .get_remote_static()
+            .ok_or(TeleportError::Protocol("noise handshake failed"))?,
+    );
+    let cipher = Arc::new(Cipher(
+        handshake
+            .into_stateless_transport_mode()
+            .map_err(handshake_error)?,
+    ));
+    reader.set_cipher(Arc::clone(&cipher), remote_static_key);
+    writer.set_cipher(cipher);
+    Ok(remote_static_key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bitcoin::secp256k1::rand::thread_rng;
+    use serde::{Deserialize, Serialize};
+    use tokio::io::{duplex, split};
+
+    use crate::transport::{Framing, WireMessage};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestMessage {
+        data: String,
+    }
+
+    impl WireMessage for TestMessage {}
+
+    #[tokio::test]
+    async fn test_handshake_and_encryption() {
+        let secp = Secp256k1::new();
+        let (tweakable_privkey, tweakable_point) = {
+            let (privkey, pubkey) = secp.generate_keypair(&mut thread_rng());
+            (
+                privkey,
+                PublicKey {
+                    compressed: true,
+                    key: pubkey,
+                },
+            )
+        };
+        let maker_keypair = NoiseKeypair::from_tweakable_privkey(&tweakable_privkey);
+        assert_eq!(
+            NoiseKeypair::from_tweakable_privkey(&tweakable_privkey).public,
+            maker_keypair.public
+        );
+        let static_key = maker_keypair.sign_with_tweakable_privkey(&tweakable_privkey);
+        assert!(verify_static_key(&static_key, &tweakable_point));
+        let other_point = PublicKey {
+            compressed: true,
+            key: secp.generate_keypair(&mut thread_rng()).1,
+        };
+        assert!(!verify_static_key(&static_key, &other_point));
+
+        let (taker_stream, maker_stream) = duplex(1 << 16);
+        let (taker_reader, taker_writer) = split(taker_stream);
+        let (maker_reader, maker_writer) = split(maker_stream);
+        let mut taker_reader = MessageReader::new(taker_reader);
+        let mut taker_writer = MessageWriter::new(taker_writer);
+        let mut maker_reader = MessageReader::new(maker_reader);
+        let mut maker_writer = MessageWriter::new(maker_writer);
+        taker_writer.set_framing(Framing::LengthPrefixed { compress: true });
+        maker_writer.set_framing(Framing::LengthPrefixed { compress: false });
+
+        let maker = tokio::spawn(async move {
+            let first_message = match maker_reader.read_frame().await.unwrap() {
+                Some(Frame::Handshake(m)) => m,
+                _ => panic!("expected handshake"),
+            };
+            respond(
+                &first_message,
+                &mut maker_reader,
+                &mut maker_writer,
+                &maker_keypair,
+            )
+            .await
+            .unwrap();
+            // larger than one noise message, so it is encrypted in chunks
+            let message: TestMessage = maker_reader.read_message().await.unwrap().unwrap();
+            assert_eq!(message.data.len(), 200000);
+            maker_writer.send(&message).await.unwrap();
+        });
+
+        let remote_static_key = initiate(&mut taker_reader, &mut taker_writer)
+            .await
+            .unwrap();
+        assert_eq!(remote_static_key, static_key.key);
+        assert_eq!(taker_reader.remote_static_key(), Some(static_key.key));
+        let message = TestMessage {
+            data: (0..200000)
+                .map(|i| (b'a' + (i % 26) as u8) as char)
+                .collect(),
+        };
+        taker_writer.send(&message).await.unwrap();
+        let echoed: TestMessage = taker_reader.read_message().await.unwrap().unwrap();
+        assert_eq!(echoed, message);
+        maker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unencrypted_message_after_handshake() {
+        let (taker_stream, maker_stream) = duplex(1 << 16);
+        let (taker_reader, mut taker_write_half) = split(taker_stream);
+        let (maker_reader, maker_writer) = split(maker_stream);
+        let mut taker_reader = MessageReader::new(taker_reader);
+        let mut maker_reader = MessageReader::new(maker_reader);
+        let mut maker_writer = MessageWriter::new(maker_writer);
+        let maker_keypair = NoiseKeypair::generate();
+
+        let maker = tokio::spawn(async move {
+            let first_message = match maker_reader.read_frame().await.unwrap() {
+                Some(Frame::Handshake(m)) => m,
+                _ => panic!("expected handshake"),
+            };
+            respond(
+                &first_message,
+                &mut maker_reader,
+                &mut maker_writer,
+                &maker_keypair,
+            )
+            .await
+            .unwrap();
+            match maker_reader.read_message::<TestMessage>().await {
+                Err(TeleportError::Protocol("unencrypted message")) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        });
+
+        let mut taker_writer = MessageWriter::new(&mut taker_write_half);
+        initiate(&mut taker_reader, &mut taker_writer)
+            .await
+            .unwrap();
+        // as if injected by someone on the path
+        let mut injector = MessageWriter::new(&mut taker_write_half);
+        injector.set_framing(Framing::LengthPrefixed { compress: false });
+        injector
+            .send(&TestMessage {
+                data: "x".to_string(),
+            })
+            .await
+            .unwrap();
+        maker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_changed_hello() {
+        let (taker_stream, maker_stream) = duplex(1 << 16);
+        let (taker_reader, mut taker_write_half) = split(taker_stream);
+        let (maker_reader, maker_writer) = split(maker_stream);
+        let mut taker_reader = MessageReader::new(taker_reader);
+        let mut maker_reader = MessageReader::new(maker_reader);
+        let mut maker_writer = MessageWriter::new(maker_writer);
+        let maker_keypair = NoiseKeypair::generate();
+
+        let maker = tokio::spawn(async move {
+            let _hello: TestMessage = maker_reader.read_message().await.unwrap().unwrap();
+            let first_message = match maker_reader.read_frame().await.unwrap() {
+                Some(Frame::Handshake(m)) => m,
+                _ => panic!("expected handshake"),
+            };
+            respond(
+                &first_message,
+                &mut maker_reader,
+                &mut maker_writer,
+                &maker_keypair,
+            )
+            .await
+        });
+
+        // the taker's hello as someone on the path sent it, the taker's writer didnt send one
+        let mut injector = MessageWriter::new(&mut taker_write_half);
+        injector
+            .send(&TestMessage {
+                data: "hello".to_string(),
+            })
+            .await
+            .unwrap();
+        let mut taker_writer = MessageWriter::new(&mut taker_write_half);
+        assert!(initiate(&mut taker_reader, &mut taker_writer)
+            .await
+            .is_err());
+        drop(taker_writer);
+        drop(taker_write_half);
+        drop(taker_reader);
+        assert!(maker.await.unwrap().is_err());
+    }
+}
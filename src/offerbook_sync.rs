@@ -1,28 +1,33 @@
-use std::{fmt, time::Duration};
+use std::{collections::HashMap, fmt, time::Duration};
 
 use tokio::{net::TcpStream, select, sync::mpsc, time::sleep};
 
-use bitcoin::Network;
+use bitcoin::{Network, OutPoint};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    blockchain_backend::BlockchainBackend,
     directory_servers::{
         sync_maker_addresses_from_directory_servers, DirectoryServerError, TOR_ADDR,
     },
     error::TeleportError,
+    fidelity_bonds::REGTEST_DUMMY_ONION_HOSTNAME,
     messages::{GiveOffer, MakerToTakerMessage, Offer, TakerToMakerMessage},
+    noise::verify_static_key,
     taker_protocol::{
         FIRST_CONNECT_ATTEMPTS, FIRST_CONNECT_ATTEMPT_TIMEOUT_SEC, FIRST_CONNECT_SLEEP_DELAY_SEC,
     },
     util::{handshake_maker, read_message, send_message},
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum MakerAddress {
     Clearnet { address: String },
     Tor { address: String },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct OfferAndAddress {
     pub offer: Offer,
     pub address: MakerAddress,
@@ -46,12 +51,46 @@ fn get_regtest_maker_addresses() -> Vec<MakerAddress> {
 }
 
 impl MakerAddress {
+    /// Address given by the user as `host:port`, a host ending in `.onion` is reached through
+    /// Tor and any other host directly.
+    pub fn from_host_and_port(address: String) -> MakerAddress {
+        if address.split(':').next().unwrap().ends_with(".onion") {
+            MakerAddress::Tor { address }
+        } else {
+            MakerAddress::Clearnet { address }
+        }
+    }
+
     pub fn get_tcpstream_address(&self) -> String {
         match &self {
             MakerAddress::Clearnet { address } => address.to_string(),
             MakerAddress::Tor { address: _ } => String::from(TOR_ADDR),
         }
     }
+
+    /// The hostname a maker at this address signs in its fidelity bond proof. Clearnet makers
+    /// only exist on regtest, where they all use a dummy onion hostname.
+    pub fn get_fidelity_bond_hostname(&self) -> &str {
+        match &self {
+            MakerAddress::Clearnet { address: _ } => REGTEST_DUMMY_ONION_HOSTNAME,
+            MakerAddress::Tor { address } => address.split(':').next().unwrap(),
+        }
+    }
+
+    /// Whether two addresses are on the same host. Onion addresses which only differ by port
+    /// are the same host. Clearnet makers only exist on regtest where they all run on
+    /// localhost, so those are only the same host if the port matches too.
+    pub fn shares_host_with(&self, other: &MakerAddress) -> bool {
+        match (self, other) {
+            (MakerAddress::Clearnet { address: a }, MakerAddress::Clearnet { address: b }) => {
+                a == b
+            }
+            (MakerAddress::Tor { address: _ }, MakerAddress::Tor { address: _ }) => {
+                self.get_fidelity_bond_hostname() == other.get_fidelity_bond_hostname()
+            }
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for MakerAddress {
@@ -66,7 +105,7 @@ impl fmt::Display for MakerAddress {
 async fn download_maker_offer_attempt_once(addr: &MakerAddress) -> Result<Offer, TeleportError> {
     log::debug!(target: "offerbook", "Connecting to {}", addr);
     let mut socket = TcpStream::connect(addr.get_tcpstream_address()).await?;
-    let (mut socket_reader, mut socket_writer) = handshake_maker(&mut socket, addr).await?;
+    let (mut socket_reader, mut socket_writer) = handshake_maker(&mut socket, addr, None).await?;
 
     send_message(
         &mut socket_writer,
@@ -80,6 +119,45 @@ async fn download_maker_offer_attempt_once(addr: &MakerAddress) -> Result<Offer,
         return Err(TeleportError::Protocol("expected method offer"));
     };
 
+    //the key must belong to whoever controls the tweakable point, and be the one we talked to
+    if let Some(noise_static_key) = &offer.noise_static_key {
+        if !verify_static_key(noise_static_key, &offer.tweakable_point) {
+            return Err(TeleportError::Protocol(
+                "invalid signature of noise static key",
+            ));
+        }
+        if socket_reader
+            .remote_static_key()
+            .is_some_and(|k| k != noise_static_key.key)
+        {
+            return Err(TeleportError::Protocol(
+                "maker's encryption key doesnt match its offer",
+            ));
+        }
+    }
+
+    //someone on the path could have changed the hellos so there was no handshake, but they cant
+    //remove the key the fidelity bond proof commits to, the proof is verified with the offerbook
+    let committed_key = offer
+        .fidelity_bond_proof
+        .as_ref()
+        .and_then(|proof| proof.noise_static_key);
+    if let Some(committed_key) = committed_key {
+        if offer.noise_static_key.as_ref().map(|k| k.key) != Some(committed_key) {
+            return Err(TeleportError::Protocol(
+                "maker's offer and fidelity bond proof have different encryption keys",
+            ));
+        }
+        //tor already encrypts connections to onion addresses
+        if let MakerAddress::Clearnet { address: _ } = addr {
+            if socket_reader.remote_static_key() != Some(committed_key) {
+                return Err(TeleportError::Protocol(
+                    "maker didnt encrypt the connection with the key its fidelity bond commits to",
+                ));
+            }
+        }
+    }
+
     log::debug!(target: "offerbook", "Obtained offer from {}", addr);
     Ok(offer)
 }
@@ -148,6 +226,72 @@ pub async fn sync_offerbook_with_addresses(
     result
 }
 
+/// Verify the fidelity bond proofs of downloaded offers and calculate the bond values.
+/// Offers with a proof that doesnt verify are dropped. A bond can only back one maker, so
+/// offers whose verified proof announces a bond UTXO which another verified proof announces
+/// too are dropped as well. Proofs which don't verify aren't counted, otherwise anyone could
+/// get an honest maker dropped by copying its proof.
+pub fn verify_fidelity_bonds(
+    rpc: &dyn BlockchainBackend,
+    offers: Vec<OfferAndAddress>,
+) -> Result<Vec<(OfferAndAddress, Option<f64>)>, TeleportError> {
+    let block_count = rpc.get_block_count()?;
+    let mediantime = rpc.get_median_time()?;
+
+    let verified_offers = offers
+        .into_iter()
+        .filter_map(|offer_address| {
+            let proof = match &offer_address.offer.fidelity_bond_proof {
+                Some(proof) => proof,
+                None => return Some((offer_address, None)),
+            };
+            match proof.verify_and_get_value(
+                rpc,
+                block_count,
+                mediantime,
+                offer_address.address.get_fidelity_bond_hostname(),
+            ) {
+                Ok(value) => Some((offer_address, Some(value))),
+                Err(e) => {
+                    log::warn!(target: "offerbook",
+                        "Rejecting offer from {}, invalid fidelity bond proof: {:?}",
+                        offer_address.address,
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect::<Vec<(OfferAndAddress, Option<f64>)>>();
+
+    let mut bond_announce_count = HashMap::<OutPoint, u32>::new();
+    for proof in verified_offers
+        .iter()
+        .filter_map(|(oa, _)| oa.offer.fidelity_bond_proof.as_ref())
+    {
+        *bond_announce_count.entry(proof.utxo).or_insert(0) += 1;
+    }
+
+    Ok(verified_offers
+        .into_iter()
+        .filter(|(offer_address, _)| {
+            let proof = match &offer_address.offer.fidelity_bond_proof {
+                Some(proof) => proof,
+                None => return true,
+            };
+            if bond_announce_count[&proof.utxo] > 1 {
+                log::warn!(target: "offerbook",
+                    "Rejecting offer from {}, its fidelity bond {} is announced by another maker",
+                    offer_address.address,
+                    proof.utxo
+                );
+                return false;
+            }
+            true
+        })
+        .collect())
+}
+
 pub async fn get_advertised_maker_addresses(
     network: Network,
 ) -> Result<Vec<MakerAddress>, DirectoryServerError> {
@@ -163,3 +307,24 @@ pub async fn sync_offerbook(
 ) -> Result<Vec<OfferAndAddress>, DirectoryServerError> {
     Ok(sync_offerbook_with_addresses(get_advertised_maker_addresses(network).await?).await)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_maker_address_from_host_and_port() {
+        assert_eq!(
+            MakerAddress::from_host_and_port("abcdef.onion:6102".to_string()),
+            MakerAddress::Tor {
+                address: "abcdef.onion:6102".to_string()
+            }
+        );
+        assert_eq!(
+            MakerAddress::from_host_and_port("localhost:6102".to_string()),
+            MakerAddress::Clearnet {
+                address: "localhost:6102".to_string()
+            }
+        );
+    }
+}
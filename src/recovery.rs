@@ -0,0 +1,595 @@
+//! Recovery of the coins of incomplete coinswaps.
+//!
+//! The contract txs of incomplete coinswaps are broadcast, and once they are confirmed the
+//! contract outputs are spent back to the wallet. Outgoing coins are spent via the timelock
+//! branch after it matures, incoming coins via the hashlock branch if the preimage is known.
+//! Progress is saved to a file next to the wallet, so recovery can be stopped and restarted.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    thread::sleep,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use bitcoin::{hashes::hash160::Hash as Hash160, Transaction, Txid};
+
+use crate::{
+    blockchain_backend::BlockchainBackend,
+    contracts::SwapCoin,
+    error::{file_format_error, TeleportError},
+    wallet_sync::{IncomingSwapCoin, OutgoingSwapCoin, Wallet, WalletSwapCoin},
+};
+
+const RECOVERY_FILE_VERSION: u32 = 0;
+
+//blocks only come every 10 minutes, but checking is cheap and lets a dropped tx be rebroadcast
+//without much delay
+const RECOVERY_POLL_DELAY_SEC: u64 = 10;
+
+/// A contract tx whose output is being spent back to the wallet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecoveringContract {
+    hashvalue: Hash160,
+    contract_tx: Transaction,
+    /// Timelock or hashlock spend of the contract output. Its input sequence is the number of
+    /// confirmations the contract tx needs before it can be broadcast.
+    spend_tx: Transaction,
+    spend_broadcasted: bool,
+    /// Miner fee of the contract tx, which can't be worked out from the tx alone
+    #[serde(default)]
+    contract_fee: u64,
+}
+
+impl RecoveringContract {
+    fn required_confirmations(&self) -> i32 {
+        self.spend_tx.input[0].sequence as i32
+    }
+
+    fn recovery_fee(&self) -> u64 {
+        self.contract_fee + self.contract_tx.output[0].value - self.spend_tx.output[0].value
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecoveryFileData {
+    version: u32,
+    contracts: Vec<RecoveringContract>,
+}
+
+fn load_recovery_file(file_path: &Path) -> Result<Vec<RecoveringContract>, TeleportError> {
+    let mut file_str = String::new();
+    match File::open(file_path) {
+        Ok(mut file) => file.read_to_string(&mut file_str)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let file_data = serde_json::from_str::<RecoveryFileData>(&file_str).map_err(io::Error::from)?;
+    if file_data.version != RECOVERY_FILE_VERSION {
+        return Err(file_format_error("unknown recovery file version"));
+    }
+    Ok(file_data.contracts)
+}
+
+fn save_recovery_file(
+    file_path: &Path,
+    contracts: &[RecoveringContract],
+) -> Result<(), TeleportError> {
+    let file = File::create(file_path)?;
+    serde_json::to_writer(
+        file,
+        &RecoveryFileData {
+            version: RECOVERY_FILE_VERSION,
+            contracts: contracts.to_vec(),
+        },
+    )
+    .map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Add the swapcoins of incomplete coinswaps which aren't being recovered yet, creating the
+/// spends of their contract outputs. Only the coinswap with `hashvalue` is added if given.
+fn add_incomplete_coinswaps(
+    rpc: &dyn BlockchainBackend,
+    wallet: &mut Wallet,
+    hashvalue: Option<Hash160>,
+    contracts: &mut Vec<RecoveringContract>,
+) -> Result<(), TeleportError> {
+    let is_new = |swap_hashvalue: &Hash160, swapcoin: &dyn WalletSwapCoin| {
+        hashvalue.is_none_or(|h| h == *swap_hashvalue)
+            && !contracts
+                .iter()
+                .any(|c| c.contract_tx.txid() == swapcoin.get_contract_tx().txid())
+    };
+    let mut incoming_swapcoins = Vec::<(Hash160, IncomingSwapCoin)>::new();
+    let mut outgoing_swapcoins = Vec::<(Hash160, OutgoingSwapCoin)>::new();
+    for (swap_hashvalue, (incoming, outgoing)) in wallet.find_incomplete_coinswaps(rpc)? {
+        for (_, incoming_swapcoin) in incoming {
+            if !is_new(&swap_hashvalue, incoming_swapcoin) {
+                continue;
+            }
+            //without the preimage the coins can only go back to the sender via the timelock
+            if incoming_swapcoin.hash_preimage.is_none() {
+                log::warn!(
+                    "Preimage of coinswap {} unknown, unable to recover incoming coin {}",
+                    swap_hashvalue,
+                    incoming_swapcoin.contract_tx.input[0].previous_output
+                );
+                continue;
+            }
+            incoming_swapcoins.push((swap_hashvalue, incoming_swapcoin.clone()));
+        }
+        for (_, outgoing_swapcoin) in outgoing {
+            if is_new(&swap_hashvalue, outgoing_swapcoin) {
+                outgoing_swapcoins.push((swap_hashvalue, outgoing_swapcoin.clone()));
+            }
+        }
+    }
+    //outgoing contract txes already on the blockchain, e.g. broadcast by the other side or
+    //found by scanning for swapcoin keys, only need their timelock spend
+    for (outgoing_swapcoin, _) in wallet.find_live_contract_unspents(rpc)?.1 {
+        let swap_hashvalue = outgoing_swapcoin.get_hashvalue();
+        if is_new(&swap_hashvalue, outgoing_swapcoin) {
+            outgoing_swapcoins.push((swap_hashvalue, outgoing_swapcoin.clone()));
+        }
+    }
+
+    for (swap_hashvalue, incoming_swapcoin) in incoming_swapcoins {
+        wallet.import_wallet_contract_redeemscript(
+            rpc,
+            &incoming_swapcoin.get_contract_redeemscript(),
+        )?;
+        let address = wallet.get_next_external_address(rpc)?;
+        let mut spend_tx = incoming_swapcoin.create_hashlock_spend_without_preimage(&address);
+        //assumes the spend tx is one-input-one-output
+        spend_tx.input[0].witness[1] = incoming_swapcoin.hash_preimage.unwrap().to_vec();
+        contracts.push(RecoveringContract {
+            hashvalue: swap_hashvalue,
+            contract_tx: incoming_swapcoin.get_fully_signed_contract_tx(),
+            spend_tx,
+            spend_broadcasted: false,
+            contract_fee: incoming_swapcoin.funding_amount
+                - incoming_swapcoin.contract_tx.output[0].value,
+        });
+    }
+    for (swap_hashvalue, outgoing_swapcoin) in outgoing_swapcoins {
+        wallet.import_wallet_contract_redeemscript(
+            rpc,
+            &outgoing_swapcoin.get_contract_redeemscript(),
+        )?;
+        let address = wallet.get_next_external_address(rpc)?;
+        contracts.push(RecoveringContract {
+            hashvalue: swap_hashvalue,
+            contract_tx: outgoing_swapcoin.get_fully_signed_contract_tx(),
+            spend_tx: outgoing_swapcoin.create_timelock_spend(&address),
+            spend_broadcasted: false,
+            contract_fee: outgoing_swapcoin.funding_amount
+                - outgoing_swapcoin.contract_tx.output[0].value,
+        });
+    }
+    Ok(())
+}
+
+/// Confirmations of a tx, None if it's neither confirmed nor in the mempool, because it was
+/// never broadcast or dropped out of the mempool.
+fn get_confirmations(rpc: &dyn BlockchainBackend, txid: &Txid) -> Option<i32> {
+    match rpc.get_transaction(txid) {
+        Ok(gettx) if gettx.confirmations > 0 => Some(gettx.confirmations),
+        Ok(_) if rpc.get_mempool_entry(txid).is_ok() => Some(0),
+        _ => None,
+    }
+}
+
+/// Broadcast the contract tx or its spend once possible, and again if it dropped out of the
+/// mempool. The miner fees of both are booked in the ledger when the spend is first broadcast.
+/// Returns true when the contract output is spent, either by our confirmed spend or by the other
+/// side of the coinswap.
+fn check_contract(
+    rpc: &dyn BlockchainBackend,
+    wallet: &Wallet,
+    contract: &mut RecoveringContract,
+) -> Result<bool, TeleportError> {
+    let contract_txid = contract.contract_tx.txid();
+    if contract.spend_broadcasted {
+        match get_confirmations(rpc, &contract.spend_tx.txid()) {
+            Some(confirmations) if confirmations > 0 => {
+                log::info!(
+                    "Spend tx {} of coinswap {} confirmed",
+                    contract.spend_tx.txid(),
+                    contract.hashvalue
+                );
+                return Ok(true);
+            }
+            Some(_) => return Ok(false),
+            None => log::info!(
+                "Spend tx {} of coinswap {} isn't in the mempool, rebroadcasting",
+                contract.spend_tx.txid(),
+                contract.hashvalue
+            ),
+        }
+    }
+
+    let confirmations = match get_confirmations(rpc, &contract_txid) {
+        Some(confirmations) => confirmations,
+        None => match rpc.send_raw_transaction(&contract.contract_tx) {
+            Ok(txid) => {
+                log::info!("Broadcasted contract tx {}", txid);
+                0
+            }
+            //the funding output may not be confirmed yet, so just try again later
+            Err(e) => {
+                log::warn!("Unable to broadcast contract tx {}: {:?}", contract_txid, e);
+                return Ok(false);
+            }
+        },
+    };
+    if confirmations < contract.required_confirmations() {
+        log::debug!(
+            "contract txout (txid={}) spendable in {} blocks",
+            contract_txid,
+            contract.required_confirmations() - confirmations
+        );
+        return Ok(false);
+    }
+    match rpc.send_raw_transaction(&contract.spend_tx) {
+        Ok(txid) => {
+            log::info!(
+                "Broadcasted spend tx {} of contract {}",
+                txid,
+                contract_txid
+            );
+            if !contract.spend_broadcasted {
+                wallet
+                    .add_swap_ledger_recovery_fee(&contract.hashvalue, contract.recovery_fee())?;
+                contract.spend_broadcasted = true;
+            }
+            Ok(false)
+        }
+        Err(e) => {
+            if rpc.get_tx_out(&contract_txid, 0)?.is_none() {
+                log::info!(
+                    "Contract output {}:0 already spent by the other side of the coinswap",
+                    contract_txid
+                );
+                return Ok(true);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Start recovering the incomplete coinswaps, or only the coinswap with `hashvalue` if given,
+/// by adding their contracts to the recovery file. Returns how many contracts are in the file.
+pub fn add_to_recovery(
+    rpc: &dyn BlockchainBackend,
+    wallet: &mut Wallet,
+    hashvalue: Option<Hash160>,
+    recovery_file_path: &Path,
+) -> Result<usize, TeleportError> {
+    let mut contracts = load_recovery_file(recovery_file_path)?;
+    add_incomplete_coinswaps(rpc, wallet, hashvalue, &mut contracts)?;
+    save_recovery_file(recovery_file_path, &contracts)?;
+    Ok(contracts.len())
+}
+
+/// Broadcast the contract txes and spends in the recovery file which can be broadcast now.
+/// Swapcoins whose contract output is spent are removed from the wallet, so they aren't
+/// recovered again. Returns true once every contract output in the file is spent.
+pub fn poll_recovery(
+    rpc: &dyn BlockchainBackend,
+    wallet: &mut Wallet,
+    recovery_file_path: &Path,
+) -> Result<bool, TeleportError> {
+    let contracts = load_recovery_file(recovery_file_path)?;
+    if contracts.is_empty() {
+        return Ok(true);
+    }
+    let mut remaining = Vec::<RecoveringContract>::new();
+    for mut contract in contracts {
+        if check_contract(rpc, wallet, &mut contract)? {
+            wallet.remove_swapcoin_with_contract(&contract.contract_tx.txid())?;
+        } else {
+            remaining.push(contract);
+        }
+    }
+    save_recovery_file(recovery_file_path, &remaining)?;
+    Ok(remaining.is_empty())
+}
+
+/// Recover the coins of incomplete coinswaps, or only of the coinswap with `hashvalue` if
+/// given. Keeps running until every contract output is spent, which for outgoing coins means
+/// waiting for the timelock to mature.
+pub fn run_recovery(
+    rpc: &dyn BlockchainBackend,
+    wallet: &mut Wallet,
+    hashvalue: Option<Hash160>,
+    recovery_file_path: &Path,
+) -> Result<(), TeleportError> {
+    let contract_count = add_to_recovery(rpc, wallet, hashvalue, recovery_file_path)?;
+    if contract_count == 0 {
+        log::info!("No incomplete coinswaps to recover");
+        return Ok(());
+    }
+    log::info!("Recovering {} contract(s)", contract_count);
+
+    while !poll_recovery(rpc, wallet, recovery_file_path)? {
+        sleep(Duration::from_secs(RECOVERY_POLL_DELAY_SEC));
+    }
+    log::info!("All coins of incomplete coinswaps recovered");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        contracts::{create_contract_redeemscript, create_senders_contract_tx, sign_contract_tx},
+        maker_ledger::{SwapLedgerEntry, SwapOutcome},
+        simulated_blockchain::SimulatedBlockchain,
+        util::calculate_maker_keys,
+        wallet_storage::temp_wallet_path,
+        wallet_sync::{
+            create_multisig_redeemscript, create_temp_wallet, generate_keypair,
+            WalletSyncAddressAmount, SWAPCOIN_KEY_PATH,
+        },
+    };
+    use bitcoin::{
+        hashes::Hash,
+        secp256k1::Secp256k1,
+        util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey},
+        Address, Amount, Network, OutPoint,
+    };
+    use bitcoin_wallet::{account::MasterKeyEntropy, mnemonic::Mnemonic};
+    use std::{fs, str::FromStr};
+
+    #[test]
+    fn test_recovered_swapcoin_not_recovered_again() {
+        let sim = SimulatedBlockchain::new();
+        let mut wallet = create_temp_wallet("recovery");
+        let wallet_path = wallet.get_wallet_file_path().to_path_buf();
+        let recovery_path = wallet_path.with_extension("recovery");
+
+        // An outgoing swapcoin whose funding tx confirmed but which the other side never
+        // finished.
+        let (my_pubkey, my_privkey) = generate_keypair();
+        let (other_pubkey, other_privkey) = generate_keypair();
+        let (timelock_pubkey, timelock_privkey) = generate_keypair();
+        let (hashlock_pubkey, _) = generate_keypair();
+        let hashvalue = Hash160::hash(&[1; 32]);
+        let multisig_redeemscript = create_multisig_redeemscript(&my_pubkey, &other_pubkey);
+        wallet
+            .import_wallet_multisig_redeemscript(&sim, &my_pubkey, &other_pubkey)
+            .unwrap();
+        let funding_amount = 1_000_000;
+        let funding_txid = sim
+            .send_to_address(
+                &Address::p2wsh(&multisig_redeemscript, Network::Regtest),
+                Amount::from_sat(funding_amount),
+            )
+            .unwrap();
+        sim.mine_blocks(1);
+        let funding_vout = sim
+            .list_unspent()
+            .unwrap()
+            .iter()
+            .find(|u| u.txid == funding_txid)
+            .unwrap()
+            .vout;
+        let contract_redeemscript =
+            create_contract_redeemscript(&hashlock_pubkey, &timelock_pubkey, hashvalue, 5);
+        let contract_tx = create_senders_contract_tx(
+            OutPoint::new(funding_txid, funding_vout),
+            funding_amount,
+            &contract_redeemscript,
+        );
+        let others_contract_sig = sign_contract_tx(
+            &contract_tx,
+            &multisig_redeemscript,
+            funding_amount,
+            &other_privkey,
+        )
+        .unwrap();
+        let mut swapcoin = OutgoingSwapCoin::new(
+            my_privkey,
+            other_pubkey,
+            contract_tx,
+            contract_redeemscript,
+            timelock_privkey,
+            funding_amount,
+        );
+        swapcoin.others_contract_sig = Some(others_contract_sig);
+        let spend_value = swapcoin
+            .create_timelock_spend(&Address::p2wsh(&multisig_redeemscript, Network::Regtest))
+            .output[0]
+            .value;
+        wallet.add_outgoing_swapcoin(swapcoin).unwrap();
+        wallet
+            .add_swap_ledger_entry(&SwapLedgerEntry {
+                hashvalue,
+                started_at: 100,
+                finished_at: Some(200),
+                incoming_amount: 1_010_000,
+                outgoing_amount: funding_amount,
+                coinswap_fee: 9_000,
+                miner_fee: 400,
+                recovery_fee: 0,
+                incoming_locktime: 10,
+                outgoing_locktime: 5,
+                outcome: SwapOutcome::TimedOut,
+            })
+            .unwrap();
+
+        assert_eq!(
+            add_to_recovery(&sim, &mut wallet, None, &recovery_path).unwrap(),
+            1
+        );
+        let mut polls = 0;
+        while !poll_recovery(&sim, &mut wallet, &recovery_path).unwrap() {
+            sim.mine_blocks(1);
+            polls += 1;
+            assert!(polls < 20, "timelock spend never confirmed");
+        }
+        assert_eq!(wallet.get_swapcoins_count(), 0);
+        // The miner fees of the contract tx and the timelock spend are booked as lost
+        assert_eq!(
+            wallet.load_swap_ledger().unwrap()[0].recovery_fee,
+            funding_amount - spend_value
+        );
+
+        // Running recovery again, even with a freshly loaded wallet, finds nothing to do
+        let mut wallet = Wallet::load_wallet_from_file(
+            &wallet_path,
+            Network::Regtest,
+            crate::wallet_sync::WalletSyncAddressAmount::Testing,
+        )
+        .unwrap();
+        assert_eq!(wallet.get_swapcoins_count(), 0);
+        run_recovery(&sim, &mut wallet, None, &recovery_path).unwrap();
+        assert!(sim.get_raw_mempool().unwrap().is_empty());
+
+        fs::remove_file(&recovery_path).unwrap();
+        fs::remove_file(&wallet_path).unwrap();
+    }
+
+    #[test]
+    fn test_outgoing_swapcoin_rebuilt_from_seed() {
+        let sim = SimulatedBlockchain::new();
+        let mnemonic = Mnemonic::new_random(MasterKeyEntropy::Sufficient).unwrap();
+        let wallet_path = temp_wallet_path("seed-swapcoin");
+        let restored_path = temp_wallet_path("seed-swapcoin-restored");
+        let recovery_path = restored_path.with_extension("recovery");
+        for path in [&wallet_path, &restored_path] {
+            Wallet::save_new_wallet_file(path, mnemonic.to_string(), "".to_string(), None).unwrap();
+        }
+        let load_wallet = |path| {
+            let wallet = Wallet::load_wallet_from_file(
+                path,
+                Network::Regtest,
+                WalletSyncAddressAmount::Testing,
+            )
+            .unwrap();
+            wallet
+                .import_initial_addresses(
+                    &sim,
+                    &wallet
+                        .get_hd_wallet_descriptors(&sim)
+                        .unwrap()
+                        .iter()
+                        .collect::<Vec<&String>>(),
+                    &Vec::<_>::new(),
+                    &Vec::<_>::new(),
+                )
+                .unwrap();
+            wallet
+        };
+
+        // The first hop of a coinswap started by the taker, whose contract tx got broadcast.
+        let mut wallet = load_wallet(&wallet_path);
+        let address = wallet.get_next_external_address(&sim).unwrap();
+        sim.send_to_address(&address, Amount::from_sat(2_000_000))
+            .unwrap();
+        sim.mine_blocks(1);
+        let preimage = wallet.get_next_swap_preimage().unwrap();
+        let (tweakable_point, tweakable_privkey) = generate_keypair();
+        let (multisig_nonces, hashlock_nonces) = wallet.get_next_first_hop_nonces(1);
+        let (multisig_pubkeys, hashlock_pubkeys) =
+            calculate_maker_keys(&tweakable_point, &multisig_nonces, &hashlock_nonces);
+        let (funding_txs, mut swapcoins, _) = wallet
+            .initalize_coinswap(
+                &sim,
+                500_000,
+                &multisig_pubkeys,
+                &hashlock_pubkeys,
+                Hash160::hash(&preimage),
+                30,
+                1000,
+            )
+            .unwrap();
+        for funding_tx in &funding_txs {
+            sim.send_raw_transaction(funding_tx).unwrap();
+        }
+        sim.mine_blocks(1);
+        let mut swapcoin = swapcoins.pop().unwrap();
+        let mut maker_privkey = tweakable_privkey;
+        maker_privkey.add_assign(&multisig_nonces[0][..]).unwrap();
+        let multisig_redeemscript = swapcoin.get_multisig_redeemscript();
+        swapcoin.others_contract_sig = Some(
+            sign_contract_tx(
+                &swapcoin.contract_tx,
+                &multisig_redeemscript,
+                swapcoin.funding_amount,
+                &maker_privkey,
+            )
+            .unwrap(),
+        );
+        let contract_txid = sim
+            .send_raw_transaction(&swapcoin.get_fully_signed_contract_tx())
+            .unwrap();
+        sim.mine_blocks(1);
+
+        // The swapcoin keys come from the seed, the preimage first and then the multisig and
+        // timelock keys.
+        let secp = Secp256k1::new();
+        let seed = mnemonic.to_seed(Some(""));
+        let derive_key = |index| {
+            ExtendedPrivKey::new_master(Network::Regtest, &seed.0)
+                .unwrap()
+                .derive_priv(&secp, &DerivationPath::from_str(SWAPCOIN_KEY_PATH).unwrap())
+                .unwrap()
+                .ckd_priv(&secp, ChildNumber::from_hardened_idx(index).unwrap())
+                .unwrap()
+                .private_key
+                .key
+        };
+        assert_eq!(swapcoin.my_privkey, derive_key(1));
+        assert_eq!(swapcoin.timelock_privkey, derive_key(2));
+
+        // A wallet restored from the seed phrase finds and rebuilds the swapcoin.
+        let mut restored = load_wallet(&restored_path);
+        assert_eq!(restored.get_swapcoins_count(), 0);
+        let scan = restored.scan_for_used_swapcoin_keys(&sim, 0).unwrap();
+        assert_eq!(
+            scan.rebuilt_contract_outpoints,
+            vec![OutPoint::new(contract_txid, 0)]
+        );
+        assert!(scan.locked_contract_outpoints.is_empty());
+        assert_eq!(
+            restored.get_swapcoin_key_index(),
+            wallet.get_swapcoin_key_index()
+        );
+        let rebuilt = restored
+            .find_outgoing_swapcoin(&multisig_redeemscript)
+            .unwrap();
+        assert_eq!(
+            rebuilt.contract_redeemscript,
+            swapcoin.contract_redeemscript
+        );
+        assert_eq!(rebuilt.contract_tx, swapcoin.contract_tx);
+        assert_eq!(rebuilt.others_contract_sig, swapcoin.others_contract_sig);
+        assert_eq!(rebuilt.funding_amount, swapcoin.funding_amount);
+
+        // Scanning again doesn't add it twice.
+        let scan = restored.scan_for_used_swapcoin_keys(&sim, 0).unwrap();
+        assert!(scan.rebuilt_contract_outpoints.is_empty());
+
+        // And its coins come back once the timelock matures.
+        assert_eq!(
+            add_to_recovery(&sim, &mut restored, None, &recovery_path).unwrap(),
+            1
+        );
+        let mut polls = 0;
+        while !poll_recovery(&sim, &mut restored, &recovery_path).unwrap() {
+            sim.mine_blocks(1);
+            polls += 1;
+            assert!(polls < 50, "timelock spend never confirmed");
+        }
+        assert_eq!(restored.get_swapcoins_count(), 0);
+
+        fs::remove_file(&recovery_path).unwrap();
+        fs::remove_file(&restored_path).unwrap();
+        fs::remove_file(&wallet_path).unwrap();
+    }
+}
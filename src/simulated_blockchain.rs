@@ -0,0 +1,1905 @@
+//! An in-memory blockchain, mempool and watch-only wallet implementing [BlockchainBackend].
+//!
+//! Lets a taker, makers and a watchtower run a coinswap against each other inside `cargo test`
+//! without a bitcoind. Blocks are only produced by [SimulatedBlockchain::mine_blocks], each one
+//! 10 minutes after the previous one, so a test fully controls the chain. Like a bitcoind shared
+//! by all the parties there is a single wallet, which everyone imports their scripts into.
+//!
+//! Mempool acceptance checks that inputs exist and are unspent, that the outputs don't exceed
+//! the inputs, coinbase maturity, nLockTime and BIP68 relative locktimes. Scripts are not
+//! executed. P2wpkh signatures and p2wsh witness scripts are checked against the outputs being
+//! spent, and the signatures in a p2wsh witness against the keys in its witness script. Errors are returned like a bitcoind's, as `TeleportError::Rpc` with its error
+//! codes.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use bitcoin::{
+    blockdata::{
+        constants::genesis_block,
+        opcodes::all,
+        script::{Builder, Instruction},
+    },
+    consensus::encode::{deserialize, serialize},
+    hashes::{hash160::Hash as Hash160, hex::FromHex, Hash},
+    secp256k1::{Message, Secp256k1, Signature},
+    util::{
+        bip143::SigHashCache,
+        bip32::{ChildNumber, DerivationPath, ExtendedPubKey, Fingerprint, KeySource},
+        merkleblock::MerkleBlock,
+        psbt::PartiallySignedTransaction,
+    },
+    Address, Amount, Block, BlockHash, BlockHeader, Network, OutPoint, PublicKey, Script,
+    SigHashType, Transaction, TxIn, TxOut, Txid,
+};
+use bitcoincore_rpc::json::{
+    CreateRawTransactionInput, ImportMultiRequest, ImportMultiRequestScriptPubkey,
+    WalletCreateFundedPsbtOptions,
+};
+
+use crate::{
+    blockchain_backend::{
+        AddressInfo, BlockchainBackend, FundedPsbt, ImportResult, ListUnspentEntry, MempoolEntry,
+        ScanObject, ScannedUnspent, TxOutInfo, TxOutSetScan, WalletTransaction,
+    },
+    error::TeleportError,
+};
+
+const NETWORK: Network = Network::Regtest;
+const COINBASE_MATURITY: u64 = 100;
+const SUBSIDY_HALVING_INTERVAL: u64 = 150;
+const BLOCK_INTERVAL_SECS: u32 = 600;
+const MEDIAN_TIME_SPAN: u64 = 11;
+
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+//sat per kvB, same as bitcoind's minimum relay fee
+const DEFAULT_FEE_RATE: u64 = 1000;
+const FAUCET_FEE_RATE: u64 = 1000;
+const DUST_LIMIT: u64 = 546;
+const SCAN_DEFAULT_RANGE_END: u64 = 1000;
+
+//error codes of bitcoind's RPC, errors are returned the same way as from bitcoind
+const RPC_MISC_ERROR: i32 = -1;
+const RPC_WALLET_ERROR: i32 = -4;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+const RPC_WALLET_INSUFFICIENT_FUNDS: i32 = -6;
+const RPC_INVALID_PARAMETER: i32 = -8;
+const RPC_DESERIALIZATION_ERROR: i32 = -22;
+const RPC_VERIFY_ERROR: i32 = -25;
+const RPC_VERIFY_REJECTED: i32 = -26;
+const RPC_VERIFY_ALREADY_IN_CHAIN: i32 = -27;
+
+const DESCRIPTOR_INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const DESCRIPTOR_CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Blockchain and wallet held in memory, see the [module docs](self).
+pub struct SimulatedBlockchain {
+    state: Mutex<SimState>,
+}
+
+impl SimulatedBlockchain {
+    /// Start a regtest chain with the faucet's first coinbase already spendable.
+    pub fn new() -> SimulatedBlockchain {
+        let sim = SimulatedBlockchain {
+            state: Mutex::new(SimState::new()),
+        };
+        sim.mine_blocks(COINBASE_MATURITY + 1);
+        sim
+    }
+
+    /// Mine blocks containing every tx in the mempool, the coinbases pay to the faucet.
+    pub fn mine_blocks(&self, count: u64) -> Vec<BlockHash> {
+        let mut state = self.state.lock().unwrap();
+        (0..count).map(|_| state.mine_block()).collect()
+    }
+
+    /// Pay from the faucet into the mempool, like `sendtoaddress` on a funded bitcoind wallet.
+    pub fn send_to_address(
+        &self,
+        address: &Address,
+        amount: Amount,
+    ) -> Result<Txid, TeleportError> {
+        let mut state = self.state.lock().unwrap();
+        let tx = state.create_faucet_tx(address.script_pubkey(), amount.as_sat())?;
+        state.accept_to_mempool(tx)
+    }
+}
+
+impl Default for SimulatedBlockchain {
+    fn default() -> SimulatedBlockchain {
+        SimulatedBlockchain::new()
+    }
+}
+
+struct Coin {
+    output: TxOut,
+    //None if the coin was created by a tx in the mempool
+    height: Option<u64>,
+    is_coinbase: bool,
+}
+
+struct MempoolTx {
+    tx: Transaction,
+    fee: u64,
+}
+
+struct ConfirmedTx {
+    tx: Transaction,
+    height: u64,
+}
+
+#[derive(Clone)]
+struct WatchedScript {
+    descriptor: Option<String>,
+    witness_script: Option<Script>,
+    key_origins: BTreeMap<PublicKey, KeySource>,
+    label: String,
+}
+
+struct SelectedInput {
+    outpoint: OutPoint,
+    output: TxOut,
+    watched: WatchedScript,
+}
+
+struct SimState {
+    blocks: Vec<Block>,
+    block_heights: HashMap<BlockHash, u64>,
+    utxos: HashMap<OutPoint, Coin>,
+    confirmed_txes: HashMap<Txid, ConfirmedTx>,
+    mempool: HashMap<Txid, MempoolTx>,
+    //acceptance order, parents always come before their children
+    mempool_order: Vec<Txid>,
+    mempool_spends: HashMap<OutPoint, Txid>,
+    watched_scripts: HashMap<Script, WatchedScript>,
+    //in the order they were seen
+    wallet_txes: Vec<Txid>,
+    wallet_txids: HashSet<Txid>,
+    locked_outpoints: HashSet<OutPoint>,
+}
+
+fn rpc_error(code: i32, message: &str) -> TeleportError {
+    TeleportError::Rpc(bitcoincore_rpc::Error::JsonRpc(
+        bitcoincore_rpc::jsonrpc::error::Error::Rpc(bitcoincore_rpc::jsonrpc::error::RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }),
+    ))
+}
+
+fn faucet_script() -> Script {
+    Builder::new().push_opcode(all::OP_PUSHNUM_1).into_script()
+}
+
+fn block_subsidy(height: u64) -> u64 {
+    let halvings = height / SUBSIDY_HALVING_INTERVAL;
+    if halvings >= 64 {
+        0
+    } else {
+        (50 * 100_000_000) >> halvings
+    }
+}
+
+fn fee_for_vsize(fee_rate: u64, vsize: u64) -> u64 {
+    (fee_rate * vsize).div_ceil(1000)
+}
+
+fn output_vsize(script_pubkey: &Script) -> u64 {
+    9 + script_pubkey.len() as u64
+}
+
+fn estimate_input_vsize(watched: &WatchedScript) -> u64 {
+    let witness_len = match &watched.witness_script {
+        //item count, the empty item for CHECKMULTISIG, a signature per key and the script
+        Some(ws) => 2 + 73 * watched.key_origins.len() + 1 + ws.len(),
+        //item count, signature and pubkey
+        None => 1 + 73 + 34,
+    };
+    (4 * 41 + witness_len as u64).div_ceil(4)
+}
+
+impl SimState {
+    fn new() -> SimState {
+        let genesis = genesis_block(NETWORK);
+        let mut state = SimState {
+            blocks: Vec::new(),
+            block_heights: HashMap::new(),
+            utxos: HashMap::new(),
+            confirmed_txes: HashMap::new(),
+            mempool: HashMap::new(),
+            mempool_order: Vec::new(),
+            mempool_spends: HashMap::new(),
+            watched_scripts: HashMap::new(),
+            wallet_txes: Vec::new(),
+            wallet_txids: HashSet::new(),
+            locked_outpoints: HashSet::new(),
+        };
+        //the genesis coinbase is unspendable, so it isnt added to the utxo set
+        state.block_heights.insert(genesis.block_hash(), 0);
+        state.blocks.push(genesis);
+        state
+    }
+
+    fn tip_height(&self) -> u64 {
+        self.blocks.len() as u64 - 1
+    }
+
+    fn tip_hash(&self) -> BlockHash {
+        self.blocks.last().unwrap().block_hash()
+    }
+
+    fn median_time_past(&self, height: u64) -> u64 {
+        let start = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times = (start..=height)
+            .map(|h| self.blocks[h as usize].header.time as u64)
+            .collect::<Vec<u64>>();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    fn confirmations(&self, height: Option<u64>) -> u32 {
+        height.map_or(0, |h| (self.tip_height() - h + 1) as u32)
+    }
+
+    fn block_by_hash(&self, hash: &BlockHash) -> Result<&Block, TeleportError> {
+        self.block_heights
+            .get(hash)
+            .map(|&height| &self.blocks[height as usize])
+            .ok_or_else(|| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "block not found"))
+    }
+
+    fn find_transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.mempool
+            .get(txid)
+            .map(|entry| &entry.tx)
+            .or_else(|| self.confirmed_txes.get(txid).map(|ctx| &ctx.tx))
+    }
+
+    //output being spent by an input, whether or not it is still unspent
+    fn find_prevout(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.find_transaction(&outpoint.txid)
+            .and_then(|tx| tx.output.get(outpoint.vout as usize))
+    }
+
+    //unspent output in the utxo set or created by a mempool tx, and not spent in the mempool
+    fn get_unspent(&self, outpoint: &OutPoint) -> Option<Coin> {
+        if self.mempool_spends.contains_key(outpoint) {
+            return None;
+        }
+        if let Some(coin) = self.utxos.get(outpoint) {
+            return Some(Coin {
+                output: coin.output.clone(),
+                height: coin.height,
+                is_coinbase: coin.is_coinbase,
+            });
+        }
+        self.mempool
+            .get(&outpoint.txid)
+            .and_then(|entry| entry.tx.output.get(outpoint.vout as usize))
+            .filter(|output| !output.script_pubkey.is_provably_unspendable())
+            .map(|output| Coin {
+                output: output.clone(),
+                height: None,
+                is_coinbase: false,
+            })
+    }
+
+    fn mine_block(&mut self) -> BlockHash {
+        let height = self.tip_height() + 1;
+        let mut txdata = self
+            .mempool_order
+            .iter()
+            .map(|txid| self.mempool[txid].tx.clone())
+            .collect::<Vec<Transaction>>();
+        let fees = self.mempool.values().map(|entry| entry.fee).sum::<u64>();
+        let has_witness = txdata
+            .iter()
+            .any(|tx| tx.input.iter().any(|i| !i.witness.is_empty()));
+
+        //the height in the coinbase makes every coinbase txid unique, as in BIP34
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_opcode(all::OP_PUSHBYTES_0)
+                    .into_script(),
+                sequence: SEQUENCE_FINAL,
+                witness: if has_witness {
+                    vec![vec![0; 32]]
+                } else {
+                    Vec::new()
+                },
+            }],
+            output: vec![TxOut {
+                value: block_subsidy(height) + fees,
+                script_pubkey: faucet_script(),
+            }],
+        };
+        txdata.insert(0, coinbase);
+
+        let prev_header = self.blocks.last().unwrap().header;
+        let mut block = Block {
+            header: BlockHeader {
+                version: 0x20000000,
+                prev_blockhash: prev_header.block_hash(),
+                merkle_root: Default::default(),
+                time: prev_header.time + BLOCK_INTERVAL_SECS,
+                bits: prev_header.bits,
+                nonce: 0,
+            },
+            txdata,
+        };
+        if has_witness {
+            let commitment = Block::compute_witness_commitment(&block.witness_root(), &[0; 32]);
+            let mut commitment_push = vec![0xaa, 0x21, 0xa9, 0xed];
+            commitment_push.extend_from_slice(&commitment[..]);
+            block.txdata[0].output.push(TxOut {
+                value: 0,
+                script_pubkey: Builder::new()
+                    .push_opcode(all::OP_RETURN)
+                    .push_slice(&commitment_push)
+                    .into_script(),
+            });
+        }
+        block.header.merkle_root = block.merkle_root();
+        //regtest difficulty is so low that this takes two attempts on average
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        self.connect_block(block, height)
+    }
+
+    fn connect_block(&mut self, block: Block, height: u64) -> BlockHash {
+        let hash = block.block_hash();
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            if !tx.is_coin_base() {
+                for input in &tx.input {
+                    self.utxos.remove(&input.previous_output);
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
+                self.utxos.insert(
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    Coin {
+                        output: output.clone(),
+                        height: Some(height),
+                        is_coinbase: tx.is_coin_base(),
+                    },
+                );
+            }
+            self.confirmed_txes.insert(
+                txid,
+                ConfirmedTx {
+                    tx: tx.clone(),
+                    height,
+                },
+            );
+        }
+        self.mempool.clear();
+        self.mempool_order.clear();
+        self.mempool_spends.clear();
+        self.block_heights.insert(hash, height);
+        self.blocks.push(block.clone());
+        //scripts may have been imported while the txes were in the mempool
+        for tx in &block.txdata {
+            self.add_if_wallet_tx(tx);
+        }
+        hash
+    }
+
+    fn create_faucet_tx(
+        &self,
+        script_pubkey: Script,
+        value: u64,
+    ) -> Result<Transaction, TeleportError> {
+        let faucet_script = faucet_script();
+        let next_height = self.tip_height() + 1;
+        let mature_utxos = self
+            .utxos
+            .iter()
+            .filter(|(_, coin)| coin.output.script_pubkey == faucet_script)
+            .filter(|(_, coin)| {
+                !coin.is_coinbase || next_height - coin.height.unwrap() >= COINBASE_MATURITY
+            })
+            .map(|(outpoint, _)| *outpoint);
+        //change from earlier faucet payments can be spent while still in the mempool
+        let mempool_change = self.mempool_order.iter().flat_map(|txid| {
+            self.mempool[txid]
+                .tx
+                .output
+                .iter()
+                .enumerate()
+                .filter(|(_, output)| output.script_pubkey == faucet_script)
+                .map(move |(vout, _)| OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                })
+        });
+        let faucet_coin = mature_utxos
+            .chain(mempool_change)
+            .filter_map(|outpoint| self.get_unspent(&outpoint).map(|coin| (outpoint, coin)))
+            .filter(|(_, coin)| coin.output.value > value + DUST_LIMIT)
+            .min_by_key(|(outpoint, coin)| (coin.output.value, *outpoint))
+            .ok_or_else(|| {
+                rpc_error(
+                    RPC_WALLET_INSUFFICIENT_FUNDS,
+                    "faucet has no coin big enough",
+                )
+            })?;
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: faucet_coin.0,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+                witness: Vec::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value,
+                    script_pubkey,
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: faucet_script,
+                },
+            ],
+        };
+        let fee = fee_for_vsize(FAUCET_FEE_RATE, (tx.get_weight() as u64).div_ceil(4));
+        tx.output[1].value = faucet_coin.1.output.value - value - fee;
+        Ok(tx)
+    }
+
+    fn accept_to_mempool(&mut self, tx: Transaction) -> Result<Txid, TeleportError> {
+        let txid = tx.txid();
+        if self.mempool.contains_key(&txid) {
+            return Ok(txid);
+        }
+        if self.confirmed_txes.contains_key(&txid) {
+            return Err(rpc_error(
+                RPC_VERIFY_ALREADY_IN_CHAIN,
+                "transaction already in block chain",
+            ));
+        }
+        if tx.is_coin_base() {
+            return Err(rpc_error(RPC_VERIFY_REJECTED, "coinbase"));
+        }
+        if tx.input.is_empty() || tx.output.is_empty() {
+            return Err(rpc_error(RPC_VERIFY_REJECTED, "bad-txns-vin-or-vout-empty"));
+        }
+
+        let next_height = self.tip_height() + 1;
+        let mut spent_outpoints = HashSet::<OutPoint>::new();
+        let mut input_value = 0;
+        for (index, input) in tx.input.iter().enumerate() {
+            if !spent_outpoints.insert(input.previous_output) {
+                return Err(rpc_error(RPC_VERIFY_REJECTED, "bad-txns-inputs-duplicate"));
+            }
+            if self.mempool_spends.contains_key(&input.previous_output) {
+                return Err(rpc_error(RPC_VERIFY_REJECTED, "txn-mempool-conflict"));
+            }
+            let coin = self
+                .get_unspent(&input.previous_output)
+                .ok_or_else(|| rpc_error(RPC_VERIFY_ERROR, "bad-txns-inputs-missingorspent"))?;
+            if coin.is_coinbase && next_height - coin.height.unwrap() < COINBASE_MATURITY {
+                return Err(rpc_error(
+                    RPC_VERIFY_REJECTED,
+                    "bad-txns-premature-spend-of-coinbase",
+                ));
+            }
+            self.check_sequence_lock(&tx, input, coin.height)?;
+            check_witness(&tx, index, &coin.output)?;
+            input_value += coin.output.value;
+        }
+        let output_value = tx.output.iter().map(|o| o.value).sum::<u64>();
+        if output_value > input_value {
+            return Err(rpc_error(RPC_VERIFY_REJECTED, "bad-txns-in-belowout"));
+        }
+        if !self.is_final_tx(&tx, next_height) {
+            return Err(rpc_error(RPC_VERIFY_REJECTED, "non-final"));
+        }
+
+        for input in &tx.input {
+            self.mempool_spends.insert(input.previous_output, txid);
+        }
+        self.add_if_wallet_tx(&tx);
+        self.mempool.insert(
+            txid,
+            MempoolTx {
+                tx,
+                fee: input_value - output_value,
+            },
+        );
+        self.mempool_order.push(txid);
+        Ok(txid)
+    }
+
+    fn is_final_tx(&self, tx: &Transaction, height: u64) -> bool {
+        if tx.lock_time == 0 {
+            return true;
+        }
+        let lock_limit = if tx.lock_time < LOCKTIME_THRESHOLD {
+            height
+        } else {
+            self.median_time_past(self.tip_height())
+        };
+        (tx.lock_time as u64) < lock_limit || tx.input.iter().all(|i| i.sequence == SEQUENCE_FINAL)
+    }
+
+    //BIP68, an unconfirmed coin counts as confirming in the next block
+    fn check_sequence_lock(
+        &self,
+        tx: &Transaction,
+        input: &TxIn,
+        coin_height: Option<u64>,
+    ) -> Result<(), TeleportError> {
+        if tx.version < 2 || input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return Ok(());
+        }
+        let next_height = self.tip_height() + 1;
+        let coin_height = coin_height.unwrap_or(next_height);
+        let lock_value = (input.sequence & SEQUENCE_LOCKTIME_MASK) as u64;
+        let is_final = if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            let coin_time = self.median_time_past(coin_height.saturating_sub(1));
+            coin_time + (lock_value << SEQUENCE_LOCKTIME_GRANULARITY)
+                <= self.median_time_past(self.tip_height())
+        } else {
+            coin_height + lock_value <= next_height
+        };
+        if is_final {
+            Ok(())
+        } else {
+            Err(rpc_error(RPC_VERIFY_REJECTED, "non-BIP68-final"))
+        }
+    }
+
+    fn is_wallet_relevant(&self, tx: &Transaction) -> bool {
+        tx.output
+            .iter()
+            .any(|o| self.watched_scripts.contains_key(&o.script_pubkey))
+            || (!tx.is_coin_base()
+                && tx.input.iter().any(|i| {
+                    self.find_prevout(&i.previous_output)
+                        .is_some_and(|o| self.watched_scripts.contains_key(&o.script_pubkey))
+                }))
+    }
+
+    //like bitcoind, txes are only added to the wallet when they are seen, an import doesnt
+    //pick up earlier txes unless given to import_pruned_funds
+    fn add_if_wallet_tx(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        if self.wallet_txids.contains(&txid) || !self.is_wallet_relevant(tx) {
+            return;
+        }
+        self.wallet_txids.insert(txid);
+        self.wallet_txes.push(txid);
+    }
+
+    fn watch_script(&mut self, script_pubkey: Script, watched: WatchedScript) {
+        match self.watched_scripts.get_mut(&script_pubkey) {
+            //importing the bare scriptpubkey again doesnt lose what the descriptor told us
+            Some(existing) if existing.descriptor.is_some() && watched.descriptor.is_none() => {
+                existing.label = watched.label
+            }
+            _ => {
+                self.watched_scripts.insert(script_pubkey, watched);
+            }
+        }
+    }
+
+    fn import_request(&mut self, request: &ImportMultiRequest) -> Result<(), TeleportError> {
+        let label = request.label.unwrap_or("").to_string();
+        if let Some(descriptor) = request.descriptor {
+            let descriptor = Descriptor::parse(descriptor, true)?;
+            let (start, end) = if descriptor.is_ranged() {
+                request.range.ok_or_else(|| {
+                    rpc_error(
+                        RPC_INVALID_PARAMETER,
+                        "descriptor is ranged, please specify the range",
+                    )
+                })?
+            } else {
+                (0, 0)
+            };
+            for index in start..=end {
+                let derived = descriptor.derive(index as u32)?;
+                self.watch_script(
+                    derived.script_pubkey,
+                    WatchedScript {
+                        descriptor: Some(derived.descriptor),
+                        witness_script: derived.witness_script,
+                        key_origins: derived.key_origins,
+                        label: label.clone(),
+                    },
+                );
+            }
+            Ok(())
+        } else if let Some(script_pubkey) = &request.script_pubkey {
+            let script_pubkey = match script_pubkey {
+                ImportMultiRequestScriptPubkey::Script(spk) => (*spk).clone(),
+                ImportMultiRequestScriptPubkey::Address(addr) => addr.script_pubkey(),
+            };
+            let witness_script = request
+                .witness_script
+                .or(request.redeem_script)
+                .filter(|ws| Script::new_v0_wsh(&ws.wscript_hash()) == script_pubkey)
+                .cloned();
+            self.watch_script(
+                script_pubkey,
+                WatchedScript {
+                    descriptor: None,
+                    witness_script,
+                    key_origins: BTreeMap::new(),
+                    label,
+                },
+            );
+            Ok(())
+        } else {
+            Err(rpc_error(
+                RPC_INVALID_PARAMETER,
+                "either desc or scriptPubKey must be provided",
+            ))
+        }
+    }
+
+    fn wallet_unspents(&self) -> Vec<(OutPoint, Coin)> {
+        self.wallet_txes
+            .iter()
+            .flat_map(|txid| {
+                let tx = self.find_transaction(txid).unwrap();
+                (0..tx.output.len()).map(move |vout| OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                })
+            })
+            .filter_map(|outpoint| self.get_unspent(&outpoint).map(|coin| (outpoint, coin)))
+            .filter(|(_, coin)| {
+                self.watched_scripts
+                    .contains_key(&coin.output.script_pubkey)
+            })
+            .collect()
+    }
+
+    fn create_funded_psbt(
+        &mut self,
+        inputs: &[CreateRawTransactionInput],
+        outputs: &HashMap<String, Amount>,
+        options: WalletCreateFundedPsbtOptions,
+    ) -> Result<FundedPsbt, TeleportError> {
+        let fee_rate = options.fee_rate.map_or(DEFAULT_FEE_RATE, |r| r.as_sat());
+        let mut recipients = outputs
+            .iter()
+            .map(|(address, amount)| {
+                Address::from_str(address)
+                    .map(|a| (address.clone(), a.script_pubkey(), amount.as_sat()))
+                    .map_err(|_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid address"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        recipients.sort();
+        let mut recipients = recipients
+            .into_iter()
+            .map(|(_, script_pubkey, value)| TxOut {
+                value,
+                script_pubkey,
+            })
+            .collect::<Vec<TxOut>>();
+
+        let mut selected = Vec::<SelectedInput>::new();
+        for input in inputs {
+            let outpoint = OutPoint {
+                txid: input.txid,
+                vout: input.vout,
+            };
+            let coin = self.get_unspent(&outpoint).ok_or_else(|| {
+                rpc_error(RPC_INVALID_PARAMETER, "input not found or already spent")
+            })?;
+            let watched = self
+                .watched_scripts
+                .get(&coin.output.script_pubkey)
+                .ok_or_else(|| rpc_error(RPC_WALLET_ERROR, "input is not in the wallet"))?
+                .clone();
+            selected.push(SelectedInput {
+                outpoint,
+                output: coin.output,
+                watched,
+            });
+        }
+
+        let subtract_fee = !options.subtract_fee_from_outputs.is_empty();
+        let target = recipients.iter().map(|o| o.value).sum::<u64>();
+        let base_vsize = 11
+            + recipients
+                .iter()
+                .map(|o| output_vsize(&o.script_pubkey))
+                .sum::<u64>();
+        let change_vsize = output_vsize(
+            &options
+                .change_address
+                .as_ref()
+                .map_or(Script::new_v0_wpkh(&Default::default()), |a| {
+                    a.script_pubkey()
+                }),
+        );
+        let inputs_value =
+            |selected: &[SelectedInput]| selected.iter().map(|s| s.output.value).sum::<u64>();
+        let inputs_vsize = |selected: &[SelectedInput]| {
+            selected
+                .iter()
+                .map(|s| estimate_input_vsize(&s.watched))
+                .sum::<u64>()
+        };
+        let needed = |selected: &[SelectedInput]| {
+            if subtract_fee {
+                target
+            } else {
+                target + fee_for_vsize(fee_rate, base_vsize + inputs_vsize(selected))
+            }
+        };
+
+        if options.add_inputs.unwrap_or(inputs.is_empty())
+            && inputs_value(&selected) < needed(&selected)
+        {
+            //everything in this wallet is watch-only, and only coins with a descriptor are
+            //solvable, so they are the only ones which can be selected
+            let mut candidates = if options.include_watching.unwrap_or(false) {
+                self.wallet_unspents()
+                    .into_iter()
+                    .filter(|(outpoint, coin)| {
+                        coin.height.is_some()
+                            && !self.locked_outpoints.contains(outpoint)
+                            && !selected.iter().any(|s| s.outpoint == *outpoint)
+                            && self.watched_scripts[&coin.output.script_pubkey]
+                                .descriptor
+                                .is_some()
+                    })
+                    .collect::<Vec<(OutPoint, Coin)>>()
+            } else {
+                Vec::new()
+            };
+            candidates
+                .sort_by_key(|(outpoint, coin)| (std::cmp::Reverse(coin.output.value), *outpoint));
+            for (outpoint, coin) in candidates {
+                if inputs_value(&selected) >= needed(&selected) {
+                    break;
+                }
+                let watched = self.watched_scripts[&coin.output.script_pubkey].clone();
+                selected.push(SelectedInput {
+                    outpoint,
+                    output: coin.output,
+                    watched,
+                });
+            }
+        }
+        if selected.is_empty() || inputs_value(&selected) < needed(&selected) {
+            return Err(rpc_error(
+                RPC_WALLET_INSUFFICIENT_FUNDS,
+                "insufficient funds",
+            ));
+        }
+
+        let total_inputs_value = inputs_value(&selected);
+        let vsize_without_change = base_vsize + inputs_vsize(&selected);
+        let fee_without_change = fee_for_vsize(fee_rate, vsize_without_change);
+        let fee_with_change = fee_for_vsize(fee_rate, vsize_without_change + change_vsize);
+        let (change_value, recipients_fee) = if subtract_fee {
+            let excess = total_inputs_value - target;
+            if excess >= DUST_LIMIT {
+                (excess, fee_with_change)
+            } else {
+                (0, fee_without_change)
+            }
+        } else {
+            let excess = total_inputs_value - target - fee_without_change;
+            let change_cost = fee_with_change - fee_without_change;
+            if excess >= change_cost + DUST_LIMIT {
+                (excess - change_cost, 0)
+            } else {
+                (0, 0)
+            }
+        };
+        if subtract_fee {
+            let share_count = options.subtract_fee_from_outputs.len() as u64;
+            for (i, &position) in options.subtract_fee_from_outputs.iter().enumerate() {
+                let share = recipients_fee / share_count
+                    + if i == 0 {
+                        recipients_fee % share_count
+                    } else {
+                        0
+                    };
+                let recipient = recipients.get_mut(position as usize).ok_or_else(|| {
+                    rpc_error(
+                        RPC_INVALID_PARAMETER,
+                        "invalid subtractFeeFromOutputs position",
+                    )
+                })?;
+                if recipient.value < share + DUST_LIMIT {
+                    return Err(rpc_error(
+                        RPC_WALLET_ERROR,
+                        "the transaction amount is too small to pay the fee",
+                    ));
+                }
+                recipient.value -= share;
+            }
+        }
+        let change_position = if change_value > 0 {
+            let change_address = options.change_address.as_ref().ok_or_else(|| {
+                rpc_error(RPC_INVALID_PARAMETER, "a change address must be given")
+            })?;
+            let position = options
+                .change_position
+                .map_or(recipients.len(), |p| p as usize)
+                .min(recipients.len());
+            recipients.insert(
+                position,
+                TxOut {
+                    value: change_value,
+                    script_pubkey: change_address.script_pubkey(),
+                },
+            );
+            position as i32
+        } else {
+            -1
+        };
+
+        let sequence = if options.replaceable == Some(true) {
+            SEQUENCE_FINAL - 2
+        } else {
+            SEQUENCE_FINAL - 1
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: selected
+                .iter()
+                .map(|s| TxIn {
+                    previous_output: s.outpoint,
+                    script_sig: Script::new(),
+                    sequence,
+                    witness: Vec::new(),
+                })
+                .collect(),
+            output: recipients,
+        };
+        let fee = total_inputs_value - tx.output.iter().map(|o| o.value).sum::<u64>();
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)
+            .map_err(|_| rpc_error(RPC_MISC_ERROR, "unable to create psbt"))?;
+        for (psbt_input, s) in psbt.inputs.iter_mut().zip(selected.iter()) {
+            psbt_input.witness_utxo = Some(s.output.clone());
+            psbt_input.witness_script = s.watched.witness_script.clone();
+            psbt_input.bip32_derivation = s.watched.key_origins.clone();
+        }
+        if options.lock_unspent == Some(true) {
+            self.locked_outpoints
+                .extend(selected.iter().map(|s| s.outpoint));
+        }
+        Ok(FundedPsbt {
+            psbt,
+            fee: Amount::from_sat(fee),
+            change_position,
+        })
+    }
+}
+
+//checks an ecdsa signature with the sighash type appended, as it appears in a witness
+fn is_valid_signature(
+    tx: &Transaction,
+    index: usize,
+    script_code: &Script,
+    value: u64,
+    sig_with_type: &[u8],
+    pubkey: &PublicKey,
+) -> bool {
+    let (sighash_type, sig) = match sig_with_type.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    let sig = match Signature::from_der(sig) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    let sighash = SigHashCache::new(tx).signature_hash(
+        index,
+        script_code,
+        value,
+        SigHashType::from_u32_consensus(*sighash_type as u32),
+    );
+    Secp256k1::verification_only()
+        .verify(
+            &Message::from_slice(&sighash[..]).unwrap(),
+            &sig,
+            &pubkey.key,
+        )
+        .is_ok()
+}
+
+fn check_witness(tx: &Transaction, index: usize, spent: &TxOut) -> Result<(), TeleportError> {
+    let witness = &tx.input[index].witness;
+    if spent.script_pubkey.is_v0_p2wpkh() {
+        if witness.len() != 2 || witness[0].is_empty() {
+            return Err(rpc_error(
+                RPC_VERIFY_REJECTED,
+                "p2wpkh witness must be a signature and pubkey",
+            ));
+        }
+        let pubkey = PublicKey::from_slice(&witness[1])
+            .map_err(|_| rpc_error(RPC_VERIFY_REJECTED, "invalid pubkey in witness"))?;
+        if pubkey
+            .wpubkey_hash()
+            .map(|wpkh| Script::new_v0_wpkh(&wpkh))
+            .as_ref()
+            != Some(&spent.script_pubkey)
+        {
+            return Err(rpc_error(
+                RPC_VERIFY_REJECTED,
+                "witness pubkey doesnt match the output",
+            ));
+        }
+        let script_code = Script::new_p2pkh(&pubkey.pubkey_hash());
+        if !is_valid_signature(tx, index, &script_code, spent.value, &witness[0], &pubkey) {
+            return Err(rpc_error(RPC_VERIFY_REJECTED, "invalid signature"));
+        }
+    } else if spent.script_pubkey.is_v0_p2wsh() {
+        let (witness_script, stack) = witness
+            .split_last()
+            .ok_or_else(|| rpc_error(RPC_VERIFY_REJECTED, "p2wsh witness is empty"))?;
+        let witness_script = Script::from(witness_script.clone());
+        if Script::new_v0_wsh(&witness_script.wscript_hash()) != spent.script_pubkey {
+            return Err(rpc_error(
+                RPC_VERIFY_REJECTED,
+                "witness script doesnt match the output",
+            ));
+        }
+        //the script isn't executed, instead every signature on the stack must be made by a
+        //different key of the script, and there must be as many as a multisig script needs
+        let instructions = witness_script
+            .instructions()
+            .collect::<Result<Vec<Instruction>, _>>()
+            .map_err(|_| rpc_error(RPC_VERIFY_REJECTED, "invalid witness script"))?;
+        let mut pubkeys = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::PushBytes(bytes) if bytes.len() == 33 => {
+                    PublicKey::from_slice(bytes).ok()
+                }
+                _ => None,
+            })
+            .collect::<Vec<PublicKey>>();
+        let required_sigs = match (instructions.first(), instructions.last()) {
+            (Some(Instruction::Op(n)), Some(Instruction::Op(all::OP_CHECKMULTISIG))) => n
+                .into_u8()
+                .checked_sub(all::OP_PUSHNUM_1.into_u8())
+                .map_or(1, |n| n as usize + 1),
+            _ => 1,
+        };
+        let mut sig_count = 0;
+        for item in stack.iter().filter(|item| !item.is_empty()) {
+            let is_sig = item.len() > 1 && Signature::from_der(&item[..item.len() - 1]).is_ok();
+            if !is_sig {
+                continue;
+            }
+            let signer = pubkeys.iter().position(|pubkey| {
+                is_valid_signature(tx, index, &witness_script, spent.value, item, pubkey)
+            });
+            match signer {
+                Some(i) => pubkeys.remove(i),
+                None => return Err(rpc_error(RPC_VERIFY_REJECTED, "invalid signature")),
+            };
+            sig_count += 1;
+        }
+        if sig_count < required_sigs {
+            return Err(rpc_error(
+                RPC_VERIFY_REJECTED,
+                "p2wsh witness is missing signatures",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Descriptor checksum defined in BIP380, returns None if the descriptor has invalid characters.
+fn descriptor_checksum(descriptor: &str) -> Option<String> {
+    fn polymod(c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+        for (bit, generator) in [
+            0xf5dee51989,
+            0xa9fdca3312,
+            0x1bab10e32d,
+            0x3706b1677a,
+            0x644d626ffd,
+        ]
+        .iter()
+        .enumerate()
+        {
+            if c0 & (1 << bit) != 0 {
+                c ^= generator;
+            }
+        }
+        c
+    }
+
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let position = DESCRIPTOR_INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|j| DESCRIPTOR_CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+fn add_descriptor_checksum(descriptor: &str) -> String {
+    format!(
+        "{}#{}",
+        descriptor,
+        descriptor_checksum(descriptor).expect("bug: descriptor with invalid characters")
+    )
+}
+
+enum DescriptorKey {
+    Single {
+        origin: Option<KeySource>,
+        key: PublicKey,
+    },
+    Extended {
+        origin: Option<KeySource>,
+        xpub: ExtendedPubKey,
+        path: DerivationPath,
+        wildcard: bool,
+    },
+}
+
+impl DescriptorKey {
+    fn parse(key: &str) -> Result<DescriptorKey, TeleportError> {
+        let (origin, key) = if let Some(origin_end) = key.find(']') {
+            let origin = key[..origin_end]
+                .strip_prefix('[')
+                .ok_or_else(|| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid key origin"))?;
+            let (fingerprint, path) = origin.split_at(origin.find('/').unwrap_or(origin.len()));
+            let fingerprint = Fingerprint::from_hex(fingerprint).map_err(|_| {
+                rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid key origin fingerprint")
+            })?;
+            let path = DerivationPath::from_str(&format!("m{}", path))
+                .map_err(|_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid key origin path"))?;
+            (Some((fingerprint, path)), &key[origin_end + 1..])
+        } else {
+            (None, key)
+        };
+
+        let mut path_elements = key.split('/');
+        let key = path_elements.next().unwrap();
+        if let Ok(xpub) = ExtendedPubKey::from_str(key) {
+            let mut path = Vec::<ChildNumber>::new();
+            let mut wildcard = false;
+            for element in path_elements {
+                if wildcard {
+                    return Err(rpc_error(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "wildcard must be last in the path",
+                    ));
+                }
+                if element == "*" {
+                    wildcard = true;
+                    continue;
+                }
+                let index = element.parse::<u32>().map_err(|_| {
+                    rpc_error(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "only unhardened xpub paths allowed",
+                    )
+                })?;
+                path.push(
+                    ChildNumber::from_normal_idx(index)
+                        .map_err(|_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid xpub path"))?,
+                );
+            }
+            Ok(DescriptorKey::Extended {
+                origin,
+                xpub,
+                path: DerivationPath::from(path),
+                wildcard,
+            })
+        } else if path_elements.next().is_none() {
+            Ok(DescriptorKey::Single {
+                origin,
+                key: PublicKey::from_str(key)
+                    .map_err(|_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid descriptor key"))?,
+            })
+        } else {
+            Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "invalid descriptor key",
+            ))
+        }
+    }
+
+    fn derive(&self, index: u32) -> Result<(PublicKey, KeySource), TeleportError> {
+        match self {
+            DescriptorKey::Single { origin, key } => {
+                let origin = origin.clone().unwrap_or_else(|| {
+                    (
+                        Fingerprint::from(&Hash160::hash(&key.to_bytes())[..4]),
+                        DerivationPath::master(),
+                    )
+                });
+                Ok((*key, origin))
+            }
+            DescriptorKey::Extended {
+                origin,
+                xpub,
+                path,
+                wildcard,
+            } => {
+                let path = if *wildcard {
+                    path.child(ChildNumber::from_normal_idx(index).map_err(|_| {
+                        rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid derivation index")
+                    })?)
+                } else {
+                    path.clone()
+                };
+                let key = xpub
+                    .derive_pub(&Secp256k1::verification_only(), &path)
+                    .map_err(|_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "unable to derive key"))?
+                    .public_key;
+                let origin = match origin {
+                    Some((fingerprint, origin_path)) => (*fingerprint, origin_path.extend(&path)),
+                    None => (xpub.fingerprint(), path),
+                };
+                Ok((key, origin))
+            }
+        }
+    }
+
+    fn is_ranged(&self) -> bool {
+        matches!(self, DescriptorKey::Extended { wildcard: true, .. })
+    }
+}
+
+//key with its origin, as in the descriptors bitcoind infers for its wallet's outputs
+fn key_with_origin(key: &PublicKey, (fingerprint, path): &KeySource) -> String {
+    format!(
+        "[{}{}]{}",
+        fingerprint,
+        path.to_string().trim_start_matches('m'),
+        key
+    )
+}
+
+enum Descriptor {
+    Wpkh(DescriptorKey),
+    WshMulti {
+        threshold: usize,
+        keys: Vec<DescriptorKey>,
+        sorted: bool,
+    },
+    Raw(Script),
+}
+
+struct DerivedScript {
+    script_pubkey: Script,
+    witness_script: Option<Script>,
+    key_origins: BTreeMap<PublicKey, KeySource>,
+    //inferred descriptor of just this script, with key origins and checksum
+    descriptor: String,
+}
+
+fn strip_function<'a>(descriptor: &'a str, name: &str) -> Option<&'a str> {
+    descriptor
+        .strip_prefix(name)
+        .and_then(|d| d.strip_prefix('('))
+        .and_then(|d| d.strip_suffix(')'))
+}
+
+impl Descriptor {
+    /// Parse the subset of descriptors teleport uses, `wpkh()`, `wsh(multi())`,
+    /// `wsh(sortedmulti())` and `raw()`.
+    fn parse(descriptor: &str, require_checksum: bool) -> Result<Descriptor, TeleportError> {
+        let descriptor = match descriptor.find('#') {
+            Some(position) => {
+                let (descriptor, checksum) = (&descriptor[..position], &descriptor[position + 1..]);
+                if descriptor_checksum(descriptor).as_deref() != Some(checksum) {
+                    return Err(rpc_error(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "invalid descriptor checksum",
+                    ));
+                }
+                descriptor
+            }
+            None if require_checksum => {
+                return Err(rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "missing descriptor checksum",
+                ))
+            }
+            None => descriptor,
+        };
+
+        if let Some(key) = strip_function(descriptor, "wpkh") {
+            Ok(Descriptor::Wpkh(DescriptorKey::parse(key)?))
+        } else if let Some(script) = strip_function(descriptor, "wsh") {
+            let (sorted, args) = if let Some(args) = strip_function(script, "sortedmulti") {
+                (true, args)
+            } else if let Some(args) = strip_function(script, "multi") {
+                (false, args)
+            } else {
+                return Err(rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "only multisig is supported inside wsh()",
+                ));
+            };
+            let mut args = args.split(',');
+            let threshold =
+                args.next().unwrap().parse::<usize>().map_err(|_| {
+                    rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid multisig threshold")
+                })?;
+            let keys = args
+                .map(DescriptorKey::parse)
+                .collect::<Result<Vec<DescriptorKey>, TeleportError>>()?;
+            if threshold == 0 || threshold > keys.len() || keys.len() > 16 {
+                return Err(rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "invalid multisig threshold",
+                ));
+            }
+            Ok(Descriptor::WshMulti {
+                threshold,
+                keys,
+                sorted,
+            })
+        } else if let Some(hex) = strip_function(descriptor, "raw") {
+            Ok(Descriptor::Raw(Script::from(Vec::from_hex(hex).map_err(
+                |_| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "invalid raw script hex"),
+            )?)))
+        } else {
+            Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "unsupported descriptor",
+            ))
+        }
+    }
+
+    fn is_ranged(&self) -> bool {
+        match self {
+            Descriptor::Wpkh(key) => key.is_ranged(),
+            Descriptor::WshMulti { keys, .. } => keys.iter().any(DescriptorKey::is_ranged),
+            Descriptor::Raw(_) => false,
+        }
+    }
+
+    fn derive(&self, index: u32) -> Result<DerivedScript, TeleportError> {
+        match self {
+            Descriptor::Wpkh(key) => {
+                let (key, origin) = key.derive(index)?;
+                let wpkh = key.wpubkey_hash().ok_or_else(|| {
+                    rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "wpkh() needs a compressed key")
+                })?;
+                Ok(DerivedScript {
+                    script_pubkey: Script::new_v0_wpkh(&wpkh),
+                    witness_script: None,
+                    descriptor: add_descriptor_checksum(&format!(
+                        "wpkh({})",
+                        key_with_origin(&key, &origin)
+                    )),
+                    key_origins: vec![(key, origin)].into_iter().collect(),
+                })
+            }
+            Descriptor::WshMulti {
+                threshold,
+                keys,
+                sorted,
+            } => {
+                let mut keys = keys
+                    .iter()
+                    .map(|k| k.derive(index))
+                    .collect::<Result<Vec<(PublicKey, KeySource)>, TeleportError>>()?;
+                if *sorted {
+                    keys.sort_by_key(|(key, _)| key.to_bytes());
+                }
+                let witness_script = keys
+                    .iter()
+                    .fold(
+                        Builder::new().push_int(*threshold as i64),
+                        |builder, (key, _)| builder.push_key(key),
+                    )
+                    .push_int(keys.len() as i64)
+                    .push_opcode(all::OP_CHECKMULTISIG)
+                    .into_script();
+                Ok(DerivedScript {
+                    script_pubkey: Script::new_v0_wsh(&witness_script.wscript_hash()),
+                    witness_script: Some(witness_script),
+                    descriptor: add_descriptor_checksum(&format!(
+                        "wsh(multi({},{}))",
+                        threshold,
+                        keys.iter()
+                            .map(|(key, origin)| key_with_origin(key, origin))
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    )),
+                    key_origins: keys.into_iter().collect(),
+                })
+            }
+            Descriptor::Raw(script) => Ok(DerivedScript {
+                script_pubkey: script.clone(),
+                witness_script: None,
+                key_origins: BTreeMap::new(),
+                descriptor: add_descriptor_checksum(&format!("raw({:x})", script)),
+            }),
+        }
+    }
+
+    fn derive_range(&self, range: Option<(u64, u64)>) -> Result<Vec<DerivedScript>, TeleportError> {
+        let (start, end) = match (self.is_ranged(), range) {
+            (true, Some(range)) => range,
+            (true, None) => {
+                return Err(rpc_error(RPC_INVALID_PARAMETER, "range must be specified"))
+            }
+            (false, None) => (0, 0),
+            (false, Some(_)) => {
+                return Err(rpc_error(
+                    RPC_INVALID_PARAMETER,
+                    "range should not be specified for an un-ranged descriptor",
+                ))
+            }
+        };
+        (start..=end).map(|i| self.derive(i as u32)).collect()
+    }
+}
+
+impl BlockchainBackend for SimulatedBlockchain {
+    fn get_block_count(&self) -> Result<u64, TeleportError> {
+        Ok(self.state.lock().unwrap().tip_height())
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, TeleportError> {
+        self.state
+            .lock()
+            .unwrap()
+            .blocks
+            .get(height as usize)
+            .map(Block::block_hash)
+            .ok_or_else(|| rpc_error(RPC_INVALID_PARAMETER, "block height out of range"))
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash, TeleportError> {
+        Ok(self.state.lock().unwrap().tip_hash())
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, TeleportError> {
+        Ok(self.state.lock().unwrap().block_by_hash(hash)?.clone())
+    }
+
+    fn get_block_txids(&self, hash: &BlockHash) -> Result<Vec<Txid>, TeleportError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .block_by_hash(hash)?
+            .txdata
+            .iter()
+            .map(Transaction::txid)
+            .collect())
+    }
+
+    fn get_block_time(&self, hash: &BlockHash) -> Result<u64, TeleportError> {
+        Ok(self.state.lock().unwrap().block_by_hash(hash)?.header.time as u64)
+    }
+
+    fn get_median_time(&self) -> Result<u64, TeleportError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.median_time_past(state.tip_height()))
+    }
+
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>, TeleportError> {
+        Ok(self.state.lock().unwrap().mempool_order.clone())
+    }
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<MempoolEntry, TeleportError> {
+        let state = self.state.lock().unwrap();
+        let entry = state
+            .mempool
+            .get(txid)
+            .ok_or_else(|| rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "transaction not in mempool"))?;
+        Ok(MempoolEntry {
+            vsize: (entry.tx.get_weight() as u64).div_ceil(4),
+            fee: Amount::from_sat(entry.fee),
+        })
+    }
+
+    fn get_tx_out(&self, txid: &Txid, vout: u32) -> Result<Option<TxOutInfo>, TeleportError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .get_unspent(&OutPoint { txid: *txid, vout })
+            .map(|coin| TxOutInfo {
+                value: Amount::from_sat(coin.output.value),
+                script_pubkey: coin.output.script_pubkey,
+                confirmations: state.confirmations(coin.height),
+            }))
+    }
+
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction, TeleportError> {
+        let state = self.state.lock().unwrap();
+        match block_hash {
+            Some(hash) => state
+                .block_by_hash(hash)?
+                .txdata
+                .iter()
+                .find(|tx| tx.txid() == *txid)
+                .cloned()
+                .ok_or_else(|| {
+                    rpc_error(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "no such transaction found in the provided block",
+                    )
+                }),
+            None => state.find_transaction(txid).cloned().ok_or_else(|| {
+                rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "no such mempool or blockchain transaction",
+                )
+            }),
+        }
+    }
+
+    fn get_tx_out_proof(
+        &self,
+        txids: &[Txid],
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>, TeleportError> {
+        let state = self.state.lock().unwrap();
+        let block = match block_hash {
+            Some(hash) => state.block_by_hash(hash)?,
+            None => {
+                let height = txids
+                    .first()
+                    .and_then(|txid| state.confirmed_txes.get(txid))
+                    .ok_or_else(|| {
+                        rpc_error(RPC_INVALID_ADDRESS_OR_KEY, "transaction not yet in block")
+                    })?
+                    .height;
+                &state.blocks[height as usize]
+            }
+        };
+        let txids = txids.iter().cloned().collect::<HashSet<Txid>>();
+        if !txids
+            .iter()
+            .all(|txid| block.txdata.iter().any(|tx| tx.txid() == *txid))
+        {
+            return Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "not all transactions found in specified or retrieved block",
+            ));
+        }
+        Ok(serialize(&MerkleBlock::from_block(block, &txids)))
+    }
+
+    fn send_raw_transaction(&self, tx: &Transaction) -> Result<Txid, TeleportError> {
+        self.state.lock().unwrap().accept_to_mempool(tx.clone())
+    }
+
+    fn scan_tx_out_set(&self, scan_objects: &[ScanObject]) -> Result<TxOutSetScan, TeleportError> {
+        let state = self.state.lock().unwrap();
+        let mut scripts = HashMap::<Script, String>::new();
+        for scan_object in scan_objects {
+            let descriptor = Descriptor::parse(&scan_object.descriptor, false)?;
+            let range = if descriptor.is_ranged() {
+                Some((
+                    0,
+                    scan_object
+                        .range_end
+                        .map_or(SCAN_DEFAULT_RANGE_END, u64::from),
+                ))
+            } else {
+                None
+            };
+            for derived in descriptor.derive_range(range)? {
+                scripts.insert(derived.script_pubkey, derived.descriptor);
+            }
+        }
+
+        let mut unspents = state
+            .utxos
+            .iter()
+            .filter_map(|(outpoint, coin)| {
+                scripts
+                    .get(&coin.output.script_pubkey)
+                    .map(|descriptor| ScannedUnspent {
+                        txid: outpoint.txid,
+                        vout: outpoint.vout,
+                        amount: Amount::from_sat(coin.output.value),
+                        height: coin.height.unwrap(),
+                        descriptor: descriptor.clone(),
+                    })
+            })
+            .collect::<Vec<ScannedUnspent>>();
+        unspents.sort_by_key(|unspent| (unspent.height, unspent.txid, unspent.vout));
+        Ok(TxOutSetScan {
+            height: state.tip_height(),
+            best_block: state.tip_hash(),
+            txouts: state.utxos.len() as u64,
+            total_amount: Amount::from_sat(unspents.iter().map(|u| u.amount.as_sat()).sum()),
+            unspents,
+        })
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<WalletTransaction, TeleportError> {
+        let state = self.state.lock().unwrap();
+        if !state.wallet_txids.contains(txid) {
+            return Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "invalid or non-wallet transaction id",
+            ));
+        }
+        let (transaction, height) = match state.confirmed_txes.get(txid) {
+            Some(ctx) => (ctx.tx.clone(), Some(ctx.height)),
+            None => (state.mempool[txid].tx.clone(), None),
+        };
+        Ok(WalletTransaction {
+            txid: *txid,
+            confirmations: state.confirmations(height) as i32,
+            blockhash: height.map(|h| state.blocks[h as usize].block_hash()),
+            blocktime: height.map(|h| state.blocks[h as usize].header.time as u64),
+            transaction,
+        })
+    }
+
+    fn list_transactions(&self, count: usize, skip: usize) -> Result<Vec<Txid>, TeleportError> {
+        let state = self.state.lock().unwrap();
+        let end = state.wallet_txes.len().saturating_sub(skip);
+        let start = end.saturating_sub(count);
+        Ok(state.wallet_txes[start..end].to_vec())
+    }
+
+    fn list_unspent(&self) -> Result<Vec<ListUnspentEntry>, TeleportError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .wallet_unspents()
+            .into_iter()
+            .map(|(outpoint, coin)| {
+                let watched = &state.watched_scripts[&coin.output.script_pubkey];
+                ListUnspentEntry {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout,
+                    address: Address::from_script(&coin.output.script_pubkey, NETWORK),
+                    label: Some(watched.label.clone()),
+                    witness_script: watched.witness_script.clone(),
+                    script_pub_key: coin.output.script_pubkey,
+                    amount: Amount::from_sat(coin.output.value),
+                    confirmations: state.confirmations(coin.height),
+                    descriptor: watched.descriptor.clone(),
+                }
+            })
+            .collect())
+    }
+
+    fn lock_unspent(&self, outpoints: &[OutPoint]) -> Result<(), TeleportError> {
+        let mut state = self.state.lock().unwrap();
+        if outpoints.iter().any(|o| state.get_unspent(o).is_none()) {
+            return Err(rpc_error(RPC_INVALID_PARAMETER, "expected unspent output"));
+        }
+        state.locked_outpoints.extend(outpoints);
+        Ok(())
+    }
+
+    fn unlock_unspent_all(&self) -> Result<(), TeleportError> {
+        self.state.lock().unwrap().locked_outpoints.clear();
+        Ok(())
+    }
+
+    fn import_multi(
+        &self,
+        requests: &[ImportMultiRequest],
+    ) -> Result<Vec<ImportResult>, TeleportError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(requests
+            .iter()
+            .map(|request| match state.import_request(request) {
+                Ok(()) => ImportResult {
+                    success: true,
+                    error: None,
+                },
+                Err(e) => ImportResult {
+                    success: false,
+                    error: Some(format!("{:?}", e)),
+                },
+            })
+            .collect())
+    }
+
+    fn import_pruned_funds(
+        &self,
+        tx: &Transaction,
+        merkleproof: &[u8],
+    ) -> Result<(), TeleportError> {
+        let mut state = self.state.lock().unwrap();
+        let merkle_block = deserialize::<MerkleBlock>(merkleproof)
+            .map_err(|_| rpc_error(RPC_DESERIALIZATION_ERROR, "invalid merkle proof"))?;
+        let mut matches = Vec::<Txid>::new();
+        let mut indexes = Vec::<u32>::new();
+        merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(|_| {
+                rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "something wrong with merkleblock",
+                )
+            })?;
+        if !matches.contains(&tx.txid()) {
+            return Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "something wrong with merkleblock",
+            ));
+        }
+        if !state
+            .block_heights
+            .contains_key(&merkle_block.header.block_hash())
+        {
+            return Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "block not found in chain",
+            ));
+        }
+        if !state.is_wallet_relevant(tx) {
+            return Err(rpc_error(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "no addresses in wallet correspond to included transaction",
+            ));
+        }
+        state.add_if_wallet_tx(tx);
+        Ok(())
+    }
+
+    fn get_address_info(&self, address: &Address) -> Result<AddressInfo, TeleportError> {
+        Ok(AddressInfo {
+            is_watchonly: self
+                .state
+                .lock()
+                .unwrap()
+                .watched_scripts
+                .contains_key(&address.script_pubkey()),
+        })
+    }
+
+    fn derive_addresses(
+        &self,
+        descriptor: &str,
+        range: Option<[u32; 2]>,
+    ) -> Result<Vec<Address>, TeleportError> {
+        Descriptor::parse(descriptor, true)?
+            .derive_range(range.map(|[start, end]| (start as u64, end as u64)))?
+            .iter()
+            .map(|derived| {
+                Address::from_script(&derived.script_pubkey, NETWORK).ok_or(rpc_error(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "descriptor does not have an address",
+                ))
+            })
+            .collect()
+    }
+
+    fn get_descriptor_with_checksum(&self, descriptor: &str) -> Result<String, TeleportError> {
+        Descriptor::parse(descriptor, false)?;
+        let descriptor = descriptor.split('#').next().unwrap();
+        Ok(add_descriptor_checksum(descriptor))
+    }
+
+    fn wallet_create_funded_psbt(
+        &self,
+        inputs: &[CreateRawTransactionInput],
+        outputs: &HashMap<String, Amount>,
+        options: WalletCreateFundedPsbtOptions,
+    ) -> Result<FundedPsbt, TeleportError> {
+        self.state
+            .lock()
+            .unwrap()
+            .create_funded_psbt(inputs, outputs, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    //errors come back like bitcoind's, with its RPC error code
+    fn error_code<T>(result: Result<T, TeleportError>) -> Option<i32> {
+        match result {
+            Err(TeleportError::Rpc(bitcoincore_rpc::Error::JsonRpc(
+                bitcoincore_rpc::jsonrpc::error::Error::Rpc(e),
+            ))) => Some(e.code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_descriptor_checksum() {
+        //test vectors from BIP380
+        assert_eq!(
+            descriptor_checksum("raw(deadbeef)"),
+            Some("89f8spxm".to_string())
+        );
+        assert!(Descriptor::parse("raw(deadbeef)#89f8spxm", true).is_ok());
+        assert!(Descriptor::parse("raw(deadbeef)#89f8spxn", true).is_err());
+        assert!(Descriptor::parse("raw(deadbeef)", true).is_err());
+        assert!(Descriptor::parse("raw(deadbeef)", false).is_ok());
+    }
+
+    #[test]
+    fn test_mempool_and_merkle_proof() {
+        let sim = SimulatedBlockchain::new();
+        let key = PublicKey::from_str(
+            "02e6642fd69bd211f93f7f1f36ca51a26a5290eb2dd1b0d8279a87bb0d480c8443",
+        )
+        .unwrap();
+        let address = Address::p2wpkh(&key, NETWORK).unwrap();
+        let descriptor = sim
+            .get_descriptor_with_checksum(&format!("wpkh({})", key))
+            .unwrap();
+        sim.import_multi(&[ImportMultiRequest {
+            descriptor: Some(&descriptor),
+            ..Default::default()
+        }])
+        .unwrap();
+
+        let txid = sim
+            .send_to_address(&address, Amount::from_sat(100_000))
+            .unwrap();
+        assert_eq!(sim.get_raw_mempool().unwrap(), vec![txid]);
+        assert_eq!(sim.get_transaction(&txid).unwrap().confirmations, 0);
+        let tx = sim.get_raw_transaction(&txid, None).unwrap();
+        //spending the same faucet coin again conflicts
+        assert!(sim.send_raw_transaction(&tx).is_ok());
+        let mut conflict = tx.clone();
+        conflict.output[0].value -= 1;
+        assert_eq!(
+            error_code(sim.send_raw_transaction(&conflict)),
+            Some(RPC_VERIFY_REJECTED)
+        );
+
+        let blockhash = sim.mine_blocks(1)[0];
+        assert!(sim.get_raw_mempool().unwrap().is_empty());
+        let unspents = sim.list_unspent().unwrap();
+        assert_eq!(unspents.len(), 1);
+        assert_eq!(unspents[0].confirmations, 1);
+        assert_eq!(unspents[0].amount, Amount::from_sat(100_000));
+        assert!(unspents[0].descriptor.is_some());
+
+        let merkleproof = sim.get_tx_out_proof(&[txid], Some(&blockhash)).unwrap();
+        assert!(sim.import_pruned_funds(&tx, &merkleproof).is_ok());
+        assert_eq!(
+            error_code(sim.send_raw_transaction(&tx)),
+            Some(RPC_VERIFY_ALREADY_IN_CHAIN)
+        );
+        assert_eq!(
+            error_code(sim.get_block(&BlockHash::default())),
+            Some(RPC_INVALID_ADDRESS_OR_KEY)
+        );
+    }
+
+    #[test]
+    fn test_p2wsh_signatures() {
+        let sim = SimulatedBlockchain::new();
+        let secp = Secp256k1::new();
+        let privkeys = [[1u8; 32], [2u8; 32], [3u8; 32]]
+            .iter()
+            .map(|k| bitcoin::secp256k1::SecretKey::from_slice(k).unwrap())
+            .collect::<Vec<_>>();
+        let pubkeys = privkeys
+            .iter()
+            .map(|k| PublicKey {
+                compressed: true,
+                key: bitcoin::secp256k1::PublicKey::from_secret_key(&secp, k),
+            })
+            .collect::<Vec<_>>();
+        let multisig = Builder::new()
+            .push_opcode(all::OP_PUSHNUM_2)
+            .push_key(&pubkeys[0])
+            .push_key(&pubkeys[1])
+            .push_opcode(all::OP_PUSHNUM_2)
+            .push_opcode(all::OP_CHECKMULTISIG)
+            .into_script();
+        let value = 100_000;
+        let txid = sim
+            .send_to_address(&Address::p2wsh(&multisig, NETWORK), Amount::from_sat(value))
+            .unwrap();
+        let funding_tx = sim.get_raw_transaction(&txid, None).unwrap();
+        let vout = funding_tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == Script::new_v0_wsh(&multisig.wscript_hash()))
+            .unwrap();
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(txid, vout as u32),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: value - 1000,
+                script_pubkey: faucet_script(),
+            }],
+        };
+        let sighash =
+            SigHashCache::new(&spend).signature_hash(0, &multisig, value, SigHashType::All);
+        let sign = |privkey| {
+            let mut sig = secp
+                .sign(&Message::from_slice(&sighash[..]).unwrap(), privkey)
+                .serialize_der()
+                .to_vec();
+            sig.push(SigHashType::All as u8);
+            sig
+        };
+        let with_witness = |sigs: Vec<Vec<u8>>| {
+            let mut tx = spend.clone();
+            tx.input[0].witness = Some(Vec::new())
+                .into_iter()
+                .chain(sigs)
+                .chain(Some(multisig.to_bytes()))
+                .collect();
+            tx
+        };
+
+        let one_sig = with_witness(vec![sign(&privkeys[0])]);
+        assert_eq!(
+            error_code(sim.send_raw_transaction(&one_sig)),
+            Some(RPC_VERIFY_REJECTED)
+        );
+        let wrong_key = with_witness(vec![sign(&privkeys[0]), sign(&privkeys[2])]);
+        assert_eq!(
+            error_code(sim.send_raw_transaction(&wrong_key)),
+            Some(RPC_VERIFY_REJECTED)
+        );
+        let same_key_twice = with_witness(vec![sign(&privkeys[0]), sign(&privkeys[0])]);
+        assert_eq!(
+            error_code(sim.send_raw_transaction(&same_key_twice)),
+            Some(RPC_VERIFY_REJECTED)
+        );
+        let signed = with_witness(vec![sign(&privkeys[0]), sign(&privkeys[1])]);
+        assert!(sim.send_raw_transaction(&signed).is_ok());
+    }
+}
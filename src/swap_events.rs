@@ -0,0 +1,81 @@
+//! Progress events of coinswaps, so that frontends can show what a [Taker] or [Maker] is doing
+//! without parsing the logs.
+//!
+//! Subscribe with [Taker::subscribe] or [Maker::subscribe] before running them. The events
+//! are delivered over an unbounded channel, so a slow frontend never holds up a coinswap.
+//!
+//! [Taker]: crate::taker_protocol::Taker
+//! [Taker::subscribe]: crate::taker_protocol::Taker::subscribe
+//! [Maker]: crate::maker_protocol::Maker
+//! [Maker::subscribe]: crate::maker_protocol::Maker::subscribe
+
+use bitcoin::{hashes::hash160::Hash as Hash160, Txid};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// Something that happened in a coinswap. Each event has the hashvalue which identifies the
+/// coinswap, the maker learns it from the first contract it is asked to sign.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SwapEvent {
+    /// Taker only. A maker was chosen for a hop of the route, the first hop being 0. If the
+    /// maker doesn't respond another one is chosen for the same hop.
+    MakerChosen {
+        hashvalue: Hash160,
+        hop: u16,
+        maker: String,
+    },
+    /// Funding txes were broadcast, by the taker for the first hop or by a maker for the hop
+    /// after it.
+    FundingBroadcast {
+        hashvalue: Hash160,
+        txids: Vec<Txid>,
+    },
+    /// Taker only. A funding tx being waited for has this many confirmations, zero meaning it
+    /// was seen in the mempool.
+    FundingConfirmations {
+        hashvalue: Hash160,
+        txid: Txid,
+        confirmations: u32,
+    },
+    /// The funding txes of a hop are confirmed and the contracts of that hop are signed. Makers
+    /// don't know where they are in the route, so `hop` is `None` for them.
+    HopEstablished {
+        hashvalue: Hash160,
+        hop: Option<u16>,
+        funding_txids: Vec<Txid>,
+    },
+    /// The hash preimage was revealed, by the taker to the makers.
+    PreimageRevealed { hashvalue: Hash160 },
+    /// The private keys of the multisigs were handed over, after which the coins can't be taken
+    /// back any more.
+    KeysHandedOver { hashvalue: Hash160 },
+    /// The coinswap completed.
+    Completed { hashvalue: Hash160 },
+    /// The coinswap failed or was cancelled. Coins already in contracts are recovered via the
+    /// timelock.
+    Failed { hashvalue: Hash160, error: String },
+}
+
+/// Sends [SwapEvent]s to the subscriber, events are dropped if there is none.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SwapEventSender {
+    sender: Option<mpsc::UnboundedSender<SwapEvent>>,
+}
+
+impl SwapEventSender {
+    /// Replaces any earlier subscriber, only the latest one gets the events.
+    pub(crate) fn subscribe(&mut self) -> mpsc::UnboundedReceiver<SwapEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.sender = Some(sender);
+        receiver
+    }
+
+    pub(crate) fn send(&self, event: SwapEvent) {
+        log::debug!("swap event {:?}", event);
+        if let Some(sender) = &self.sender {
+            //the subscriber may have stopped listening, which is fine
+            let _ = sender.send(event);
+        }
+    }
+}
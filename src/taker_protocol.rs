@@ -13,37 +13,57 @@
 
 use std::{
     collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    future::{self, Future},
+    io::{self, Read},
     iter::once,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::{net::TcpStream, select, time::sleep};
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::{mpsc::UnboundedReceiver, watch},
+    time::sleep,
+};
 
 use bitcoin::{
-    consensus::encode::deserialize,
-    hashes::{hash160::Hash as Hash160, hex::ToHex, Hash},
+    hashes::{
+        hash160::Hash as Hash160,
+        hex::{FromHex, ToHex},
+        Hash,
+    },
     secp256k1::{
-        rand::{rngs::OsRng, RngCore},
+        rand::{rngs::OsRng, Rng},
         SecretKey,
     },
     util::ecdsa::PublicKey,
     BlockHash, OutPoint, Script, Transaction, Txid,
 };
-use bitcoincore_rpc::{Client, RpcApi};
 
 use crate::{
-    contracts::{find_funding_output, SwapCoin, WatchOnlySwapCoin},
-    error::TeleportError,
+    blockchain_backend::BlockchainBackend,
+    contracts::{calculate_coinswap_fee, find_funding_output, SwapCoin, WatchOnlySwapCoin},
+    error::{file_format_error, TeleportError},
     messages::{
         ContractSigsAsRecvrAndSender, ContractSigsForRecvr, ContractSigsForRecvrAndSender,
-        ContractSigsForSender, FundingTxInfo, MultisigPrivkey, Preimage, PrivKeyHandover,
-        TakerToMakerMessage,
+        ContractSigsForSender, FundingTxInfo, MultisigPrivkey, Offer, Preimage, PrivKeyHandover,
+        ProtocolErrorCode, TakerToMakerMessage,
     },
+    swap_events::{SwapEvent, SwapEventSender},
 };
 
 use crate::{
-    offerbook_sync::{sync_offerbook, MakerAddress, OfferAndAddress},
-    wallet_sync::{generate_keypair, IncomingSwapCoin, OutgoingSwapCoin, Wallet},
+    offerbook_sync::{
+        get_advertised_maker_addresses, sync_offerbook_with_addresses, verify_fidelity_bonds,
+        MakerAddress, OfferAndAddress,
+    },
+    wallet_storage::WalletStorage,
+    wallet_sync::{IncomingSwapCoin, OutgoingSwapCoin, Wallet, WalletSwapCoin},
 };
 
 use crate::watchtower_protocol::{
@@ -80,19 +100,33 @@ const RECONNECT_ATTEMPT_TIMEOUT_SEC: u64 = 60 * 5;
 
 /// Various global configurations defining the Taker behavior.
 /// TODO: Optionally read this from a config file.
-struct TakerConfig {
-    refund_locktime: u16,
-    refund_locktime_step: u16,
-
-    first_connect_attempts: u32,
-    first_connect_sleep_delay_sec: u64,
-    first_connect_attempt_timeout_sec: u64,
-
-    reconnect_attempts: u32,
-    reconnect_short_sleep_delay: u64,
-    reconnect_long_sleep_delay: u64,
-    short_long_sleep_delay_transition: u32,
-    reconnect_attempt_timeout_sec: u64,
+#[derive(Debug, Clone)]
+pub struct TakerConfig {
+    /// Locktime in blocks of the contracts of the last hop.
+    pub refund_locktime: u16,
+    /// How much the locktime increases for each hop closer to the taker.
+    pub refund_locktime_step: u16,
+
+    /// Attempts at connecting to a maker before any of our txes are confirmed.
+    pub first_connect_attempts: u32,
+    /// Seconds to wait between those attempts.
+    pub first_connect_sleep_delay_sec: u64,
+    /// Timeout in seconds of each of those attempts.
+    pub first_connect_attempt_timeout_sec: u64,
+
+    /// Attempts at reconnecting to a maker once our txes are confirmed.
+    pub reconnect_attempts: u32,
+    /// Seconds to wait between the first reconnect attempts.
+    pub reconnect_short_sleep_delay: u64,
+    /// Seconds to wait between the later reconnect attempts.
+    pub reconnect_long_sleep_delay: u64,
+    /// Reconnect attempts after which the long delay is used.
+    pub short_long_sleep_delay_transition: u32,
+    /// Timeout in seconds of each reconnect attempt.
+    pub reconnect_attempt_timeout_sec: u64,
+
+    /// Makers to download offers from instead of the advertised ones.
+    pub maker_addresses: Option<Vec<MakerAddress>>,
 }
 
 impl Default for TakerConfig {
@@ -108,6 +142,7 @@ impl Default for TakerConfig {
             reconnect_long_sleep_delay: RECONNECT_LONG_SLEEP_DELAY_SEC,
             short_long_sleep_delay_transition: SHORT_LONG_SLEEP_DELAY_TRANSITION,
             reconnect_attempt_timeout_sec: RECONNECT_ATTEMPT_TIMEOUT_SEC,
+            maker_addresses: None,
         }
     }
 }
@@ -115,7 +150,7 @@ impl Default for TakerConfig {
 /// Swap specific parameters. These are user's policy and can differ among swaps.
 /// SwapParams govern the criteria to find suitable set of makers from the offerbook.
 /// If no maker matches with a given SwapParam, that coinswap round will fail.
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct SwapParams {
     /// Total Amount to Swap.
     pub send_amount: u64,
@@ -123,6 +158,10 @@ pub struct SwapParams {
     pub maker_count: u16,
     /// How many splits
     pub tx_count: u32,
+    /// Minimum fidelity bond value a maker needs to be chosen. Zero allows makers without a bond.
+    pub min_fidelity_bond_value: f64,
+    /// Maximum total coinswap fee in sats paid to all makers in the route, not counting miner fees.
+    pub max_total_fee: Option<u64>,
     // TODO: Following two should be moved to TakerConfig as global configuration.
     /// Confirmation count required for funding txs.
     pub required_confirms: i32,
@@ -130,40 +169,335 @@ pub struct SwapParams {
     pub fee_rate: u64,
 }
 
-/// An ephemeral Offerbook tracking good and bad makers. Currently, Offerbook is initiated
-/// at start of every swap. So good and bad maker list will ot be persisted.
-// TODO: Persist the offerbook in disk.
+//a maker's failure score halves every day, so one flaky connection is soon forgotten
+// but a maker that keeps failing our swaps builds up enough score to get banned
+const FAILURE_SCORE_HALF_LIFE_SEC: u64 = 60 * 60 * 24;
+const BAN_FAILURE_SCORE: f64 = 3.0;
+const BAN_DURATION_SEC: u64 = 60 * 60 * 24 * 3;
+const MAX_STORED_FAILURE_REASONS: usize = 10;
+
+const OFFERBOOK_FILE_VERSION: u32 = 0;
+
+//how often to check whether the timelock of our contracts has matured after a maker deviated
+const TIMELOCK_RECOVERY_POLL_DELAY_SEC: u64 = 10;
+
+//chance of choosing a maker ignoring fidelity bonds, so that makers without a bond
+// still get some coinswaps and new makers have a way to start out
+const BONDLESS_MAKERS_ALLOWANCE: f64 = 0.125;
+
+fn get_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_secs()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MakerFailure {
+    time: u64,
+    reason: String,
+}
+
+/// History of our past interactions with a single maker, persisted across swaps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MakerReputation {
+    success_count: u32,
+    failure_count: u32,
+    /// Unix time the maker last responded to us, either with an offer or in a swap.
+    last_seen: Option<u64>,
+    /// Most recent failures, oldest first.
+    failures: Vec<MakerFailure>,
+    /// Failure score as of `failure_score_time`, decays with [FAILURE_SCORE_HALF_LIFE_SEC].
+    failure_score: f64,
+    failure_score_time: u64,
+    banned_until: Option<u64>,
+}
+
+impl MakerReputation {
+    fn get_failure_score(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.failure_score_time);
+        self.failure_score * 0.5f64.powf(age as f64 / FAILURE_SCORE_HALF_LIFE_SEC as f64)
+    }
+
+    fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn record_seen(&mut self, now: u64) {
+        self.last_seen = Some(now);
+    }
+
+    fn record_success(&mut self, now: u64) {
+        self.success_count += 1;
+        self.last_seen = Some(now);
+        self.failure_score = self.get_failure_score(now) / 2.0;
+        self.failure_score_time = now;
+    }
+
+    fn record_failure(&mut self, now: u64, reason: String) {
+        self.failure_count += 1;
+        self.failure_score = self.get_failure_score(now) + 1.0;
+        self.failure_score_time = now;
+        self.failures.push(MakerFailure { time: now, reason });
+        if self.failures.len() > MAX_STORED_FAILURE_REASONS {
+            self.failures.remove(0);
+        }
+        if self.failure_score >= BAN_FAILURE_SCORE {
+            self.banned_until = Some(now + BAN_DURATION_SEC);
+        }
+    }
+
+    //broadcasting a contract tx mid-swap is deliberate, so unlike failures it is never forgiven
+    fn record_deviation(&mut self, now: u64, reason: String) {
+        self.record_failure(now, reason);
+        self.banned_until = Some(u64::MAX);
+    }
+}
+
+const SWAP_JOURNAL_FILE_VERSION: u32 = 0;
+const SWAP_JOURNAL_AAD: &[u8] = b"swap_journal";
+
+//the state holds the private keys of our swapcoins and the preimage, so its encrypted with the
+//wallet key if the wallet is
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JournaledSwapState<S> {
+    Plaintext(S),
+    //hex of the nonce and ciphertext of the json serialized state
+    Encrypted(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwapJournalFileData<S> {
+    version: u32,
+    ongoing_swap_state: JournaledSwapState<S>,
+}
+
+fn read_swap_journal(
+    file_path: &Path,
+    storage: &WalletStorage,
+) -> Result<OngoingSwapState, TeleportError> {
+    let mut file_str = String::new();
+    File::open(file_path)?.read_to_string(&mut file_str)?;
+    let file_data = serde_json::from_str::<SwapJournalFileData<OngoingSwapState>>(&file_str)
+        .map_err(io::Error::from)?;
+    if file_data.version != SWAP_JOURNAL_FILE_VERSION {
+        return Err(file_format_error("unknown swap journal file version"));
+    }
+    match file_data.ongoing_swap_state {
+        //journaled before the wallet was encrypted, its encrypted when next written
+        JournaledSwapState::Plaintext(state) => Ok(state),
+        JournaledSwapState::Encrypted(data) => {
+            let data = Vec::<u8>::from_hex(&data)
+                .map_err(|_| TeleportError::Protocol("swap journal corrupted"))?;
+            let state = storage.unseal_file_data(&data, SWAP_JOURNAL_AAD)?;
+            Ok(serde_json::from_slice(&state).map_err(io::Error::from)?)
+        }
+    }
+}
+
+/// Write the swap journal, replacing the file atomically so a crash while writing leaves the
+/// previous journal intact.
+fn write_swap_journal(
+    file_path: &Path,
+    storage: &WalletStorage,
+    ongoing_swap_state: &OngoingSwapState,
+) -> Result<(), TeleportError> {
+    let state = serde_json::to_vec(ongoing_swap_state).map_err(io::Error::from)?;
+    let ongoing_swap_state = match storage.seal_file_data(&state, SWAP_JOURNAL_AAD) {
+        Some(data) => JournaledSwapState::Encrypted(data.to_hex()),
+        None => JournaledSwapState::Plaintext(ongoing_swap_state),
+    };
+    let tmp_file_path = file_path.with_extension("swap.tmp");
+    let file = File::create(&tmp_file_path)?;
+    serde_json::to_writer(
+        &file,
+        &SwapJournalFileData {
+            version: SWAP_JOURNAL_FILE_VERSION,
+            ongoing_swap_state,
+        },
+    )
+    .map_err(io::Error::from)?;
+    file.sync_all()?;
+    fs::rename(&tmp_file_path, file_path)?;
+    Ok(())
+}
+
+/// Change the passphrase of the wallet, and encrypt the swap journal of an interrupted coinswap
+/// with the new one.
+pub fn change_wallet_passphrase(
+    storage: &mut WalletStorage,
+    swap_journal_file_path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(), TeleportError> {
+    let ongoing_swap_state = match read_swap_journal(swap_journal_file_path, storage) {
+        Ok(state) => Some(state),
+        Err(TeleportError::Disk(e)) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    storage.change_passphrase(passphrase)?;
+    if let Some(state) = ongoing_swap_state {
+        write_swap_journal(swap_journal_file_path, storage, &state)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct OfferBookFileData {
+    version: u32,
+    makers: Vec<(MakerAddress, MakerReputation)>,
+}
+
+/// Offerbook tracking good and bad makers. The good and bad maker sets only cover the current
+/// swap round, while the reputation of every maker we have dealt with is persisted in the
+/// offerbook file so that makers which failed earlier swaps are avoided or banned.
 #[derive(Debug, Default)]
 struct OfferBook {
     all_makers: BTreeSet<OfferAndAddress>,
     good_makers: BTreeSet<OfferAndAddress>,
     bad_makers: BTreeSet<OfferAndAddress>,
+    reputations: HashMap<MakerAddress, MakerReputation>,
+    /// Values of the verified fidelity bonds of makers in this swap round.
+    fidelity_bond_values: HashMap<MakerAddress, f64>,
+    file_path: Option<PathBuf>,
 }
 
 impl OfferBook {
+    /// Load maker reputations from the offerbook file, starting afresh if it doesn't exist yet.
+    fn load_from_file(file_path: &Path) -> Result<OfferBook, TeleportError> {
+        let reputations = match File::open(file_path) {
+            Ok(mut file) => {
+                let mut file_str = String::new();
+                file.read_to_string(&mut file_str)?;
+                let file_data = serde_json::from_str::<OfferBookFileData>(&file_str)
+                    .map_err(io::Error::from)?;
+                if file_data.version != OFFERBOOK_FILE_VERSION {
+                    return Err(file_format_error("unknown offerbook file version"));
+                }
+                file_data.makers.into_iter().collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(OfferBook {
+            reputations,
+            file_path: Some(file_path.to_path_buf()),
+            ..OfferBook::default()
+        })
+    }
+
+    fn save_to_disk(&self) -> Result<(), TeleportError> {
+        let file_path = match &self.file_path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let file_data = OfferBookFileData {
+            version: OFFERBOOK_FILE_VERSION,
+            makers: self
+                .reputations
+                .iter()
+                .map(|(address, reputation)| (address.clone(), reputation.clone()))
+                .collect(),
+        };
+        let file = File::create(file_path)?;
+        serde_json::to_writer(file, &file_data).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    //a failure to save the reputations shouldnt abort a swap that is in progress
+    fn save_or_log(&self) {
+        if let Err(e) = self.save_to_disk() {
+            log::warn!("unable to save offerbook file: {:?}", e);
+        }
+    }
+
+    fn is_banned(&self, address: &MakerAddress, now: u64) -> bool {
+        self.reputations
+            .get(address)
+            .is_some_and(|r| r.is_banned(now))
+    }
+
+    fn get_failure_score(&self, address: &MakerAddress, now: u64) -> f64 {
+        self.reputations
+            .get(address)
+            .map_or(0.0, |r| r.get_failure_score(now))
+    }
+
+    fn get_fidelity_bond_value(&self, address: &MakerAddress) -> f64 {
+        self.fidelity_bond_values
+            .get(address)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// All makers that haven't been tried in this swap round and aren't currently banned.
     fn get_all_untried(&self) -> BTreeSet<OfferAndAddress> {
+        let now = get_unix_time();
         // TODO: Remove the clones and return BTreeSet<&OfferAndAddress>
         self.all_makers
             .difference(&self.bad_makers.union(&self.good_makers).cloned().collect())
+            .filter(|oa| !self.is_banned(&oa.address, now))
             .cloned()
             .collect()
     }
 
-    fn add_new_offer(&mut self, offer: &OfferAndAddress) -> bool {
+    fn add_new_offer(&mut self, offer: &OfferAndAddress, fidelity_bond_value: Option<f64>) -> bool {
+        self.reputations
+            .entry(offer.address.clone())
+            .or_default()
+            .record_seen(get_unix_time());
+        if let Some(value) = fidelity_bond_value {
+            self.fidelity_bond_values
+                .insert(offer.address.clone(), value);
+        }
         self.all_makers.insert(offer.clone())
     }
 
     fn add_good_maker(&mut self, good_maker: &OfferAndAddress) -> bool {
+        self.reputations
+            .entry(good_maker.address.clone())
+            .or_default()
+            .record_success(get_unix_time());
+        self.save_or_log();
         self.good_makers.insert(good_maker.clone())
     }
 
-    fn add_bad_maker(&mut self, bad_maker: &OfferAndAddress) -> bool {
+    fn add_bad_maker(&mut self, bad_maker: &OfferAndAddress, reason: String) -> bool {
+        let now = get_unix_time();
+        let reputation = self
+            .reputations
+            .entry(bad_maker.address.clone())
+            .or_default();
+        reputation.record_failure(now, reason);
+        if reputation.is_banned(now) {
+            log::warn!(
+                "Maker {} banned after {} failures",
+                bad_maker.address,
+                reputation.failure_count
+            );
+        }
+        self.save_or_log();
+        self.bad_makers.insert(bad_maker.clone())
+    }
+
+    /// Ban a maker which deviated from the protocol, so that it's never chosen again.
+    fn add_deviating_maker(&mut self, bad_maker: &OfferAndAddress, reason: String) -> bool {
+        log::warn!(
+            "Maker {} banned for deviating: {}",
+            bad_maker.address,
+            reason
+        );
+        self.reputations
+            .entry(bad_maker.address.clone())
+            .or_default()
+            .record_deviation(get_unix_time(), reason);
+        self.save_or_log();
         self.bad_makers.insert(bad_maker.clone())
     }
 }
 
 // Defines the Taker's position in the current ongoing swap.
-#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 enum TakerPosition {
     #[default]
     /// Taker is the First Peer of the swap (Sender Side)
@@ -178,8 +512,9 @@ enum TakerPosition {
 /// performing a swap. Various data are appended into the lists and are oly read from the last entry as the
 /// swap progresses. This ensures the swap state is always consistent.
 ///
-/// This states can be used to recover from a failed swap round.
-#[derive(Default)]
+/// This states can be used to recover from a failed swap round. It is journaled to disk after
+/// every protocol step, so that an interrupted swap can be resumed with `resume-coinswap`.
+#[derive(Default, Serialize, Deserialize)]
 struct OngoingSwapState {
     /// SwapParams used in current swap round.
     pub swap_params: SwapParams,
@@ -192,18 +527,26 @@ struct OngoingSwapState {
     /// Information regarding all the swap participants (Makers).
     /// The last entry at the end of the swap round will be the Taker, as it's the last peer.
     pub peer_infos: Vec<NextPeerInfo>,
-    /// List of funding transactions with optional merkleproofs.
+    /// Our own funding transactions of the first hop, journaled before they are broadcast.
+    pub my_funding_txs: Vec<Transaction>,
+    /// List of confirmed funding transactions with their merkleproofs, one entry for each hop.
     pub funding_txs: Vec<(Vec<Transaction>, Vec<String>)>,
+    /// Contract txs and signatures requested by the last maker to which sigs were sent.
+    pub contract_sigs_as_recvr_and_sender: Option<ContractSigsAsRecvrAndSender>,
     /// The preimage being used for this coinswap round.
     pub active_preimage: Preimage,
     /// Enum defining the position of the Taker at each steps of a multihop swap.
     pub taker_position: TakerPosition,
     /// Height that the wallet last checked for relevant transactions of this swap.
     pub last_synced_height: Option<u64>,
+    /// Set once contract txs of this swap were seen on the network. The swap can't continue
+    /// and our outgoing coins are being recovered via the timelock.
+    #[serde(default)]
+    pub contracts_broadcasted: bool,
 }
 
 /// Information for the next maker in the hop.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct NextPeerInfo {
     peer: OfferAndAddress,
     multisig_pubkeys: Vec<PublicKey>,
@@ -212,56 +555,248 @@ struct NextPeerInfo {
     contract_reedemscripts: Vec<Script>,
 }
 
+/// Handle to cancel the coinswap of a [Taker] from another task or thread.
+#[derive(Debug, Clone)]
+pub struct TakerHandle {
+    cancel: Arc<watch::Sender<bool>>,
+}
+
+impl TakerHandle {
+    /// Makes the running [Taker::send_coinswap] or [Taker::resume_coinswap] return
+    /// [TeleportError::Cancelled]. If funding txes were already broadcast the swap stays in the
+    /// swap journal, to be finished later with [Taker::resume_coinswap] or by recovery.
+    /// Does nothing if no coinswap is running.
+    pub fn cancel(&self) {
+        // sending only fails once the taker is dropped
+        let _ = self.cancel.send(true);
+    }
+}
+
 /// The Taker structure that performs bulk of the coinswap protocol. Taker connects
 /// to multiple Makers and send protocol messages sequentially to them. The communication
 /// sequence and corresponding SwapCoin infos are stored in `ongoing_swap_state`.
-struct Taker<'taker> {
-    /// Wllate managed by the Taker.
-    // TODO: Take ownership instead of reference.
-    wallet: &'taker mut Wallet,
-    /// RPC client used for wallet operations.
-    // TODO: This should be owned by the wallet.
-    rpc: &'taker Client,
+///
+/// The Taker runs on the caller's tokio runtime, so it can be embedded in an application which
+/// already has one. The wallet is shared with the application, but shouldn't be spent from
+/// while a coinswap is running.
+pub struct Taker {
+    /// Wallet managed by the Taker.
+    wallet: Arc<RwLock<Wallet>>,
+    /// Blockchain backend used for wallet operations.
+    rpc: Arc<dyn BlockchainBackend>,
     config: TakerConfig,
     offerbook: OfferBook,
     ongoing_swap_state: OngoingSwapState,
+    /// File the [OngoingSwapState] is journaled to.
+    swap_journal_file_path: PathBuf,
+    cancel: Arc<watch::Sender<bool>>,
+    cancel_rx: watch::Receiver<bool>,
+    events: SwapEventSender,
 }
 
-impl<'taker> Taker<'taker> {
+impl Taker {
     // ######## MAIN PUBLIC INTERFACE ############
 
-    /// Initialize a Taker with a wallet, rpc and seed offers.
-    fn init(wallet: &'taker mut Wallet, rpc: &'taker Client, offers: Vec<OfferAndAddress>) -> Self {
-        let mut offerbook = OfferBook::default();
-        offers.iter().for_each(|offer| {
-            offerbook.add_new_offer(offer);
-        });
-        Self {
+    /// Initialize a Taker with a wallet, a blockchain backend and the offerbook loaded from disk.
+    pub fn new(
+        rpc: Arc<dyn BlockchainBackend>,
+        wallet: Arc<RwLock<Wallet>>,
+        config: TakerConfig,
+        offerbook_file_path: &Path,
+        swap_journal_file_path: &Path,
+    ) -> Result<Taker, TeleportError> {
+        let (cancel, cancel_rx) = watch::channel(false);
+        Ok(Self {
             wallet,
             rpc,
-            config: TakerConfig::default(),
-            offerbook,
+            config,
+            offerbook: OfferBook::load_from_file(offerbook_file_path)?,
             ongoing_swap_state: OngoingSwapState::default(),
+            swap_journal_file_path: swap_journal_file_path.to_path_buf(),
+            cancel: Arc::new(cancel),
+            cancel_rx,
+            events: SwapEventSender::default(),
+        })
+    }
+
+    /// Get a [TakerHandle] to cancel the coinswap.
+    pub fn handle(&self) -> TakerHandle {
+        TakerHandle {
+            cancel: Arc::clone(&self.cancel),
         }
     }
 
+    /// Receive [SwapEvent]s about the progress of coinswaps. Only the latest subscriber gets
+    /// the events.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<SwapEvent> {
+        self.events.subscribe()
+    }
+
+    /// The wallet used by the Taker.
+    pub fn wallet(&self) -> &Arc<RwLock<Wallet>> {
+        &self.wallet
+    }
+
     /// Perform a coinswap round with given [SwapParams]. The Taker will try to perform swap with makers
     /// in it's [OfferBook] sequentially as per the maker_count given in swap params.
     /// If [SwapParams] doesn't fit suitably with any available offers, or not enough makers
     /// respond back, the swap round will fail.
     pub async fn send_coinswap(&mut self, swap_params: SwapParams) -> Result<(), TeleportError> {
-        // Generate new random preimage and initiate the first hop.
-        let mut preimage = [0u8; 32];
-        let mut rng = OsRng::new().unwrap();
-        rng.fill_bytes(&mut preimage);
+        if self.swap_journal_file_path.exists() {
+            return Err(TeleportError::Protocol(
+                "an interrupted coinswap exists, finish it with `resume-coinswap` first",
+            ));
+        }
+        let cancelled = self.cancelled();
+        let ret = select! {
+            ret = self.start_coinswap(swap_params) => ret,
+            _ = cancelled => {
+                log::info!("Coinswap cancelled");
+                Err(TeleportError::Cancelled)
+            }
+        };
+        self.send_failed_event(&ret);
+        //a journal without coins in the swap would only stop the next coinswap from starting
+        if ret.is_err() && self.swap_journal_file_path.exists() {
+            if let Err(e) = self.discard_unfunded_swap() {
+                log::warn!("unable to check if the coinswap was funded: {:?}", e);
+            }
+        }
+        ret
+    }
+
+    /// Continue the swap round in the swap journal. If that fails, the coins locked in the swap
+    /// are recovered by broadcasting the contract transactions.
+    pub async fn resume_coinswap(&mut self) -> Result<(), TeleportError> {
+        let cancelled = self.cancelled();
+        let ret = select! {
+            ret = self.resume_from_journal() => ret,
+            _ = cancelled => {
+                log::info!("Coinswap cancelled, it can be resumed again from the swap journal");
+                Err(TeleportError::Cancelled)
+            }
+        };
+        self.send_failed_event(&ret);
+        ret
+    }
+
+    // forgets cancels from before this coinswap run, and resolves on the next one
+    fn cancelled(&self) -> impl Future<Output = ()> {
+        let _ = self.cancel.send(false);
+        let mut cancel = self.cancel_rx.clone();
+        async move {
+            while !*cancel.borrow() {
+                if cancel.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        }
+    }
+
+    fn send_failed_event(&self, ret: &Result<(), TeleportError>) {
+        if let Err(e) = ret {
+            self.events.send(SwapEvent::Failed {
+                hashvalue: self.get_preimage_hash(),
+                error: format!("{:?}", e),
+            });
+        }
+    }
+
+    /// Download the offers from all makers and add them to the offerbook.
+    async fn sync_offerbook(&mut self) -> Result<(), TeleportError> {
+        let maker_addresses = match &self.config.maker_addresses {
+            Some(maker_addresses) => maker_addresses.clone(),
+            None => {
+                let network = self.wallet.read().unwrap().network;
+                get_advertised_maker_addresses(network).await?
+            }
+        };
+        get_verified_offers(self.rpc.as_ref(), maker_addresses)
+            .await?
+            .iter()
+            .for_each(|(offer, fidelity_bond_value)| {
+                self.offerbook.add_new_offer(offer, *fidelity_bond_value);
+            });
+        self.offerbook.save_or_log();
+        Ok(())
+    }
+
+    async fn start_coinswap(&mut self, swap_params: SwapParams) -> Result<(), TeleportError> {
+        // Derive a new preimage first, so that events of this swap have its hashvalue.
+        // It comes from the seed so that the contracts can be rebuilt without the wallet file.
+        let preimage = self.wallet.write().unwrap().get_next_swap_preimage()?;
 
         self.ongoing_swap_state.active_preimage = preimage;
         self.ongoing_swap_state.swap_params = swap_params;
 
+        self.sync_offerbook().await?;
         self.init_first_hop().await?;
+        self.continue_coinswap().await
+    }
+
+    async fn resume_from_journal(&mut self) -> Result<(), TeleportError> {
+        let journaled_state = read_swap_journal(
+            &self.swap_journal_file_path,
+            self.wallet.read().unwrap().get_storage(),
+        );
+        self.ongoing_swap_state = match journaled_state {
+            Ok(state) => state,
+            Err(TeleportError::Disk(e)) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!("No interrupted coinswap to resume");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        //more makers are only needed if the route wasnt complete when the swap was interrupted
+        if self.ongoing_swap_state.peer_infos.len()
+            <= self.ongoing_swap_state.swap_params.maker_count as usize
+        {
+            self.sync_offerbook().await?;
+        }
+        log::info!(
+            "Resuming coinswap {} at hop {} of {}",
+            self.get_preimage_hash(),
+            self.ongoing_swap_state.funding_txs.len(),
+            self.ongoing_swap_state.swap_params.maker_count + 1
+        );
+        if let Err(e) = self.continue_coinswap().await {
+            //the timelock recovery after a maker deviated finishes the swap itself
+            if !self.swap_journal_file_path.exists() {
+                return Err(e);
+            }
+            log::error!(
+                "Unable to continue coinswap, recovering coins from the contracts. error={:?}",
+                e
+            );
+            let hashvalue = self.get_preimage_hash();
+            self.recover_from_swap()?;
+            self.events.send(SwapEvent::Failed {
+                hashvalue,
+                error: format!("{:?}", e),
+            });
+        }
+        Ok(())
+    }
+
+    /// Continue a coinswap round from the [OngoingSwapState], either right after the first hop
+    /// was initiated or after loading the state from the swap journal. The number of hops whose
+    /// funding txs have confirmed tells how far along the route the swap got.
+    async fn continue_coinswap(&mut self) -> Result<(), TeleportError> {
+        if self.ongoing_swap_state.contracts_broadcasted {
+            return self.recover_via_timelock().await;
+        }
+        if self.ongoing_swap_state.funding_txs.is_empty() {
+            self.ongoing_swap_state.taker_position = TakerPosition::FirstPeer;
+            self.broadcast_first_hop_funding_txs().await?;
+            if self.ongoing_swap_state.contracts_broadcasted {
+                return self.recover_via_timelock().await;
+            }
+            self.journal_swap_state_or_log();
+        }
 
         // Iterate until `maker_count` numbers of Makers are found and initiate swap between them sequentially.
-        for maker_index in 0..self.ongoing_swap_state.swap_params.maker_count {
+        let first_maker_index = (self.ongoing_swap_state.funding_txs.len() - 1) as u16;
+        for maker_index in first_maker_index..self.ongoing_swap_state.swap_params.maker_count {
             if maker_index == 0 {
                 self.ongoing_swap_state.taker_position = TakerPosition::FirstPeer
             } else if maker_index == self.ongoing_swap_state.swap_params.maker_count - 1 {
@@ -270,66 +805,249 @@ impl<'taker> Taker<'taker> {
                 self.ongoing_swap_state.taker_position = TakerPosition::WatchOnly
             }
 
-            // Refund lock time decreases by `refund_locktime_step` for each hop.
-            let maker_refund_locktime = self.config.refund_locktime
-                + self.config.refund_locktime_step
-                    * (self.ongoing_swap_state.swap_params.maker_count - maker_index - 1);
+            // Signatures for this hop were exchanged already if the state was journaled after
+            // that, and only the funding txs are left to wait for.
+            if self.ongoing_swap_state.peer_infos.len() == maker_index as usize + 1 {
+                // Refund lock time decreases by `refund_locktime_step` for each hop.
+                let maker_refund_locktime = self.config.refund_locktime
+                    + self.config.refund_locktime_step
+                        * (self.ongoing_swap_state.swap_params.maker_count - maker_index - 1);
 
-            let funding_tx_infos = self.funding_info_for_next_maker();
+                let funding_tx_infos = self.funding_info_for_next_maker();
 
-            let (next_swap_info, contract_sigs_as_recvr_and_sender) = self
-                .send_sigs_init_next_hop(maker_refund_locktime, &funding_tx_infos)
-                .await?;
+                let (next_swap_info, contract_sigs_as_recvr_and_sender) = self
+                    .send_sigs_init_next_hop(maker_refund_locktime, &funding_tx_infos)
+                    .await?;
 
-            self.ongoing_swap_state
-                .peer_infos
-                .push(next_swap_info.clone());
+                self.ongoing_swap_state
+                    .peer_infos
+                    .push(next_swap_info.clone());
+                self.ongoing_swap_state.contract_sigs_as_recvr_and_sender =
+                    Some(contract_sigs_as_recvr_and_sender);
+                self.journal_swap_state_or_log();
+            }
+
+            let next_funding_txids = self
+                .ongoing_swap_state
+                .contract_sigs_as_recvr_and_sender
+                .as_ref()
+                .expect("contract sigs expected after exchanging signatures")
+                .senders_contract_txs_info
+                .iter()
+                .map(|senders_contract_tx_info| {
+                    senders_contract_tx_info.contract_tx.input[0]
+                        .previous_output
+                        .txid
+                })
+                .collect::<Vec<Txid>>();
 
             // Watch for funding txs between the makers, as well as existing contract txs. If any maker publishes contract tx,
-            // thats a breach of the protocol. And the else block currently panics.
-            // TODO: Recovery script should be run automatically when this happens.
-            // With more logging information of which maker deviated, and banning their fidelity bond.
-            if let Some((next_funding_txes, next_funding_tx_merkleproofs)) = self
-                .watch_for_txs(
-                    &contract_sigs_as_recvr_and_sender
-                        .senders_contract_txs_info
-                        .iter()
-                        .map(|senders_contract_tx_info| {
-                            senders_contract_tx_info.contract_tx.input[0]
-                                .previous_output
-                                .txid
-                        })
-                        .collect::<Vec<Txid>>(),
-                )
-                .await?
+            // thats a breach of the protocol, the deviating makers are banned and our coins are recovered.
+            if let Some((next_funding_txes, next_funding_tx_merkleproofs)) =
+                self.watch_for_txs(&next_funding_txids).await?
             {
                 self.ongoing_swap_state
                     .funding_txs
                     .push((next_funding_txes, next_funding_tx_merkleproofs));
+                self.events.send(SwapEvent::HopEstablished {
+                    hashvalue: self.get_preimage_hash(),
+                    hop: Some(maker_index + 1),
+                    funding_txids: next_funding_txids,
+                });
             } else {
-                log::info!(concat!(
-                    "Somebody deviated from the protocol by broadcasting one or more contract",
-                    " transactions! Use main method `recover-from-incomplete-coinswap` to recover",
-                    " coins"
-                ));
-                panic!("ending");
+                return self.recover_via_timelock().await;
             }
 
             // For the last hop, initiate the incoming swapcoins, and request the sigs for it.
             if self.ongoing_swap_state.taker_position == TakerPosition::LastPeer {
-                let incoming_swapcoins =
-                    self.create_incoming_swapcoins(&contract_sigs_as_recvr_and_sender)?;
+                let incoming_swapcoins = self.create_incoming_swapcoins(
+                    self.ongoing_swap_state
+                        .contract_sigs_as_recvr_and_sender
+                        .as_ref()
+                        .expect("contract sigs expected after exchanging signatures"),
+                )?;
                 self.ongoing_swap_state.incoming_swapcoins = incoming_swapcoins;
                 self.request_sigs_for_incoming_swap().await?;
             }
+            self.journal_swap_state_or_log();
         } // Contract establishment completed.
 
         self.settle_all_swaps().await?;
+        let hashvalue = self.get_preimage_hash();
+        self.events.send(SwapEvent::KeysHandedOver { hashvalue });
         self.save_and_reset_swap_round();
+        self.remove_swap_journal()?;
         log::info!("Successfully Completed Coinswap");
+        self.events.send(SwapEvent::Completed { hashvalue });
         Ok(())
     }
 
+    /// Broadcast the contract transactions of the ongoing swap, so that the coins locked in it
+    /// come back to the wallet after the timelock. Used when a swap can't be continued.
+    fn recover_from_swap(&mut self) -> Result<(), TeleportError> {
+        if self.discard_unfunded_swap()? {
+            return Ok(());
+        }
+        let hashvalue = self.get_preimage_hash();
+        let wallet = self.wallet.read().unwrap();
+        let incomplete_coinswaps = wallet.find_incomplete_coinswaps(self.rpc.as_ref())?;
+        if let Some((incoming_swapcoins, outgoing_swapcoins)) = incomplete_coinswaps.get(&hashvalue)
+        {
+            for swapcoin in incoming_swapcoins
+                .iter()
+                .map(|(_, i)| *i as &dyn WalletSwapCoin)
+                .chain(
+                    outgoing_swapcoins
+                        .iter()
+                        .map(|(_, o)| *o as &dyn WalletSwapCoin),
+                )
+            {
+                wallet.import_wallet_contract_redeemscript(
+                    self.rpc.as_ref(),
+                    &swapcoin.get_contract_redeemscript(),
+                )?;
+                let txid = self
+                    .rpc
+                    .send_raw_transaction(&swapcoin.get_fully_signed_contract_tx())?;
+                log::info!("Broadcasted contract tx {}", txid);
+            }
+            log::info!(
+                "Coins of coinswap {} will return to the wallet after the timelock",
+                hashvalue
+            );
+        } else {
+            log::info!("No coins of coinswap {} are locked in contracts", hashvalue);
+        }
+        drop(incomplete_coinswaps);
+        drop(wallet);
+        self.clear_ongoing_swaps();
+        self.remove_swap_journal()
+    }
+
+    /// Forget the ongoing swap and remove its journal if our funding txs of the first hop were
+    /// never broadcast, as no coins are locked in it then. Returns whether it was forgotten.
+    fn discard_unfunded_swap(&mut self) -> Result<bool, TeleportError> {
+        //so that a node which can't be reached isn't taken for one which doesnt know the txs
+        self.rpc.get_block_count()?;
+        let funded = !self.ongoing_swap_state.funding_txs.is_empty()
+            || self
+                .ongoing_swap_state
+                .my_funding_txs
+                .iter()
+                .any(|tx| self.rpc.get_transaction(&tx.txid()).is_ok());
+        if funded {
+            return Ok(false);
+        }
+        log::info!(
+            "Funding txs of coinswap {} were never broadcast, nothing to recover",
+            self.get_preimage_hash()
+        );
+        self.clear_ongoing_swaps();
+        self.remove_swap_journal()?;
+        Ok(true)
+    }
+
+    /// Recover our outgoing coins after contract txs were broadcast during the swap. Our own
+    /// contract txs are broadcast, and once they are confirmed for long enough the coins are
+    /// spent via the timelock branch back to the wallet. This waits until the timelock matures.
+    async fn recover_via_timelock(&mut self) -> Result<(), TeleportError> {
+        log::warn!(concat!(
+            "Somebody deviated from the protocol by broadcasting one or more contract",
+            " transactions! Recovering coins via the timelock"
+        ));
+        self.journal_swap_state_or_log();
+
+        let outgoing_swapcoins = self.ongoing_swap_state.outgoing_swapcoins.clone();
+        for outgoing_swapcoin in &outgoing_swapcoins {
+            self.wallet
+                .read()
+                .unwrap()
+                .import_wallet_contract_redeemscript(
+                    self.rpc.as_ref(),
+                    &outgoing_swapcoin.get_contract_redeemscript(),
+                )?;
+            let contract_tx = outgoing_swapcoin.get_fully_signed_contract_tx();
+            if self.rpc.get_transaction(&contract_tx.txid()).is_ok() {
+                continue;
+            }
+            match self.rpc.send_raw_transaction(&contract_tx) {
+                Ok(txid) => log::info!("Broadcasted contract tx {}", txid),
+                //usually because the contract tx was already broadcast by someone else
+                Err(e) => log::warn!(
+                    "Unable to broadcast contract tx {}: {:?}",
+                    contract_tx.txid(),
+                    e
+                ),
+            }
+        }
+
+        let addresses = self
+            .wallet
+            .read()
+            .unwrap()
+            .get_next_internal_addresses(self.rpc.as_ref(), outgoing_swapcoins.len() as u32)?;
+        let mut timelock_spends = outgoing_swapcoins
+            .iter()
+            .zip(addresses.iter())
+            .map(|(osc, addr)| (osc.contract_tx.txid(), osc.create_timelock_spend(addr)))
+            .collect::<Vec<(Txid, Transaction)>>();
+        while !timelock_spends.is_empty() {
+            let mut remaining = Vec::<(Txid, Transaction)>::new();
+            for (contract_txid, timelock_spend) in timelock_spends {
+                if self.rpc.get_transaction(&timelock_spend.txid()).is_ok() {
+                    continue;
+                }
+                let confirmations = match self.rpc.get_transaction(&contract_txid) {
+                    Ok(gettx) => gettx.confirmations,
+                    //if we lose connection to the node, just try again later
+                    Err(_e) => 0,
+                };
+                if confirmations < timelock_spend.input[0].sequence as i32 {
+                    log::debug!(
+                        "timelock txout (txid={}) maturing in {} blocks",
+                        contract_txid,
+                        timelock_spend.input[0].sequence as i32 - confirmations
+                    );
+                    remaining.push((contract_txid, timelock_spend));
+                    continue;
+                }
+                let txid = self.rpc.send_raw_transaction(&timelock_spend)?;
+                log::info!("Broadcasted timelock spend tx {}", txid);
+            }
+            timelock_spends = remaining;
+            if !timelock_spends.is_empty() {
+                sleep(Duration::from_secs(TIMELOCK_RECOVERY_POLL_DELAY_SEC)).await;
+            }
+        }
+
+        log::info!(
+            "Coins of coinswap {} recovered via the timelock",
+            self.get_preimage_hash()
+        );
+        self.clear_ongoing_swaps();
+        self.remove_swap_journal()?;
+        Err(TeleportError::Protocol(
+            "contract txs were broadcast by a maker, coinswap aborted",
+        ))
+    }
+
+    /// Ban the makers who could have broadcast the contract txs seen on the network, see
+    /// [makers_holding_contract_sigs].
+    fn record_deviating_makers(&mut self, broadcasted_hops: &[usize]) {
+        let watchonly_hop_count = self.ongoing_swap_state.watchonly_swapcoins.len();
+        for (maker_index, hop) in
+            makers_holding_contract_sigs(broadcasted_hops, watchonly_hop_count)
+        {
+            if let Some(peer_info) = self.ongoing_swap_state.peer_infos.get(maker_index) {
+                let maker = peer_info.peer.clone();
+                self.offerbook.add_deviating_maker(
+                    &maker,
+                    format!("broadcast contract txs of hop {} during coinswap", hop),
+                );
+            }
+        }
+    }
+
     // ######## PROTOCOL SUBROUTINES ############
 
     /// Initiate the first coinswap hop. Makers are selected from the [OfferBook], and round will
@@ -346,22 +1064,47 @@ impl<'taker> Taker<'taker> {
         // Loop until we find a live maker who responded to our signature request.
         let funding_txs = loop {
             let maker = self.choose_next_maker()?.clone();
-            let (multisig_pubkeys, multisig_nonces, hashlock_pubkeys, hashlock_nonces) =
-                generate_maker_keys(
+            self.events.send(SwapEvent::MakerChosen {
+                hashvalue: self.get_preimage_hash(),
+                hop: 0,
+                maker: maker.address.to_string(),
+            });
+            // The nonces are derived from the multisig keys of our outgoing swapcoins, so the
+            // wallet lock is held until those keys are taken.
+            let (
+                funding_txs,
+                mut outgoing_swapcoins,
+                multisig_pubkeys,
+                multisig_nonces,
+                hashlock_nonces,
+            ) = {
+                let mut wallet = self.wallet.write().unwrap();
+                let (multisig_nonces, hashlock_nonces) =
+                    wallet.get_next_first_hop_nonces(self.ongoing_swap_state.swap_params.tx_count);
+                let (multisig_pubkeys, hashlock_pubkeys) = calculate_maker_keys(
                     &maker.offer.tweakable_point,
-                    self.ongoing_swap_state.swap_params.tx_count,
+                    &multisig_nonces,
+                    &hashlock_nonces,
                 );
 
-            //TODO: Figure out where to use the fee.
-            let (funding_txs, mut outgoing_swapcoins, _fee) = self.wallet.initalize_coinswap(
-                self.rpc,
-                self.ongoing_swap_state.swap_params.send_amount,
-                &multisig_pubkeys,
-                &hashlock_pubkeys,
-                self.get_preimage_hash(),
-                swap_locktime,
-                self.ongoing_swap_state.swap_params.fee_rate,
-            )?;
+                //TODO: Figure out where to use the fee.
+                let (funding_txs, outgoing_swapcoins, _fee) = wallet.initalize_coinswap(
+                    self.rpc.as_ref(),
+                    self.ongoing_swap_state.swap_params.send_amount,
+                    &multisig_pubkeys,
+                    &hashlock_pubkeys,
+                    self.get_preimage_hash(),
+                    swap_locktime,
+                    self.ongoing_swap_state.swap_params.fee_rate,
+                )?;
+                (
+                    funding_txs,
+                    outgoing_swapcoins,
+                    multisig_pubkeys,
+                    multisig_nonces,
+                    hashlock_nonces,
+                )
+            };
 
             let contract_reedemscripts = outgoing_swapcoins
                 .iter()
@@ -371,7 +1114,7 @@ impl<'taker> Taker<'taker> {
             // Request for Sender's Signatures
             let contract_sigs = match self
                 .req_sigs_for_sender(
-                    &maker.address,
+                    &maker,
                     &outgoing_swapcoins,
                     &multisig_nonces,
                     &hashlock_nonces,
@@ -382,7 +1125,10 @@ impl<'taker> Taker<'taker> {
                 Ok(contract_sigs) => contract_sigs,
                 Err(e) => {
                     // Bad maker, mark it, and try next one.
-                    self.offerbook.add_bad_maker(&maker);
+                    self.offerbook.add_bad_maker(
+                        &maker,
+                        format!("failed to sign senders contract tx: {:?}", e),
+                    );
                     log::debug!(
                         "Failed to obtain senders contract tx signature from first_maker {}: {:?}",
                         maker.address,
@@ -412,47 +1158,76 @@ impl<'taker> Taker<'taker> {
                 });
 
             for outgoing_swapcoin in &outgoing_swapcoins {
-                self.wallet.add_outgoing_swapcoin(outgoing_swapcoin.clone());
+                self.wallet
+                    .write()
+                    .unwrap()
+                    .add_outgoing_swapcoin(outgoing_swapcoin.clone())?;
             }
-            self.wallet.save_to_disk().unwrap();
 
             self.ongoing_swap_state.outgoing_swapcoins = outgoing_swapcoins;
 
             break funding_txs;
         };
+        self.ongoing_swap_state.my_funding_txs = funding_txs;
 
-        // Boradcast amd wait for funding txs to confirm
-        log::debug!("My Funding Txids:  {:#?}", funding_txs);
+        // Nothing is broadcast until the swap state is safely on disk, otherwise a crash
+        // could leave coins in the multisig without us knowing the route.
+        self.journal_swap_state()
+    }
+
+    /// Broadcast our funding txs of the first hop, unless they already are, and wait for them
+    /// to confirm.
+    async fn broadcast_first_hop_funding_txs(&mut self) -> Result<(), TeleportError> {
+        log::debug!(
+            "My Funding Txids:  {:#?}",
+            self.ongoing_swap_state.my_funding_txs
+        );
         log::debug!(
             "Outgoing SwapCoins: {:#?}",
             self.ongoing_swap_state.outgoing_swapcoins
         );
 
-        let funding_txids = funding_txs
+        let funding_txids = self
+            .ongoing_swap_state
+            .my_funding_txs
             .iter()
             .map(|tx| {
+                //when resuming, the funding txes may have been broadcast before the crash
+                if self.rpc.get_transaction(&tx.txid()).is_ok() {
+                    return Ok(tx.txid());
+                }
                 let txid = self.rpc.send_raw_transaction(tx)?;
                 log::info!("Broadcasting My Funding Tx: {}", txid);
                 assert_eq!(txid, tx.txid());
                 Ok(txid)
             })
-            .collect::<Result<_, TeleportError>>()?;
-
-        //unwrap the option without checking for Option::None because we passed no contract txes
-        //to watch and therefore they cant be broadcast
-        let (funding_txs, funding_tx_merkleproofs) =
-            self.watch_for_txs(&funding_txids).await?.unwrap();
+            .collect::<Result<Vec<_>, TeleportError>>()?;
+        self.events.send(SwapEvent::FundingBroadcast {
+            hashvalue: self.get_preimage_hash(),
+            txids: funding_txids.clone(),
+        });
 
-        self.ongoing_swap_state
-            .funding_txs
-            .push((funding_txs, funding_tx_merkleproofs));
+        //our own contract txs are watched here, if they get broadcast the swap state is marked
+        if let Some((funding_txs, funding_tx_merkleproofs)) =
+            self.watch_for_txs(&funding_txids).await?
+        {
+            self.ongoing_swap_state
+                .funding_txs
+                .push((funding_txs, funding_tx_merkleproofs));
+            self.events.send(SwapEvent::HopEstablished {
+                hashvalue: self.get_preimage_hash(),
+                hop: Some(0),
+                funding_txids,
+            });
+        }
 
         Ok(())
     }
 
     /// Return a list of confirmed funding txs with their corresponding merkel proofs.
     /// Returns None, if any of the watching contract transactions has been broadcasted,
-    /// which indicates violation of the protocol by one of the Makers.
+    /// which indicates violation of the protocol by one of the Makers. The deviating makers
+    /// are banned and the swap state is marked for recovery.
     async fn watch_for_txs(
         &mut self,
         funding_txids: &Vec<Txid>,
@@ -497,19 +1272,20 @@ impl<'taker> Taker<'taker> {
             required_confirmations
         );
         let mut txids_seen_once = HashSet::<Txid>::new();
+        let mut txid_confirmations = HashMap::<Txid, i32>::new();
         loop {
             for txid in funding_txids {
                 if txid_tx_map.contains_key(txid) {
                     continue;
                 }
-                let gettx = match self.rpc.get_transaction(txid, Some(true)) {
+                let gettx = match self.rpc.get_transaction(txid) {
                     Ok(r) => r,
                     //if we lose connection to the node, just try again, no point returning an error
                     Err(_e) => continue,
                 };
                 if !txids_seen_once.contains(txid) {
                     txids_seen_once.insert(*txid);
-                    if gettx.info.confirmations == 0 {
+                    if gettx.confirmations == 0 {
                         let mempool_tx = match self.rpc.get_mempool_entry(txid) {
                             Ok(m) => m,
                             Err(_e) => continue,
@@ -517,14 +1293,24 @@ impl<'taker> Taker<'taker> {
                         log::info!(
                             "Seen in mempool: {} [{:.1} sat/vbyte]",
                             txid,
-                            mempool_tx.fees.base.as_sat() as f32 / mempool_tx.vsize as f32
+                            mempool_tx.fee.as_sat() as f32 / mempool_tx.vsize as f32
                         );
                     }
                 }
+                if gettx.confirmations >= 0
+                    && txid_confirmations.insert(*txid, gettx.confirmations)
+                        != Some(gettx.confirmations)
+                {
+                    self.events.send(SwapEvent::FundingConfirmations {
+                        hashvalue: self.get_preimage_hash(),
+                        txid: *txid,
+                        confirmations: gettx.confirmations as u32,
+                    });
+                }
                 //TODO handle confirm<0
-                if gettx.info.confirmations >= required_confirmations {
-                    txid_tx_map.insert(*txid, deserialize::<Transaction>(&gettx.hex).unwrap());
-                    txid_blockhash_map.insert(*txid, gettx.info.blockhash.unwrap());
+                if gettx.confirmations >= required_confirmations {
+                    txid_tx_map.insert(*txid, gettx.transaction);
+                    txid_blockhash_map.insert(*txid, gettx.blockhash.unwrap());
                     log::debug!(
                         "funding tx {} reached {} confirmation(s)",
                         txid,
@@ -545,12 +1331,12 @@ impl<'taker> Taker<'taker> {
                             .get_tx_out_proof(&[txid], Some(txid_blockhash_map.get(&txid).unwrap()))
                             .map(|gettxoutproof_result| gettxoutproof_result.to_hex())
                     })
-                    .collect::<Result<Vec<String>, bitcoincore_rpc::Error>>()?;
+                    .collect::<Result<Vec<String>, TeleportError>>()?;
                 return Ok(Some((txes, merkleproofs)));
             }
             if !contracts_to_watch.is_empty() {
                 let contracts_broadcasted = check_for_broadcasted_contract_txes(
-                    self.rpc,
+                    self.rpc.as_ref(),
                     &contracts_to_watch
                         .iter()
                         .map(|txes| ContractsInfo {
@@ -571,6 +1357,22 @@ impl<'taker> Taker<'taker> {
                 )?;
                 if !contracts_broadcasted.is_empty() {
                     log::info!("Contract transactions were broadcasted! Aborting");
+                    //groups are in hop order, the last one being our own outgoing contracts
+                    let broadcasted_hops = contracts_to_watch
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, txes)| {
+                            contracts_broadcasted.iter().any(|contract| {
+                                contract
+                                    .contract_txes
+                                    .iter()
+                                    .any(|ctx| txes.iter().any(|tx| tx.txid() == ctx.tx.txid()))
+                            })
+                        })
+                        .map(|(hop, _)| hop)
+                        .collect::<Vec<usize>>();
+                    self.record_deviating_makers(&broadcasted_hops);
+                    self.ongoing_swap_state.contracts_broadcasted = true;
                     return Ok(None);
                 }
             }
@@ -699,7 +1501,7 @@ impl<'taker> Taker<'taker> {
                                 &self.ongoing_swap_state.peer_infos.last().expect("at least one active maker expected").peer.address,
                                 e
                             );
-                            if ii <= self.config.reconnect_attempts {
+                            if ii <= self.config.reconnect_attempts && is_retryable(&e) {
                                 sleep(Duration::from_secs(
                                     if ii <= self.config.short_long_sleep_delay_transition {
                                         self.config.reconnect_short_sleep_delay
@@ -748,8 +1550,12 @@ impl<'taker> Taker<'taker> {
 
         log::info!("Connecting to {}", this_maker.address);
         let mut socket = TcpStream::connect(this_maker.address.get_tcpstream_address()).await?;
-        let (mut socket_reader, mut socket_writer) =
-            handshake_maker(&mut socket, &this_maker.address).await?;
+        let (mut socket_reader, mut socket_writer) = handshake_maker(
+            &mut socket,
+            &this_maker.address,
+            this_maker.offer.noise_static_key.as_ref(),
+        )
+        .await?;
         let mut next_maker = this_maker.clone();
         let (
             next_peer_multisig_pubkeys,
@@ -767,13 +1573,19 @@ impl<'taker> Taker<'taker> {
                 next_peer_hashlock_pubkeys,
                 next_peer_hashlock_keys_or_nonces,
             ) = if self.ongoing_swap_state.taker_position == TakerPosition::LastPeer {
-                let (my_recv_ms_pubkeys, my_recv_ms_nonce): (Vec<_>, Vec<_>) =
-                    (0..self.ongoing_swap_state.swap_params.tx_count)
-                        .map(|_| generate_keypair())
-                        .unzip();
-                let (my_recv_hashlock_pubkeys, my_recv_hashlock_nonce): (Vec<_>, Vec<_>) = (0
-                    ..self.ongoing_swap_state.swap_params.tx_count)
-                    .map(|_| generate_keypair())
+                let (my_recv_ms_pubkeys, my_recv_ms_nonce): (Vec<_>, Vec<_>) = self
+                    .wallet
+                    .write()
+                    .unwrap()
+                    .get_next_swapcoin_keypairs(self.ongoing_swap_state.swap_params.tx_count)?
+                    .into_iter()
+                    .unzip();
+                let (my_recv_hashlock_pubkeys, my_recv_hashlock_nonce): (Vec<_>, Vec<_>) = self
+                    .wallet
+                    .write()
+                    .unwrap()
+                    .get_next_swapcoin_keypairs(self.ongoing_swap_state.swap_params.tx_count)?
+                    .into_iter()
                     .unzip();
                 (
                     my_recv_ms_pubkeys,
@@ -783,6 +1595,11 @@ impl<'taker> Taker<'taker> {
                 )
             } else {
                 next_maker = self.choose_next_maker()?.clone();
+                self.events.send(SwapEvent::MakerChosen {
+                    hashvalue: self.get_preimage_hash(),
+                    hop: self.ongoing_swap_state.peer_infos.len() as u16,
+                    maker: next_maker.address.to_string(),
+                });
                 //next_maker is only ever accessed when the next peer is a maker, not a taker
                 //i.e. if its ever used when is_taker_next_peer == true, then thats a bug
                 generate_maker_keys(
@@ -869,7 +1686,7 @@ impl<'taker> Taker<'taker> {
                 )?;
                 let sigs = match self
                     .req_sigs_for_sender(
-                        &next_maker.address,
+                        &next_maker,
                         &watchonly_swapcoins,
                         &next_peer_multisig_keys_or_nonces,
                         &next_peer_hashlock_keys_or_nonces,
@@ -882,7 +1699,10 @@ impl<'taker> Taker<'taker> {
                         r
                     }
                     Err(e) => {
-                        self.offerbook.add_bad_maker(&next_maker);
+                        self.offerbook.add_bad_maker(
+                            &next_maker,
+                            format!("failed to sign senders contract tx: {:?}", e),
+                        );
                         log::debug!(
                             "Fail to obtain sender's contract tx signature from next_maker {}: {:?}",
                             next_maker.address,
@@ -921,10 +1741,10 @@ impl<'taker> Taker<'taker> {
         } else {
             // If Next Maker is the Receiver, and Previous Maker is the Sender, request Previous Maker to sign the Reciever's Contract Tx.
             assert!(previous_maker.is_some());
-            let previous_maker_addr = &previous_maker.unwrap().peer.address;
+            let previous_maker = &previous_maker.unwrap().peer;
             log::info!(
                 "===> Sending SignReceiversContractTx, previous maker is {}",
-                previous_maker_addr,
+                previous_maker.address,
             );
             let previous_maker_watchonly_swapcoins =
                 if self.ongoing_swap_state.taker_position == TakerPosition::LastPeer {
@@ -935,7 +1755,7 @@ impl<'taker> Taker<'taker> {
                         [self.ongoing_swap_state.watchonly_swapcoins.len() - 2]
                 };
             self.req_sigs_for_recvr(
-                previous_maker_addr,
+                previous_maker,
                 previous_maker_watchonly_swapcoins,
                 &contract_sigs_as_recvr_sender.receivers_contract_txs,
             )
@@ -992,7 +1812,7 @@ impl<'taker> Taker<'taker> {
         // is not equal to p2wsh(next_swap_contract_redeemscripts)
         for swapcoin in &next_swapcoins {
             crate::wallet_sync::import_watchonly_redeemscript(
-                self.rpc,
+                self.rpc.as_ref(),
                 &swapcoin.get_multisig_redeemscript(),
             )?
         }
@@ -1115,18 +1935,26 @@ impl<'taker> Taker<'taker> {
                 o_ms_pubkey1
             };
 
-            self.wallet.import_wallet_multisig_redeemscript(
-                self.rpc,
-                &o_ms_pubkey1,
-                &o_ms_pubkey2,
-            )?;
-            self.wallet.import_tx_with_merkleproof(
-                self.rpc,
+            self.wallet
+                .read()
+                .unwrap()
+                .import_wallet_multisig_redeemscript(
+                    self.rpc.as_ref(),
+                    &o_ms_pubkey1,
+                    &o_ms_pubkey2,
+                )?;
+            self.wallet.read().unwrap().import_tx_with_merkleproof(
+                self.rpc.as_ref(),
                 funding_tx,
                 funding_tx_merkleproof.clone(),
             )?;
             self.wallet
-                .import_wallet_contract_redeemscript(self.rpc, next_contract_redeemscript)?;
+                .read()
+                .unwrap()
+                .import_wallet_contract_redeemscript(
+                    self.rpc.as_ref(),
+                    next_contract_redeemscript,
+                )?;
 
             let mut incoming_swapcoin = IncomingSwapCoin::new(
                 maker_funded_multisig_privkey,
@@ -1161,7 +1989,7 @@ impl<'taker> Taker<'taker> {
         );
         let receiver_contract_sig = self
             .req_sigs_for_recvr(
-                &last_maker.address,
+                &last_maker,
                 &self.ongoing_swap_state.incoming_swapcoins,
                 &self
                     .ongoing_swap_state
@@ -1180,11 +2008,12 @@ impl<'taker> Taker<'taker> {
             incoming_swapcoin.others_contract_sig = Some(receiver_contract_sig);
         }
         for incoming_swapcoin in &self.ongoing_swap_state.incoming_swapcoins {
-            self.wallet.add_incoming_swapcoin(incoming_swapcoin.clone());
+            self.wallet
+                .write()
+                .unwrap()
+                .add_incoming_swapcoin(incoming_swapcoin.clone())?;
         }
 
-        self.wallet.save_to_disk().unwrap();
-
         Ok(())
     }
 
@@ -1192,18 +2021,19 @@ impl<'taker> Taker<'taker> {
     /// Keep trying until `first_connect_attempts` limit, with time delay of `first_connect_sleep_delay_sec`.
     async fn req_sigs_for_sender<S: SwapCoin>(
         &self,
-        maker_address: &MakerAddress,
+        maker: &OfferAndAddress,
         outgoing_swapcoins: &[S],
         maker_multisig_nonces: &[SecretKey],
         maker_hashlock_nonces: &[SecretKey],
         locktime: u16,
     ) -> Result<ContractSigsForSender, TeleportError> {
+        let maker_address = &maker.address;
         let mut ii = 0;
         loop {
             ii += 1;
             select! {
                 ret = req_sigs_for_sender_once(
-                    maker_address,
+                    maker,
                     outgoing_swapcoins,
                     maker_multisig_nonces,
                     maker_hashlock_nonces,
@@ -1218,7 +2048,7 @@ impl<'taker> Taker<'taker> {
                                 maker_address,
                                 e
                             );
-                            if ii <= self.config.first_connect_attempts {
+                            if ii <= self.config.first_connect_attempts && is_retryable(&e) {
                                 sleep(Duration::from_secs(self.config.first_connect_sleep_delay_sec)).await;
                                 continue;
                             } else {
@@ -1249,16 +2079,17 @@ impl<'taker> Taker<'taker> {
     /// after `short_long_sleep_delay_transition` time.
     async fn req_sigs_for_recvr<S: SwapCoin>(
         &self,
-        maker_address: &MakerAddress,
+        maker: &OfferAndAddress,
         incoming_swapcoins: &[S],
         receivers_contract_txes: &[Transaction],
     ) -> Result<ContractSigsForRecvr, TeleportError> {
+        let maker_address = &maker.address;
         let mut ii = 0;
         loop {
             ii += 1;
             select! {
                 ret = req_sigs_for_recvr_once(
-                    maker_address,
+                    maker,
                     incoming_swapcoins,
                     receivers_contract_txes,
                 ) => {
@@ -1271,7 +2102,7 @@ impl<'taker> Taker<'taker> {
                                 maker_address,
                                 e
                             );
-                            if ii <= self.config.reconnect_attempts {
+                            if ii <= self.config.reconnect_attempts && is_retryable(&e) {
                                 sleep(Duration::from_secs(
                                     if ii <= self.config.short_long_sleep_delay_transition {
                                         self.config.reconnect_short_sleep_delay
@@ -1310,13 +2141,14 @@ impl<'taker> Taker<'taker> {
         let mut outgoing_privkeys: Option<Vec<MultisigPrivkey>> = None;
 
         // Because the last peer info is the Taker, we take upto (0..n-1), where n = peer_info.len()
-        let maker_addresses = self.ongoing_swap_state.peer_infos
+        let makers = self.ongoing_swap_state.peer_infos
             [0..self.ongoing_swap_state.peer_infos.len() - 1]
             .iter()
-            .map(|si| si.peer.address.clone())
+            .map(|si| si.peer.clone())
             .collect::<Vec<_>>();
 
-        for (index, maker_address) in maker_addresses.iter().enumerate() {
+        for (index, maker) in makers.iter().enumerate() {
+            let maker_address = &maker.address;
             if index == 0 {
                 self.ongoing_swap_state.taker_position = TakerPosition::FirstPeer;
             } else if index == (self.ongoing_swap_state.swap_params.maker_count - 1) as usize {
@@ -1365,7 +2197,7 @@ impl<'taker> Taker<'taker> {
                 ii += 1;
                 select! {
                     ret = self.settle_one_coinswap(
-                        maker_address,
+                        maker,
                         index,
                         &mut outgoing_privkeys,
                         &senders_multisig_redeemscripts,
@@ -1378,7 +2210,7 @@ impl<'taker> Taker<'taker> {
                                 maker_address,
                                 e
                             );
-                            if ii <= self.config.reconnect_attempts {
+                            if ii <= self.config.reconnect_attempts && is_retryable(&e) {
                                 sleep(Duration::from_secs(
                                     if ii <= self.config.short_long_sleep_delay_transition {
                                         self.config.reconnect_short_sleep_delay
@@ -1415,16 +2247,21 @@ impl<'taker> Taker<'taker> {
     /// [Internal] Setlle one swap. This is recursively called for all the makers.
     async fn settle_one_coinswap<'a>(
         &mut self,
-        maker_address: &MakerAddress,
+        maker: &OfferAndAddress,
         index: usize,
         outgoing_privkeys: &mut Option<Vec<MultisigPrivkey>>,
         senders_multisig_redeemscripts: &Vec<Script>,
         receivers_multisig_redeemscripts: &Vec<Script>,
     ) -> Result<(), TeleportError> {
+        let maker_address = &maker.address;
         log::info!("Connecting to {}", maker_address);
         let mut socket = TcpStream::connect(maker_address.get_tcpstream_address()).await?;
-        let (mut socket_reader, mut socket_writer) =
-            handshake_maker(&mut socket, maker_address).await?;
+        let (mut socket_reader, mut socket_writer) = handshake_maker(
+            &mut socket,
+            maker_address,
+            maker.offer.noise_static_key.as_ref(),
+        )
+        .await?;
 
         log::info!("===> Sending HashPreimage to {}", maker_address);
         let maker_private_key_handover = send_hash_preimage_and_get_private_keys(
@@ -1436,6 +2273,11 @@ impl<'taker> Taker<'taker> {
         )
         .await?;
         log::info!("<=== Received PrivateKeyHandover from {}", maker_address);
+        if index == 0 {
+            self.events.send(SwapEvent::PreimageRevealed {
+                hashvalue: self.get_preimage_hash(),
+            });
+        }
 
         let privkeys_reply = if self.ongoing_swap_state.taker_position == TakerPosition::FirstPeer {
             self.ongoing_swap_state
@@ -1452,7 +2294,8 @@ impl<'taker> Taker<'taker> {
             *outgoing_privkeys = None;
             reply
         };
-        if self.ongoing_swap_state.taker_position == TakerPosition::LastPeer {
+        let applied_privkeys = if self.ongoing_swap_state.taker_position == TakerPosition::LastPeer
+        {
             check_and_apply_maker_private_keys(
                 &mut self.ongoing_swap_state.incoming_swapcoins,
                 &maker_private_key_handover.multisig_privkeys,
@@ -1467,7 +2310,15 @@ impl<'taker> Taker<'taker> {
             );
             *outgoing_privkeys = Some(maker_private_key_handover.multisig_privkeys);
             ret
-        }?;
+        };
+        if applied_privkeys.is_err() {
+            return reject(
+                &mut socket_writer,
+                ProtocolErrorCode::InvalidPrivateKey,
+                "wrong privkey",
+            )
+            .await;
+        }
         log::info!("===> Sending PrivateKeyHandover to {}", maker_address);
         send_message(
             &mut socket_writer,
@@ -1481,18 +2332,76 @@ impl<'taker> Taker<'taker> {
 
     // ######## UTILITY AND HELPERS ############
 
-    /// Choose a suitable **untried** maker address from the offerbook that fits the swap params.
+    /// Choose a suitable **untried** maker from the offerbook that fits the swap params.
+    /// Makers are picked at random weighted by their fidelity bond value, so that a sybil
+    /// attacker has to lock up lots of coins to be picked often. Makers sharing a host or bond
+    /// with a maker already in the route are skipped, and makers which failed recently
+    /// are given less weight.
     fn choose_next_maker(&self) -> Result<OfferAndAddress, TeleportError> {
-        let send_amount = self.ongoing_swap_state.swap_params.send_amount;
+        let swap_params = &self.ongoing_swap_state.swap_params;
+        let send_amount = swap_params.send_amount;
         if send_amount == 0 {
             return Err(TeleportError::Protocol("Coinswap send amount not set!!"));
         }
 
-        Ok(self
+        let route_makers = self
+            .ongoing_swap_state
+            .peer_infos
+            .iter()
+            .map(|peer_info| &peer_info.peer)
+            .collect::<Vec<_>>();
+        let route_fee = route_makers
+            .iter()
+            .map(|maker| estimate_maker_fee(&maker.offer, send_amount))
+            .sum::<u64>();
+
+        let now = get_unix_time();
+        let candidates = self
             .offerbook
             .get_all_untried()
-            .iter()
-            .find(|oa| send_amount > oa.offer.min_size && send_amount < oa.offer.max_size)
+            .into_iter()
+            .filter(|oa| send_amount > oa.offer.min_size && send_amount < oa.offer.max_size)
+            .filter(|oa| {
+                self.offerbook.get_fidelity_bond_value(&oa.address)
+                    >= swap_params.min_fidelity_bond_value
+            })
+            .filter(|oa| {
+                swap_params.max_total_fee.is_none_or(|max_total_fee| {
+                    route_fee + estimate_maker_fee(&oa.offer, send_amount) <= max_total_fee
+                })
+            })
+            .filter(|oa| !route_makers.iter().any(|maker| is_same_operator(maker, oa)))
+            .map(|oa| {
+                let penalty = 1.0 + self.offerbook.get_failure_score(&oa.address, now);
+                let bond_value = self.offerbook.get_fidelity_bond_value(&oa.address);
+                (oa, bond_value, penalty)
+            })
+            .collect::<Vec<_>>();
+
+        let mut rng = OsRng::new().unwrap();
+        let chosen = if rng.gen::<f64>() >= BONDLESS_MAKERS_ALLOWANCE {
+            choose_by_weight(
+                candidates
+                    .iter()
+                    .map(|(oa, bond_value, penalty)| (oa, bond_value / penalty))
+                    .collect(),
+                rng.gen(),
+            )
+        } else {
+            None
+        };
+        //also reached when none of the candidates have a fidelity bond
+        let chosen = chosen.or_else(|| {
+            choose_by_weight(
+                candidates
+                    .iter()
+                    .map(|(oa, _, penalty)| (oa, 1.0 / penalty))
+                    .collect(),
+                rng.gen(),
+            )
+        });
+
+        Ok(chosen
             .ok_or(TeleportError::Protocol(
                 "Could not find suitable maker matching requirements of swap parameters",
             ))?
@@ -1514,6 +2423,29 @@ impl<'taker> Taker<'taker> {
         self.ongoing_swap_state = OngoingSwapState::default();
     }
 
+    /// Write the [OngoingSwapState] to the swap journal.
+    fn journal_swap_state(&self) -> Result<(), TeleportError> {
+        write_swap_journal(
+            &self.swap_journal_file_path,
+            self.wallet.read().unwrap().get_storage(),
+            &self.ongoing_swap_state,
+        )
+    }
+
+    //once funds are committed its better to keep going with a stale journal than to stop
+    fn journal_swap_state_or_log(&self) {
+        if let Err(e) = self.journal_swap_state() {
+            log::warn!("unable to write swap journal: {:?}", e);
+        }
+    }
+
+    fn remove_swap_journal(&self) -> Result<(), TeleportError> {
+        match fs::remove_file(&self.swap_journal_file_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     /// Save all the finalized swap data and reset the [OngoingSwapState].
     fn save_and_reset_swap_round(&mut self) {
         for (index, watchonly_swapcoin) in self
@@ -1540,38 +2472,332 @@ impl<'taker> Taker<'taker> {
                 .collect::<Vec<_>>()
         );
 
+        let mut wallet = self.wallet.write().unwrap();
         for incoming_swapcoin in &self.ongoing_swap_state.incoming_swapcoins {
-            self.wallet
-                .find_incoming_swapcoin_mut(&incoming_swapcoin.get_multisig_redeemscript())
+            let multisig_redeemscript = incoming_swapcoin.get_multisig_redeemscript();
+            wallet
+                .find_incoming_swapcoin_mut(&multisig_redeemscript)
                 .unwrap()
                 .other_privkey = incoming_swapcoin.other_privkey;
+            wallet
+                .save_incoming_swapcoin(&multisig_redeemscript)
+                .unwrap();
         }
-        self.wallet.save_to_disk().unwrap();
+        drop(wallet);
 
         self.clear_ongoing_swaps();
     }
 }
 
+//makers currently charge for just 1 block of time, see handle_proof_of_funding
+fn estimate_maker_fee(offer: &Offer, amount: u64) -> u64 {
+    calculate_coinswap_fee(
+        offer.absolute_fee_sat,
+        offer.amount_relative_fee_ppb,
+        offer.time_relative_fee_ppb,
+        amount,
+        1,
+    )
+}
+
+/// A maker which rejected a request with an error message would only reject it again, unless
+/// it had a problem of its own.
+fn is_retryable(e: &TeleportError) -> bool {
+    match e {
+        TeleportError::Peer(error) => error.code.is_retryable(),
+        _ => true,
+    }
+}
+
+/// Two offers are assumed to come from the same operator if they share a host or a fidelity bond.
+fn is_same_operator(a: &OfferAndAddress, b: &OfferAndAddress) -> bool {
+    let same_bond = match (&a.offer.fidelity_bond_proof, &b.offer.fidelity_bond_proof) {
+        (Some(a_proof), Some(b_proof)) => a_proof.utxo == b_proof.utxo,
+        _ => false,
+    };
+    same_bond || a.address.shares_host_with(&b.address)
+}
+
+/// Pick an item with probability proportional to its weight, `random` being uniform in [0, 1).
+/// Returns None if there are no items with a positive weight.
+fn choose_by_weight<T>(items: Vec<(T, f64)>, random: f64) -> Option<T> {
+    let total_weight = items.iter().map(|(_, weight)| weight).sum::<f64>();
+    if total_weight <= 0.0 {
+        return None;
+    }
+    let mut target = random * total_weight;
+    let mut last_positive = None;
+    for (item, weight) in items {
+        if weight <= 0.0 {
+            continue;
+        }
+        if target < weight {
+            return Some(item);
+        }
+        target -= weight;
+        last_positive = Some(item);
+    }
+    //only reached through floating point rounding
+    last_positive
+}
+
 #[tokio::main]
-pub async fn start_taker(rpc: &Client, wallet: &mut Wallet, config: SwapParams) {
-    match run(rpc, wallet, config).await {
-        Ok(_o) => (),
-        Err(e) => log::error!("err {:?}", e),
+pub async fn start_taker(
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    config: SwapParams,
+    offerbook_file_path: &Path,
+    swap_journal_file_path: &Path,
+) {
+    let result = match Taker::new(
+        rpc,
+        wallet,
+        TakerConfig::default(),
+        offerbook_file_path,
+        swap_journal_file_path,
+    ) {
+        Ok(mut taker) => taker.send_coinswap(config).await,
+        Err(e) => Err(e),
     };
+    if let Err(e) = result {
+        log::error!("err {:?}", e);
+    }
 }
 
-/// The main driver innitializing and starting a swap round.
-async fn run(
-    rpc: &Client,
-    wallet: &mut Wallet,
-    swap_params: SwapParams,
-) -> Result<(), TeleportError> {
-    let offers_addresses = sync_offerbook(wallet.network)
-        .await
-        .expect("unable to sync maker addresses from directory servers");
+/// Download the offers from the makers and verify their fidelity bonds.
+async fn get_verified_offers(
+    rpc: &dyn BlockchainBackend,
+    maker_addresses: Vec<MakerAddress>,
+) -> Result<Vec<(OfferAndAddress, Option<f64>)>, TeleportError> {
+    let offers_addresses = sync_offerbook_with_addresses(maker_addresses).await;
     log::info!("<=== Got Offers ({} offers)", offers_addresses.len());
     log::debug!("Offers : {:#?}", offers_addresses);
-    let mut taker = Taker::init(wallet, rpc, offers_addresses);
-    taker.send_coinswap(swap_params).await?;
-    Ok(())
+    let offers_addresses = verify_fidelity_bonds(rpc, offers_addresses)?;
+    for (offer_address, fidelity_bond_value) in offers_addresses.iter() {
+        if let Some(value) = fidelity_bond_value {
+            log::info!(
+                "Maker {} has fidelity bond value {:.0}",
+                offer_address.address,
+                value
+            );
+        }
+    }
+    Ok(offers_addresses)
+}
+
+#[tokio::main]
+pub async fn resume_taker(
+    rpc: Arc<dyn BlockchainBackend>,
+    wallet: Arc<RwLock<Wallet>>,
+    offerbook_file_path: &Path,
+    swap_journal_file_path: &Path,
+) {
+    let result = match Taker::new(
+        rpc,
+        wallet,
+        TakerConfig::default(),
+        offerbook_file_path,
+        swap_journal_file_path,
+    ) {
+        Ok(mut taker) => taker.resume_coinswap().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log::error!("err {:?}", e);
+    }
+}
+
+/// Which makers held both signatures of the contract txs of the broadcasted hops, as pairs of
+/// maker index and hop number counting our own outgoing hop as hop 1. Hops are numbered as
+/// grouped in `watch_for_txs`, the hops between makers in order followed by our own outgoing
+/// hop. The sender of a hop gets the receiver's signatures before funding it, the receiver only
+/// gets the sender's signatures after that funding confirmed, together with the request to
+/// fund the next hop. So the receiver of the last watched hop can't have broadcast it, and we
+/// didn't broadcast our own contract txs so the first maker did.
+fn makers_holding_contract_sigs(
+    broadcasted_hops: &[usize],
+    watchonly_hop_count: usize,
+) -> Vec<(usize, usize)> {
+    let mut makers = Vec::<(usize, usize)>::new();
+    for &hop in broadcasted_hops {
+        if hop >= watchonly_hop_count {
+            makers.push((0, 1));
+            continue;
+        }
+        makers.push((hop, hop + 2));
+        if hop + 1 < watchonly_hop_count {
+            makers.push((hop + 1, hop + 2));
+        }
+    }
+    makers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        simulated_blockchain::SimulatedBlockchain, wallet_storage::temp_wallet_path,
+        wallet_sync::create_temp_wallet,
+    };
+
+    #[test]
+    fn test_maker_reputation_ban_and_decay() {
+        let mut reputation = MakerReputation::default();
+        let now = 1_000_000;
+
+        reputation.record_failure(now, "timeout".to_string());
+        reputation.record_failure(now, "timeout".to_string());
+        assert!(!reputation.is_banned(now));
+        assert_eq!(reputation.get_failure_score(now), 2.0);
+
+        // Failures are forgotten over time.
+        let later = now + FAILURE_SCORE_HALF_LIFE_SEC;
+        assert_eq!(reputation.get_failure_score(later), 1.0);
+        reputation.record_success(later);
+        assert_eq!(reputation.get_failure_score(later), 0.5);
+
+        // But failing repeatedly in a short while gets the maker banned.
+        reputation.record_failure(later, "timeout".to_string());
+        reputation.record_failure(later, "timeout".to_string());
+        reputation.record_failure(later, "bad signature".to_string());
+        assert!(reputation.is_banned(later));
+        assert!(!reputation.is_banned(later + BAN_DURATION_SEC));
+        assert_eq!(reputation.failure_count, 5);
+        assert_eq!(reputation.failures.last().unwrap().reason, "bad signature");
+
+        // Broadcasting contract txs is never forgiven.
+        let mut deviator = MakerReputation::default();
+        deviator.record_deviation(now, "broadcast contract txs".to_string());
+        assert!(deviator.is_banned(now + BAN_DURATION_SEC * 1000));
+    }
+
+    #[test]
+    fn test_choose_by_weight() {
+        let items = vec![("a", 1.0), ("b", 0.0), ("c", 3.0)];
+        assert_eq!(choose_by_weight(items.clone(), 0.0), Some("a"));
+        assert_eq!(choose_by_weight(items.clone(), 0.24), Some("a"));
+        assert_eq!(choose_by_weight(items.clone(), 0.25), Some("c"));
+        assert_eq!(choose_by_weight(items.clone(), 0.999), Some("c"));
+
+        // Nothing to choose when no item has any weight, e.g. no maker has a fidelity bond.
+        assert_eq!(choose_by_weight(vec![("a", 0.0), ("b", 0.0)], 0.5), None);
+        assert_eq!(choose_by_weight(Vec::<(&str, f64)>::new(), 0.5), None);
+    }
+
+    #[test]
+    fn test_makers_holding_contract_sigs() {
+        // Our own outgoing hop is the last group, only the first maker could have broadcast it.
+        assert_eq!(makers_holding_contract_sigs(&[0], 0), vec![(0, 1)]);
+        assert_eq!(makers_holding_contract_sigs(&[2], 2), vec![(0, 1)]);
+
+        // The receiver of the last watched hop wasn't given the sender's signatures yet.
+        assert_eq!(makers_holding_contract_sigs(&[1], 2), vec![(1, 3)]);
+        assert_eq!(
+            makers_holding_contract_sigs(&[0, 2], 2),
+            vec![(0, 2), (1, 2), (0, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_only_stops_running_coinswap() {
+        let wallet = create_temp_wallet("cancel");
+        let wallet_path = wallet.get_wallet_file_path().to_path_buf();
+        let taker = Taker::new(
+            Arc::new(SimulatedBlockchain::new()),
+            Arc::new(RwLock::new(wallet)),
+            TakerConfig::default(),
+            &wallet_path.with_extension("offerbook"),
+            &wallet_path.with_extension("swap"),
+        )
+        .unwrap();
+        let handle = taker.handle();
+
+        // A cancel while no coinswap is running doesn't cancel the next one.
+        handle.cancel();
+        let mut cancelled = Box::pin(taker.cancelled());
+        let short_wait = Duration::from_millis(100);
+        assert!(tokio::time::timeout(short_wait, &mut cancelled)
+            .await
+            .is_err());
+        handle.cancel();
+        assert!(tokio::time::timeout(short_wait, cancelled).await.is_ok());
+
+        fs::remove_file(&wallet_path).unwrap();
+    }
+
+    #[test]
+    fn test_swap_journal_encrypted_with_wallet() {
+        let wallet_path = temp_wallet_path("journal");
+        let journal_path = wallet_path.with_extension("swap");
+        let mut storage = WalletStorage::create(&wallet_path, "seed words", "", None).unwrap();
+        let state = OngoingSwapState {
+            last_synced_height: Some(100),
+            ..OngoingSwapState::default()
+        };
+        write_swap_journal(&journal_path, &storage, &state).unwrap();
+        assert!(fs::read_to_string(&journal_path)
+            .unwrap()
+            .contains("last_synced_height"));
+
+        // Encrypting the wallet encrypts the journal of an interrupted coinswap too.
+        change_wallet_passphrase(&mut storage, &journal_path, Some("passphrase")).unwrap();
+        assert!(!fs::read_to_string(&journal_path)
+            .unwrap()
+            .contains("last_synced_height"));
+        let state = read_swap_journal(&journal_path, &storage).unwrap();
+        assert_eq!(state.last_synced_height, Some(100));
+
+        fs::remove_file(&journal_path).unwrap();
+        fs::remove_file(&wallet_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_without_funding_broadcast() {
+        let wallet = create_temp_wallet("resume-unfunded");
+        let wallet_path = wallet.get_wallet_file_path().to_path_buf();
+        let offerbook_path = wallet_path.with_extension("offerbook");
+        let journal_path = wallet_path.with_extension("swap");
+
+        // Interrupted after journaling a funding tx which wasn't broadcast, and can't be now.
+        let funding_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: OutPoint::new(Txid::from_slice(&[1; 32]).unwrap(), 0),
+                ..Default::default()
+            }],
+            output: vec![bitcoin::TxOut {
+                value: 100_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let state = OngoingSwapState {
+            swap_params: SwapParams {
+                maker_count: 2,
+                ..SwapParams::default()
+            },
+            my_funding_txs: vec![funding_tx],
+            ..OngoingSwapState::default()
+        };
+        write_swap_journal(&journal_path, wallet.get_storage(), &state).unwrap();
+
+        let mut taker = Taker::new(
+            Arc::new(SimulatedBlockchain::new()),
+            Arc::new(RwLock::new(wallet)),
+            TakerConfig {
+                maker_addresses: Some(Vec::new()),
+                ..TakerConfig::default()
+            },
+            &offerbook_path,
+            &journal_path,
+        )
+        .unwrap();
+        // Nothing is locked in the swap, so the journal is dropped instead of blocking the
+        // next coinswap.
+        taker.resume_coinswap().await.unwrap();
+        assert!(!journal_path.exists());
+
+        let _ = fs::remove_file(&offerbook_path);
+        fs::remove_file(&wallet_path).unwrap();
+    }
 }
@@ -0,0 +1,431 @@
+//! Framing of the messages between takers, makers and watchtowers.
+//!
+//! Messages are JSON. Peers speaking protocol version 1 or later send them as frames of a 4 byte
+//! big-endian payload length, a flags byte and the payload, which may be deflate compressed if
+//! the receiver announced [Capabilities::COMPRESSION]. Older peers send one message per line.
+//! The hello messages are always sent as lines, since the protocol version is not known yet
+//! when they are sent.
+//!
+//! Reading accepts both, a frame can't start with `{` because its length is far below 2^24.
+//! Frames may also carry a noise handshake, after which all frames are encrypted, see
+//! [crate::noise].
+//! Nothing larger than [MAX_MESSAGE_SIZE] is buffered, each message type may have a smaller
+//! limit, and a message which started arriving has to be complete within
+//! [MESSAGE_READ_TIMEOUT].
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+
+use crate::{
+    error::TeleportError,
+    messages::Capabilities,
+    noise::{self, Cipher},
+};
+
+/// Largest message of any type, in bytes. Compressed payloads are limited both before and after
+/// decompression.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+/// Limit for messages which don't grow with the number of transactions in a coinswap.
+pub const SMALL_MESSAGE_SIZE: usize = 16 * 1024;
+/// Time allowed for the rest of a message to arrive after its first byte, so a peer can't
+/// hold a connection by sending very slowly. Waiting for the next message is up to the caller.
+pub const MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// First protocol version in which messages after the hellos are length-prefixed frames.
+pub const FRAMED_PROTOCOL_VERSION: u32 = 1;
+
+const FRAME_HEADER_LEN: usize = 5;
+const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE + noise::MAX_CIPHERTEXT_OVERHEAD;
+const FLAG_COMPRESSED: u8 = 1 << 0;
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+//handshake frames are never combined with other flags
+const FLAG_HANDSHAKE: u8 = 1 << 2;
+//smaller payloads dont get any smaller by compressing them
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// A message which can be sent with this module.
+pub trait WireMessage: Serialize + DeserializeOwned {
+    /// Largest serialized size accepted for the message with this `method` tag, in bytes. It's
+    /// looked up before the message is parsed, messages without a tag are looked up with "".
+    fn max_size(_method: &str) -> usize {
+        MAX_MESSAGE_SIZE
+    }
+}
+
+//just the tag of a message, so its limit is known before the rest is parsed
+#[derive(Deserialize)]
+struct MessageTag<'a> {
+    #[serde(borrow)]
+    method: Option<&'a str>,
+}
+
+/// How messages are written to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON message per line, for the hellos and peers older than
+    /// [FRAMED_PROTOCOL_VERSION].
+    Lines,
+    LengthPrefixed {
+        compress: bool,
+    },
+}
+
+impl Framing {
+    /// The framing to use with a peer after the hellos.
+    pub fn negotiated(protocol_version: u32, peer_capabilities: Capabilities) -> Framing {
+        if protocol_version >= FRAMED_PROTOCOL_VERSION {
+            Framing::LengthPrefixed {
+                compress: peer_capabilities.contains(Capabilities::COMPRESSION),
+            }
+        } else {
+            Framing::Lines
+        }
+    }
+}
+
+/// A frame read from a peer.
+pub(crate) enum Frame {
+    /// The payload of a message, decrypted and decompressed.
+    Message(Vec<u8>),
+    /// A message of the noise handshake, see [crate::noise].
+    Handshake(Vec<u8>),
+}
+
+/// Write half of a connection, which remembers the framing agreed with the peer and encrypts
+/// once a noise handshake was done.
+pub struct MessageWriter<W> {
+    writer: W,
+    framing: Framing,
+    cipher: Option<Arc<Cipher>>,
+    nonce: u64,
+    //the first line sent, which is the hello
+    hello: Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    /// Starts out writing lines, for the hellos.
+    pub fn new(writer: W) -> MessageWriter<W> {
+        MessageWriter {
+            writer,
+            framing: Framing::Lines,
+            cipher: None,
+            nonce: 0,
+            hello: Vec::new(),
+        }
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: Arc<Cipher>) {
+        self.cipher = Some(cipher);
+    }
+
+    /// The hello line sent, empty if nothing was sent as a line.
+    pub(crate) fn hello(&self) -> &[u8] {
+        &self.hello
+    }
+
+    pub async fn send<M: WireMessage>(&mut self, message: &M) -> Result<(), TeleportError> {
+        let payload = serde_json::to_vec(message).map_err(io::Error::from)?;
+        let bytes = match (self.framing, &self.cipher) {
+            (Framing::Lines, None) => {
+                let mut line = payload;
+                line.push(b'\n');
+                if self.hello.is_empty() {
+                    self.hello = line.clone();
+                }
+                line
+            }
+            (framing, cipher) => {
+                let (mut flags, mut payload) = (0, payload);
+                if framing == (Framing::LengthPrefixed { compress: true })
+                    && payload.len() >= COMPRESSION_THRESHOLD
+                {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(&payload)?;
+                    payload = encoder.finish()?;
+                    flags |= FLAG_COMPRESSED;
+                }
+                if payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(TeleportError::Protocol("message too large"));
+                }
+                if let Some(cipher) = cipher {
+                    payload = cipher.encrypt(&mut self.nonce, &payload)?;
+                    flags |= FLAG_ENCRYPTED;
+                }
+                encode_frame(flags, &payload)
+            }
+        };
+        self.writer.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn send_handshake(&mut self, message: &[u8]) -> Result<(), TeleportError> {
+        self.writer
+            .write_all(&encode_frame(FLAG_HANDSHAKE, message))
+            .await?;
+        Ok(())
+    }
+}
+
+fn encode_frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.push(flags);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read half of a connection, reading messages in either framing.
+pub struct MessageReader<R> {
+    reader: BufReader<R>,
+    cipher: Option<Arc<Cipher>>,
+    nonce: u64,
+    remote_static_key: Option<[u8; 32]>,
+    //the first line read, which is the hello
+    hello: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R) -> MessageReader<R> {
+        MessageReader {
+            reader: BufReader::new(reader),
+            cipher: None,
+            nonce: 0,
+            remote_static_key: None,
+            hello: Vec::new(),
+        }
+    }
+
+    /// Once the connection is encrypted, the static key the peer proved it has.
+    pub fn remote_static_key(&self) -> Option<[u8; 32]> {
+        self.remote_static_key
+    }
+
+    /// The hello line read, empty if nothing was read as a line.
+    pub(crate) fn hello(&self) -> &[u8] {
+        &self.hello
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: Arc<Cipher>, remote_static_key: [u8; 32]) {
+        self.cipher = Some(cipher);
+        self.remote_static_key = Some(remote_static_key);
+    }
+
+    /// Reads the next message, None means the peer closed the connection.
+    pub async fn read_message<M: WireMessage>(&mut self) -> Result<Option<M>, TeleportError> {
+        match self.read_frame().await? {
+            Some(Frame::Message(payload)) => decode_message(&payload).map(Some),
+            Some(Frame::Handshake(_)) => Err(TeleportError::Protocol("unexpected handshake")),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn read_frame(&mut self) -> Result<Option<Frame>, TeleportError> {
+        let first_byte = match self.reader.fill_buf().await?.first() {
+            Some(b) => *b,
+            None => return Ok(None),
+        };
+        timeout(MESSAGE_READ_TIMEOUT, async {
+            if first_byte == b'{' {
+                self.read_line_payload().await.map(Frame::Message)
+            } else {
+                self.read_frame_payload().await
+            }
+        })
+        .await
+        .map_err(|_| {
+            TeleportError::Network(Box::new(io::Error::new(
+                ErrorKind::TimedOut,
+                "timed out reading message",
+            )))
+        })?
+        .map(Some)
+    }
+
+    async fn read_line_payload(&mut self) -> Result<Vec<u8>, TeleportError> {
+        if self.cipher.is_some() {
+            return Err(TeleportError::Protocol("unencrypted message"));
+        }
+        let mut line = Vec::new();
+        (&mut self.reader)
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if line.len() > MAX_MESSAGE_SIZE {
+            return Err(TeleportError::Protocol("message too large"));
+        }
+        if self.hello.is_empty() {
+            self.hello = line.clone();
+        }
+        Ok(line)
+    }
+
+    async fn read_frame_payload(&mut self) -> Result<Frame, TeleportError> {
+        let length = self.reader.read_u32().await? as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(TeleportError::Protocol("message too large"));
+        }
+        let flags = self.reader.read_u8().await?;
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload).await?;
+        if flags == FLAG_HANDSHAKE {
+            if self.cipher.is_some() {
+                return Err(TeleportError::Protocol("unexpected handshake"));
+            }
+            return Ok(Frame::Handshake(payload));
+        }
+        if flags & !(FLAG_COMPRESSED | FLAG_ENCRYPTED) != 0 {
+            return Err(TeleportError::Protocol("unknown frame flags"));
+        }
+        //once encrypted, anything else could have been injected by someone on the path
+        let payload = match (&self.cipher, flags & FLAG_ENCRYPTED != 0) {
+            (Some(cipher), true) => cipher.decrypt(&mut self.nonce, &payload)?,
+            (None, false) => payload,
+            (Some(_), false) => return Err(TeleportError::Protocol("unencrypted message")),
+            (None, true) => {
+                return Err(TeleportError::Protocol(
+                    "encrypted message before handshake",
+                ))
+            }
+        };
+        if payload.len() > MAX_MESSAGE_SIZE {
+            return Err(TeleportError::Protocol("message too large"));
+        }
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(Frame::Message(payload));
+        }
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(payload.as_slice())
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| TeleportError::Protocol("invalid compressed message"))?;
+        if decompressed.len() > MAX_MESSAGE_SIZE {
+            return Err(TeleportError::Protocol("message too large"));
+        }
+        Ok(Frame::Message(decompressed))
+    }
+}
+
+/// Checks the payload of a [Frame::Message] against the message type's limit and parses it.
+pub(crate) fn decode_message<M: WireMessage>(payload: &[u8]) -> Result<M, TeleportError> {
+    let tag: MessageTag = serde_json::from_slice(payload)
+        .map_err(|_| TeleportError::Protocol("message parsing error"))?;
+    if payload.len() > M::max_size(tag.method.unwrap_or_default()) {
+        return Err(TeleportError::Protocol("message too large"));
+    }
+    serde_json::from_slice(payload).map_err(|_| TeleportError::Protocol("message parsing error"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestMessage {
+        data: String,
+    }
+
+    impl WireMessage for TestMessage {
+        fn max_size(_method: &str) -> usize {
+            SMALL_MESSAGE_SIZE
+        }
+    }
+
+    async fn round_trip(framing: Framing, message: &TestMessage) -> Vec<u8> {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.set_framing(framing);
+        writer.send(message).await.unwrap();
+        writer.send(message).await.unwrap();
+        let bytes = writer.writer;
+        let mut reader = MessageReader::new(bytes.as_slice());
+        for _ in 0..2 {
+            let read: TestMessage = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(&read, message);
+        }
+        assert!(reader
+            .read_message::<TestMessage>()
+            .await
+            .unwrap()
+            .is_none());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_framings() {
+        let message = TestMessage {
+            data: "a".repeat(4000),
+        };
+        let lines = round_trip(Framing::Lines, &message).await;
+        let frames = round_trip(Framing::LengthPrefixed { compress: false }, &message).await;
+        let compressed = round_trip(Framing::LengthPrefixed { compress: true }, &message).await;
+        assert_eq!(lines.len(), frames.len() - 2 * (FRAME_HEADER_LEN - 1));
+        assert!(compressed.len() < frames.len() / 10);
+    }
+
+    #[tokio::test]
+    async fn test_size_limits() {
+        // over the message type's limit, but under the overall one
+        let message = TestMessage {
+            data: "a".repeat(SMALL_MESSAGE_SIZE),
+        };
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.set_framing(Framing::LengthPrefixed { compress: true });
+        writer.send(&message).await.unwrap();
+        let bytes = writer.writer;
+        match MessageReader::new(bytes.as_slice())
+            .read_message::<TestMessage>()
+            .await
+        {
+            Err(TeleportError::Protocol("message too large")) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // the limit is checked before parsing, a message which wouldn't parse is too large too
+        let mut bytes = b"{\"data\":5,\"padding\":\"".to_vec();
+        bytes.resize(SMALL_MESSAGE_SIZE + 10, b'a');
+        bytes.extend_from_slice(b"\"}\n");
+        match MessageReader::new(bytes.as_slice())
+            .read_message::<TestMessage>()
+            .await
+        {
+            Err(TeleportError::Protocol("message too large")) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a length prefix over the overall limit is rejected before reading the payload
+        let mut bytes = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        bytes.push(0);
+        match MessageReader::new(bytes.as_slice())
+            .read_message::<TestMessage>()
+            .await
+        {
+            Err(TeleportError::Protocol("message too large")) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a line which never ends
+        let mut bytes = b"{\"data\":\"".to_vec();
+        bytes.resize(MAX_MESSAGE_SIZE + 10, b'a');
+        match MessageReader::new(bytes.as_slice())
+            .read_message::<TestMessage>()
+            .await
+        {
+            Err(TeleportError::Protocol("message too large")) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
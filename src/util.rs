@@ -5,12 +5,9 @@ use std::io::ErrorKind;
 use bitcoin::{secp256k1::SecretKey, PublicKey, Script, Transaction};
 
 use bitcoin::hashes::hash160::Hash as Hash160;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpStream,
-    },
+use tokio::net::{
+    tcp::{ReadHalf, WriteHalf},
+    TcpStream,
 };
 use tokio_socks::tcp::Socks5Stream;
 
@@ -21,47 +18,70 @@ use crate::{
     },
     error::TeleportError,
     messages::{
-        ContractSigsAsRecvrAndSender, ContractSigsForRecvr, ContractSigsForSender,
-        ContractTxInfoForRecvr, ContractTxInfoForSender, FundingTxInfo, HashPreimage,
-        MakerToTakerMessage, MultisigPrivkey, NextHopInfo, Preimage, PrivKeyHandover,
-        ProofOfFunding, ReqContractSigsForRecvr, ReqContractSigsForSender, TakerHello,
-        TakerToMakerMessage,
+        negotiate_protocol_version, Capabilities, ContractSigsAsRecvrAndSender,
+        ContractSigsForRecvr, ContractSigsForSender, ContractTxInfoForRecvr,
+        ContractTxInfoForSender, FundingTxInfo, HashPreimage, MakerToTakerMessage, MultisigPrivkey,
+        NextHopInfo, NoiseStaticKey, Preimage, PrivKeyHandover, ProofOfFunding, ProtocolError,
+        ProtocolErrorCode, ReqContractSigsForRecvr, ReqContractSigsForSender, TakerHello,
+        TakerToMakerMessage, PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN,
     },
+    noise,
     offerbook_sync::{MakerAddress, OfferAndAddress},
+    transport::{Framing, MessageReader, MessageWriter},
 };
 
 /// Send message to a Maker.
 pub async fn send_message(
-    socket_writer: &mut WriteHalf<'_>,
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
     message: TakerToMakerMessage,
 ) -> Result<(), TeleportError> {
     log::debug!("==> {:#?}", message);
-    let mut result_bytes = serde_json::to_vec(&message).map_err(|e| std::io::Error::from(e))?;
-    result_bytes.push(b'\n');
-    socket_writer.write_all(&result_bytes).await?;
-    Ok(())
+    socket_writer.send(&message).await
 }
 
 /// Read a Maker Message
 pub async fn read_message(
-    reader: &mut BufReader<ReadHalf<'_>>,
+    reader: &mut MessageReader<ReadHalf<'_>>,
 ) -> Result<MakerToTakerMessage, TeleportError> {
-    let mut line = String::new();
-    let n = reader.read_line(&mut line).await?;
-    if n == 0 {
-        return Err(TeleportError::Network(Box::new(std::io::Error::new(
-            ErrorKind::ConnectionReset,
-            "EOF",
-        ))));
-    }
-    let message: MakerToTakerMessage = match serde_json::from_str(&line) {
-        Ok(r) => r,
-        Err(_e) => return Err(TeleportError::Protocol("json parsing error")),
+    let message: MakerToTakerMessage = match reader.read_message().await? {
+        Some(m) => m,
+        None => {
+            return Err(TeleportError::Network(Box::new(std::io::Error::new(
+                ErrorKind::ConnectionReset,
+                "EOF",
+            ))))
+        }
     };
     log::debug!("<== {:#?}", message);
+    if let MakerToTakerMessage::Error(error) = message {
+        log::warn!(
+            "Maker rejected our message: {:?} {}",
+            error.code,
+            error.message
+        );
+        return Err(TeleportError::Peer(error));
+    }
     Ok(message)
 }
 
+/// Tell the Maker why its message was rejected, the connection should be closed after this.
+pub(crate) async fn reject<T>(
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
+    code: ProtocolErrorCode,
+    message: &'static str,
+) -> Result<T, TeleportError> {
+    //the connection is closed anyway, so a failure to send is ignored
+    let _ = send_message(
+        socket_writer,
+        TakerToMakerMessage::Error(ProtocolError {
+            code,
+            message: message.to_string(),
+        }),
+    )
+    .await;
+    Err(TeleportError::Protocol(message))
+}
+
 /// Apply the maker's privatekey to swapcoins, and check it's the correct privkey for corresponding pubkey.
 pub fn check_and_apply_maker_private_keys<S: SwapCoin>(
     swapcoins: &mut Vec<S>,
@@ -101,24 +121,51 @@ pub fn generate_maker_keys(
     )
 }
 
+/// Calculate the Maker's Multisig and HashLock keys from the given nonce values, for when the
+/// nonces must be found again later.
+pub fn calculate_maker_keys(
+    tweakable_point: &PublicKey,
+    multisig_nonces: &[SecretKey],
+    hashlock_nonces: &[SecretKey],
+) -> (Vec<PublicKey>, Vec<PublicKey>) {
+    let calculate = |nonces: &[SecretKey]| {
+        nonces
+            .iter()
+            .map(|nonce| contracts::calculate_maker_pubkey_from_nonce(*tweakable_point, *nonce))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    (calculate(multisig_nonces), calculate(hashlock_nonces))
+}
+
 /// Performs a handshake with a Maker and returns and Reader and Writer halves.
+/// Clearnet connections are encrypted if the Maker supports it, and must be if its offer has a
+/// `noise_static_key`.
 pub async fn handshake_maker<'a>(
     socket: &'a mut TcpStream,
     maker_address: &MakerAddress,
-) -> Result<(BufReader<ReadHalf<'a>>, WriteHalf<'a>), TeleportError> {
+    noise_static_key: Option<&NoiseStaticKey>,
+) -> Result<(MessageReader<ReadHalf<'a>>, MessageWriter<WriteHalf<'a>>), TeleportError> {
     let socket = match maker_address {
         MakerAddress::Clearnet { address: _ } => socket,
         MakerAddress::Tor { address } => Socks5Stream::connect_with_socket(socket, address.clone())
             .await?
             .into_inner(),
     };
-    let (reader, mut socket_writer) = socket.split();
-    let mut socket_reader = BufReader::new(reader);
+    let (reader, writer) = socket.split();
+    let mut socket_reader = MessageReader::new(reader);
+    let mut socket_writer = MessageWriter::new(writer);
+    //tor already encrypts connections to onion addresses
+    let capabilities = match maker_address {
+        MakerAddress::Clearnet { address: _ } => Capabilities::ours(),
+        MakerAddress::Tor { address: _ } => Capabilities::ours().without(Capabilities::NOISE),
+    };
     send_message(
         &mut socket_writer,
         TakerToMakerMessage::TakerHello(TakerHello {
-            protocol_version_min: 0,
-            protocol_version_max: 0,
+            protocol_version_min: PROTOCOL_VERSION_MIN,
+            protocol_version_max: PROTOCOL_VERSION_MAX,
+            capabilities,
         }),
     )
     .await?;
@@ -126,24 +173,78 @@ pub async fn handshake_maker<'a>(
         if let MakerToTakerMessage::MakerHello(m) = read_message(&mut socket_reader).await? {
             m
         } else {
-            return Err(TeleportError::Protocol("expected method makerhello"));
+            return reject(
+                &mut socket_writer,
+                ProtocolErrorCode::UnexpectedMessage,
+                "expected method makerhello",
+            )
+            .await;
         };
-    log::debug!("{:#?}", makerhello);
+    let protocol_version = match negotiate_protocol_version(
+        makerhello.protocol_version_min,
+        makerhello.protocol_version_max,
+    ) {
+        Some(v) => v,
+        None => {
+            log::warn!(
+                "Maker {} speaks protocol versions {} to {}, we speak {} to {}",
+                maker_address,
+                makerhello.protocol_version_min,
+                makerhello.protocol_version_max,
+                PROTOCOL_VERSION_MIN,
+                PROTOCOL_VERSION_MAX
+            );
+            return reject(
+                &mut socket_writer,
+                ProtocolErrorCode::UnsupportedVersion,
+                "no protocol version in common with maker",
+            )
+            .await;
+        }
+    };
+    log::debug!(
+        "Maker {} protocol version={} capabilities={:?}",
+        maker_address,
+        protocol_version,
+        makerhello.capabilities
+    );
+    let framing = Framing::negotiated(protocol_version, makerhello.capabilities);
+    socket_writer.set_framing(framing);
+    if capabilities.contains(Capabilities::NOISE) {
+        if makerhello.capabilities.contains(Capabilities::NOISE) && framing != Framing::Lines {
+            let remote_static_key = noise::initiate(&mut socket_reader, &mut socket_writer).await?;
+            if noise_static_key.is_some_and(|k| k.key != remote_static_key) {
+                return Err(TeleportError::Protocol(
+                    "maker's encryption key doesnt match its offer",
+                ));
+            }
+        } else if noise_static_key.is_some() {
+            //its offer says it encrypts, so someone on the path may have changed its hello
+            return Err(TeleportError::Protocol(
+                "maker didnt encrypt the connection",
+            ));
+        }
+    }
     Ok((socket_reader, socket_writer))
 }
 
 /// Request signatures for sender side of the hop. Attempt once.
 pub(crate) async fn req_sigs_for_sender_once<S: SwapCoin>(
-    maker_address: &MakerAddress,
+    maker: &OfferAndAddress,
     outgoing_swapcoins: &[S],
     maker_multisig_nonces: &[SecretKey],
     maker_hashlock_nonces: &[SecretKey],
     locktime: u16,
 ) -> Result<ContractSigsForSender, TeleportError> {
+    let maker_address = &maker.address;
     log::info!("Connecting to {}", maker_address);
     let mut socket = TcpStream::connect(maker_address.get_tcpstream_address()).await?;
-    let (mut socket_reader, mut socket_writer) =
-        handshake_maker(&mut socket, maker_address).await?;
+    let (mut socket_reader, mut socket_writer) = handshake_maker(
+        &mut socket,
+        maker_address,
+        maker.offer.noise_static_key.as_ref(),
+    )
+    .await?;
     log::info!("===> Sending SignSendersContractTx to {}", maker_address);
     let txs_info = maker_multisig_nonces
         .iter()
@@ -176,14 +277,20 @@ pub(crate) async fn req_sigs_for_sender_once<S: SwapCoin>(
     {
         m
     } else {
-        return Err(TeleportError::Protocol(
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::UnexpectedMessage,
             "expected method senderscontractsig",
-        ));
+        )
+        .await;
     };
     if maker_senders_contract_sig.sigs.len() != outgoing_swapcoins.len() {
-        return Err(TeleportError::Protocol(
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::InvalidContract,
             "wrong number of signatures from maker",
-        ));
+        )
+        .await;
     }
     if maker_senders_contract_sig
         .sigs
@@ -191,7 +298,12 @@ pub(crate) async fn req_sigs_for_sender_once<S: SwapCoin>(
         .zip(outgoing_swapcoins.iter())
         .any(|(sig, outgoing_swapcoin)| !outgoing_swapcoin.verify_contract_tx_sender_sig(&sig))
     {
-        return Err(TeleportError::Protocol("invalid signature from maker"));
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::InvalidContract,
+            "invalid signature from maker",
+        )
+        .await;
     }
     log::info!("<=== Received SendersContractSig from {}", maker_address);
     Ok(maker_senders_contract_sig)
@@ -199,14 +311,19 @@ pub(crate) async fn req_sigs_for_sender_once<S: SwapCoin>(
 
 /// Request signatures for receiver side of the hop. Attempt once.
 pub(crate) async fn req_sigs_for_recvr_once<S: SwapCoin>(
-    maker_address: &MakerAddress,
+    maker: &OfferAndAddress,
     incoming_swapcoins: &[S],
     receivers_contract_txes: &[Transaction],
 ) -> Result<ContractSigsForRecvr, TeleportError> {
+    let maker_address = &maker.address;
     log::info!("Connecting to {}", maker_address);
     let mut socket = TcpStream::connect(maker_address.get_tcpstream_address()).await?;
-    let (mut socket_reader, mut socket_writer) =
-        handshake_maker(&mut socket, maker_address).await?;
+    let (mut socket_reader, mut socket_writer) = handshake_maker(
+        &mut socket,
+        maker_address,
+        maker.offer.noise_static_key.as_ref(),
+    )
+    .await?;
     send_message(
         &mut socket_writer,
         TakerToMakerMessage::ReqContractSigsForRecvr(ReqContractSigsForRecvr {
@@ -226,14 +343,20 @@ pub(crate) async fn req_sigs_for_recvr_once<S: SwapCoin>(
     {
         m
     } else {
-        return Err(TeleportError::Protocol(
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::UnexpectedMessage,
             "expected method receiverscontractsig",
-        ));
+        )
+        .await;
     };
     if maker_receiver_contract_sig.sigs.len() != incoming_swapcoins.len() {
-        return Err(TeleportError::Protocol(
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::InvalidContract,
             "wrong number of signatures from maker",
-        ));
+        )
+        .await;
     }
     if maker_receiver_contract_sig
         .sigs
@@ -241,7 +364,12 @@ pub(crate) async fn req_sigs_for_recvr_once<S: SwapCoin>(
         .zip(incoming_swapcoins.iter())
         .any(|(sig, swapcoin)| !swapcoin.verify_contract_tx_receiver_sig(&sig))
     {
-        return Err(TeleportError::Protocol("invalid signature from maker"));
+        return reject(
+            &mut socket_writer,
+            ProtocolErrorCode::InvalidContract,
+            "invalid signature from maker",
+        )
+        .await;
     }
 
     log::info!("<=== Received ReceiversContractSig from {}", maker_address);
@@ -250,8 +378,8 @@ pub(crate) async fn req_sigs_for_recvr_once<S: SwapCoin>(
 
 /// [Internal] Send a Proof funding to the maker and init next hop.
 pub(crate) async fn send_proof_of_funding_and_init_next_hop(
-    socket_reader: &mut BufReader<ReadHalf<'_>>,
-    socket_writer: &mut WriteHalf<'_>,
+    socket_reader: &mut MessageReader<ReadHalf<'_>>,
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
     this_maker: &OfferAndAddress,
     funding_tx_infos: &Vec<FundingTxInfo>,
     next_peer_multisig_pubkeys: &Vec<PublicKey>,
@@ -286,27 +414,36 @@ pub(crate) async fn send_proof_of_funding_and_init_next_hop(
         {
             m
         } else {
-            return Err(TeleportError::Protocol(
+            return reject(
+                socket_writer,
+                ProtocolErrorCode::UnexpectedMessage,
                 "expected method signsendersandreceiverscontracttxes",
-            ));
+            )
+            .await;
         };
     if maker_sign_sender_and_receiver_contracts
         .receivers_contract_txs
         .len()
         != funding_tx_infos.len()
     {
-        return Err(TeleportError::Protocol(
+        return reject(
+            socket_writer,
+            ProtocolErrorCode::InvalidContract,
             "wrong number of receivers contracts tx from maker",
-        ));
+        )
+        .await;
     }
     if maker_sign_sender_and_receiver_contracts
         .senders_contract_txs_info
         .len()
         != next_peer_multisig_pubkeys.len()
     {
-        return Err(TeleportError::Protocol(
+        return reject(
+            socket_writer,
+            ProtocolErrorCode::InvalidContract,
             "wrong number of senders contract txes from maker",
-        ));
+        )
+        .await;
     }
 
     let funding_tx_values = funding_tx_infos
@@ -343,7 +480,12 @@ pub(crate) async fn send_proof_of_funding_and_init_next_hop(
         / 1000;
     let calculated_next_amount = this_amount - coinswap_fees - miner_fees_paid_by_taker;
     if calculated_next_amount != next_amount {
-        return Err(TeleportError::Protocol("next_amount incorrect"));
+        return reject(
+            socket_writer,
+            ProtocolErrorCode::AmountOutOfRange,
+            "next_amount incorrect",
+        )
+        .await;
     }
     log::info!(
         "this_amount={} coinswap_fees={} miner_fees_paid_by_taker={} next_amount={}",
@@ -390,8 +532,8 @@ pub(crate) async fn send_proof_of_funding_and_init_next_hop(
 
 /// Send hash preimage via the writer and read the response.
 pub(crate) async fn send_hash_preimage_and_get_private_keys(
-    socket_reader: &mut BufReader<ReadHalf<'_>>,
-    socket_writer: &mut WriteHalf<'_>,
+    socket_reader: &mut MessageReader<ReadHalf<'_>>,
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
     senders_multisig_redeemscripts: &Vec<Script>,
     receivers_multisig_redeemscripts: &Vec<Script>,
     preimage: &Preimage,
@@ -410,14 +552,20 @@ pub(crate) async fn send_hash_preimage_and_get_private_keys(
         if let MakerToTakerMessage::RespPrivKeyHandover(m) = read_message(socket_reader).await? {
             m
         } else {
-            return Err(TeleportError::Protocol(
+            return reject(
+                socket_writer,
+                ProtocolErrorCode::UnexpectedMessage,
                 "expected method privatekeyhandover",
-            ));
+            )
+            .await;
         };
     if maker_private_key_handover.multisig_privkeys.len() != receivers_multisig_redeemscripts_len {
-        return Err(TeleportError::Protocol(
+        return reject(
+            socket_writer,
+            ProtocolErrorCode::InvalidPrivateKey,
             "wrong number of private keys from maker",
-        ));
+        )
+        .await;
     }
     Ok(maker_private_key_handover)
 }
@@ -0,0 +1,1392 @@
+//! SQLite storage of the wallet file.
+//!
+//! The seed phrase, address indexes, swapcoins, the prevout to contract cache, the UTXO
+//! reservations, the state of the maker's connections and the maker's swap ledger each have
+//! their own table, so that an update only touches the rows it changes. The schema is upgraded
+//! with [MIGRATIONS] when the wallet is opened, and wallet files in the older JSON format are
+//! imported into SQLite the first time they are opened.
+//!
+//! A wallet can be encrypted with a passphrase. The seed phrase, swapcoins and maker
+//! connections, which hold all the private keys, are then stored encrypted with
+//! ChaCha20-Poly1305 under a key stretched from the passphrase with Argon2id. The indexes, the
+//! contract cache, the reservations and the swap ledger are stored in plaintext. Files kept
+//! next to the wallet file which hold private keys, like the taker's swap journal, are
+//! encrypted with the same key through [WalletStorage::seal_file_data].
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use bitcoin::{
+    hashes::{hash160::Hash as Hash160, Hash},
+    secp256k1::rand::{rngs::OsRng, RngCore},
+    OutPoint, Script, Txid,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rusqlite::{params, types::Value, Connection, OptionalExtension, Transaction};
+
+use crate::{
+    contracts::SwapCoin,
+    error::{file_format_error, TeleportError},
+    maker_ledger::{SwapLedgerEntry, SwapOutcome},
+    wallet_sync::{IncomingSwapCoin, OutgoingSwapCoin},
+};
+
+//each entry upgrades the schema by one version, the version is kept in `PRAGMA user_version`
+//never edit an entry once released, add a new one instead
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE wallet (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        seedphrase TEXT NOT NULL,
+        extension TEXT NOT NULL
+    );
+    CREATE TABLE wallet_index (
+        name TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE incoming_swapcoin (
+        multisig_redeemscript BLOB PRIMARY KEY,
+        swapcoin TEXT NOT NULL
+    );
+    CREATE TABLE outgoing_swapcoin (
+        multisig_redeemscript BLOB PRIMARY KEY,
+        swapcoin TEXT NOT NULL
+    );
+    CREATE TABLE prevout_contract (
+        txid BLOB NOT NULL,
+        vout INTEGER NOT NULL,
+        contract_scriptpubkey BLOB NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+",
+    "
+    CREATE TABLE encryption (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        salt BLOB NOT NULL,
+        m_cost INTEGER NOT NULL,
+        t_cost INTEGER NOT NULL,
+        p_cost INTEGER NOT NULL,
+        check_value BLOB NOT NULL
+    );
+",
+    "
+    CREATE TABLE utxo_reservation (
+        txid BLOB NOT NULL,
+        vout INTEGER NOT NULL,
+        expiry INTEGER NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+",
+    "
+    CREATE TABLE maker_connection (
+        id INTEGER PRIMARY KEY,
+        state TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE swap_ledger (
+        hashvalue BLOB PRIMARY KEY,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        incoming_amount INTEGER NOT NULL,
+        outgoing_amount INTEGER NOT NULL,
+        coinswap_fee INTEGER NOT NULL,
+        miner_fee INTEGER NOT NULL,
+        recovery_fee INTEGER NOT NULL DEFAULT 0,
+        incoming_locktime INTEGER NOT NULL,
+        outgoing_locktime INTEGER NOT NULL,
+        outcome TEXT NOT NULL
+    );
+",
+];
+
+pub const EXTERNAL_INDEX: &str = "external_index";
+pub const SWAPCOIN_KEY_INDEX: &str = "swapcoin_key_index";
+
+//the maker and other commands like wallet-balance may use the wallet file at the same time
+const BUSY_TIMEOUT_SEC: u64 = 10;
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+const JSON_WALLET_FILE_VERSION: u32 = 0;
+
+/// Env var holding the passphrase of an encrypted wallet, for running without a terminal.
+pub const PASSPHRASE_ENV: &str = "TELEPORT_WALLET_PASSPHRASE";
+/// Env var holding the path of a file which contains the passphrase of an encrypted wallet.
+pub const PASSPHRASE_FILE_ENV: &str = "TELEPORT_WALLET_PASSPHRASE_FILE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+//encrypted with the wallet key so a wrong passphrase can be told apart from a corrupted file
+const CHECK_PLAINTEXT: &[u8] = b"teleport wallet";
+const CHECK_AAD: &[u8] = b"check";
+const SEEDPHRASE_AAD: &[u8] = b"seedphrase";
+const EXTENSION_AAD: &[u8] = b"extension";
+const INCOMING_SWAPCOIN_TABLE: &str = "incoming_swapcoin";
+const OUTGOING_SWAPCOIN_TABLE: &str = "outgoing_swapcoin";
+const MAKER_CONNECTION_TABLE: &str = "maker_connection";
+
+/// The wallet file format used before the wallet was stored in SQLite.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JsonWalletFileData {
+    version: u32,
+    seedphrase: String,
+    extension: String,
+    external_index: u32,
+    #[serde(default)]
+    swapcoin_key_index: u32,
+    incoming_swapcoins: Vec<IncomingSwapCoin>,
+    outgoing_swapcoins: Vec<OutgoingSwapCoin>,
+    prevout_to_contract_map: HashMap<OutPoint, Script>,
+}
+
+/// Row of the encryption table, which only exists if the wallet is encrypted.
+struct EncryptionParams {
+    salt: [u8; SALT_LEN],
+    params: Params,
+    check_value: Vec<u8>,
+}
+
+/// Authenticated encryption of the secret columns of the wallet file.
+struct WalletCipher {
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_LEN],
+    params: Params,
+}
+
+impl WalletCipher {
+    fn new(passphrase: &str) -> Result<WalletCipher, TeleportError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng::new().unwrap().fill_bytes(&mut salt);
+        WalletCipher::derive(passphrase, salt, Params::default())
+    }
+
+    fn derive(
+        passphrase: &str,
+        salt: [u8; SALT_LEN],
+        params: Params,
+    ) -> Result<WalletCipher, TeleportError> {
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|_| TeleportError::Protocol("unable to derive wallet key"))?;
+        Ok(WalletCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            salt,
+            params,
+        })
+    }
+
+    /// Returns the nonce followed by the ciphertext. The associated data ties the ciphertext to
+    /// the row it is stored in, so rows can't be swapped around in the file.
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng::new().unwrap().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("chacha20poly1305 encryption can't fail for wallet sized data");
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        data
+    }
+
+    fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, TeleportError> {
+        if data.len() < NONCE_LEN {
+            return Err(file_format_error("wallet file data corrupted"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| file_format_error("wallet file data corrupted"))
+    }
+}
+
+pub struct WalletStorage {
+    file_path: PathBuf,
+    //rusqlite connections can't be shared between threads, but the wallet is
+    conn: Mutex<Connection>,
+    //none if the wallet isn't encrypted
+    cipher: Option<WalletCipher>,
+}
+
+impl WalletStorage {
+    /// Create a new wallet file, failing if it already exists. The wallet is encrypted if a
+    /// passphrase is given.
+    pub fn create(
+        file_path: &Path,
+        seedphrase: &str,
+        extension: &str,
+        passphrase: Option<&str>,
+    ) -> Result<WalletStorage, TeleportError> {
+        if file_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "wallet file exists").into());
+        }
+        let cipher = passphrase.map(WalletCipher::new).transpose()?;
+        let mut conn = open_connection(file_path)?;
+        let tx = conn.transaction()?;
+        write_wallet_secrets(&tx, cipher.as_ref(), seedphrase, extension)?;
+        tx.commit()?;
+        Ok(WalletStorage {
+            file_path: file_path.to_path_buf(),
+            conn: Mutex::new(conn),
+            cipher,
+        })
+    }
+
+    /// Open a wallet file, upgrading its schema if needed. A JSON wallet file is first
+    /// imported into SQLite.
+    ///
+    /// If the wallet is encrypted and no passphrase is given, it is read with
+    /// [get_wallet_passphrase].
+    pub fn open(
+        file_path: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<WalletStorage, TeleportError> {
+        let mut header = Vec::<u8>::new();
+        File::open(file_path)?
+            .take(SQLITE_HEADER.len() as u64)
+            .read_to_end(&mut header)?;
+        if header != SQLITE_HEADER {
+            import_json_wallet_file(file_path)?;
+        }
+        let conn = open_connection(file_path)?;
+        let cipher = match read_encryption_params(&conn)? {
+            Some(encryption) => {
+                let passphrase = match passphrase {
+                    Some(passphrase) => passphrase.to_string(),
+                    None => get_wallet_passphrase(file_path)?,
+                };
+                let cipher = WalletCipher::derive(&passphrase, encryption.salt, encryption.params)?;
+                if cipher.decrypt(&encryption.check_value, CHECK_AAD).is_err() {
+                    return Err(TeleportError::Protocol("wrong wallet passphrase"));
+                }
+                Some(cipher)
+            }
+            None => None,
+        };
+        Ok(WalletStorage {
+            file_path: file_path.to_path_buf(),
+            conn: Mutex::new(conn),
+            cipher,
+        })
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Re-encrypt the wallet with a new passphrase in a single transaction, or store it
+    /// unencrypted if no passphrase is given.
+    pub fn change_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), TeleportError> {
+        let new_cipher = passphrase.map(WalletCipher::new).transpose()?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let (seedphrase, extension) = read_wallet_secrets(&tx, self.cipher.as_ref())?;
+        let incoming_swapcoins = load_swapcoin_table::<IncomingSwapCoin>(
+            &tx,
+            self.cipher.as_ref(),
+            INCOMING_SWAPCOIN_TABLE,
+        )?;
+        let outgoing_swapcoins = load_swapcoin_table::<OutgoingSwapCoin>(
+            &tx,
+            self.cipher.as_ref(),
+            OUTGOING_SWAPCOIN_TABLE,
+        )?;
+        let maker_connections = load_maker_connections(&tx, self.cipher.as_ref())?;
+        tx.execute("DELETE FROM wallet", [])?;
+        tx.execute("DELETE FROM encryption", [])?;
+        write_wallet_secrets(&tx, new_cipher.as_ref(), &seedphrase, &extension)?;
+        insert_swapcoins(
+            &tx,
+            new_cipher.as_ref(),
+            incoming_swapcoins.iter(),
+            outgoing_swapcoins.iter(),
+        )?;
+        for (id, state) in maker_connections {
+            insert_maker_connection(&tx, new_cipher.as_ref(), Some(id), &state)?;
+        }
+        tx.commit()?;
+        drop(conn);
+        self.cipher = new_cipher;
+        if self.cipher.is_some() {
+            remove_json_backup(&self.file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt data which is kept in a file next to the wallet file with the wallet key, None if
+    /// the wallet isn't encrypted. The associated data names what the data is, like the table
+    /// and key of a row, so one file's contents can't be passed off as another's.
+    pub fn seal_file_data(&self, plaintext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        self.cipher
+            .as_ref()
+            .map(|cipher| cipher.encrypt(plaintext, aad))
+    }
+
+    pub fn unseal_file_data(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, TeleportError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(data, aad),
+            None => Err(TeleportError::Protocol(
+                "encrypted data but the wallet isn't encrypted",
+            )),
+        }
+    }
+
+    pub fn delete(&self) -> Result<(), TeleportError> {
+        Ok(fs::remove_file(&self.file_path)?)
+    }
+
+    pub fn get_seedphrase_and_extension(&self) -> Result<(String, String), TeleportError> {
+        read_wallet_secrets(&self.conn.lock().unwrap(), self.cipher.as_ref())
+    }
+
+    pub fn get_index(&self, name: &str) -> Result<u32, TeleportError> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM wallet_index WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    pub fn set_index(&self, name: &str, value: u32) -> Result<(), TeleportError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO wallet_index (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_swapcoins(
+        &self,
+    ) -> Result<(Vec<IncomingSwapCoin>, Vec<OutgoingSwapCoin>), TeleportError> {
+        let conn = self.conn.lock().unwrap();
+        Ok((
+            load_swapcoin_table(&conn, self.cipher.as_ref(), INCOMING_SWAPCOIN_TABLE)?,
+            load_swapcoin_table(&conn, self.cipher.as_ref(), OUTGOING_SWAPCOIN_TABLE)?,
+        ))
+    }
+
+    /// Insert the swapcoin, or replace the one with the same multisig redeemscript.
+    pub fn save_incoming_swapcoin(&self, swapcoin: &IncomingSwapCoin) -> Result<(), TeleportError> {
+        insert_swapcoin(
+            &self.conn.lock().unwrap(),
+            self.cipher.as_ref(),
+            INCOMING_SWAPCOIN_TABLE,
+            swapcoin,
+        )
+    }
+
+    /// Insert the swapcoin, or replace the one with the same multisig redeemscript.
+    pub fn save_outgoing_swapcoin(&self, swapcoin: &OutgoingSwapCoin) -> Result<(), TeleportError> {
+        insert_swapcoin(
+            &self.conn.lock().unwrap(),
+            self.cipher.as_ref(),
+            OUTGOING_SWAPCOIN_TABLE,
+            swapcoin,
+        )
+    }
+
+    /// Insert the swapcoins of a coinswap, or replace those with the same multisig
+    /// redeemscripts. Either all of them are saved or none are.
+    pub fn save_swapcoins(
+        &self,
+        incoming_swapcoins: &[IncomingSwapCoin],
+        outgoing_swapcoins: &[OutgoingSwapCoin],
+    ) -> Result<(), TeleportError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        insert_swapcoins(
+            &tx,
+            self.cipher.as_ref(),
+            incoming_swapcoins.iter(),
+            outgoing_swapcoins.iter(),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete the swapcoins with the multisig redeemscript from both swapcoin tables.
+    pub fn delete_swapcoin(&self, multisig_redeemscript: &Script) -> Result<(), TeleportError> {
+        let conn = self.conn.lock().unwrap();
+        for table in &[INCOMING_SWAPCOIN_TABLE, OUTGOING_SWAPCOIN_TABLE] {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE multisig_redeemscript = ?1", table),
+                params![multisig_redeemscript.as_bytes()],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_prevout_contract(
+        &self,
+        prevout: &OutPoint,
+    ) -> Result<Option<Script>, TeleportError> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT contract_scriptpubkey FROM prevout_contract WHERE txid = ?1 AND vout = ?2",
+                params![&prevout.txid[..], prevout.vout],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .map(Script::from))
+    }
+
+    pub fn add_prevout_contract(
+        &self,
+        prevout: &OutPoint,
+        contract: &Script,
+    ) -> Result<(), TeleportError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO prevout_contract (txid, vout, contract_scriptpubkey)
+                VALUES (?1, ?2, ?3)",
+            params![&prevout.txid[..], prevout.vout, contract.as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    /// Outpoints reserved until after `now`, a unix timestamp.
+    pub fn get_reserved_utxos(&self, now: u64) -> Result<HashSet<OutPoint>, TeleportError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT txid, vout FROM utxo_reservation WHERE expiry > ?1")?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, u32>(1)?))
+        })?;
+        let mut reserved = HashSet::new();
+        for row in rows {
+            let (txid, vout) = row?;
+            reserved.insert(OutPoint {
+                txid: Txid::from_slice(&txid)
+                    .map_err(|_| file_format_error("invalid txid in utxo reservation table"))?,
+                vout,
+            });
+        }
+        Ok(reserved)
+    }
+
+    /// Reserve the outpoints until `expiry`, a unix timestamp. Expired reservations are deleted.
+    pub fn reserve_utxos(
+        &self,
+        outpoints: &[OutPoint],
+        now: u64,
+        expiry: u64,
+    ) -> Result<(), TeleportError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM utxo_reservation WHERE expiry <= ?1",
+            params![now],
+        )?;
+        for outpoint in outpoints {
+            tx.execute(
+                "INSERT OR REPLACE INTO utxo_reservation (txid, vout, expiry) VALUES (?1, ?2, ?3)",
+                params![&outpoint.txid[..], outpoint.vout, expiry],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn release_utxos(&self, outpoints: &[OutPoint]) -> Result<(), TeleportError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for outpoint in outpoints {
+            tx.execute(
+                "DELETE FROM utxo_reservation WHERE txid = ?1 AND vout = ?2",
+                params![&outpoint.txid[..], outpoint.vout],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Save the state of a maker's connection, `None` adds a new row. Returns the row id.
+    pub fn save_maker_connection(
+        &self,
+        id: Option<i64>,
+        state: &str,
+    ) -> Result<i64, TeleportError> {
+        let conn = self.conn.lock().unwrap();
+        insert_maker_connection(&conn, self.cipher.as_ref(), id, state)
+    }
+
+    pub fn load_maker_connections(&self) -> Result<Vec<(i64, String)>, TeleportError> {
+        load_maker_connections(&self.conn.lock().unwrap(), self.cipher.as_ref())
+    }
+
+    pub fn delete_maker_connection(&self, id: i64) -> Result<(), TeleportError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM maker_connection WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Add a coinswap to the ledger, an existing entry for the same hashvalue is kept.
+    pub fn add_swap_ledger_entry(&self, entry: &SwapLedgerEntry) -> Result<(), TeleportError> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO swap_ledger (hashvalue, started_at, finished_at,
+                incoming_amount, outgoing_amount, coinswap_fee, miner_fee, recovery_fee,
+                incoming_locktime, outgoing_locktime, outcome)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                &entry.hashvalue[..],
+                entry.started_at,
+                entry.finished_at,
+                entry.incoming_amount,
+                entry.outgoing_amount,
+                entry.coinswap_fee,
+                entry.miner_fee,
+                entry.recovery_fee,
+                entry.incoming_locktime,
+                entry.outgoing_locktime,
+                entry.outcome.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Set the outcome of a coinswap which is still in progress, at `finished_at`, a unix
+    /// timestamp.
+    pub fn finish_swap_ledger_entry(
+        &self,
+        hashvalue: &Hash160,
+        outcome: SwapOutcome,
+        finished_at: u64,
+    ) -> Result<(), TeleportError> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE swap_ledger SET outcome = ?1, finished_at = ?2
+                WHERE hashvalue = ?3 AND outcome = ?4",
+            params![
+                outcome.as_str(),
+                finished_at,
+                &hashvalue[..],
+                SwapOutcome::InProgress.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Add to the recovery fee of a coinswap in the ledger, coinswaps which aren't in it are
+    /// ignored.
+    pub fn add_swap_ledger_recovery_fee(
+        &self,
+        hashvalue: &Hash160,
+        fee: u64,
+    ) -> Result<(), TeleportError> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE swap_ledger SET recovery_fee = recovery_fee + ?1 WHERE hashvalue = ?2",
+            params![fee, &hashvalue[..]],
+        )?;
+        Ok(())
+    }
+
+    /// The whole ledger, oldest coinswap first.
+    pub fn load_swap_ledger(&self) -> Result<Vec<SwapLedgerEntry>, TeleportError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT hashvalue, started_at, finished_at, incoming_amount, outgoing_amount,
+                coinswap_fee, miner_fee, recovery_fee, incoming_locktime, outgoing_locktime,
+                outcome
+                FROM swap_ledger ORDER BY started_at",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                SwapLedgerEntry {
+                    hashvalue: Hash160::default(),
+                    started_at: row.get(1)?,
+                    finished_at: row.get(2)?,
+                    incoming_amount: row.get(3)?,
+                    outgoing_amount: row.get(4)?,
+                    coinswap_fee: row.get(5)?,
+                    miner_fee: row.get(6)?,
+                    recovery_fee: row.get(7)?,
+                    incoming_locktime: row.get(8)?,
+                    outgoing_locktime: row.get(9)?,
+                    outcome: SwapOutcome::InProgress,
+                },
+                row.get::<_, String>(10)?,
+            ))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (hashvalue, mut entry, outcome) = row?;
+            entry.hashvalue = Hash160::from_slice(&hashvalue)
+                .map_err(|_| file_format_error("invalid hashvalue in swap ledger table"))?;
+            entry.outcome = outcome
+                .parse()
+                .map_err(|_| file_format_error("invalid outcome in swap ledger table"))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+fn open_connection(file_path: &Path) -> Result<Connection, TeleportError> {
+    let mut conn = Connection::open(file_path)?;
+    conn.busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SEC))?;
+    //so the plaintext secrets don't stay behind in free pages after encrypting the wallet
+    conn.pragma_update(None, "secure_delete", true)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// Apply the migrations the wallet file doesn't have yet.
+fn migrate(conn: &mut Connection) -> Result<(), TeleportError> {
+    let tx = conn.transaction()?;
+    let version = tx.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(file_format_error(
+            "wallet file is from a newer version of teleport",
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::debug!(target: "wallet", "migrating wallet file to version {}", i + 1);
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Read the passphrase of an encrypted wallet from the `TELEPORT_WALLET_PASSPHRASE` env var, or
+/// from the file named by `TELEPORT_WALLET_PASSPHRASE_FILE`, or else prompt for it.
+pub fn get_wallet_passphrase(file_path: &Path) -> Result<String, TeleportError> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if let Ok(passphrase_file) = env::var(PASSPHRASE_FILE_ENV) {
+        let passphrase = fs::read_to_string(passphrase_file)?;
+        //files written by editors or echo usually end with a newline
+        return Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+    Ok(rpassword::prompt_password(format!(
+        "Enter passphrase for wallet {}: ",
+        file_path.display()
+    ))?)
+}
+
+fn read_encryption_params(conn: &Connection) -> Result<Option<EncryptionParams>, TeleportError> {
+    let row = conn
+        .query_row(
+            "SELECT salt, m_cost, t_cost, p_cost, check_value FROM encryption WHERE id = 0",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, u32>(2)?,
+                    row.get::<_, u32>(3)?,
+                    row.get::<_, Vec<u8>>(4)?,
+                ))
+            },
+        )
+        .optional()?;
+    let (salt, m_cost, t_cost, p_cost, check_value) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    Ok(Some(EncryptionParams {
+        salt: <[u8; SALT_LEN]>::try_from(salt.as_slice())
+            .map_err(|_| file_format_error("wallet file data corrupted"))?,
+        params: Params::new(m_cost, t_cost, p_cost, None)
+            .map_err(|_| file_format_error("wallet file data corrupted"))?,
+        check_value,
+    }))
+}
+
+/// Encrypt `plaintext` if the wallet is encrypted, ciphertexts are stored as blobs and
+/// plaintext as text.
+fn seal(cipher: Option<&WalletCipher>, plaintext: &str, aad: &[u8]) -> Value {
+    match cipher {
+        Some(cipher) => Value::Blob(cipher.encrypt(plaintext.as_bytes(), aad)),
+        None => Value::Text(plaintext.to_string()),
+    }
+}
+
+fn unseal(
+    cipher: Option<&WalletCipher>,
+    value: Value,
+    aad: &[u8],
+) -> Result<String, TeleportError> {
+    match (cipher, value) {
+        (Some(cipher), Value::Blob(data)) => String::from_utf8(cipher.decrypt(&data, aad)?)
+            .map_err(|_| file_format_error("wallet file data corrupted")),
+        (None, Value::Text(plaintext)) => Ok(plaintext),
+        _ => Err(file_format_error("wallet file data corrupted")),
+    }
+}
+
+/// Write the seed phrase and extension, and the encryption params if the wallet is encrypted.
+fn write_wallet_secrets(
+    tx: &Transaction,
+    cipher: Option<&WalletCipher>,
+    seedphrase: &str,
+    extension: &str,
+) -> Result<(), TeleportError> {
+    if let Some(cipher) = cipher {
+        tx.execute(
+            "INSERT INTO encryption (id, salt, m_cost, t_cost, p_cost, check_value)
+                VALUES (0, ?1, ?2, ?3, ?4, ?5)",
+            params![
+                &cipher.salt[..],
+                cipher.params.m_cost(),
+                cipher.params.t_cost(),
+                cipher.params.p_cost(),
+                cipher.encrypt(CHECK_PLAINTEXT, CHECK_AAD)
+            ],
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO wallet (id, seedphrase, extension) VALUES (0, ?1, ?2)",
+        params![
+            seal(cipher, seedphrase, SEEDPHRASE_AAD),
+            seal(cipher, extension, EXTENSION_AAD)
+        ],
+    )?;
+    Ok(())
+}
+
+fn read_wallet_secrets(
+    conn: &Connection,
+    cipher: Option<&WalletCipher>,
+) -> Result<(String, String), TeleportError> {
+    let (seedphrase, extension) = conn.query_row(
+        "SELECT seedphrase, extension FROM wallet WHERE id = 0",
+        [],
+        |row| Ok((row.get::<_, Value>(0)?, row.get::<_, Value>(1)?)),
+    )?;
+    Ok((
+        unseal(cipher, seedphrase, SEEDPHRASE_AAD)?,
+        unseal(cipher, extension, EXTENSION_AAD)?,
+    ))
+}
+
+//the table name and multisig redeemscript of a swapcoin row
+fn swapcoin_aad(table: &str, multisig_redeemscript: &[u8]) -> Vec<u8> {
+    let mut aad = table.as_bytes().to_vec();
+    aad.extend(multisig_redeemscript);
+    aad
+}
+
+fn load_swapcoin_table<S: serde::de::DeserializeOwned>(
+    conn: &Connection,
+    cipher: Option<&WalletCipher>,
+    table: &str,
+) -> Result<Vec<S>, TeleportError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT multisig_redeemscript, swapcoin FROM {}",
+        table
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Value>(1)?))
+    })?;
+    let mut swapcoins = Vec::<S>::new();
+    for row in rows {
+        let (multisig_redeemscript, swapcoin) = row?;
+        let swapcoin = unseal(
+            cipher,
+            swapcoin,
+            &swapcoin_aad(table, &multisig_redeemscript),
+        )?;
+        swapcoins.push(serde_json::from_str(&swapcoin).map_err(io::Error::from)?);
+    }
+    Ok(swapcoins)
+}
+
+fn insert_swapcoin<S: SwapCoin + serde::Serialize>(
+    conn: &Connection,
+    cipher: Option<&WalletCipher>,
+    table: &str,
+    swapcoin: &S,
+) -> Result<(), TeleportError> {
+    let multisig_redeemscript = swapcoin.get_multisig_redeemscript();
+    let swapcoin = serde_json::to_string(swapcoin).map_err(io::Error::from)?;
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {} (multisig_redeemscript, swapcoin) VALUES (?1, ?2)",
+            table
+        ),
+        params![
+            multisig_redeemscript.as_bytes(),
+            seal(
+                cipher,
+                &swapcoin,
+                &swapcoin_aad(table, multisig_redeemscript.as_bytes())
+            )
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_swapcoins<'a>(
+    tx: &Transaction,
+    cipher: Option<&WalletCipher>,
+    incoming_swapcoins: impl Iterator<Item = &'a IncomingSwapCoin>,
+    outgoing_swapcoins: impl Iterator<Item = &'a OutgoingSwapCoin>,
+) -> Result<(), TeleportError> {
+    for swapcoin in incoming_swapcoins {
+        insert_swapcoin(tx, cipher, INCOMING_SWAPCOIN_TABLE, swapcoin)?;
+    }
+    for swapcoin in outgoing_swapcoins {
+        insert_swapcoin(tx, cipher, OUTGOING_SWAPCOIN_TABLE, swapcoin)?;
+    }
+    Ok(())
+}
+
+fn load_maker_connections(
+    conn: &Connection,
+    cipher: Option<&WalletCipher>,
+) -> Result<Vec<(i64, String)>, TeleportError> {
+    let mut stmt = conn.prepare("SELECT id, state FROM maker_connection")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, Value>(1)?))
+    })?;
+    let mut connections = Vec::new();
+    for row in rows {
+        let (id, state) = row?;
+        connections.push((id, unseal(cipher, state, &maker_connection_aad(id))?));
+    }
+    Ok(connections)
+}
+
+//the row id is part of the associated data, so states can't be swapped between rows
+fn maker_connection_aad(id: i64) -> Vec<u8> {
+    let mut aad = MAKER_CONNECTION_TABLE.as_bytes().to_vec();
+    aad.extend(&id.to_be_bytes());
+    aad
+}
+
+fn insert_maker_connection(
+    conn: &Connection,
+    cipher: Option<&WalletCipher>,
+    id: Option<i64>,
+    state: &str,
+) -> Result<i64, TeleportError> {
+    //the id of a new row is picked before inserting it, as it's sealed into the state
+    let (id, sql) = match id {
+        Some(id) => (
+            id,
+            "INSERT OR REPLACE INTO maker_connection (id, state) VALUES (?1, ?2)",
+        ),
+        None => (
+            conn.query_row(
+                "SELECT COALESCE(MAX(id), 0) + 1 FROM maker_connection",
+                [],
+                |row| row.get::<_, i64>(0),
+            )?,
+            "INSERT INTO maker_connection (id, state) VALUES (?1, ?2)",
+        ),
+    };
+    conn.execute(
+        sql,
+        params![id, seal(cipher, state, &maker_connection_aad(id))],
+    )?;
+    Ok(id)
+}
+
+/// Convert a JSON wallet file into SQLite. The SQLite file is written next to it first, read
+/// back and compared entry by entry with the JSON file, and only then swapped in. The JSON file
+/// isn't kept, it holds the seed phrase and swapcoin keys in plaintext.
+fn import_json_wallet_file(file_path: &Path) -> Result<(), TeleportError> {
+    let mut file_str = String::new();
+    File::open(file_path)?.read_to_string(&mut file_str)?;
+    let file_data =
+        serde_json::from_str::<JsonWalletFileData>(&file_str).map_err(io::Error::from)?;
+    if file_data.version != JSON_WALLET_FILE_VERSION {
+        return Err(file_format_error("unknown wallet file version"));
+    }
+    log::info!(target: "wallet", "importing JSON wallet file {:?} into SQLite", file_path);
+
+    let mut file_name = file_path.as_os_str().to_os_string();
+    file_name.push(".sqlite-tmp");
+    let tmp_file_path = PathBuf::from(file_name);
+    if tmp_file_path.exists() {
+        //left over from an import which was interrupted
+        fs::remove_file(&tmp_file_path)?;
+    }
+    let mut conn = open_connection(&tmp_file_path)?;
+    let tx = conn.transaction()?;
+    write_wallet_secrets(&tx, None, &file_data.seedphrase, &file_data.extension)?;
+    for (name, value) in [
+        (EXTERNAL_INDEX, file_data.external_index),
+        (SWAPCOIN_KEY_INDEX, file_data.swapcoin_key_index),
+    ] {
+        tx.execute(
+            "INSERT INTO wallet_index (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )?;
+    }
+    insert_swapcoins(
+        &tx,
+        None,
+        file_data.incoming_swapcoins.iter(),
+        file_data.outgoing_swapcoins.iter(),
+    )?;
+    for (prevout, contract) in &file_data.prevout_to_contract_map {
+        tx.execute(
+            "INSERT INTO prevout_contract (txid, vout, contract_scriptpubkey) VALUES (?1, ?2, ?3)",
+            params![&prevout.txid[..], prevout.vout, contract.as_bytes()],
+        )?;
+    }
+    tx.commit()?;
+    verify_json_wallet_import(&conn, &file_data)?;
+    drop(conn);
+
+    fs::rename(&tmp_file_path, file_path)?;
+    Ok(())
+}
+
+fn verify_json_wallet_import(
+    conn: &Connection,
+    file_data: &JsonWalletFileData,
+) -> Result<(), TeleportError> {
+    let (incoming_swapcoins, outgoing_swapcoins) = (
+        load_swapcoin_table::<IncomingSwapCoin>(conn, None, INCOMING_SWAPCOIN_TABLE)?,
+        load_swapcoin_table::<OutgoingSwapCoin>(conn, None, OUTGOING_SWAPCOIN_TABLE)?,
+    );
+    let index = |name: &str| {
+        conn.query_row(
+            "SELECT value FROM wallet_index WHERE name = ?1",
+            params![name],
+            |row| row.get::<_, u32>(0),
+        )
+    };
+    let prevout_contract_count =
+        conn.query_row("SELECT COUNT(*) FROM prevout_contract", [], |row| {
+            row.get::<_, usize>(0)
+        })?;
+    let mut prevout_contracts_match = true;
+    for (prevout, contract) in &file_data.prevout_to_contract_map {
+        let imported_contract = conn
+            .query_row(
+                "SELECT contract_scriptpubkey FROM prevout_contract WHERE txid = ?1 AND vout = ?2",
+                params![&prevout.txid[..], prevout.vout],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        prevout_contracts_match &= imported_contract.as_deref() == Some(contract.as_bytes());
+    }
+    if read_wallet_secrets(conn, None)?
+        != (file_data.seedphrase.clone(), file_data.extension.clone())
+        || index(EXTERNAL_INDEX)? != file_data.external_index
+        || index(SWAPCOIN_KEY_INDEX)? != file_data.swapcoin_key_index
+        || !swapcoins_match(&incoming_swapcoins, &file_data.incoming_swapcoins)?
+        || !swapcoins_match(&outgoing_swapcoins, &file_data.outgoing_swapcoins)?
+        || prevout_contract_count != file_data.prevout_to_contract_map.len()
+        || !prevout_contracts_match
+    {
+        return Err(TeleportError::Protocol(
+            "imported wallet file doesnt match the JSON wallet file",
+        ));
+    }
+    Ok(())
+}
+
+//whether the swapcoins read back from SQLite are exactly those of the JSON file, in any order
+fn swapcoins_match<S: SwapCoin + serde::Serialize>(
+    imported: &[S],
+    original: &[S],
+) -> Result<bool, TeleportError> {
+    let by_multisig = |swapcoins: &[S]| {
+        swapcoins
+            .iter()
+            .map(|swapcoin| {
+                Ok((
+                    swapcoin.get_multisig_redeemscript(),
+                    serde_json::to_value(swapcoin).map_err(io::Error::from)?,
+                ))
+            })
+            .collect::<Result<HashMap<Script, serde_json::Value>, TeleportError>>()
+    };
+    Ok(imported.len() == original.len() && by_multisig(imported)? == by_multisig(original)?)
+}
+
+/// Remove the plaintext copy of a JSON wallet file which older versions kept after importing it.
+fn remove_json_backup(file_path: &Path) -> Result<(), TeleportError> {
+    let mut backup_file_name = file_path.as_os_str().to_os_string();
+    backup_file_name.push(".json-backup");
+    match fs::remove_file(&backup_file_name) {
+        Ok(()) => {
+            log::info!(target: "wallet", "removed plaintext wallet backup {:?}", backup_file_name);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Path of a test's wallet file in the temp dir, with any file left by an earlier run removed.
+#[cfg(test)]
+pub(crate) fn temp_wallet_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "teleport-test-{}-{}.teleport",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_import_json_wallet_file() {
+        let path = temp_wallet_path("import");
+        let prevout = OutPoint {
+            txid: Txid::from_slice(&[1u8; 32]).unwrap(),
+            vout: 3,
+        };
+        let contract = Script::from(vec![0u8; 34]);
+        let (other_pubkey, my_privkey) = crate::wallet_sync::generate_keypair();
+        let swapcoin = IncomingSwapCoin {
+            my_privkey,
+            other_pubkey,
+            other_privkey: None,
+            contract_tx: bitcoin::Transaction {
+                version: 2,
+                lock_time: 0,
+                input: Vec::new(),
+                output: Vec::new(),
+            },
+            contract_redeemscript: Script::new(),
+            hashlock_privkey: my_privkey,
+            funding_amount: 100_000,
+            others_contract_sig: None,
+            hash_preimage: Some([3; 32]),
+        };
+        let file_data = JsonWalletFileData {
+            version: JSON_WALLET_FILE_VERSION,
+            seedphrase: "seed words".to_string(),
+            extension: "ext".to_string(),
+            external_index: 7,
+            swapcoin_key_index: 0,
+            incoming_swapcoins: vec![swapcoin.clone()],
+            outgoing_swapcoins: Vec::new(),
+            prevout_to_contract_map: vec![(prevout, contract.clone())].into_iter().collect(),
+        };
+        serde_json::to_writer(File::create(&path).unwrap(), &file_data).unwrap();
+
+        // An imported swapcoin which differs from the JSON file's fails the check.
+        let mut changed_swapcoin = swapcoin.clone();
+        changed_swapcoin.funding_amount += 1;
+        assert!(!swapcoins_match(&[changed_swapcoin], &file_data.incoming_swapcoins).unwrap());
+
+        let storage = WalletStorage::open(&path, None).unwrap();
+        assert_eq!(
+            storage.get_seedphrase_and_extension().unwrap(),
+            ("seed words".to_string(), "ext".to_string())
+        );
+        assert_eq!(storage.get_index(EXTERNAL_INDEX).unwrap(), 7);
+        assert_eq!(
+            storage.load_swapcoins().unwrap().0[0].hash_preimage,
+            swapcoin.hash_preimage
+        );
+        assert_eq!(
+            storage.get_prevout_contract(&prevout).unwrap(),
+            Some(contract)
+        );
+        drop(storage);
+
+        // Opening again uses the imported SQLite file.
+        let storage = WalletStorage::open(&path, None).unwrap();
+        storage.set_index(EXTERNAL_INDEX, 8).unwrap();
+        assert_eq!(storage.get_index(EXTERNAL_INDEX).unwrap(), 8);
+        assert_eq!(storage.get_index(SWAPCOIN_KEY_INDEX).unwrap(), 0);
+
+        // The plaintext JSON file isn't kept.
+        let mut backup_path = path.as_os_str().to_os_string();
+        backup_path.push(".json-backup");
+        assert!(!Path::new(&backup_path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_wallet_file() {
+        let path = temp_wallet_path("encrypted");
+        let storage =
+            WalletStorage::create(&path, "seed words", "ext", Some("passphrase")).unwrap();
+        let id = storage
+            .save_maker_connection(None, "connection state")
+            .unwrap();
+        drop(storage);
+        let file_bytes = fs::read(&path).unwrap();
+        assert!(!file_bytes
+            .windows(b"seed words".len())
+            .any(|w| w == b"seed words"));
+
+        assert!(WalletStorage::open(&path, Some("wrong")).is_err());
+        let mut storage = WalletStorage::open(&path, Some("passphrase")).unwrap();
+        assert!(storage.is_encrypted());
+        assert_eq!(
+            storage.get_seedphrase_and_extension().unwrap(),
+            ("seed words".to_string(), "ext".to_string())
+        );
+
+        // Removing the passphrase leaves a plaintext wallet which opens without one.
+        storage.change_passphrase(None).unwrap();
+        drop(storage);
+        let mut storage = WalletStorage::open(&path, None).unwrap();
+        assert!(!storage.is_encrypted());
+        assert_eq!(
+            storage.get_seedphrase_and_extension().unwrap(),
+            ("seed words".to_string(), "ext".to_string())
+        );
+        assert_eq!(
+            storage.load_maker_connections().unwrap(),
+            vec![(id, "connection state".to_string())]
+        );
+
+        // A connection's state can't be passed off as another row's.
+        storage.change_passphrase(Some("passphrase")).unwrap();
+        let other_id = storage
+            .save_maker_connection(None, "other connection state")
+            .unwrap();
+        assert_ne!(other_id, id);
+        storage
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE maker_connection SET state =
+                    (SELECT state FROM maker_connection WHERE id = ?1) WHERE id = ?2",
+                params![other_id, id],
+            )
+            .unwrap();
+        assert!(storage.load_maker_connections().is_err());
+        storage.delete_maker_connection(id).unwrap();
+        storage.delete_maker_connection(other_id).unwrap();
+
+        // Encrypting removes a plaintext backup left by an older JSON import.
+        let mut backup_path = path.as_os_str().to_os_string();
+        backup_path.push(".json-backup");
+        fs::write(&backup_path, "seed words").unwrap();
+        storage.change_passphrase(Some("new passphrase")).unwrap();
+        assert!(!Path::new(&backup_path).exists());
+        let sealed = storage.seal_file_data(b"journal", b"aad").unwrap();
+        assert_eq!(
+            storage.unseal_file_data(&sealed, b"aad").unwrap(),
+            b"journal"
+        );
+        assert!(storage.unseal_file_data(&sealed, b"other").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_utxo_reservations() {
+        let path = temp_wallet_path("reservations");
+        let storage = WalletStorage::create(&path, "seed words", "ext", None).unwrap();
+        let outpoint = |vout| OutPoint {
+            txid: Txid::from_slice(&[1; 32]).unwrap(),
+            vout,
+        };
+        storage
+            .reserve_utxos(&[outpoint(0), outpoint(1)], 100, 200)
+            .unwrap();
+        storage.reserve_utxos(&[outpoint(2)], 100, 300).unwrap();
+        drop(storage);
+
+        let storage = WalletStorage::open(&path, None).unwrap();
+        assert_eq!(
+            storage.get_reserved_utxos(150).unwrap(),
+            [outpoint(0), outpoint(1), outpoint(2)]
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            storage.get_reserved_utxos(200).unwrap(),
+            [outpoint(2)].iter().cloned().collect::<HashSet<_>>()
+        );
+        storage.release_utxos(&[outpoint(2)]).unwrap();
+        assert_eq!(storage.get_reserved_utxos(150).unwrap().len(), 2);
+
+        // Reserving again deletes the expired reservations.
+        storage.reserve_utxos(&[], 250, 300).unwrap();
+        assert!(storage.get_reserved_utxos(0).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_swapcoin() {
+        let path = temp_wallet_path("swapcoin");
+        let storage =
+            WalletStorage::create(&path, "seed words", "ext", Some("passphrase")).unwrap();
+        let (other_pubkey, my_privkey) = crate::wallet_sync::generate_keypair();
+        let mut swapcoin = IncomingSwapCoin {
+            my_privkey,
+            other_pubkey,
+            other_privkey: None,
+            contract_tx: bitcoin::Transaction {
+                version: 2,
+                lock_time: 0,
+                input: Vec::new(),
+                output: Vec::new(),
+            },
+            contract_redeemscript: Script::new(),
+            hashlock_privkey: my_privkey,
+            funding_amount: 100_000,
+            others_contract_sig: None,
+            hash_preimage: None,
+        };
+        storage.save_incoming_swapcoin(&swapcoin).unwrap();
+
+        // Saving it again replaces the row rather than adding another.
+        swapcoin.hash_preimage = Some([2; 32]);
+        storage.save_incoming_swapcoin(&swapcoin).unwrap();
+        let (incoming, outgoing) = storage.load_swapcoins().unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].hash_preimage, Some([2; 32]));
+        assert!(outgoing.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_swapcoins_all_or_nothing() {
+        let path = temp_wallet_path("swapcoins");
+        let storage = WalletStorage::create(&path, "seed words", "ext", None).unwrap();
+        let contract_tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        let (other_pubkey, my_privkey) = crate::wallet_sync::generate_keypair();
+        let incoming = vec![IncomingSwapCoin {
+            my_privkey,
+            other_pubkey,
+            other_privkey: None,
+            contract_tx: contract_tx.clone(),
+            contract_redeemscript: Script::new(),
+            hashlock_privkey: my_privkey,
+            funding_amount: 100_000,
+            others_contract_sig: None,
+            hash_preimage: None,
+        }];
+        let (other_pubkey, my_privkey) = crate::wallet_sync::generate_keypair();
+        let outgoing = vec![OutgoingSwapCoin {
+            my_privkey,
+            other_pubkey,
+            contract_tx,
+            contract_redeemscript: Script::new(),
+            timelock_privkey: my_privkey,
+            funding_amount: 100_000,
+            others_contract_sig: None,
+            hash_preimage: None,
+        }];
+
+        // The outgoing swapcoin fails to insert after the incoming one was inserted
+        storage
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_outgoing BEFORE INSERT ON outgoing_swapcoin
+                    BEGIN SELECT RAISE(ABORT, 'disk full'); END;",
+            )
+            .unwrap();
+        assert!(storage.save_swapcoins(&incoming, &outgoing).is_err());
+        let (loaded_incoming, loaded_outgoing) = storage.load_swapcoins().unwrap();
+        assert!(loaded_incoming.is_empty());
+        assert!(loaded_outgoing.is_empty());
+
+        storage
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch("DROP TRIGGER fail_outgoing")
+            .unwrap();
+        storage.save_swapcoins(&incoming, &outgoing).unwrap();
+        let (loaded_incoming, loaded_outgoing) = storage.load_swapcoins().unwrap();
+        assert_eq!(loaded_incoming.len(), 1);
+        assert_eq!(loaded_outgoing.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_swap_ledger() {
+        let path = temp_wallet_path("ledger");
+        let storage = WalletStorage::create(&path, "seed words", "ext", None).unwrap();
+        let mut entry = SwapLedgerEntry {
+            hashvalue: Hash160::from_slice(&[2; 20]).unwrap(),
+            started_at: 100,
+            finished_at: None,
+            incoming_amount: 500_000,
+            outgoing_amount: 494_000,
+            coinswap_fee: 5_000,
+            miner_fee: 400,
+            recovery_fee: 0,
+            incoming_locktime: 50,
+            outgoing_locktime: 40,
+            outcome: SwapOutcome::InProgress,
+        };
+        storage.add_swap_ledger_entry(&entry).unwrap();
+        // Adding the same coinswap again keeps the first entry.
+        storage
+            .add_swap_ledger_entry(&SwapLedgerEntry {
+                started_at: 150,
+                ..entry.clone()
+            })
+            .unwrap();
+        storage
+            .finish_swap_ledger_entry(&entry.hashvalue, SwapOutcome::Completed, 200)
+            .unwrap();
+        // Only coinswaps in progress can be finished.
+        storage
+            .finish_swap_ledger_entry(&entry.hashvalue, SwapOutcome::TimedOut, 300)
+            .unwrap();
+        storage
+            .add_swap_ledger_recovery_fee(&entry.hashvalue, 100)
+            .unwrap();
+        storage
+            .add_swap_ledger_recovery_fee(&entry.hashvalue, 150)
+            .unwrap();
+        drop(storage);
+
+        let storage = WalletStorage::open(&path, None).unwrap();
+        entry.finished_at = Some(200);
+        entry.recovery_fee = 250;
+        entry.outcome = SwapOutcome::Completed;
+        assert_eq!(storage.load_swap_ledger().unwrap(), vec![entry]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,13 +4,11 @@
 // ways too such as a lightweight wallet method
 
 use std::{
-    fs,
-    fs::{File, OpenOptions},
     io,
-    io::Read,
     path::Path,
     str::FromStr,
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use std::collections::{HashMap, HashSet};
@@ -25,6 +23,7 @@ use bitcoin::{
     hashes::{
         hash160::Hash as Hash160,
         hex::{FromHex, ToHex},
+        sha256, Hash,
     },
     secp256k1,
     secp256k1::{Secp256k1, SecretKey, Signature},
@@ -32,27 +31,28 @@ use bitcoin::{
         bip143::SigHashCache,
         bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey},
         ecdsa::PublicKey,
-        psbt::serialize::Serialize,
+        psbt::{serialize::Serialize, PartiallySignedTransaction},
     },
     Address, Amount, Network, OutPoint, SigHashType, Transaction, TxIn, TxOut, Txid,
 };
 
-use bitcoincore_rpc::{
-    json::{
-        ImportMultiOptions, ImportMultiRequest, ImportMultiRequestScriptPubkey,
-        ImportMultiRescanSince, ListUnspentResultEntry,
-    },
-    Client, RpcApi,
+use bitcoincore_rpc::json::{
+    ImportMultiRequest, ImportMultiRequestScriptPubkey, ImportMultiRescanSince,
 };
 
-use serde_json::{json, Value};
-
 use bitcoin::secp256k1::rand::{rngs::OsRng, RngCore};
 
 use chrono::NaiveDateTime;
 
 use crate::{
-    contracts, contracts::SwapCoin, error::TeleportError, fidelity_bonds, messages::Preimage,
+    blockchain_backend::{BlockchainBackend, ListUnspentEntry, ScanObject},
+    contracts,
+    contracts::SwapCoin,
+    error::TeleportError,
+    fidelity_bonds,
+    maker_ledger::{SwapLedgerEntry, SwapOutcome},
+    messages::Preimage,
+    wallet_storage::{self, WalletStorage},
 };
 
 //these subroutines are coded so that as much as possible they keep all their
@@ -60,26 +60,41 @@ use crate::{
 //for example which privkey corresponds to a scriptpubkey is stored in hd paths
 
 const DERIVATION_PATH: &str = "m/84'/1'/0'";
-const WALLET_FILE_VERSION: u32 = 0;
-
-//TODO the wallet file format is probably best handled with sqlite
+//private keys of swapcoins get handed over to the other side at the end of a coinswap
+// so they must be hardened children, otherwise one key and an xpub would reveal the others
+pub(crate) const SWAPCOIN_KEY_PATH: &str = "m/84'/1'/0'/3'";
+//how many unused swapcoin keys to look ahead for when scanning the blockchain
+const SWAPCOIN_KEY_GAP_LIMIT: u32 = 100;
+//the secrets of a coinswap the wallet starts are hashes of swapcoin keys with these tags
+const PREIMAGE_TAG: &[u8] = b"teleport-preimage";
+const MULTISIG_NONCE_TAG: &[u8] = b"teleport-multisig-nonce";
+const HASHLOCK_NONCE_TAG: &[u8] = b"teleport-hashlock-nonce";
+//longest contract locktime tried when rebuilding a swapcoin, about two weeks of blocks
+const MAX_REBUILD_LOCKTIME: u16 = 2016;
+
+fn get_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_secs()
+}
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct WalletFileData {
-    version: u32,
-    seedphrase: String,
-    extension: String,
-    external_index: u32,
-    incoming_swapcoins: Vec<IncomingSwapCoin>,
-    outgoing_swapcoins: Vec<OutgoingSwapCoin>,
-    prevout_to_contract_map: HashMap<OutPoint, Script>,
+/// Contract outputs of past coinswaps which are still unspent, found by
+/// [Wallet::scan_for_used_swapcoin_keys].
+#[derive(Debug, Default)]
+pub struct SwapcoinKeyScan {
+    /// Contracts of the swapcoins which were rebuilt and added to the wallet.
+    pub rebuilt_contract_outpoints: Vec<OutPoint>,
+    /// Contracts which couldn't be rebuilt from the seed phrase.
+    pub locked_contract_outpoints: Vec<OutPoint>,
 }
 
 pub struct Wallet {
     pub network: Network,
     pub master_key: ExtendedPrivKey,
-    wallet_file_name: String,
+    storage: WalletStorage,
     external_index: u32,
+    swapcoin_key_index: u32,
     initial_address_import_count: usize,
     incoming_swapcoins: HashMap<Script, IncomingSwapCoin>,
     outgoing_swapcoins: HashMap<Script, OutgoingSwapCoin>,
@@ -128,7 +143,7 @@ impl FromStr for DisplayAddressType {
     }
 }
 
-//data needed to find information  in addition to ListUnspentResultEntry
+//data needed to find information  in addition to ListUnspentEntry
 //about a UTXO required to spend it
 #[derive(Debug, Clone)]
 pub enum UTXOSpendInfo {
@@ -617,50 +632,42 @@ impl Wallet {
         }
     }
 
+    /// Create a new wallet file, encrypted if a passphrase is given.
     pub fn save_new_wallet_file<P: AsRef<Path>>(
         wallet_file_name: P,
         seedphrase: String,
         extension: String,
+        passphrase: Option<String>,
     ) -> Result<(), TeleportError> {
-        let wallet_file_data = WalletFileData {
-            version: WALLET_FILE_VERSION,
-            seedphrase,
-            extension,
-            external_index: 0,
-            incoming_swapcoins: Vec::new(),
-            outgoing_swapcoins: Vec::new(),
-            prevout_to_contract_map: HashMap::<OutPoint, Script>::new(),
-        };
-        let wallet_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(wallet_file_name)?;
-        serde_json::to_writer(wallet_file, &wallet_file_data).map_err(|e| io::Error::from(e))?;
+        WalletStorage::create(
+            wallet_file_name.as_ref(),
+            &seedphrase,
+            &extension,
+            passphrase.as_deref(),
+        )?;
         Ok(())
     }
 
-    fn load_wallet_file_data<P: AsRef<Path>>(
+    /// Load a wallet file. The passphrase of an encrypted wallet is read from the
+    /// `TELEPORT_WALLET_PASSPHRASE` or `TELEPORT_WALLET_PASSPHRASE_FILE` env vars, or else
+    /// prompted for.
+    pub fn load_wallet_from_file<P: AsRef<Path>>(
         wallet_file_name: P,
-    ) -> Result<WalletFileData, TeleportError> {
-        let mut wallet_file = File::open(wallet_file_name)?;
-        let mut wallet_file_str = String::new();
-        wallet_file.read_to_string(&mut wallet_file_str)?;
-        Ok(serde_json::from_str::<WalletFileData>(&wallet_file_str)
-            .map_err(|e| io::Error::from(e))?)
+        network: Network,
+        sync_amount: WalletSyncAddressAmount,
+    ) -> Result<Wallet, TeleportError> {
+        Wallet::load_wallet_from_file_with_passphrase(wallet_file_name, None, network, sync_amount)
     }
 
-    pub fn load_wallet_from_file<P: AsRef<Path>>(
+    pub fn load_wallet_from_file_with_passphrase<P: AsRef<Path>>(
         wallet_file_name: P,
+        passphrase: Option<&str>,
         network: Network,
         sync_amount: WalletSyncAddressAmount,
     ) -> Result<Wallet, TeleportError> {
-        let wallet_file_name = wallet_file_name
-            .as_ref()
-            .as_os_str()
-            .to_string_lossy()
-            .to_string();
-        let wallet_file_data = Wallet::load_wallet_file_data(&wallet_file_name)?;
-        let mnemonic_ret = mnemonic::Mnemonic::from_str(&wallet_file_data.seedphrase);
+        let storage = WalletStorage::open(wallet_file_name.as_ref(), passphrase)?;
+        let (seedphrase, extension) = storage.get_seedphrase_and_extension()?;
+        let mnemonic_ret = mnemonic::Mnemonic::from_str(&seedphrase);
         if mnemonic_ret.is_err() {
             return Err(TeleportError::Disk(io::Error::new(
                 io::ErrorKind::Other,
@@ -668,34 +675,33 @@ impl Wallet {
             )));
         }
 
-        let seed = mnemonic_ret
-            .unwrap()
-            .to_seed(Some(&wallet_file_data.extension));
+        let seed = mnemonic_ret.unwrap().to_seed(Some(&extension));
         let xprv = ExtendedPrivKey::new_master(network, &seed.0).unwrap();
 
+        let external_index = storage.get_index(wallet_storage::EXTERNAL_INDEX)?;
+        let swapcoin_key_index = storage.get_index(wallet_storage::SWAPCOIN_KEY_INDEX)?;
+        let (incoming_swapcoins, outgoing_swapcoins) = storage.load_swapcoins()?;
         log::debug!(target: "wallet",
             "loaded wallet file, external_index={} incoming_swapcoins={} outgoing_swapcoins={}",
-            wallet_file_data.external_index,
-            wallet_file_data.incoming_swapcoins.len(), wallet_file_data.outgoing_swapcoins.len());
+            external_index, incoming_swapcoins.len(), outgoing_swapcoins.len());
 
         let wallet = Wallet {
             network,
             master_key: xprv,
-            wallet_file_name,
-            external_index: wallet_file_data.external_index,
+            storage,
+            external_index,
+            swapcoin_key_index,
             initial_address_import_count: match sync_amount {
                 WalletSyncAddressAmount::Normal => 5000,
                 WalletSyncAddressAmount::Testing => 6,
             },
-            incoming_swapcoins: wallet_file_data
-                .incoming_swapcoins
-                .iter()
-                .map(|sc| (sc.get_multisig_redeemscript(), sc.clone()))
+            incoming_swapcoins: incoming_swapcoins
+                .into_iter()
+                .map(|sc| (sc.get_multisig_redeemscript(), sc))
                 .collect::<HashMap<Script, IncomingSwapCoin>>(),
-            outgoing_swapcoins: wallet_file_data
-                .outgoing_swapcoins
-                .iter()
-                .map(|sc| (sc.get_multisig_redeemscript(), sc.clone()))
+            outgoing_swapcoins: outgoing_swapcoins
+                .into_iter()
+                .map(|sc| (sc.get_multisig_redeemscript(), sc))
                 .collect::<HashMap<Script, OutgoingSwapCoin>>(),
             offer_maxsize_cache: 0,
             timelocked_script_index_map: fidelity_bonds::generate_all_timelocked_addresses(&xprv),
@@ -703,38 +709,275 @@ impl Wallet {
         Ok(wallet)
     }
 
+    pub fn get_storage(&self) -> &WalletStorage {
+        &self.storage
+    }
+
+    pub fn get_wallet_file_path(&self) -> &Path {
+        self.storage.file_path()
+    }
+
     pub fn delete_wallet_file(&self) -> Result<(), TeleportError> {
-        Ok(fs::remove_file(&self.wallet_file_name)?)
+        self.storage.delete()
     }
 
     pub fn update_external_index(&mut self, new_external_index: u32) -> Result<(), TeleportError> {
         self.external_index = new_external_index;
-        let mut wallet_file_data = Wallet::load_wallet_file_data(&self.wallet_file_name)?;
-        wallet_file_data.external_index = new_external_index;
-        let wallet_file = File::create(&self.wallet_file_name[..])?;
-        serde_json::to_writer(wallet_file, &wallet_file_data).map_err(|e| io::Error::from(e))?;
-        Ok(())
+        self.storage
+            .set_index(wallet_storage::EXTERNAL_INDEX, new_external_index)
     }
 
-    pub fn get_external_index(&self) -> u32 {
-        self.external_index
+    fn update_swapcoin_key_index(
+        &mut self,
+        new_swapcoin_key_index: u32,
+    ) -> Result<(), TeleportError> {
+        self.swapcoin_key_index = new_swapcoin_key_index;
+        self.storage
+            .set_index(wallet_storage::SWAPCOIN_KEY_INDEX, new_swapcoin_key_index)
     }
 
-    pub fn save_to_disk(&self) -> Result<(), TeleportError> {
-        let mut wallet_file_data = Wallet::load_wallet_file_data(&self.wallet_file_name)?;
-        wallet_file_data.incoming_swapcoins = self
-            .incoming_swapcoins
-            .values()
-            .cloned()
-            .collect::<Vec<IncomingSwapCoin>>();
-        wallet_file_data.outgoing_swapcoins = self
-            .outgoing_swapcoins
-            .values()
-            .cloned()
-            .collect::<Vec<OutgoingSwapCoin>>();
-        let wallet_file = File::create(&self.wallet_file_name[..])?;
-        serde_json::to_writer(wallet_file, &wallet_file_data).map_err(|e| io::Error::from(e))?;
-        Ok(())
+    fn derive_swapcoin_keypair(&self, index: u32) -> (PublicKey, SecretKey) {
+        let secp = Secp256k1::new();
+        let privkey = self
+            .master_key
+            .derive_priv(&secp, &DerivationPath::from_str(SWAPCOIN_KEY_PATH).unwrap())
+            .unwrap()
+            .ckd_priv(&secp, ChildNumber::from_hardened_idx(index).unwrap())
+            .unwrap()
+            .private_key;
+        (privkey.public_key(&secp), privkey.key)
+    }
+
+    /// Derive the next `count` swapcoin keypairs from the seed. The key index is saved to the
+    /// wallet file before the keys are returned, so that no key is ever used twice.
+    pub fn get_next_swapcoin_keypairs(
+        &mut self,
+        count: u32,
+    ) -> Result<Vec<(PublicKey, SecretKey)>, TeleportError> {
+        let first_index = self.swapcoin_key_index;
+        self.update_swapcoin_key_index(first_index + count)?;
+        Ok((first_index..first_index + count)
+            .map(|index| self.derive_swapcoin_keypair(index))
+            .collect())
+    }
+
+    fn derive_swap_preimage(&self, index: u32) -> Preimage {
+        let privkey = self.derive_swapcoin_keypair(index).1;
+        sha256::Hash::hash(&[PREIMAGE_TAG, &privkey[..]].concat()).into_inner()
+    }
+
+    /// Derive the preimage of a new coinswap from the next swapcoin key, so that the hashvalue
+    /// of its contracts can be found again from the seed phrase.
+    pub fn get_next_swap_preimage(&mut self) -> Result<Preimage, TeleportError> {
+        let index = self.swapcoin_key_index;
+        self.update_swapcoin_key_index(index + 1)?;
+        Ok(self.derive_swap_preimage(index))
+    }
+
+    //nonces of the first maker's multisig and hashlock keys, from our multisig key
+    fn derive_first_hop_nonces(&self, multisig_key_index: u32) -> (SecretKey, SecretKey) {
+        let privkey = self.derive_swapcoin_keypair(multisig_key_index).1;
+        let nonce = |tag: &[u8]| {
+            SecretKey::from_slice(&sha256::Hash::hash(&[tag, &privkey[..]].concat())).unwrap()
+        };
+        (nonce(MULTISIG_NONCE_TAG), nonce(HASHLOCK_NONCE_TAG))
+    }
+
+    /// Nonces for the first maker's multisig and hashlock keys of the next `count` outgoing
+    /// swapcoins, derived from the multisig keys the next [Wallet::initalize_coinswap] will use.
+    /// That makes the first maker's keys, and with them the contracts of the first hop, possible
+    /// to find from the seed phrase. Must be followed by [Wallet::initalize_coinswap] without
+    /// other swapcoin keys being derived in between.
+    pub fn get_next_first_hop_nonces(&self, count: u32) -> (Vec<SecretKey>, Vec<SecretKey>) {
+        (0..count)
+            .map(|i| self.derive_first_hop_nonces(self.swapcoin_key_index + 2 * i))
+            .unzip()
+    }
+
+    /// Scan the blocks from `start_height` for multisig outputs spent with one of our swapcoin
+    /// keys, for when the wallet was recovered from only the seed phrase. The swapcoin key
+    /// index is moved past every key found, so that no key gets used twice.
+    ///
+    /// Outgoing swapcoins of the first hop of coinswaps the wallet started are rebuilt from
+    /// their contract txes and added to the wallet, so [crate::recovery] can get their coins
+    /// back once the timelock matures. Their keys, preimage and the first maker's nonces are
+    /// all derived from the seed, the locktime is found by trying each up to
+    /// [MAX_REBUILD_LOCKTIME]. Other swapcoins can't be rebuilt because their contracts hold
+    /// keys of the other side which never appear on the blockchain, so the outputs of those
+    /// contract txes are only listed. Swapcoins whose multisig output is still unspent can't be
+    /// found at all, an unspent p2wsh output doesn't show its script.
+    pub fn scan_for_used_swapcoin_keys(
+        &mut self,
+        rpc: &dyn BlockchainBackend,
+        start_height: u64,
+    ) -> Result<SwapcoinKeyScan, TeleportError> {
+        let mut pubkey_indexes = HashMap::<PublicKey, u32>::new();
+        let mut next_index = self.swapcoin_key_index;
+        let mut scan = SwapcoinKeyScan::default();
+        let tip_height = rpc.get_block_count()?;
+        for height in start_height..(tip_height + 1) {
+            while (pubkey_indexes.len() as u32) < next_index + SWAPCOIN_KEY_GAP_LIMIT {
+                let index = pubkey_indexes.len() as u32;
+                pubkey_indexes.insert(self.derive_swapcoin_keypair(index).0, index);
+            }
+            let block = rpc.get_block(&rpc.get_block_hash(height)?)?;
+            for tx in &block.txdata {
+                for input in &tx.input {
+                    //multisig spends have the witness [empty, sig, sig, 2-of-2 redeemscript]
+                    if input.witness.len() != 4 || input.witness[3].len() != 71 {
+                        continue;
+                    }
+                    let multisig_redeemscript = Script::from(input.witness[3].clone());
+                    let (pubkey1, pubkey2) =
+                        match contracts::read_pubkeys_from_multisig_redeemscript(
+                            &multisig_redeemscript,
+                        ) {
+                            Some(pubkeys) => pubkeys,
+                            None => continue,
+                        };
+                    if create_multisig_redeemscript(&pubkey1, &pubkey2) != multisig_redeemscript {
+                        continue;
+                    }
+                    let (index, other_pubkey) =
+                        match (pubkey_indexes.get(&pubkey1), pubkey_indexes.get(&pubkey2)) {
+                            (Some(&index), _) => (index, pubkey2),
+                            (None, Some(&index)) => (index, pubkey1),
+                            (None, None) => continue,
+                        };
+                    log::debug!(target: "wallet",
+                        "swapcoin key {} used in tx {} at height {}", index, tx.txid(), height);
+                    //outgoing swapcoins use the next key as timelock key
+                    next_index = next_index.max(index + 2);
+                    //contract txes are one-input-one-output
+                    if tx.input.len() != 1
+                        || tx.output.len() != 1
+                        || !tx.output[0].script_pubkey.is_v0_p2wsh()
+                        || rpc.get_tx_out(&tx.txid(), 0)?.is_none()
+                        || self
+                            .find_incoming_swapcoin(&multisig_redeemscript)
+                            .is_some()
+                        || self
+                            .find_outgoing_swapcoin(&multisig_redeemscript)
+                            .is_some()
+                    {
+                        continue;
+                    }
+                    let contract_outpoint = OutPoint {
+                        txid: tx.txid(),
+                        vout: 0,
+                    };
+                    match self.rebuild_outgoing_swapcoin(rpc, tx, index, other_pubkey)? {
+                        Some(swapcoin) => {
+                            log::info!(target: "wallet",
+                                "rebuilt outgoing swapcoin with contract {}", contract_outpoint);
+                            self.add_outgoing_swapcoin(swapcoin)?;
+                            scan.rebuilt_contract_outpoints.push(contract_outpoint);
+                        }
+                        None => scan.locked_contract_outpoints.push(contract_outpoint),
+                    }
+                }
+            }
+        }
+        if next_index > self.swapcoin_key_index {
+            self.update_swapcoin_key_index(next_index)?;
+        }
+        Ok(scan)
+    }
+
+    //returns None if the contract isnt one of the first hop of a coinswap we started
+    fn rebuild_outgoing_swapcoin(
+        &self,
+        rpc: &dyn BlockchainBackend,
+        contract_tx: &Transaction,
+        multisig_key_index: u32,
+        other_pubkey: PublicKey,
+    ) -> Result<Option<OutgoingSwapCoin>, TeleportError> {
+        let secp = Secp256k1::new();
+        let (my_pubkey, my_privkey) = self.derive_swapcoin_keypair(multisig_key_index);
+        let (timelock_pubkey, timelock_privkey) =
+            self.derive_swapcoin_keypair(multisig_key_index + 1);
+        //both of the maker's keys are its tweakable point tweaked by a nonce, so the hashlock
+        //key is the multisig key tweaked by the difference of the nonces
+        let (multisig_nonce, hashlock_nonce) = self.derive_first_hop_nonces(multisig_key_index);
+        let mut tweak = multisig_nonce;
+        tweak.negate_assign();
+        let mut hashlock_pubkey = other_pubkey;
+        if tweak.add_assign(&hashlock_nonce[..]).is_err()
+            || hashlock_pubkey
+                .key
+                .add_exp_assign(&secp, &tweak[..])
+                .is_err()
+        {
+            return Ok(None);
+        }
+
+        //the preimage was derived from a key before the multisig key
+        let contract_scriptpubkey = &contract_tx.output[0].script_pubkey;
+        let contract_redeemscript = match (multisig_key_index.saturating_sub(SWAPCOIN_KEY_GAP_LIMIT)
+            ..multisig_key_index)
+            .rev()
+            .map(|index| Hash160::hash(&self.derive_swap_preimage(index)))
+            .flat_map(|hashvalue| {
+                (1..=MAX_REBUILD_LOCKTIME).map(move |locktime| {
+                    contracts::create_contract_redeemscript(
+                        &hashlock_pubkey,
+                        &timelock_pubkey,
+                        hashvalue,
+                        locktime,
+                    )
+                })
+            })
+            .find(|redeemscript| {
+                contracts::redeemscript_to_scriptpubkey(redeemscript) == *contract_scriptpubkey
+            }) {
+            Some(redeemscript) => redeemscript,
+            None => return Ok(None),
+        };
+
+        let funding_outpoint = contract_tx.input[0].previous_output;
+        let funding_amount = rpc
+            .get_raw_transaction(&funding_outpoint.txid, None)?
+            .output[funding_outpoint.vout as usize]
+            .value;
+        //the signatures are in the order of the keys in the multisig redeemscript
+        let witness = &contract_tx.input[0].witness;
+        let other_sig = if other_pubkey.serialize()[..] < my_pubkey.serialize()[..] {
+            &witness[1]
+        } else {
+            &witness[2]
+        };
+        let others_contract_sig = match Signature::from_der(&other_sig[..other_sig.len() - 1]) {
+            Ok(sig) => sig,
+            Err(_) => return Ok(None),
+        };
+
+        import_multisig_redeemscript_descriptor(
+            rpc,
+            &my_pubkey,
+            &other_pubkey,
+            &self.get_core_wallet_label(),
+        )?;
+        self.import_wallet_contract_redeemscript(rpc, &contract_redeemscript)?;
+        let mut unsigned_contract_tx = contract_tx.clone();
+        unsigned_contract_tx.input[0].witness = Vec::new();
+        let mut swapcoin = OutgoingSwapCoin::new(
+            my_privkey,
+            other_pubkey,
+            unsigned_contract_tx,
+            contract_redeemscript,
+            timelock_privkey,
+            funding_amount,
+        );
+        swapcoin.others_contract_sig = Some(others_contract_sig);
+        Ok(Some(swapcoin))
+    }
+
+    pub fn get_swapcoin_key_index(&self) -> u32 {
+        self.swapcoin_key_index
+    }
+
+    pub fn get_external_index(&self) -> u32 {
+        self.external_index
     }
 
     pub fn find_incoming_swapcoin(
@@ -758,14 +1001,80 @@ impl Wallet {
         self.incoming_swapcoins.get_mut(multisig_redeemscript)
     }
 
-    pub fn add_incoming_swapcoin(&mut self, coin: IncomingSwapCoin) {
+    /// Save the swapcoin changed through [Wallet::find_incoming_swapcoin_mut] to the wallet
+    /// file.
+    pub fn save_incoming_swapcoin(
+        &self,
+        multisig_redeemscript: &Script,
+    ) -> Result<(), TeleportError> {
+        match self.incoming_swapcoins.get(multisig_redeemscript) {
+            Some(coin) => self.storage.save_incoming_swapcoin(coin),
+            None => Err(TeleportError::Protocol("multisig_redeemscript not found")),
+        }
+    }
+
+    /// Add the swapcoin, replacing any with the same multisig redeemscript, and save it to the
+    /// wallet file.
+    pub fn add_incoming_swapcoin(&mut self, coin: IncomingSwapCoin) -> Result<(), TeleportError> {
+        self.storage.save_incoming_swapcoin(&coin)?;
         self.incoming_swapcoins
             .insert(coin.get_multisig_redeemscript(), coin);
+        Ok(())
     }
 
-    pub fn add_outgoing_swapcoin(&mut self, coin: OutgoingSwapCoin) {
+    /// Add the swapcoin, replacing any with the same multisig redeemscript, and save it to the
+    /// wallet file.
+    pub fn add_outgoing_swapcoin(&mut self, coin: OutgoingSwapCoin) -> Result<(), TeleportError> {
+        self.storage.save_outgoing_swapcoin(&coin)?;
         self.outgoing_swapcoins
             .insert(coin.get_multisig_redeemscript(), coin);
+        Ok(())
+    }
+
+    /// Remove the swapcoin whose contract tx has the txid, once its coins are recovered.
+    /// Returns false if there is no such swapcoin.
+    pub fn remove_swapcoin_with_contract(
+        &mut self,
+        contract_txid: &Txid,
+    ) -> Result<bool, TeleportError> {
+        let multisig_redeemscript = match self
+            .incoming_swapcoins
+            .iter()
+            .map(|(rs, sc)| (rs, sc.get_contract_tx().txid()))
+            .chain(
+                self.outgoing_swapcoins
+                    .iter()
+                    .map(|(rs, sc)| (rs, sc.get_contract_tx().txid())),
+            )
+            .find(|(_, txid)| txid == contract_txid)
+        {
+            Some((rs, _)) => rs.clone(),
+            None => return Ok(false),
+        };
+        self.storage.delete_swapcoin(&multisig_redeemscript)?;
+        self.incoming_swapcoins.remove(&multisig_redeemscript);
+        self.outgoing_swapcoins.remove(&multisig_redeemscript);
+        Ok(true)
+    }
+
+    /// Add the swapcoins of a coinswap like [Wallet::add_incoming_swapcoin] and
+    /// [Wallet::add_outgoing_swapcoin], saving all of them to the wallet file or none.
+    pub fn add_swapcoins(
+        &mut self,
+        incoming_swapcoins: Vec<IncomingSwapCoin>,
+        outgoing_swapcoins: Vec<OutgoingSwapCoin>,
+    ) -> Result<(), TeleportError> {
+        self.storage
+            .save_swapcoins(&incoming_swapcoins, &outgoing_swapcoins)?;
+        for coin in incoming_swapcoins {
+            self.incoming_swapcoins
+                .insert(coin.get_multisig_redeemscript(), coin);
+        }
+        for coin in outgoing_swapcoins {
+            self.outgoing_swapcoins
+                .insert(coin.get_multisig_redeemscript(), coin);
+        }
+        Ok(())
     }
 
     pub fn get_swapcoins_count(&self) -> usize {
@@ -795,13 +1104,10 @@ impl Wallet {
         prevout: &OutPoint,
         contract_scriptpubkey: &Script,
     ) -> Result<bool, TeleportError> {
-        let wallet_file_data = Wallet::load_wallet_file_data(&self.wallet_file_name[..])?;
-        Ok(
-            match wallet_file_data.prevout_to_contract_map.get(prevout) {
-                Some(c) => c == contract_scriptpubkey,
-                None => true,
-            },
-        )
+        Ok(match self.storage.get_prevout_contract(prevout)? {
+            Some(c) => &c == contract_scriptpubkey,
+            None => true,
+        })
     }
 
     pub fn add_prevout_and_contract_to_cache(
@@ -809,20 +1115,14 @@ impl Wallet {
         prevout: OutPoint,
         contract: Script,
     ) -> Result<(), TeleportError> {
-        let mut wallet_file_data = Wallet::load_wallet_file_data(&self.wallet_file_name[..])?;
-        wallet_file_data
-            .prevout_to_contract_map
-            .insert(prevout, contract);
-        let wallet_file = File::create(&self.wallet_file_name[..])?;
-        serde_json::to_writer(wallet_file, &wallet_file_data).map_err(|e| io::Error::from(e))?;
-        Ok(())
+        self.storage.add_prevout_contract(&prevout, &contract)
     }
 
     //pub fn get_recovery_phrase_from_file()
 
     fn is_xpub_descriptor_imported(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         descriptor: &str,
     ) -> Result<bool, TeleportError> {
         let first_addr = rpc.derive_addresses(&descriptor, Some([0, 0]))?[0].clone();
@@ -830,27 +1130,25 @@ impl Wallet {
         let last_addr =
             rpc.derive_addresses(&descriptor, Some([last_index, last_index]))?[0].clone();
 
-        let first_addr_imported = rpc
-            .get_address_info(&first_addr)?
-            .is_watchonly
-            .unwrap_or(false);
-        let last_addr_imported = rpc
-            .get_address_info(&last_addr)?
-            .is_watchonly
-            .unwrap_or(false);
+        let first_addr_imported = rpc.get_address_info(&first_addr)?.is_watchonly;
+        let last_addr_imported = rpc.get_address_info(&last_addr)?.is_watchonly;
 
         Ok(first_addr_imported && last_addr_imported)
     }
 
-    fn is_swapcoin_descriptor_imported(&self, rpc: &Client, descriptor: &str) -> bool {
+    fn is_swapcoin_descriptor_imported(
+        &self,
+        rpc: &dyn BlockchainBackend,
+        descriptor: &str,
+    ) -> bool {
         let addr = rpc.derive_addresses(&descriptor, None).unwrap()[0].clone();
-        rpc.get_address_info(&addr)
-            .unwrap()
-            .is_watchonly
-            .unwrap_or(false)
+        rpc.get_address_info(&addr).unwrap().is_watchonly
     }
 
-    pub fn get_hd_wallet_descriptors(&self, rpc: &Client) -> Result<Vec<String>, TeleportError> {
+    pub fn get_hd_wallet_descriptors(
+        &self,
+        rpc: &dyn BlockchainBackend,
+    ) -> Result<Vec<String>, TeleportError> {
         let secp = Secp256k1::new();
         let wallet_xpub = ExtendedPubKey::from_private(
             &secp,
@@ -860,14 +1158,10 @@ impl Wallet {
                 .unwrap(),
         );
         let address_type = [0, 1];
-        let descriptors: Result<Vec<String>, bitcoincore_rpc::Error> = address_type
+        address_type
             .iter()
-            .map(|at| {
-                rpc.get_descriptor_info(&format!("wpkh({}/{}/*)", wallet_xpub, at))
-                    .map(|getdescriptorinfo_result| getdescriptorinfo_result.descriptor)
-            })
-            .collect();
-        descriptors.map_err(|e| TeleportError::Rpc(e))
+            .map(|at| rpc.get_descriptor_with_checksum(&format!("wpkh({}/{}/*)", wallet_xpub, at)))
+            .collect()
     }
 
     pub fn get_core_wallet_label(&self) -> String {
@@ -878,7 +1172,7 @@ impl Wallet {
 
     pub fn import_initial_addresses(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         hd_descriptors_to_import: &[&String],
         swapcoin_descriptors_to_import: &[String],
         contract_scriptpubkeys_to_import: &[Script],
@@ -933,12 +1227,7 @@ impl Wallet {
             )
             .collect::<Vec<ImportMultiRequest>>();
 
-        let result = rpc.import_multi(
-            &import_requests,
-            Some(&ImportMultiOptions {
-                rescan: Some(false),
-            }),
-        )?;
+        let result = rpc.import_multi(&import_requests)?;
         for r in result {
             if !r.success {
                 return Err(TeleportError::Rpc(
@@ -949,7 +1238,7 @@ impl Wallet {
         Ok(())
     }
 
-    pub fn startup_sync(&mut self, rpc: &Client) -> Result<(), TeleportError> {
+    pub fn startup_sync(&mut self, rpc: &dyn BlockchainBackend) -> Result<(), TeleportError> {
         //TODO many of these unwraps to be replaced with proper error handling
         let hd_descriptors = self.get_hd_wallet_descriptors(rpc)?;
         let hd_descriptors_to_import = hd_descriptors
@@ -967,7 +1256,7 @@ impl Wallet {
                     sc.get_my_pubkey()
                 )
             })
-            .map(|d| rpc.get_descriptor_info(&d).unwrap().descriptor)
+            .map(|d| rpc.get_descriptor_with_checksum(&d).unwrap())
             .filter(|d| !self.is_swapcoin_descriptor_imported(rpc, &d))
             .collect::<Vec<String>>();
 
@@ -981,7 +1270,7 @@ impl Wallet {
                         sc.get_my_pubkey()
                     )
                 })
-                .map(|d| rpc.get_descriptor_info(&d).unwrap().descriptor)
+                .map(|d| rpc.get_descriptor_with_checksum(&d).unwrap())
                 .filter(|d| !self.is_swapcoin_descriptor_imported(rpc, &d)),
         );
 
@@ -995,7 +1284,7 @@ impl Wallet {
                 )
             })
             .filter(|(_, result_gai)| result_gai.is_ok())
-            .filter(|(_, result_gai)| !(result_gai.as_ref().unwrap().is_watchonly.unwrap_or(false)))
+            .filter(|(_, result_gai)| !(result_gai.as_ref().unwrap().is_watchonly))
             .map(|(c_spk, _)| c_spk)
             .collect::<Vec<Script>>();
 
@@ -1012,9 +1301,7 @@ impl Wallet {
                     )
                 })
                 .filter(|(_, result_gai)| result_gai.is_ok())
-                .filter(|(_, result_gai)| {
-                    !(result_gai.as_ref().unwrap().is_watchonly.unwrap_or(false))
-                })
+                .filter(|(_, result_gai)| !(result_gai.as_ref().unwrap().is_watchonly))
                 .map(|(c_spk, _)| c_spk),
         );
 
@@ -1031,14 +1318,9 @@ impl Wallet {
         );
         log::debug!(target: "wallet", "first_timelocked_addr={} last_timelocked_addr={}",
             first_timelocked_addr, last_timelocked_addr);
-        let is_timelock_branch_imported = rpc
-            .get_address_info(&first_timelocked_addr)?
-            .is_watchonly
-            .unwrap_or(false)
-            && rpc
-                .get_address_info(&last_timelocked_addr)?
-                .is_watchonly
-                .unwrap_or(false);
+        let is_timelock_branch_imported =
+            rpc.get_address_info(&first_timelocked_addr)?.is_watchonly
+                && rpc.get_address_info(&last_timelocked_addr)?.is_watchonly;
 
         log::debug!(target: "wallet",
             concat!("hd_descriptors_to_import.len = {} swapcoin_descriptors_to_import.len = {}",
@@ -1062,61 +1344,50 @@ impl Wallet {
             &contract_scriptpubkeys_to_import,
         )?;
 
-        rpc.call::<Value>("scantxoutset", &[json!("abort")])?;
-        let desc_list = hd_descriptors_to_import
+        let scan_objects = hd_descriptors_to_import
             .iter()
-            .map(|d| {
-                json!(
-                {"desc": d,
-                "range": self.initial_address_import_count-1})
+            .map(|d| ScanObject {
+                descriptor: d.to_string(),
+                range_end: Some(self.initial_address_import_count as u32 - 1),
             })
-            .chain(swapcoin_descriptors_to_import.iter().map(|d| json!(d)))
+            .chain(swapcoin_descriptors_to_import.iter().map(|d| ScanObject {
+                descriptor: d.to_string(),
+                range_end: None,
+            }))
             .chain(
                 contract_scriptpubkeys_to_import
                     .iter()
-                    .map(|spk| json!({ "desc": format!("raw({:x})", spk) })),
-            )
-            .chain(
-                self.timelocked_script_index_map
-                    .keys()
-                    .map(|spk| json!({ "desc": format!("raw({:x})", spk) })),
+                    .chain(self.timelocked_script_index_map.keys())
+                    .map(|spk| ScanObject {
+                        descriptor: format!("raw({:x})", spk),
+                        range_end: None,
+                    }),
             )
-            .collect::<Vec<Value>>();
+            .collect::<Vec<ScanObject>>();
 
-        let scantxoutset_result: Value =
-            rpc.call("scantxoutset", &[json!("start"), json!(desc_list)])?;
-        if !scantxoutset_result["success"].as_bool().unwrap() {
-            return Err(TeleportError::Rpc(
-                bitcoincore_rpc::Error::UnexpectedStructure,
-            ));
-        }
+        let scan = rpc.scan_tx_out_set(&scan_objects)?;
         log::info!(target: "wallet", "TxOut set scan complete, found {} btc",
-            Amount::from_sat(convert_json_rpc_bitcoin_to_satoshis(&scantxoutset_result["total_amount"])),
+            scan.total_amount,
         );
-        let unspent_list = scantxoutset_result["unspents"].as_array().unwrap();
         log::debug!(target: "wallet", "scantxoutset found_coins={} txouts={} height={} bestblock={}",
-            unspent_list.len(),
-            scantxoutset_result["txouts"].as_u64().unwrap(),
-            scantxoutset_result["height"].as_u64().unwrap(),
-            scantxoutset_result["bestblock"].as_str().unwrap(),
+            scan.unspents.len(),
+            scan.txouts,
+            scan.height,
+            scan.best_block,
         );
-        for unspent in unspent_list {
-            let blockhash = rpc.get_block_hash(unspent["height"].as_u64().unwrap())?;
-            let txid = Txid::from_hex(unspent["txid"].as_str().unwrap()).unwrap();
-            let rawtx = rpc.get_raw_transaction_hex(&txid, Some(&blockhash));
-            if let Ok(rawtx_hex) = rawtx {
+        for unspent in scan.unspents {
+            let blockhash = rpc.get_block_hash(unspent.height)?;
+            let rawtx = rpc.get_raw_transaction(&unspent.txid, Some(&blockhash));
+            if let Ok(rawtx) = rawtx {
                 log::debug!(target: "wallet", "found coin {}:{} {} height={} {}",
-                    txid,
-                    unspent["vout"].as_u64().unwrap(),
-                    Amount::from_sat(convert_json_rpc_bitcoin_to_satoshis(&unspent["amount"])),
-                    unspent["height"].as_u64().unwrap(),
-                    unspent["desc"].as_str().unwrap(),
+                    unspent.txid,
+                    unspent.vout,
+                    unspent.amount,
+                    unspent.height,
+                    unspent.descriptor,
                 );
-                let merkleproof = rpc.get_tx_out_proof(&[txid], Some(&blockhash))?.to_hex();
-                rpc.call(
-                    "importprunedfunds",
-                    &[Value::String(rawtx_hex), Value::String(merkleproof)],
-                )?;
+                let merkleproof = rpc.get_tx_out_proof(&[unspent.txid], Some(&blockhash))?;
+                rpc.import_pruned_funds(&rawtx, &merkleproof)?;
             } else {
                 log::error!(target: "wallet", "block pruned, TODO add UTXO to wallet file");
                 panic!("teleport doesnt work with pruning yet, try rescanning");
@@ -1158,7 +1429,7 @@ impl Wallet {
 
     fn is_utxo_ours_and_spendable_get_pointer(
         &self,
-        u: &ListUnspentResultEntry,
+        u: &ListUnspentEntry,
         option_contract_scriptpubkeys_outgoing_swapcoins: Option<
             &HashMap<Script, &OutgoingSwapCoin>,
         >,
@@ -1250,17 +1521,24 @@ impl Wallet {
         }
     }
 
-    pub fn lock_all_nonwallet_unspents(&self, rpc: &Client) -> Result<(), TeleportError> {
-        //rpc.unlock_unspent(&[])?;
-        //https://github.com/rust-bitcoin/rust-bitcoincore-rpc/issues/148
-        rpc.call::<Value>("lockunspent", &[Value::Bool(true)])?;
+    //also locks the reserved utxos, so walletcreatefundedpsbt doesnt pick them
+    pub fn lock_all_nonwallet_unspents(
+        &self,
+        rpc: &dyn BlockchainBackend,
+    ) -> Result<(), TeleportError> {
+        rpc.unlock_unspent_all()?;
 
-        let all_unspents = rpc.list_unspent(Some(0), Some(9999999), None, None, None)?;
+        let reserved_utxos = self.get_reserved_utxos()?;
+        let all_unspents = rpc.list_unspent()?;
         let utxos_to_lock = &all_unspents
             .into_iter()
             .filter(|u| {
                 self.is_utxo_ours_and_spendable_get_pointer(u, None, None, false)
                     .is_none()
+                    || reserved_utxos.contains(&OutPoint {
+                        txid: u.txid,
+                        vout: u.vout,
+                    })
             })
             .map(|u| OutPoint {
                 txid: u.txid,
@@ -1271,12 +1549,83 @@ impl Wallet {
         Ok(())
     }
 
+    /// Keep the outpoints out of funding transactions and the offer maxsize until they are
+    /// released or the expiry passes. Reservations are saved in the wallet file, so they hold
+    /// across restarts and for other processes using the same wallet.
+    pub fn reserve_utxos(
+        &self,
+        outpoints: &[OutPoint],
+        expiry: Duration,
+    ) -> Result<(), TeleportError> {
+        let now = get_unix_time();
+        log::debug!(target: "wallet", "reserving utxos {:?} for {:?}", outpoints, expiry);
+        self.storage
+            .reserve_utxos(outpoints, now, now + expiry.as_secs())
+    }
+
+    pub fn release_utxos(&self, outpoints: &[OutPoint]) -> Result<(), TeleportError> {
+        log::debug!(target: "wallet", "releasing utxos {:?}", outpoints);
+        self.storage.release_utxos(outpoints)
+    }
+
+    pub fn get_reserved_utxos(&self) -> Result<HashSet<OutPoint>, TeleportError> {
+        self.storage.get_reserved_utxos(get_unix_time())
+    }
+
+    /// Save the state of one of the maker's connections, so an in-flight swap survives a crash.
+    /// `None` adds a new entry, the returned id is used to update or delete it later.
+    pub fn save_maker_connection(
+        &self,
+        id: Option<i64>,
+        state: &str,
+    ) -> Result<i64, TeleportError> {
+        self.storage.save_maker_connection(id, state)
+    }
+
+    pub fn load_maker_connections(&self) -> Result<Vec<(i64, String)>, TeleportError> {
+        self.storage.load_maker_connections()
+    }
+
+    pub fn delete_maker_connection(&self, id: i64) -> Result<(), TeleportError> {
+        self.storage.delete_maker_connection(id)
+    }
+
+    /// Record a coinswap the maker funded in its ledger, see [crate::maker_ledger].
+    pub fn add_swap_ledger_entry(&self, entry: &SwapLedgerEntry) -> Result<(), TeleportError> {
+        self.storage.add_swap_ledger_entry(entry)
+    }
+
+    /// Record how a coinswap in the ledger ended, coinswaps which already ended are left as
+    /// they are.
+    pub fn finish_swap_ledger_entry(
+        &self,
+        hashvalue: &Hash160,
+        outcome: SwapOutcome,
+    ) -> Result<(), TeleportError> {
+        self.storage
+            .finish_swap_ledger_entry(hashvalue, outcome, get_unix_time())
+    }
+
+    /// Book miner fees which recovering the coins of a coinswap cost, see
+    /// [crate::maker_ledger].
+    pub fn add_swap_ledger_recovery_fee(
+        &self,
+        hashvalue: &Hash160,
+        fee: u64,
+    ) -> Result<(), TeleportError> {
+        self.storage.add_swap_ledger_recovery_fee(hashvalue, fee)
+    }
+
+    pub fn load_swap_ledger(&self) -> Result<Vec<SwapLedgerEntry>, TeleportError> {
+        self.storage.load_swap_ledger()
+    }
+
     pub fn list_unspent_from_wallet(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         include_live_contracts: bool,
         include_fidelity_bonds: bool,
-    ) -> Result<Vec<(ListUnspentResultEntry, UTXOSpendInfo)>, TeleportError> {
+    ) -> Result<Vec<(ListUnspentEntry, UTXOSpendInfo)>, TeleportError> {
         let (contract_scriptpubkeys_outgoing_swapcoins, contract_scriptpubkeys_incoming_swapcoins) =
             if include_live_contracts {
                 (
@@ -1289,10 +1638,9 @@ impl Wallet {
                     HashMap::<Script, &IncomingSwapCoin>::new(),
                 )
             };
-        rpc.call::<Value>("lockunspent", &[Value::Bool(true)])
-            .map_err(|e| TeleportError::Rpc(e))?;
+        rpc.unlock_unspent_all()?;
         Ok(rpc
-            .list_unspent(Some(0), Some(9999999), None, None, None)?
+            .list_unspent()?
             .iter()
             .map(|u| {
                 (
@@ -1315,24 +1663,23 @@ impl Wallet {
             })
             .filter(|(_u, o_info)| o_info.is_some())
             .map(|(u, o_info)| (u.clone(), o_info.unwrap()))
-            .collect::<Vec<(ListUnspentResultEntry, UTXOSpendInfo)>>())
+            .collect::<Vec<(ListUnspentEntry, UTXOSpendInfo)>>())
     }
 
     pub fn find_incomplete_coinswaps(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
     ) -> Result<
         HashMap<
             Hash160,
             (
-                Vec<(ListUnspentResultEntry, &IncomingSwapCoin)>,
-                Vec<(ListUnspentResultEntry, &OutgoingSwapCoin)>,
+                Vec<(ListUnspentEntry, &IncomingSwapCoin)>,
+                Vec<(ListUnspentEntry, &OutgoingSwapCoin)>,
             ),
         >,
         TeleportError,
     > {
-        rpc.call::<Value>("lockunspent", &[Value::Bool(true)])
-            .map_err(|e| TeleportError::Rpc(e))?;
+        rpc.unlock_unspent_all()?;
 
         let completed_coinswap_hashvalues = self
             .incoming_swapcoins
@@ -1344,8 +1691,8 @@ impl Wallet {
         let mut incomplete_swapcoin_groups = HashMap::<
             Hash160,
             (
-                Vec<(ListUnspentResultEntry, &IncomingSwapCoin)>,
-                Vec<(ListUnspentResultEntry, &OutgoingSwapCoin)>,
+                Vec<(ListUnspentEntry, &IncomingSwapCoin)>,
+                Vec<(ListUnspentEntry, &OutgoingSwapCoin)>,
             ),
         >::new();
         let get_hashvalue = |s: &dyn SwapCoin| {
@@ -1355,7 +1702,7 @@ impl Wallet {
             }
             Some(swapcoin_hashvalue)
         };
-        for utxo in rpc.list_unspent(Some(0), Some(9999999), None, None, None)? {
+        for utxo in rpc.list_unspent()? {
             if utxo.descriptor.is_none() {
                 continue;
             }
@@ -1369,8 +1716,8 @@ impl Wallet {
                     incomplete_swapcoin_groups
                         .entry(swapcoin_hashvalue)
                         .or_insert((
-                            Vec::<(ListUnspentResultEntry, &IncomingSwapCoin)>::new(),
-                            Vec::<(ListUnspentResultEntry, &OutgoingSwapCoin)>::new(),
+                            Vec::<(ListUnspentEntry, &IncomingSwapCoin)>::new(),
+                            Vec::<(ListUnspentEntry, &OutgoingSwapCoin)>::new(),
                         ))
                         .0
                         .push((utxo, s));
@@ -1380,8 +1727,8 @@ impl Wallet {
                     incomplete_swapcoin_groups
                         .entry(swapcoin_hashvalue)
                         .or_insert((
-                            Vec::<(ListUnspentResultEntry, &IncomingSwapCoin)>::new(),
-                            Vec::<(ListUnspentResultEntry, &OutgoingSwapCoin)>::new(),
+                            Vec::<(ListUnspentEntry, &IncomingSwapCoin)>::new(),
+                            Vec::<(ListUnspentEntry, &OutgoingSwapCoin)>::new(),
                         ))
                         .1
                         .push((utxo, s));
@@ -1397,11 +1744,11 @@ impl Wallet {
     // i.e. where there are UTXOs protected by contract_redeemscript's that we know about
     pub fn find_live_contract_unspents(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
     ) -> Result<
         (
-            Vec<(&IncomingSwapCoin, ListUnspentResultEntry)>,
-            Vec<(&OutgoingSwapCoin, ListUnspentResultEntry)>,
+            Vec<(&IncomingSwapCoin, ListUnspentEntry)>,
+            Vec<(&OutgoingSwapCoin, ListUnspentEntry)>,
         ),
         TeleportError,
     > {
@@ -1411,9 +1758,8 @@ impl Wallet {
         let contract_scriptpubkeys_outgoing_swapcoins =
             self.create_contract_scriptpubkey_outgoing_swapcoin_hashmap();
 
-        rpc.call::<Value>("lockunspent", &[Value::Bool(true)])
-            .map_err(|e| TeleportError::Rpc(e))?;
-        let listunspent = rpc.list_unspent(Some(0), Some(9999999), None, None, None)?;
+        rpc.unlock_unspent_all()?;
+        let listunspent = rpc.list_unspent()?;
 
         let (incoming_swapcoins_utxos, outgoing_swapcoins_utxos): (Vec<_>, Vec<_>) = listunspent
             .iter()
@@ -1431,15 +1777,19 @@ impl Wallet {
             incoming_swapcoins_utxos
                 .iter()
                 .map(|isc_osc_u| (*isc_osc_u.0.unwrap(), isc_osc_u.2.clone()))
-                .collect::<Vec<(&IncomingSwapCoin, ListUnspentResultEntry)>>(),
+                .collect::<Vec<(&IncomingSwapCoin, ListUnspentEntry)>>(),
             outgoing_swapcoins_utxos
                 .iter()
                 .map(|isc_osc_u| (*isc_osc_u.1.unwrap(), isc_osc_u.2.clone()))
-                .collect::<Vec<(&OutgoingSwapCoin, ListUnspentResultEntry)>>(),
+                .collect::<Vec<(&OutgoingSwapCoin, ListUnspentEntry)>>(),
         ))
     }
 
-    fn find_hd_next_index(&self, rpc: &Client, address_type: u32) -> Result<u32, TeleportError> {
+    fn find_hd_next_index(
+        &self,
+        rpc: &dyn BlockchainBackend,
+        address_type: u32,
+    ) -> Result<u32, TeleportError> {
         let mut max_index: i32 = -1;
         //TODO error handling
         let utxos = self.list_unspent_from_wallet(rpc, false, false)?;
@@ -1461,7 +1811,10 @@ impl Wallet {
         Ok((max_index + 1) as u32)
     }
 
-    pub fn get_next_external_address(&mut self, rpc: &Client) -> Result<Address, TeleportError> {
+    pub fn get_next_external_address(
+        &mut self,
+        rpc: &dyn BlockchainBackend,
+    ) -> Result<Address, TeleportError> {
         let receive_branch_descriptor = &self.get_hd_wallet_descriptors(rpc)?[0];
         let receive_address = rpc.derive_addresses(
             receive_branch_descriptor,
@@ -1474,7 +1827,7 @@ impl Wallet {
 
     pub fn get_next_internal_addresses(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         count: u32,
     ) -> Result<Vec<Address>, TeleportError> {
         let next_change_addr_index = self.find_hd_next_index(rpc, 1)?;
@@ -1485,9 +1838,21 @@ impl Wallet {
         )?)
     }
 
-    pub fn refresh_offer_maxsize_cache(&mut self, rpc: Arc<Client>) -> Result<(), TeleportError> {
-        let utxos = self.list_unspent_from_wallet(&rpc, false, false)?;
-        let balance: Amount = utxos.iter().fold(Amount::ZERO, |acc, u| acc + u.0.amount);
+    pub fn refresh_offer_maxsize_cache(
+        &mut self,
+        rpc: Arc<dyn BlockchainBackend>,
+    ) -> Result<(), TeleportError> {
+        let reserved_utxos = self.get_reserved_utxos()?;
+        let utxos = self.list_unspent_from_wallet(rpc.as_ref(), false, false)?;
+        let balance: Amount = utxos
+            .iter()
+            .filter(|(u, _)| {
+                !reserved_utxos.contains(&OutPoint {
+                    txid: u.txid,
+                    vout: u.vout,
+                })
+            })
+            .fold(Amount::ZERO, |acc, u| acc + u.0.amount);
         self.offer_maxsize_cache = balance.as_sat();
         Ok(())
     }
@@ -1589,73 +1954,56 @@ impl Wallet {
 
     pub fn from_walletcreatefundedpsbt_to_tx(
         &self,
-        rpc: &Client,
-        psbt: &String,
+        psbt: &PartiallySignedTransaction,
     ) -> Result<Transaction, TeleportError> {
-        //TODO rust-bitcoin handles psbt, use those functions instead
-        let decoded_psbt = rpc.call::<Value>("decodepsbt", &[Value::String(psbt.to_string())])?;
-        log::debug!(target: "wallet", "decoded_psbt = {:?}", decoded_psbt);
-
-        //TODO proper error handling, theres many unwrap()s here
-        //make this function return Result<>
-        let inputs = decoded_psbt["tx"]["vin"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|vin| TxIn {
-                previous_output: OutPoint {
-                    txid: Txid::from_hex(vin["txid"].as_str().unwrap()).unwrap(),
-                    vout: vin["vout"].as_u64().unwrap() as u32,
-                },
-                sequence: 0,
-                witness: Vec::new(),
-                script_sig: Script::new(),
-            })
-            .collect::<Vec<TxIn>>();
-        let outputs = decoded_psbt["tx"]["vout"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|vout| TxOut {
-                script_pubkey: Builder::from(
-                    Vec::from_hex(vout["scriptPubKey"]["hex"].as_str().unwrap()).unwrap(),
-                )
-                .into_script(),
-                value: convert_json_rpc_bitcoin_to_satoshis(&vout["value"]),
-            })
-            .collect::<Vec<TxOut>>();
-
         let mut tx = Transaction {
-            input: inputs,
-            output: outputs,
+            input: psbt
+                .global
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|input| TxIn {
+                    previous_output: input.previous_output,
+                    sequence: 0,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: psbt.global.unsigned_tx.output.clone(),
             lock_time: 0,
             version: 2,
         };
         log::debug!(target: "wallet", "tx = {:?}", tx);
 
-        let mut inputs_info = decoded_psbt["inputs"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|input_info| (input_info, input_info["bip32_derivs"].as_array().unwrap()))
-            .map(|(input_info, bip32_info)| {
-                if bip32_info.len() == 2 {
-                    UTXOSpendInfo::SwapCoin {
-                        multisig_redeemscript: Builder::from(
-                            Vec::from_hex(&input_info["witness_script"]["hex"].as_str().unwrap())
-                                .unwrap(),
-                        )
-                        .into_script(),
-                    }
-                } else {
-                    UTXOSpendInfo::SeedCoin {
-                        path: bip32_info[0]["path"].as_str().unwrap().to_string(),
-                        input_value: convert_json_rpc_bitcoin_to_satoshis(
-                            &input_info["witness_utxo"]["amount"],
-                        ),
-                    }
-                }
-            });
+        let mut inputs_info =
+            psbt.inputs
+                .iter()
+                .map(|input_info| {
+                    Ok(if input_info.bip32_derivation.len() == 2 {
+                        UTXOSpendInfo::SwapCoin {
+                            multisig_redeemscript: input_info.witness_script.clone().ok_or(
+                                TeleportError::Protocol("psbt input without witness script"),
+                            )?,
+                        }
+                    } else {
+                        UTXOSpendInfo::SeedCoin {
+                            path: input_info
+                                .bip32_derivation
+                                .values()
+                                .next()
+                                .ok_or(TeleportError::Protocol("psbt input without key origin"))?
+                                .1
+                                .to_string(),
+                            input_value: input_info
+                                .witness_utxo
+                                .as_ref()
+                                .ok_or(TeleportError::Protocol("psbt input without witness utxo"))?
+                                .value,
+                        }
+                    })
+                })
+                .collect::<Result<Vec<UTXOSpendInfo>, TeleportError>>()?
+                .into_iter();
         log::debug!(target: "wallet", "inputs_info = {:?}", inputs_info);
         self.sign_transaction(&mut tx, &mut inputs_info);
 
@@ -1668,18 +2016,16 @@ impl Wallet {
 
     fn create_and_import_coinswap_address(
         &mut self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         other_pubkey: &PublicKey,
+        (my_pubkey, my_privkey): (PublicKey, SecretKey),
     ) -> (Address, SecretKey) {
-        let (my_pubkey, my_privkey) = generate_keypair();
-
         let descriptor = rpc
-            .get_descriptor_info(&format!(
+            .get_descriptor_with_checksum(&format!(
                 "wsh(sortedmulti(2,{},{}))",
                 my_pubkey, other_pubkey
             ))
-            .unwrap()
-            .descriptor;
+            .unwrap();
 
         import_multisig_redeemscript_descriptor(
             rpc,
@@ -1701,15 +2047,15 @@ impl Wallet {
 
     pub fn import_wallet_contract_redeemscript(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         redeemscript: &Script,
-    ) -> Result<(), bitcoincore_rpc::Error> {
+    ) -> Result<(), TeleportError> {
         import_redeemscript(rpc, redeemscript, &self.get_core_wallet_label())
     }
 
     pub fn import_wallet_multisig_redeemscript(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         pubkey1: &PublicKey,
         pubkey2: &PublicKey,
     ) -> Result<(), TeleportError> {
@@ -1723,16 +2069,13 @@ impl Wallet {
 
     pub fn import_tx_with_merkleproof(
         &self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         tx: &Transaction,
         merkleproof: String,
     ) -> Result<(), TeleportError> {
-        let rawtx_hex = bitcoin::consensus::encode::serialize(tx).to_hex();
-
-        rpc.call(
-            "importprunedfunds",
-            &[Value::String(rawtx_hex), Value::String(merkleproof)],
-        )?;
+        let merkleproof = Vec::<u8>::from_hex(&merkleproof)
+            .map_err(|_| TeleportError::Protocol("invalid merkle proof hex"))?;
+        rpc.import_pruned_funds(tx, &merkleproof)?;
         log::debug!(target: "wallet", "import_tx_with_merkleproof txid={}", tx.txid());
         Ok(())
     }
@@ -1741,7 +2084,7 @@ impl Wallet {
     /// Returns, the Funding Transactions, [`OutgoingSwapCoin`]s and the Total Miner fees.
     pub fn initalize_coinswap(
         &mut self,
-        rpc: &Client,
+        rpc: &dyn BlockchainBackend,
         total_coinswap_amount: u64,
         other_multisig_pubkeys: &[PublicKey],
         hashlock_pubkeys: &[PublicKey],
@@ -1749,9 +2092,18 @@ impl Wallet {
         locktime: u16,
         fee_rate: u64,
     ) -> Result<(Vec<Transaction>, Vec<OutgoingSwapCoin>, u64), TeleportError> {
+        //each multisig key is followed by its timelock key, so one can be found from the other
+        let (my_multisig_keypairs, mut my_timelock_keypairs): (Vec<_>, Vec<_>) = self
+            .get_next_swapcoin_keypairs(2 * other_multisig_pubkeys.len() as u32)?
+            .chunks(2)
+            .map(|keypairs| (keypairs[0], keypairs[1]))
+            .unzip();
         let (coinswap_addresses, my_multisig_privkeys): (Vec<_>, Vec<_>) = other_multisig_pubkeys
             .iter()
-            .map(|other_key| self.create_and_import_coinswap_address(rpc, other_key))
+            .zip(my_multisig_keypairs)
+            .map(|(other_key, my_keypair)| {
+                self.create_and_import_coinswap_address(rpc, other_key, my_keypair)
+            })
             .unzip();
         log::debug!(target: "wallet", "coinswap_addresses = {:?}", coinswap_addresses);
 
@@ -1785,7 +2137,7 @@ impl Wallet {
             .zip(other_multisig_pubkeys.iter())
             .zip(hashlock_pubkeys.iter())
         {
-            let (timelock_pubkey, timelock_privkey) = generate_keypair();
+            let (timelock_pubkey, timelock_privkey) = my_timelock_keypairs.remove(0);
             let contract_redeemscript = contracts::create_contract_redeemscript(
                 hashlock_pubkey,
                 &timelock_pubkey,
@@ -1847,34 +2199,28 @@ pub fn create_multisig_redeemscript(key1: &PublicKey, key2: &PublicKey) -> Scrip
 }
 
 pub fn import_watchonly_redeemscript(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     redeemscript: &Script,
-) -> Result<(), bitcoincore_rpc::Error> {
+) -> Result<(), TeleportError> {
     import_redeemscript(rpc, redeemscript, &WATCH_ONLY_SWAPCOIN_LABEL.to_string())
 }
 
 fn import_multisig_redeemscript_descriptor(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     pubkey1: &PublicKey,
     pubkey2: &PublicKey,
     address_label: &String,
-) -> Result<(), bitcoincore_rpc::Error> {
-    let descriptor = rpc
-        .get_descriptor_info(&format!("wsh(sortedmulti(2,{},{}))", pubkey1, pubkey2))?
-        .descriptor;
+) -> Result<(), TeleportError> {
+    let descriptor =
+        rpc.get_descriptor_with_checksum(&format!("wsh(sortedmulti(2,{},{}))", pubkey1, pubkey2))?;
     let result = rpc
-        .import_multi(
-            &[ImportMultiRequest {
-                timestamp: ImportMultiRescanSince::Now,
-                descriptor: Some(&descriptor),
-                watchonly: Some(true),
-                label: Some(&address_label),
-                ..Default::default()
-            }],
-            Some(&ImportMultiOptions {
-                rescan: Some(false),
-            }),
-        )
+        .import_multi(&[ImportMultiRequest {
+            timestamp: ImportMultiRescanSince::Now,
+            descriptor: Some(&descriptor),
+            watchonly: Some(true),
+            label: Some(address_label),
+            ..Default::default()
+        }])
         .unwrap();
     for r in result {
         if !r.success {
@@ -1886,27 +2232,24 @@ fn import_multisig_redeemscript_descriptor(
 }
 
 pub fn import_redeemscript(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     redeemscript: &Script,
     address_label: &String,
-) -> Result<(), bitcoincore_rpc::Error> {
+) -> Result<(), TeleportError> {
     let spk = contracts::redeemscript_to_scriptpubkey(&redeemscript);
-    let result = rpc.import_multi(
-        &[ImportMultiRequest {
-            timestamp: ImportMultiRescanSince::Now,
-            script_pubkey: Some(ImportMultiRequestScriptPubkey::Script(&spk)),
-            redeem_script: Some(redeemscript),
-            watchonly: Some(true),
-            label: Some(&address_label),
-            ..Default::default()
-        }],
-        Some(&ImportMultiOptions {
-            rescan: Some(false),
-        }),
-    )?;
+    let result = rpc.import_multi(&[ImportMultiRequest {
+        timestamp: ImportMultiRescanSince::Now,
+        script_pubkey: Some(ImportMultiRequestScriptPubkey::Script(&spk)),
+        redeem_script: Some(redeemscript),
+        watchonly: Some(true),
+        label: Some(address_label),
+        ..Default::default()
+    }])?;
     for r in result {
         if !r.success {
-            return Err(bitcoincore_rpc::Error::UnexpectedStructure);
+            return Err(TeleportError::Rpc(
+                bitcoincore_rpc::Error::UnexpectedStructure,
+            ));
         }
     }
     Ok(())
@@ -1934,15 +2277,16 @@ fn apply_two_signatures_to_2of2_multisig_spend(
     input.witness.push(redeemscript.to_bytes());
 }
 
-pub fn convert_json_rpc_bitcoin_to_satoshis(amount: &Value) -> u64 {
-    //to avoid floating point arithmetic, convert the bitcoin amount to
-    //string with 8 decimal places, then remove the decimal point to
-    //obtain the value in satoshi
-    //this is necessary because the json rpc represents bitcoin values
-    //as floats :(
-    format!("{:.8}", amount.as_f64().unwrap())
-        .replace(".", "")
-        .parse::<u64>()
+/// New regtest wallet with a random seed phrase, in the temp dir at
+/// [temp_wallet_path](crate::wallet_storage::temp_wallet_path).
+#[cfg(test)]
+pub(crate) fn create_temp_wallet(name: &str) -> Wallet {
+    let path = crate::wallet_storage::temp_wallet_path(name);
+    let mnemonic =
+        mnemonic::Mnemonic::new_random(bitcoin_wallet::account::MasterKeyEntropy::Sufficient)
+            .unwrap();
+    Wallet::save_new_wallet_file(&path, mnemonic.to_string(), "".to_string(), None).unwrap();
+    Wallet::load_wallet_from_file(&path, Network::Regtest, WalletSyncAddressAmount::Testing)
         .unwrap()
 }
 
@@ -3,14 +3,18 @@ const WATCHTOWER_HOSTPORT: &str = "localhost:6103";
 use std::time::Duration;
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
+    net::{tcp::ReadHalf, TcpStream},
     select,
     time::sleep,
 };
 
 use crate::{
     error::TeleportError,
+    messages::{
+        negotiate_protocol_version, Capabilities, PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN,
+    },
+    noise,
+    transport::{Framing, MessageReader, MessageWriter},
     watchtower_protocol::{
         ContractsInfo, MakerToWatchtowerMessage, Ping, WatchContractTxes, WatchtowerToMakerMessage,
     },
@@ -28,9 +32,21 @@ pub async fn test_watchtower_client(contracts_to_watch: ContractsInfo) {
         .unwrap();
 }
 
-fn parse_message(line: &str) -> Result<WatchtowerToMakerMessage, TeleportError> {
-    serde_json::from_str::<WatchtowerToMakerMessage>(line)
-        .map_err(|_| TeleportError::Protocol("watchtower sent invalid message"))
+async fn read_message(
+    socket_reader: &mut MessageReader<ReadHalf<'_>>,
+) -> Result<WatchtowerToMakerMessage, TeleportError> {
+    match socket_reader.read_message().await? {
+        Some(WatchtowerToMakerMessage::Error(error)) => {
+            log::warn!(
+                "Watchtower rejected our message: {:?} {}",
+                error.code,
+                error.message
+            );
+            Err(TeleportError::Peer(error))
+        }
+        Some(message) => Ok(message),
+        None => Err(TeleportError::Protocol("watchtower eof")),
+    }
 }
 
 pub async fn register_coinswap_with_watchtowers(
@@ -38,8 +54,8 @@ pub async fn register_coinswap_with_watchtowers(
 ) -> Result<(), TeleportError> {
     send_message_to_watchtowers(&MakerToWatchtowerMessage::WatchContractTxes(
         WatchContractTxes {
-            protocol_version_min: 0,
-            protocol_version_max: 0,
+            protocol_version_min: PROTOCOL_VERSION_MIN,
+            protocol_version_max: PROTOCOL_VERSION_MAX,
             contracts_to_watch,
         },
     ))
@@ -51,8 +67,8 @@ pub async fn register_coinswap_with_watchtowers(
 pub async fn ping_watchtowers() -> Result<(), TeleportError> {
     log::debug!("pinging watchtowers");
     send_message_to_watchtowers(&MakerToWatchtowerMessage::Ping(Ping {
-        protocol_version_min: 0,
-        protocol_version_max: 0,
+        protocol_version_min: PROTOCOL_VERSION_MIN,
+        protocol_version_max: PROTOCOL_VERSION_MAX,
     }))
     .await
 }
@@ -64,40 +80,56 @@ async fn send_message_to_watchtowers_once(
 
     let mut socket = TcpStream::connect(WATCHTOWER_HOSTPORT).await?;
 
-    let (socket_reader, mut socket_writer) = socket.split();
-    let mut socket_reader = BufReader::new(socket_reader);
+    let (socket_reader, socket_writer) = socket.split();
+    let mut socket_reader = MessageReader::new(socket_reader);
+    let mut socket_writer = MessageWriter::new(socket_writer);
 
-    let mut message_packet = serde_json::to_vec(message).unwrap();
-    message_packet.push(b'\n');
-    socket_writer.write_all(&message_packet).await?;
-
-    let mut line1 = String::new();
-    if socket_reader.read_line(&mut line1).await? == 0 {
-        return Err(TeleportError::Protocol("watchtower eof"));
+    //the watchtower says hello first, the framing of our message depends on its version
+    let watchtower_hello = if let WatchtowerToMakerMessage::WatchtowerHello(h) =
+        read_message(&mut socket_reader).await?
+    {
+        h
+    } else {
+        log::trace!(target: "watchtower_client", "wrong protocol message");
+        return Err(TeleportError::Protocol(
+            "wrong protocol message from watchtower",
+        ));
+    };
+    log::trace!(target: "watchtower_client", "watchtower hello = {:?}", watchtower_hello);
+    let protocol_version = match negotiate_protocol_version(
+        watchtower_hello.protocol_version_min,
+        watchtower_hello.protocol_version_max,
+    ) {
+        Some(v) => v,
+        None => {
+            log::warn!(
+                "Watchtower speaks protocol versions {} to {}, we speak {} to {}",
+                watchtower_hello.protocol_version_min,
+                watchtower_hello.protocol_version_max,
+                PROTOCOL_VERSION_MIN,
+                PROTOCOL_VERSION_MAX
+            );
+            return Err(TeleportError::Protocol(
+                "no protocol version in common with watchtower",
+            ));
+        }
+    };
+    let framing = Framing::negotiated(protocol_version, watchtower_hello.capabilities);
+    socket_writer.set_framing(framing);
+    if watchtower_hello.capabilities.contains(Capabilities::NOISE) && framing != Framing::Lines {
+        noise::initiate(&mut socket_reader, &mut socket_writer).await?;
     }
-    let _watchtower_hello =
-        if let WatchtowerToMakerMessage::WatchtowerHello(h) = parse_message(&line1)? {
-            h
+    socket_writer.send(message).await?;
+
+    let _success =
+        if let WatchtowerToMakerMessage::Success(s) = read_message(&mut socket_reader).await? {
+            s
         } else {
-            log::trace!(target: "watchtower_client", "wrong protocol message");
+            log::trace!(target: "watchtower_client", "wrong protocol message2");
             return Err(TeleportError::Protocol(
-                "wrong protocol message from watchtower",
+                "wrong protocol message2 from watchtower",
             ));
         };
-    log::trace!(target: "watchtower_client", "watchtower hello = {:?}", _watchtower_hello);
-
-    let mut line2 = String::new();
-    if socket_reader.read_line(&mut line2).await? == 0 {
-        return Err(TeleportError::Protocol("watchtower eof"));
-    }
-    let _success = if let WatchtowerToMakerMessage::Success(s) = parse_message(&line2)? {
-        s
-    } else {
-        log::trace!(target: "watchtower_client", "wrong protocol message2");
-        return Err(TeleportError::Protocol(
-            "wrong protocol message2 from watchtower",
-        ));
-    };
 
     Ok(())
 }
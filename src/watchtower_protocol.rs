@@ -14,7 +14,6 @@ use std::{
 };
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::{tcp::WriteHalf, TcpListener},
     select,
     sync::mpsc,
@@ -27,18 +26,23 @@ use bitcoin::{
     hashes::{hash160::Hash as Hash160, Hash},
     Address, Network, Script, Transaction, Txid,
 };
-use bitcoincore_rpc::{
-    json::{GetBlockResult, ListTransactionResult},
-    Client, RpcApi,
-};
 
 use crate::{
+    blockchain_backend::BlockchainBackend,
     contracts::{
         create_contract_redeemscript, read_hashlock_pubkey_from_contract,
         read_hashvalue_from_contract, read_locktime_from_contract,
         read_timelock_pubkey_from_contract,
     },
     error::TeleportError,
+    messages::{
+        negotiate_protocol_version, Capabilities, ProtocolError, ProtocolErrorCode,
+        PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN,
+    },
+    noise::{self, NoiseKeypair},
+    transport::{
+        self, Frame, Framing, MessageReader, MessageWriter, WireMessage, SMALL_MESSAGE_SIZE,
+    },
     wallet_sync::import_redeemscript,
 };
 
@@ -91,10 +95,21 @@ pub enum MakerToWatchtowerMessage {
     WatchContractTxes(WatchContractTxes),
 }
 
+impl WireMessage for MakerToWatchtowerMessage {
+    fn max_size(method: &str) -> usize {
+        match method {
+            "ping" => SMALL_MESSAGE_SIZE,
+            _ => transport::MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WatchtowerHello {
     pub protocol_version_min: u32,
     pub protocol_version_max: u32,
+    #[serde(default)]
+    pub capabilities: Capabilities,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,6 +120,15 @@ pub struct Success;
 pub enum WatchtowerToMakerMessage {
     WatchtowerHello(WatchtowerHello),
     Success(Success),
+    /// Sent instead of [Success] when the maker's message is rejected, the connection is closed
+    /// after it.
+    Error(ProtocolError),
+}
+
+impl WireMessage for WatchtowerToMakerMessage {
+    fn max_size(_method: &str) -> usize {
+        SMALL_MESSAGE_SIZE
+    }
 }
 
 //the point of these Display structs is so that ContractsInfo can be printed
@@ -146,7 +170,7 @@ impl ContractsInfoDisplay {
 
 #[tokio::main]
 pub async fn start_watchtower(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     data_file_path: &PathBuf,
     network: Network,
     kill_flag: Arc<RwLock<bool>>,
@@ -158,7 +182,7 @@ pub async fn start_watchtower(
 }
 
 async fn run(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     data_file_path: &PathBuf,
     network: Network,
     kill_flag: Arc<RwLock<bool>>,
@@ -166,6 +190,7 @@ async fn run(
     //TODO port number in config file
     let port = 6103;
     let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+    let noise_keypair = Arc::new(NoiseKeypair::generate());
     log::info!("Starting teleport watchtower. Listening On Port {}", port);
 
     let data_file = read_from_data_file(data_file_path);
@@ -235,7 +260,7 @@ async fn run(
                 let mut data_file = read_from_data_file(data_file_path)?;
 
                 let contract_check_result = run_contract_checks(
-                    &rpc,
+                    rpc,
                     network,
                     &mut data_file.coinswap_in_progress_contracts,
                     &mut data_file.last_checked_block_height,
@@ -265,16 +290,19 @@ async fn run(
         log::info!("<=== [{}] | Accepted Connection From", addr.port());
         let server_loop_err_comms_tx = server_loop_err_comms_tx.clone();
         let watched_txes_comms_tx = watched_txes_comms_tx.clone();
+        let noise_keypair = Arc::clone(&noise_keypair);
 
         tokio::spawn(async move {
-            let (socket_reader, mut socket_writer) = socket.split();
-            let mut reader = BufReader::new(socket_reader);
+            let (socket_reader, socket_writer) = socket.split();
+            let mut reader = MessageReader::new(socket_reader);
+            let mut socket_writer = MessageWriter::new(socket_writer);
 
             if let Err(e) = send_message(
                 &mut socket_writer,
                 &WatchtowerToMakerMessage::WatchtowerHello(WatchtowerHello {
-                    protocol_version_min: 0,
-                    protocol_version_max: 0,
+                    protocol_version_min: PROTOCOL_VERSION_MIN,
+                    protocol_version_max: PROTOCOL_VERSION_MAX,
+                    capabilities: Capabilities::ours(),
                 }),
             )
             .await
@@ -283,24 +311,59 @@ async fn run(
                 return;
             }
 
+            //the maker has no hello to send, so a handshake can only be the first frame
+            let mut first_frame = true;
             loop {
-                let mut line = String::new();
-                match reader.read_line(&mut line).await {
-                    Ok(n) if n == 0 => {
+                let read_ret = reader.read_frame().await;
+                let handshake_allowed = std::mem::replace(&mut first_frame, false);
+                let request = match read_ret {
+                    Ok(Some(Frame::Message(payload))) => {
+                        match transport::decode_message(&payload) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                log::error!("error reading from socket: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(Some(Frame::Handshake(message))) if handshake_allowed => {
+                        match noise::respond(
+                            &message,
+                            &mut reader,
+                            &mut socket_writer,
+                            &noise_keypair,
+                        )
+                        .await
+                        {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                log::error!("noise handshake failed: {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(Some(Frame::Handshake(_))) => {
+                        log::error!("unexpected handshake");
+                        break;
+                    }
+                    Ok(None) => {
                         log::info!("Connection closed by peer");
                         break;
                     }
-                    Ok(_n) => (),
                     Err(e) => {
                         log::error!("error reading from socket: {:?}", e);
                         break;
                     }
                 };
 
-                line = line.trim_end().to_string();
-                let message_result = handle_message(line, &watched_txes_comms_tx).await;
+                let message_result = handle_message(request, &watched_txes_comms_tx).await;
                 match message_result {
-                    Ok(()) => {
+                    Ok(protocol_version) => {
+                        //makers dont announce capabilities to watchtowers, so no compression
+                        socket_writer.set_framing(Framing::negotiated(
+                            protocol_version,
+                            Capabilities::default(),
+                        ));
                         let success_message = WatchtowerToMakerMessage::Success(Success);
                         if let Err(e) = send_message(&mut socket_writer, &success_message).await {
                             log::error!("closing due to io error sending message: {:?}", e);
@@ -309,9 +372,20 @@ async fn run(
                     }
                     Err(err) => {
                         log::error!("error handling request: {:?}", err);
+                        if let TeleportError::Rejected(code, message) = err {
+                            //the connection is closed anyway, so a failure to send is ignored
+                            let error = WatchtowerToMakerMessage::Error(ProtocolError {
+                                code,
+                                message: message.to_string(),
+                            });
+                            let _ = send_message(&mut socket_writer, &error).await;
+                        }
                         match err {
                             TeleportError::Network(_e) => (),
                             TeleportError::Protocol(_e) => (),
+                            TeleportError::Cancelled => (),
+                            TeleportError::Peer(_e) => (),
+                            TeleportError::Rejected(_c, _e) => (),
                             TeleportError::Disk(e) => server_loop_err_comms_tx
                                 .send(TeleportError::Disk(e))
                                 .await
@@ -324,6 +398,10 @@ async fn run(
                                 .send(TeleportError::Socks(e))
                                 .await
                                 .unwrap(),
+                            TeleportError::Database(e) => server_loop_err_comms_tx
+                                .send(TeleportError::Database(e))
+                                .await
+                                .unwrap(),
                         };
                         break;
                     }
@@ -334,24 +412,44 @@ async fn run(
 }
 
 async fn send_message(
-    socket_writer: &mut WriteHalf<'_>,
+    socket_writer: &mut MessageWriter<WriteHalf<'_>>,
     message: &WatchtowerToMakerMessage,
 ) -> Result<(), TeleportError> {
-    let mut message_bytes = serde_json::to_vec(message).map_err(|e| std::io::Error::from(e))?;
-    message_bytes.push(b'\n');
-    socket_writer.write_all(&message_bytes).await?;
-    Ok(())
+    socket_writer.send(message).await
 }
 
+//returns the protocol version in common with the maker
 async fn handle_message(
-    line: String,
+    request: MakerToWatchtowerMessage,
     watched_txes_comms_tx: &mpsc::Sender<ContractsInfo>,
-) -> Result<(), TeleportError> {
-    let request: MakerToWatchtowerMessage = match serde_json::from_str(&line) {
-        Ok(r) => r,
-        Err(_e) => return Err(TeleportError::Protocol("message parsing error")),
-    };
+) -> Result<u32, TeleportError> {
     log::debug!("request = {:?}", request);
+    let (protocol_version_min, protocol_version_max) = match &request {
+        MakerToWatchtowerMessage::Ping(ping) => {
+            (ping.protocol_version_min, ping.protocol_version_max)
+        }
+        MakerToWatchtowerMessage::WatchContractTxes(watch_contract_txes_message) => (
+            watch_contract_txes_message.protocol_version_min,
+            watch_contract_txes_message.protocol_version_max,
+        ),
+    };
+    let protocol_version =
+        match negotiate_protocol_version(protocol_version_min, protocol_version_max) {
+            Some(v) => v,
+            None => {
+                log::warn!(
+                    "Maker speaks protocol versions {} to {}, we speak {} to {}",
+                    protocol_version_min,
+                    protocol_version_max,
+                    PROTOCOL_VERSION_MIN,
+                    PROTOCOL_VERSION_MAX
+                );
+                return Err(TeleportError::Rejected(
+                    ProtocolErrorCode::UnsupportedVersion,
+                    "no protocol version in common with maker",
+                ));
+            }
+        };
     match request {
         MakerToWatchtowerMessage::Ping(_ping) => {}
         MakerToWatchtowerMessage::WatchContractTxes(watch_contract_txes_message) => {
@@ -364,7 +462,7 @@ async fn handle_message(
             //only the maker knows this watchtower's address though, a maker wont crash their own
         }
     }
-    Ok(())
+    Ok(protocol_version)
 }
 
 fn read_from_data_file<P: AsRef<Path>>(
@@ -387,13 +485,13 @@ fn write_to_data_file<P: AsRef<Path>>(
 }
 
 fn run_contract_checks(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     network: Network,
     coinswap_in_progress_contracts: &mut Vec<ContractsInfo>,
     last_checked_block_height: &mut Option<u64>,
     live_contracts: &mut Vec<ContractsInfo>,
     last_checked_txid: &mut Option<Txid>,
-) -> Result<(), bitcoincore_rpc::Error> {
+) -> Result<(), TeleportError> {
     log::debug!(
         "coinswap_in_progress_contracts = {:?}",
         coinswap_in_progress_contracts
@@ -445,14 +543,14 @@ fn run_contract_checks(
 // if a tx is in mempool use another way
 enum TxidListType {
     FromMempool(Vec<Txid>),
-    FromBlock(GetBlockResult),
+    FromBlock { height: u64, txids: Vec<Txid> },
 }
 
 pub fn check_for_broadcasted_contract_txes(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     coinswap_in_progress_contracts: &[ContractsInfo],
     last_checked_block_height: &mut Option<u64>,
-) -> Result<Vec<ContractsInfo>, bitcoincore_rpc::Error> {
+) -> Result<Vec<ContractsInfo>, TeleportError> {
     let mut network_txs = Vec::<TxidListType>::new();
 
     let mempool_txids = rpc.get_raw_mempool()?;
@@ -465,9 +563,9 @@ pub fn check_for_broadcasted_contract_txes(
     let blockchain_tip_height = rpc.get_block_count()?;
     //note the plus one here
     for height in (last_checked_block_height.unwrap() + 1)..(blockchain_tip_height + 1) {
-        let block_info = rpc.get_block_info(&rpc.get_block_hash(height)?)?;
-        log::debug!("height = {}, txes.len = {}", height, block_info.tx.len());
-        network_txs.push(TxidListType::FromBlock(block_info));
+        let txids = rpc.get_block_txids(&rpc.get_block_hash(height)?)?;
+        log::debug!("height = {}, txes.len = {}", height, txids.len());
+        network_txs.push(TxidListType::FromBlock { height, txids });
     }
     *last_checked_block_height = Some(blockchain_tip_height);
 
@@ -478,13 +576,9 @@ pub fn check_for_broadcasted_contract_txes(
                 log::debug!("mempool_txids.len = {}", txids.len());
                 txids
             }
-            TxidListType::FromBlock(block_info) => {
-                log::debug!(
-                    "height = {}, block_txes.len = {}",
-                    block_info.height,
-                    block_info.tx.len()
-                );
-                block_info.tx
+            TxidListType::FromBlock { height, txids } => {
+                log::debug!("height = {}, block_txes.len = {}", height, txids.len());
+                txids
             }
         };
         let network_txids = txid_list.into_iter().collect::<HashSet<Txid>>();
@@ -544,10 +638,10 @@ pub fn check_for_broadcasted_contract_txes(
 }
 
 fn import_broadcasted_contract_redeemscripts(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     network: Network,
     broadcasted_contracts: &[ContractsInfo],
-) -> Result<(), bitcoincore_rpc::Error> {
+) -> Result<(), TeleportError> {
     log::debug!(
         "broadcasted transactions, now importing their redeemscripts = {:?}",
         broadcasted_contracts
@@ -576,26 +670,22 @@ fn import_broadcasted_contract_redeemscripts(
 }
 
 fn check_for_hashlock_spends(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     live_contracts: &[ContractsInfo],
     last_checked_txid: &mut Option<Txid>,
-) -> Result<Vec<ContractsInfo>, bitcoincore_rpc::Error> {
+) -> Result<Vec<ContractsInfo>, TeleportError> {
     if last_checked_txid.is_none() {
-        *last_checked_txid = Some(
-            rpc.list_transactions(None, Some(1), Some(0), Some(true))?[0]
-                .info
-                .txid,
-        );
+        *last_checked_txid = Some(rpc.list_transactions(1, 0)?[0]);
         log::debug!(
             "initial setting of last_checked_txid = {:?}",
             last_checked_txid.unwrap()
         );
     }
     const BATCH_SIZE: usize = 100;
-    let mut wallet_transactions = Vec::<ListTransactionResult>::new();
+    let mut wallet_transactions = Vec::<Txid>::new();
     for batch in 0..1000 {
         let skip = batch * BATCH_SIZE as usize;
-        let mut txes = rpc.list_transactions(None, Some(BATCH_SIZE), Some(skip), Some(true))?;
+        let mut txes = rpc.list_transactions(BATCH_SIZE, skip)?;
         if txes.is_empty() {
             break;
         }
@@ -603,7 +693,7 @@ fn check_for_hashlock_spends(
         let found_txid = if let Some((position, _txid)) = txes
             .iter()
             .enumerate()
-            .find(|(_i, tx)| tx.info.txid == last_checked_txid.unwrap())
+            .find(|(_i, txid)| **txid == last_checked_txid.unwrap())
         {
             txes.truncate(position);
             true
@@ -615,15 +705,9 @@ fn check_for_hashlock_spends(
             break;
         }
     }
-    log::debug!(
-        "wallet_transactions = {:?}",
-        wallet_transactions
-            .iter()
-            .map(|ltr| ltr.info.txid)
-            .collect::<Vec<Txid>>()
-    );
+    log::debug!("wallet_transactions = {:?}", wallet_transactions);
     if !wallet_transactions.is_empty() {
-        *last_checked_txid = Some(wallet_transactions[0].info.txid);
+        *last_checked_txid = Some(wallet_transactions[0]);
         log::debug!("updating last_checked_txid to: {:?}", last_checked_txid);
     } else {
         log::debug!(
@@ -633,21 +717,18 @@ fn check_for_hashlock_spends(
     }
     let mut closed_contracts = Vec::<ContractsInfo>::new();
     let mut already_checked_txids = HashSet::<Txid>::new();
-    for wallet_tx in wallet_transactions {
-        if already_checked_txids.contains(&wallet_tx.info.txid) {
+    for wallet_txid in wallet_transactions {
+        if already_checked_txids.contains(&wallet_txid) {
             continue;
         }
-        let tx = rpc
-            .get_transaction(&wallet_tx.info.txid, Some(true))?
-            .transaction()
-            .unwrap();
+        let tx = rpc.get_transaction(&wallet_txid)?.transaction;
         for input in tx.input {
             //TODO most of this below for checking whether a tx spends using a preimage we're
             //interested in should be in its own function so its easier to test
             if input.witness.len() < 3 {
                 log::debug!(
                     "txid={} not hashlock spend, witness not enough elements",
-                    wallet_tx.info.txid
+                    wallet_txid
                 );
                 continue;
             }
@@ -658,7 +739,7 @@ fn check_for_hashlock_spends(
                 } else {
                     log::debug!(
                         "txid={} not hashlock spend, unable to obtain pub_hashlock",
-                        wallet_tx.info.txid
+                        wallet_txid
                     );
                     continue;
                 };
@@ -668,7 +749,7 @@ fn check_for_hashlock_spends(
                 } else {
                     log::debug!(
                         "txid={} not hashlock spend, unable to obtain pub_hashlock",
-                        wallet_tx.info.txid
+                        wallet_txid
                     );
                     continue;
                 };
@@ -677,7 +758,7 @@ fn check_for_hashlock_spends(
             } else {
                 log::debug!(
                     "txid={} not hashlock spend, unable to obtain locktime",
-                    wallet_tx.info.txid
+                    wallet_txid
                 );
                 continue;
             };
@@ -686,7 +767,7 @@ fn check_for_hashlock_spends(
             } else {
                 log::debug!(
                     "txid={} not hashlock spend, unable to obtain hashvalue",
-                    wallet_tx.info.txid
+                    wallet_txid
                 );
                 continue;
             };
@@ -695,7 +776,7 @@ fn check_for_hashlock_spends(
             {
                 log::debug!(
                     "txid={} not hashlock spend, tx not in contract_redeemscript form",
-                    wallet_tx.info.txid
+                    wallet_txid
                 );
                 continue;
             }
@@ -703,7 +784,7 @@ fn check_for_hashlock_spends(
             if Hash160::hash(&preimage) != hashvalue {
                 log::debug!(
                     "txid={} not hashlock spend, preimage does not match",
-                    wallet_tx.info.txid
+                    wallet_txid
                 );
                 continue;
             }
@@ -716,7 +797,7 @@ fn check_for_hashlock_spends(
                 if contract_hashvalue != hashvalue {
                     log::debug!(
                         "txid={} not hashlock spend, hashvalue doesnt match contract being monitored",
-                        wallet_tx.info.txid
+                        wallet_txid
                     );
                     continue;
                 }
@@ -725,7 +806,7 @@ fn check_for_hashlock_spends(
                 closed_contracts.push(live_contract.clone());
                 log::info!(
                     "Found hashlock spend (txid={}) for one of our contracts, hashvalue={}",
-                    wallet_tx.info.txid,
+                    wallet_txid,
                     hashvalue
                 );
                 for contract_transaction in &live_contract.contract_txes {
@@ -748,15 +829,15 @@ fn check_for_hashlock_spends(
                 }
             }
         }
-        already_checked_txids.insert(wallet_tx.info.txid);
+        already_checked_txids.insert(wallet_txid);
     }
     Ok(closed_contracts)
 }
 
 fn check_for_timelock_maturity(
-    rpc: &Client,
+    rpc: &dyn BlockchainBackend,
     live_contracts: &mut [ContractsInfo],
-) -> Result<Vec<ContractsInfo>, bitcoincore_rpc::Error> {
+) -> Result<Vec<ContractsInfo>, TeleportError> {
     let mut closed_contracts = Vec::<ContractsInfo>::new();
     for live_contract in live_contracts {
         for contract_transaction in &mut live_contract.contract_txes {
@@ -764,12 +845,12 @@ fn check_for_timelock_maturity(
                 continue;
             }
             let timelock_spend = contract_transaction.timelock_spend.as_ref().unwrap();
-            let gettx = rpc.get_transaction(&contract_transaction.tx.txid(), Some(true))?;
-            if gettx.info.confirmations < (timelock_spend.input[0].sequence as i32) {
+            let gettx = rpc.get_transaction(&contract_transaction.tx.txid())?;
+            if gettx.confirmations < (timelock_spend.input[0].sequence as i32) {
                 log::debug!(
                     "timelock txout (txid={}) maturing in {} blocks",
                     contract_transaction.tx.txid(),
-                    ((timelock_spend.input[0].sequence as i32) - gettx.info.confirmations)
+                    ((timelock_spend.input[0].sequence as i32) - gettx.confirmations)
                 );
                 continue;
             }
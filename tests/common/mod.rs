@@ -0,0 +1,94 @@
+// Helpers shared by the tests which run against the simulated blockchain. Each test binary only
+// uses some of them.
+#![allow(dead_code)]
+
+use bitcoin::Network;
+use bitcoin_wallet::mnemonic;
+
+use teleport::{
+    simulated_blockchain::SimulatedBlockchain,
+    swap_events::SwapEvent,
+    wallet_sync::{Wallet, WalletSyncAddressAmount},
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    path::Path,
+    sync::Arc,
+    time,
+};
+
+// Helper function to create new wallet
+pub fn create_wallet_and_import(sim: &SimulatedBlockchain, filename: &Path) -> Wallet {
+    let mnemonic =
+        mnemonic::Mnemonic::new_random(bitcoin_wallet::account::MasterKeyEntropy::Sufficient)
+            .unwrap();
+
+    Wallet::save_new_wallet_file(filename, mnemonic.to_string(), "".to_string(), None).unwrap();
+
+    let wallet =
+        Wallet::load_wallet_from_file(filename, Network::Regtest, WalletSyncAddressAmount::Testing)
+            .unwrap();
+    wallet
+        .import_initial_addresses(
+            sim,
+            &wallet
+                .get_hd_wallet_descriptors(sim)
+                .unwrap()
+                .iter()
+                .collect::<Vec<&String>>(),
+            &Vec::<_>::new(),
+            &Vec::<_>::new(),
+        )
+        .unwrap();
+
+    wallet
+}
+
+pub fn load_and_sync_wallet(sim: &SimulatedBlockchain, filename: &Path) -> Wallet {
+    let mut wallet =
+        Wallet::load_wallet_from_file(filename, Network::Regtest, WalletSyncAddressAmount::Testing)
+            .unwrap();
+    wallet.startup_sync(sim).unwrap();
+    wallet
+}
+
+// Port which nothing is listening on, for a maker or the daemon to listen on
+pub fn unused_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Mines a block whenever funding txes are broadcast, so the coinswap never waits for blocks, and
+// passes the events on
+pub fn mine_on_funding_broadcast(
+    sim: Arc<SimulatedBlockchain>,
+    mut events: UnboundedReceiver<SwapEvent>,
+) -> UnboundedReceiver<SwapEvent> {
+    let (events_tx, events_rx) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if matches!(event, SwapEvent::FundingBroadcast { .. }) {
+                sim.mine_blocks(1);
+            }
+            let _ = events_tx.send(event);
+        }
+    });
+    events_rx
+}
+
+// Waits until `done` is true, checking it a few times a second
+pub async fn wait_for<F: FnMut() -> bool>(what: &str, mut done: F) {
+    for _ in 0..300 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
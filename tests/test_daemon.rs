@@ -0,0 +1,136 @@
+mod common;
+use common::{create_wallet_and_import, unused_port};
+
+use bitcoin::util::amount::Amount;
+
+use serde_json::{json, Value};
+
+use teleport::{
+    daemon::{self, DaemonConfig},
+    maker_protocol::MakerConfig,
+    simulated_blockchain::SimulatedBlockchain,
+};
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    thread, time,
+};
+
+// Sends one HTTP request to the daemon, returns the status code and body
+fn http_post(port: u16, cookie: Option<&str>, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let auth = cookie
+        .map(|c| {
+            use base64::Engine;
+            format!(
+                "Authorization: Basic {}\r\n",
+                base64::engine::general_purpose::STANDARD.encode(c)
+            )
+        })
+        .unwrap_or_default();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\n{}Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\r\n{}",
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, b)| b.to_string())
+        .unwrap_or_default();
+    (status, body)
+}
+
+fn rpc_call(port: u16, cookie: &str, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let (status, body) = http_post(port, Some(cookie), &request.to_string());
+    assert_eq!(status, 200);
+    serde_json::from_str(&body).unwrap()
+}
+
+#[test]
+fn test_daemon_rpc() {
+    teleport::setup_logger();
+
+    let data_dir = std::env::temp_dir().join(format!("teleport-daemon-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let wallet_path = data_dir.join("daemon-wallet");
+    let cookie_path = wallet_path.with_extension("cookie");
+
+    let sim = Arc::new(SimulatedBlockchain::new());
+    let mut wallet = create_wallet_and_import(&sim, &wallet_path);
+    let address = wallet.get_next_external_address(sim.as_ref()).unwrap();
+    sim.send_to_address(&address, Amount::from_btc(0.05).unwrap())
+        .unwrap();
+    sim.mine_blocks(1);
+    wallet.startup_sync(sim.as_ref()).unwrap();
+
+    let port = unused_port();
+    let daemon_thread = {
+        let sim = sim.clone();
+        let wallet_path = wallet_path.clone();
+        thread::spawn(move || {
+            daemon::start_daemon(
+                sim,
+                wallet,
+                &wallet_path,
+                DaemonConfig {
+                    port,
+                    ..DaemonConfig::default()
+                },
+                MakerConfig::default(),
+            );
+        })
+    };
+
+    // The cookie file is written once the daemon is listening
+    let mut waited = 0;
+    while !cookie_path.exists() {
+        assert!(waited < 100, "daemon didn't start");
+        thread::sleep(time::Duration::from_millis(100));
+        waited += 1;
+    }
+    let cookie = std::fs::read_to_string(&cookie_path).unwrap();
+
+    // Requests without the cookie are rejected
+    let (status, _) = http_post(port, None, r#"{"jsonrpc":"2.0","id":1,"method":"getinfo"}"#);
+    assert_eq!(status, 401);
+    let (status, _) = http_post(
+        port,
+        Some("__cookie__:wrong"),
+        r#"{"jsonrpc":"2.0","id":1,"method":"getinfo"}"#,
+    );
+    assert_eq!(status, 401);
+
+    let info = rpc_call(port, &cookie, "getinfo", Value::Null);
+    assert_eq!(info["result"]["network"], "regtest");
+
+    let balance = rpc_call(port, &cookie, "getbalance", Value::Null);
+    assert_eq!(balance["result"]["balance"], 5_000_000);
+    assert_eq!(balance["result"]["coin_count"], 1);
+
+    let new_address = rpc_call(port, &cookie, "getnewaddress", Value::Null);
+    assert!(new_address["result"].as_str().unwrap().starts_with("bcrt1"));
+
+    let unknown = rpc_call(port, &cookie, "nosuchmethod", Value::Null);
+    assert_eq!(unknown["error"]["code"], -32601);
+    let bad_params = rpc_call(port, &cookie, "getbalance", json!({ "foo": 1 }));
+    assert_eq!(bad_params["error"]["code"], -32602);
+
+    let status = rpc_call(port, &cookie, "getswapstatus", Value::Null);
+    assert_eq!(status["result"], Value::Null);
+
+    rpc_call(port, &cookie, "stop", Value::Null);
+    daemon_thread.join().unwrap();
+    assert!(!cookie_path.exists());
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
@@ -0,0 +1,347 @@
+mod common;
+use common::{
+    create_wallet_and_import, load_and_sync_wallet, mine_on_funding_broadcast, unused_port,
+};
+
+use bitcoin::{util::amount::Amount, Network};
+
+use serde_json::{json, Value};
+
+use teleport::{
+    fidelity_bonds::YearAndMonth,
+    maker_admin,
+    maker_ledger::SwapOutcome,
+    maker_protocol::{Maker, MakerConfig},
+    offerbook_sync::MakerAddress,
+    simulated_blockchain::SimulatedBlockchain,
+    swap_events::SwapEvent,
+    taker_protocol::{SwapParams, Taker, TakerConfig},
+    wallet_sync::Wallet,
+    watchtower_protocol,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use std::{
+    sync::{Arc, RwLock},
+    thread, time,
+};
+
+fn get_balance(sim: &SimulatedBlockchain, wallet: &Wallet) -> (usize, Amount) {
+    let utxos = wallet.list_unspent_from_wallet(sim, false, false).unwrap();
+    let balance = utxos
+        .iter()
+        .fold(Amount::ZERO, |acc, (u, _)| acc + u.amount);
+    (utxos.len(), balance)
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Value {
+    let length = reader.read_u32().await.unwrap() as usize;
+    assert_eq!(reader.read_u8().await.unwrap(), 0);
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await.unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+// A noise handshake is only accepted right after the hellos. Starting one later would let
+// someone on the path take over a connection the taker already trusts.
+async fn check_late_handshake_rejected(port: u16) {
+    let mut socket = BufReader::new(TcpStream::connect(("localhost", port)).await.unwrap());
+    let mut makerhello = String::new();
+    socket.read_line(&mut makerhello).await.unwrap();
+    assert!(makerhello.contains("makerhello"));
+    //error messages but no encryption, so the offer comes back in a plain frame
+    socket
+        .write_all(
+            concat!(
+                r#"{"method":"takerhello","protocol_version_min":1,"protocol_version_max":1,"#,
+                r#""capabilities":2}"#,
+                "\n",
+                r#"{"method":"reqgiveoffer"}"#,
+                "\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(read_frame(&mut socket).await["method"], "respoffer");
+    //an empty handshake frame
+    socket.write_all(&[0, 0, 0, 0, 1 << 2]).await.unwrap();
+    let error = read_frame(&mut socket).await;
+    assert_eq!(error["method"], "error");
+    assert_eq!(error["code"], 1);
+}
+
+// Same coinswap as test_standard_coinswap, but against the in-memory simulated blockchain so it
+// doesn't need a bitcoin node. The makers and taker run as tasks on the test's runtime, the way
+// an application embedding them would run them.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_simulated_coinswap() {
+    teleport::setup_logger();
+
+    let data_dir =
+        std::env::temp_dir().join(format!("teleport-sim-coinswap-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let watchtower_data = data_dir.join("watchtower.dat");
+    let taker_path = data_dir.join("taker-wallet");
+    let maker1_path = data_dir.join("maker-wallet-1");
+    let maker2_path = data_dir.join("maker-wallet-2");
+
+    let sim = Arc::new(SimulatedBlockchain::new());
+
+    let mut taker_wallet = create_wallet_and_import(&sim, &taker_path);
+    let mut maker1_wallet = create_wallet_and_import(&sim, &maker1_path);
+    let mut maker2_wallet = create_wallet_and_import(&sim, &maker2_path);
+
+    // Create 3 taker and maker address and send 0.05 btc to each
+    for _ in 0..3 {
+        for wallet in [&mut taker_wallet, &mut maker1_wallet, &mut maker2_wallet].iter_mut() {
+            let address = wallet.get_next_external_address(sim.as_ref()).unwrap();
+            sim.send_to_address(&address, Amount::from_btc(0.05).unwrap())
+                .unwrap();
+        }
+    }
+
+    // Create a fidelity bond for each maker
+    for wallet in [&maker1_wallet, &maker2_wallet].iter() {
+        let fbond_address = wallet.get_timelocked_address(&YearAndMonth::new(2030, 1)).0;
+        sim.send_to_address(&fbond_address, Amount::from_btc(0.05).unwrap())
+            .unwrap();
+    }
+
+    sim.mine_blocks(1);
+
+    // Check inital wallet assertions
+    assert_eq!(taker_wallet.get_external_index(), 3);
+    assert_eq!(maker1_wallet.get_external_index(), 3);
+    assert_eq!(maker2_wallet.get_external_index(), 3);
+
+    assert_eq!(
+        taker_wallet
+            .list_unspent_from_wallet(sim.as_ref(), false, true)
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        maker1_wallet
+            .list_unspent_from_wallet(sim.as_ref(), false, true)
+            .unwrap()
+            .len(),
+        4
+    );
+    assert_eq!(
+        maker2_wallet
+            .list_unspent_from_wallet(sim.as_ref(), false, true)
+            .unwrap()
+            .len(),
+        4
+    );
+
+    taker_wallet
+        .lock_all_nonwallet_unspents(sim.as_ref())
+        .unwrap();
+    maker1_wallet
+        .lock_all_nonwallet_unspents(sim.as_ref())
+        .unwrap();
+    maker2_wallet
+        .lock_all_nonwallet_unspents(sim.as_ref())
+        .unwrap();
+
+    let kill_flag = Arc::new(RwLock::new(false));
+
+    // Start watchtower, makers and taker to execute a coinswap
+    let watchtower_thread = {
+        let sim = sim.clone();
+        let kill_flag = kill_flag.clone();
+        let watchtower_data = watchtower_data.clone();
+        thread::spawn(move || {
+            watchtower_protocol::start_watchtower(
+                sim.as_ref(),
+                &watchtower_data,
+                Network::Regtest,
+                kill_flag,
+            );
+        })
+    };
+
+    let maker_ports = [unused_port(), unused_port()];
+    let mut makers = [&maker1_path, &maker2_path]
+        .iter()
+        .zip(maker_ports.iter())
+        .map(|(maker_path, port)| {
+            let wallet = load_and_sync_wallet(&sim, maker_path);
+            Maker::new(
+                sim.clone(),
+                Arc::new(RwLock::new(wallet)),
+                MakerConfig {
+                    port: *port,
+                    ..MakerConfig::default()
+                },
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let maker_handles = makers.iter().map(Maker::handle).collect::<Vec<_>>();
+    let mut maker_events = makers
+        .iter_mut()
+        .map(|maker| mine_on_funding_broadcast(sim.clone(), maker.subscribe()))
+        .collect::<Vec<_>>();
+    let maker_tasks = makers
+        .into_iter()
+        .map(|maker| tokio::spawn(async move { maker.run().await }))
+        .collect::<Vec<_>>();
+
+    // The makers listen for takers before they create their admin sockets
+    let admin_sockets = [&maker1_path, &maker2_path]
+        .iter()
+        .map(|maker_path| maker_path.with_extension("sock"))
+        .collect::<Vec<_>>();
+    for admin_socket in admin_sockets.iter() {
+        while maker_admin::send_request(admin_socket, "listconnections", Value::Null)
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+        }
+    }
+    check_late_handshake_rejected(maker_ports[0]).await;
+
+    let (taker_task, mut taker_events) = {
+        let wallet = load_and_sync_wallet(&sim, &taker_path);
+        let mut taker = Taker::new(
+            sim.clone(),
+            Arc::new(RwLock::new(wallet)),
+            TakerConfig {
+                maker_addresses: Some(
+                    maker_ports
+                        .iter()
+                        .map(|port| MakerAddress::Clearnet {
+                            address: format!("localhost:{}", port),
+                        })
+                        .collect(),
+                ),
+                ..TakerConfig::default()
+            },
+            &taker_path.with_extension("offerbook"),
+            &taker_path.with_extension("swap"),
+        )
+        .unwrap();
+        let taker_events = mine_on_funding_broadcast(sim.clone(), taker.subscribe());
+        let taker_task = tokio::spawn(async move {
+            taker
+                .send_coinswap(SwapParams {
+                    send_amount: 500000,
+                    maker_count: 2,
+                    tx_count: 3,
+                    required_confirms: 1,
+                    fee_rate: 1000,
+                    ..SwapParams::default()
+                })
+                .await
+        });
+        (taker_task, taker_events)
+    };
+
+    taker_task.await.unwrap().unwrap();
+    // The taker is done once it sent its last message, wait for the makers to handle it
+    let mut maker_events_seen = vec![Vec::new(), Vec::new()];
+    for (events, events_seen) in maker_events.iter_mut().zip(maker_events_seen.iter_mut()) {
+        while !matches!(events_seen.last(), Some(SwapEvent::Completed { .. })) {
+            events_seen.push(events.recv().await.unwrap());
+        }
+    }
+
+    // The first maker is drained through its admin socket, the coinswap is already complete
+    let admin_socket = &admin_sockets[0];
+    let liquidity = maker_admin::send_request(admin_socket, "getliquidity", Value::Null)
+        .await
+        .unwrap();
+    assert!(liquidity["result"]["balance"].as_u64().unwrap() > 0);
+    assert_eq!(liquidity["result"]["incomplete_coinswaps"], json!([]));
+    let invalid_offer =
+        maker_admin::send_request(admin_socket, "setofferconfig", json!({ "min_size": 0 }))
+            .await
+            .unwrap();
+    assert_eq!(invalid_offer["error"]["code"], -32602);
+    maker_admin::send_request(admin_socket, "drain", Value::Null)
+        .await
+        .unwrap();
+    let status = maker_admin::send_request(admin_socket, "listconnections", Value::Null)
+        .await
+        .unwrap();
+    assert_eq!(status["result"]["draining"], true);
+
+    *kill_flag.write().unwrap() = true;
+    maker_handles[1].shutdown();
+    for maker_task in maker_tasks {
+        tokio::time::timeout(time::Duration::from_secs(30), maker_task)
+            .await
+            .expect("maker didn't shut down")
+            .unwrap()
+            .unwrap();
+    }
+    assert!(!admin_socket.exists());
+    watchtower_thread.join().unwrap();
+
+    // The event channels are closed now that the taker and makers are dropped
+    let mut events = Vec::new();
+    while let Some(event) = taker_events.recv().await {
+        events.push(event);
+    }
+    let hashvalue = match events.first() {
+        Some(SwapEvent::MakerChosen { hashvalue, hop, .. }) if *hop == 0 => *hashvalue,
+        e => panic!("unexpected first taker event {:?}", e),
+    };
+    let established_hops = events
+        .iter()
+        .filter_map(|e| match e {
+            SwapEvent::HopEstablished { hop, .. } => *hop,
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(established_hops, vec![0, 1, 2]);
+    assert_eq!(events.last(), Some(&SwapEvent::Completed { hashvalue }));
+
+    for (mut maker_events, mut events) in maker_events.into_iter().zip(maker_events_seen) {
+        while let Some(event) = maker_events.recv().await {
+            events.push(event);
+        }
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, SwapEvent::FundingBroadcast { .. })));
+        assert!(events.contains(&SwapEvent::PreimageRevealed { hashvalue }));
+        assert_eq!(events.last(), Some(&SwapEvent::Completed { hashvalue }));
+    }
+
+    // Recreate the wallets
+    let taker_wallet = load_and_sync_wallet(&sim, &taker_path);
+    let maker1_wallet = load_and_sync_wallet(&sim, &maker1_path);
+    let maker2_wallet = load_and_sync_wallet(&sim, &maker2_path);
+
+    // Check assertions
+    assert_eq!(taker_wallet.get_swapcoins_count(), 6);
+    assert_eq!(maker1_wallet.get_swapcoins_count(), 6);
+    assert_eq!(maker2_wallet.get_swapcoins_count(), 6);
+
+    let (utxo_count, balance) = get_balance(&sim, &taker_wallet);
+    assert_eq!(utxo_count, 6);
+    assert!(balance < Amount::from_btc(0.15).unwrap());
+
+    for maker_wallet in [&maker1_wallet, &maker2_wallet].iter() {
+        let (utxo_count, balance) = get_balance(&sim, maker_wallet);
+        assert_eq!(utxo_count, 6);
+        assert!(balance > Amount::from_btc(0.15).unwrap());
+
+        let ledger = maker_wallet.load_swap_ledger().unwrap();
+        assert_eq!(ledger.len(), 1);
+        assert_eq!(ledger[0].hashvalue, hashvalue);
+        assert_eq!(ledger[0].outcome, SwapOutcome::Completed);
+        assert!(ledger[0].earned() > 0);
+    }
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
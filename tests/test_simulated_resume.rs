@@ -0,0 +1,207 @@
+mod common;
+use common::{
+    create_wallet_and_import, load_and_sync_wallet, mine_on_funding_broadcast, unused_port,
+};
+
+use bitcoin::{util::amount::Amount, Network};
+
+use serde_json::Value;
+
+use teleport::{
+    fidelity_bonds::YearAndMonth,
+    maker_admin,
+    maker_protocol::{Maker, MakerConfig},
+    offerbook_sync::MakerAddress,
+    simulated_blockchain::SimulatedBlockchain,
+    swap_events::SwapEvent,
+    taker_protocol::{SwapParams, Taker, TakerConfig},
+    watchtower_protocol,
+};
+
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    thread, time,
+};
+
+fn new_taker(sim: &Arc<SimulatedBlockchain>, taker_path: &Path, maker_ports: &[u16]) -> Taker {
+    Taker::new(
+        sim.clone(),
+        Arc::new(RwLock::new(load_and_sync_wallet(sim, taker_path))),
+        TakerConfig {
+            maker_addresses: Some(
+                maker_ports
+                    .iter()
+                    .map(|port| MakerAddress::Clearnet {
+                        address: format!("localhost:{}", port),
+                    })
+                    .collect(),
+            ),
+            ..TakerConfig::default()
+        },
+        &taker_path.with_extension("offerbook"),
+        &taker_path.with_extension("swap"),
+    )
+    .unwrap()
+}
+
+// The taker is stopped right after broadcasting its funding txes, as if it crashed. A new taker
+// finishes the coinswap from the swap journal.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_simulated_resume() {
+    teleport::setup_logger();
+
+    let data_dir = std::env::temp_dir().join(format!("teleport-sim-resume-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let watchtower_data = data_dir.join("watchtower.dat");
+    let taker_path = data_dir.join("taker-wallet");
+    let journal_path = taker_path.with_extension("swap");
+    let maker_paths = [
+        data_dir.join("maker-wallet-1"),
+        data_dir.join("maker-wallet-2"),
+    ];
+
+    let sim = Arc::new(SimulatedBlockchain::new());
+
+    let mut taker_wallet = create_wallet_and_import(&sim, &taker_path);
+    let mut maker_wallets = maker_paths
+        .iter()
+        .map(|maker_path| create_wallet_and_import(&sim, maker_path))
+        .collect::<Vec<_>>();
+    for _ in 0..3 {
+        for wallet in maker_wallets.iter_mut().chain(Some(&mut taker_wallet)) {
+            let address = wallet.get_next_external_address(sim.as_ref()).unwrap();
+            sim.send_to_address(&address, Amount::from_btc(0.05).unwrap())
+                .unwrap();
+        }
+    }
+    for wallet in maker_wallets.iter() {
+        let fbond_address = wallet.get_timelocked_address(&YearAndMonth::new(2030, 1)).0;
+        sim.send_to_address(&fbond_address, Amount::from_btc(0.05).unwrap())
+            .unwrap();
+    }
+    sim.mine_blocks(1);
+    for wallet in maker_wallets.iter().chain(Some(&taker_wallet)) {
+        wallet.lock_all_nonwallet_unspents(sim.as_ref()).unwrap();
+    }
+
+    let kill_flag = Arc::new(RwLock::new(false));
+    let watchtower_thread = {
+        let sim = sim.clone();
+        let kill_flag = kill_flag.clone();
+        let watchtower_data = watchtower_data.clone();
+        thread::spawn(move || {
+            watchtower_protocol::start_watchtower(
+                sim.as_ref(),
+                &watchtower_data,
+                Network::Regtest,
+                kill_flag,
+            );
+        })
+    };
+
+    let maker_ports = [unused_port(), unused_port()];
+    let mut makers = maker_paths
+        .iter()
+        .zip(maker_ports.iter())
+        .map(|(maker_path, port)| {
+            Maker::new(
+                sim.clone(),
+                Arc::new(RwLock::new(load_and_sync_wallet(&sim, maker_path))),
+                MakerConfig {
+                    port: *port,
+                    ..MakerConfig::default()
+                },
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let maker_handles = makers.iter().map(Maker::handle).collect::<Vec<_>>();
+    let mut maker_events = makers
+        .iter_mut()
+        .map(|maker| mine_on_funding_broadcast(sim.clone(), maker.subscribe()))
+        .collect::<Vec<_>>();
+    let maker_tasks = makers
+        .into_iter()
+        .map(|maker| tokio::spawn(async move { maker.run().await }))
+        .collect::<Vec<_>>();
+    for maker_path in maker_paths.iter() {
+        while maker_admin::send_request(
+            &maker_path.with_extension("sock"),
+            "listconnections",
+            Value::Null,
+        )
+        .await
+        .is_err()
+        {
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+        }
+    }
+
+    let swap_params = SwapParams {
+        send_amount: 500000,
+        maker_count: 2,
+        tx_count: 3,
+        required_confirms: 1,
+        fee_rate: 1000,
+        ..SwapParams::default()
+    };
+    let mut taker = new_taker(&sim, &taker_path, &maker_ports);
+    let taker_handle = taker.handle();
+    let mut taker_events = taker.subscribe();
+    let taker_task = tokio::spawn(async move { taker.send_coinswap(swap_params).await });
+    let hashvalue = loop {
+        match taker_events.recv().await.unwrap() {
+            SwapEvent::FundingBroadcast { hashvalue, .. } => break hashvalue,
+            _ => continue,
+        }
+    };
+    taker_handle.cancel();
+    let taker_error = taker_task.await.unwrap().unwrap_err();
+    assert_eq!(format!("{:?}", taker_error), "Cancelled");
+    assert!(journal_path.exists());
+
+    // A new coinswap can't start while the interrupted one is unfinished
+    let mut taker = new_taker(&sim, &taker_path, &maker_ports);
+    let taker_error = taker.send_coinswap(swap_params).await.unwrap_err();
+    assert!(format!("{:?}", taker_error).contains("resume-coinswap"));
+
+    sim.mine_blocks(1);
+    let mut taker = new_taker(&sim, &taker_path, &maker_ports);
+    let mut taker_events = mine_on_funding_broadcast(sim.clone(), taker.subscribe());
+    taker.resume_coinswap().await.unwrap();
+    assert!(!journal_path.exists());
+    drop(taker);
+    let mut events = Vec::new();
+    while let Some(event) = taker_events.recv().await {
+        events.push(event);
+    }
+    assert_eq!(events.last(), Some(&SwapEvent::Completed { hashvalue }));
+
+    for events in maker_events.iter_mut() {
+        loop {
+            match events.recv().await.unwrap() {
+                SwapEvent::Completed { hashvalue: h } if h == hashvalue => break,
+                _ => continue,
+            }
+        }
+    }
+
+    *kill_flag.write().unwrap() = true;
+    for maker_handle in maker_handles.iter() {
+        maker_handle.shutdown();
+    }
+    for maker_task in maker_tasks {
+        tokio::time::timeout(time::Duration::from_secs(30), maker_task)
+            .await
+            .expect("maker didn't shut down")
+            .unwrap()
+            .unwrap();
+    }
+    watchtower_thread.join().unwrap();
+
+    let taker_wallet = load_and_sync_wallet(&sim, &taker_path);
+    assert_eq!(taker_wallet.get_swapcoins_count(), 6);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
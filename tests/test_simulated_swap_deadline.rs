@@ -0,0 +1,245 @@
+mod common;
+use common::{
+    create_wallet_and_import, load_and_sync_wallet, mine_on_funding_broadcast, unused_port,
+    wait_for,
+};
+
+use bitcoin::{util::amount::Amount, Network, Txid};
+
+use serde_json::Value;
+
+use teleport::{
+    blockchain_backend::BlockchainBackend,
+    contracts::read_locktime_from_contract,
+    fidelity_bonds::YearAndMonth,
+    maker_admin,
+    maker_protocol::{Maker, MakerConfig},
+    offerbook_sync::MakerAddress,
+    simulated_blockchain::SimulatedBlockchain,
+    swap_events::SwapEvent,
+    taker_protocol::{SwapParams, Taker, TakerConfig},
+    watchtower_protocol,
+};
+
+use tokio::sync::mpsc::unbounded_channel;
+
+use std::{
+    sync::{Arc, RwLock},
+    thread, time,
+};
+
+// The taker vanishes after a maker broadcast its funding tx. Once the swap deadline passed the
+// maker must broadcast its contract txes, and spend its outgoing contracts via the timelock
+// once it matures.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_simulated_swap_deadline() {
+    teleport::setup_logger();
+
+    let data_dir =
+        std::env::temp_dir().join(format!("teleport-sim-deadline-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let watchtower_data = data_dir.join("watchtower.dat");
+    let taker_path = data_dir.join("taker-wallet");
+    let maker_paths = [
+        data_dir.join("maker-wallet-1"),
+        data_dir.join("maker-wallet-2"),
+    ];
+
+    let sim = Arc::new(SimulatedBlockchain::new());
+
+    let mut taker_wallet = create_wallet_and_import(&sim, &taker_path);
+    let mut maker_wallets = maker_paths
+        .iter()
+        .map(|maker_path| create_wallet_and_import(&sim, maker_path))
+        .collect::<Vec<_>>();
+    for _ in 0..3 {
+        for wallet in maker_wallets.iter_mut().chain(Some(&mut taker_wallet)) {
+            let address = wallet.get_next_external_address(sim.as_ref()).unwrap();
+            sim.send_to_address(&address, Amount::from_btc(0.05).unwrap())
+                .unwrap();
+        }
+    }
+    for wallet in maker_wallets.iter() {
+        let fbond_address = wallet.get_timelocked_address(&YearAndMonth::new(2030, 1)).0;
+        sim.send_to_address(&fbond_address, Amount::from_btc(0.05).unwrap())
+            .unwrap();
+    }
+    sim.mine_blocks(1);
+    for wallet in maker_wallets.iter().chain(Some(&taker_wallet)) {
+        wallet.lock_all_nonwallet_unspents(sim.as_ref()).unwrap();
+    }
+
+    let kill_flag = Arc::new(RwLock::new(false));
+    let watchtower_thread = {
+        let sim = sim.clone();
+        let kill_flag = kill_flag.clone();
+        let watchtower_data = watchtower_data.clone();
+        thread::spawn(move || {
+            watchtower_protocol::start_watchtower(
+                sim.as_ref(),
+                &watchtower_data,
+                Network::Regtest,
+                kill_flag,
+            );
+        })
+    };
+
+    let maker_config = MakerConfig {
+        swap_deadline_check_interval_secs: 1,
+        ..MakerConfig::default()
+    };
+    let swap_deadline_blocks = maker_config.swap_deadline_blocks;
+    let maker_ports = [unused_port(), unused_port()];
+    let maker_wallets = maker_paths
+        .iter()
+        .map(|maker_path| Arc::new(RwLock::new(load_and_sync_wallet(&sim, maker_path))))
+        .collect::<Vec<_>>();
+    let mut makers = maker_wallets
+        .iter()
+        .zip(maker_ports.iter())
+        .map(|(wallet, port)| {
+            Maker::new(
+                sim.clone(),
+                wallet.clone(),
+                MakerConfig {
+                    port: *port,
+                    ..maker_config.clone()
+                },
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let maker_handles = makers.iter().map(Maker::handle).collect::<Vec<_>>();
+    // The events of both makers, with the index of the maker
+    let (maker_events_tx, mut maker_events) = unbounded_channel();
+    for (i, maker) in makers.iter_mut().enumerate() {
+        let mut events = maker.subscribe();
+        let maker_events_tx = maker_events_tx.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let _ = maker_events_tx.send((i, event));
+            }
+        });
+    }
+    let maker_tasks = makers
+        .into_iter()
+        .map(|maker| tokio::spawn(async move { maker.run().await }))
+        .collect::<Vec<_>>();
+    for maker_path in maker_paths.iter() {
+        while maker_admin::send_request(
+            &maker_path.with_extension("sock"),
+            "listconnections",
+            Value::Null,
+        )
+        .await
+        .is_err()
+        {
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+        }
+    }
+
+    let mut taker = Taker::new(
+        sim.clone(),
+        Arc::new(RwLock::new(load_and_sync_wallet(&sim, &taker_path))),
+        TakerConfig {
+            maker_addresses: Some(
+                maker_ports
+                    .iter()
+                    .map(|port| MakerAddress::Clearnet {
+                        address: format!("localhost:{}", port),
+                    })
+                    .collect(),
+            ),
+            ..TakerConfig::default()
+        },
+        &taker_path.with_extension("offerbook"),
+        &taker_path.with_extension("swap"),
+    )
+    .unwrap();
+    let taker_handle = taker.handle();
+    // Only the taker's funding is mined, so the taker is still waiting for the maker's
+    let _taker_events = mine_on_funding_broadcast(sim.clone(), taker.subscribe());
+    let taker_task = tokio::spawn(async move {
+        taker
+            .send_coinswap(SwapParams {
+                send_amount: 500000,
+                maker_count: 2,
+                tx_count: 3,
+                required_confirms: 1,
+                fee_rate: 1000,
+                ..SwapParams::default()
+            })
+            .await
+    });
+
+    let (maker, hashvalue) = loop {
+        match maker_events.recv().await.unwrap() {
+            (i, SwapEvent::FundingBroadcast { hashvalue, .. }) => break (i, hashvalue),
+            _ => continue,
+        }
+    };
+    taker_handle.cancel();
+    let taker_error = taker_task.await.unwrap().unwrap_err();
+    assert_eq!(format!("{:?}", taker_error), "Cancelled");
+    sim.mine_blocks(1);
+
+    // The maker's outgoing contracts, which only it can spend
+    let contracts = {
+        let wallet = maker_wallets[maker].read().unwrap();
+        let incomplete_coinswaps = wallet.find_incomplete_coinswaps(sim.as_ref()).unwrap();
+        incomplete_coinswaps[&hashvalue]
+            .1
+            .iter()
+            .map(|(_, swapcoin)| {
+                (
+                    swapcoin.contract_tx.txid(),
+                    read_locktime_from_contract(&swapcoin.contract_redeemscript).unwrap(),
+                )
+            })
+            .collect::<Vec<(Txid, u16)>>()
+    };
+    assert!(!contracts.is_empty());
+
+    sim.mine_blocks(swap_deadline_blocks as u64);
+    loop {
+        match maker_events.recv().await.unwrap() {
+            (i, SwapEvent::Failed { hashvalue: h, .. }) if i == maker && h == hashvalue => break,
+            _ => continue,
+        }
+    }
+    wait_for("the contract txes to be broadcast", || {
+        contracts
+            .iter()
+            .all(|(txid, _)| sim.get_raw_transaction(txid, None).is_ok())
+    })
+    .await;
+    sim.mine_blocks(1);
+
+    let locktime = contracts
+        .iter()
+        .map(|(_, locktime)| *locktime)
+        .max()
+        .unwrap();
+    sim.mine_blocks(locktime as u64);
+    wait_for("the timelocked contract outputs to be spent", || {
+        contracts
+            .iter()
+            .all(|(txid, _)| sim.get_tx_out(txid, 0).unwrap().is_none())
+    })
+    .await;
+
+    *kill_flag.write().unwrap() = true;
+    for maker_handle in maker_handles.iter() {
+        maker_handle.shutdown();
+    }
+    for maker_task in maker_tasks {
+        tokio::time::timeout(time::Duration::from_secs(30), maker_task)
+            .await
+            .expect("maker didn't shut down")
+            .unwrap()
+            .unwrap();
+    }
+    watchtower_thread.join().unwrap();
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
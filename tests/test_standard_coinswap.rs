@@ -3,8 +3,10 @@ use bitcoin_wallet::mnemonic;
 use bitcoincore_rpc::{Client, RpcApi};
 
 use teleport::{
+    config::RpcConfig,
     fidelity_bonds::YearAndMonth,
-    maker_protocol::MakerBehavior,
+    maker_protocol::MakerConfig,
+    taker_protocol::SwapParams,
     wallet_sync::{Wallet, WalletSyncAddressAmount},
 };
 
@@ -29,7 +31,7 @@ fn create_wallet_and_import(rpc: &Client, filename: PathBuf) -> Wallet {
         mnemonic::Mnemonic::new_random(bitcoin_wallet::account::MasterKeyEntropy::Sufficient)
             .unwrap();
 
-    Wallet::save_new_wallet_file(&filename, mnemonic.to_string(), "".to_string()).unwrap();
+    Wallet::save_new_wallet_file(&filename, mnemonic.to_string(), "".to_string(), None).unwrap();
 
     let wallet =
         Wallet::load_wallet_from_file(filename, Network::Regtest, WalletSyncAddressAmount::Testing)
@@ -37,9 +39,9 @@ fn create_wallet_and_import(rpc: &Client, filename: PathBuf) -> Wallet {
     // import intital addresses to core
     wallet
         .import_initial_addresses(
-            &rpc,
+            rpc,
             &wallet
-                .get_hd_wallet_descriptors(&rpc)
+                .get_hd_wallet_descriptors(rpc)
                 .unwrap()
                 .iter()
                 .collect::<Vec<&String>>(),
@@ -63,7 +65,7 @@ pub fn generate_1_block(rpc: &Client) {
 async fn test_standard_coinswap() {
     teleport::setup_logger();
 
-    let (rpc, network) = teleport::get_bitcoin_rpc().unwrap();
+    let (rpc, network) = teleport::get_bitcoin_rpc(&RpcConfig::default()).unwrap();
     assert_eq!(network, Network::Regtest);
 
     // unlock all utxos to avoid "insufficient fund" error
@@ -194,6 +196,7 @@ async fn test_standard_coinswap() {
     let kill_flag_watchtower = kill_flag.clone();
     let watchtower_thread = thread::spawn(|| {
         teleport::run_watchtower(
+            &RpcConfig::default(),
             &PathBuf::from_str(WATCHTOWER_DATA).unwrap(),
             Some(kill_flag_watchtower),
         );
@@ -202,22 +205,28 @@ async fn test_standard_coinswap() {
     let kill_flag_maker1 = kill_flag.clone();
     let maker1_thread = thread::spawn(|| {
         teleport::run_maker(
+            &RpcConfig::default(),
             &PathBuf::from_str(MAKER1).unwrap(),
             WalletSyncAddressAmount::Testing,
-            6102,
-            MakerBehavior::Normal,
-            Some(kill_flag_maker1),
+            MakerConfig {
+                port: 6102,
+                kill_flag: kill_flag_maker1,
+                ..MakerConfig::default()
+            },
         );
     });
 
     let kill_flag_maker2 = kill_flag.clone();
     let maker2_thread = thread::spawn(|| {
         teleport::run_maker(
+            &RpcConfig::default(),
             &PathBuf::from_str(MAKER2).unwrap(),
             WalletSyncAddressAmount::Testing,
-            16102,
-            MakerBehavior::Normal,
-            Some(kill_flag_maker2),
+            MakerConfig {
+                port: 16102,
+                kill_flag: kill_flag_maker2,
+                ..MakerConfig::default()
+            },
         );
     });
 
@@ -225,12 +234,17 @@ async fn test_standard_coinswap() {
         // Wait and then start the taker
         thread::sleep(time::Duration::from_secs(20));
         teleport::run_taker(
+            &RpcConfig::default(),
             &PathBuf::from_str(TAKER).unwrap(),
             WalletSyncAddressAmount::Testing,
-            1000,
-            500000,
-            2,
-            3,
+            SwapParams {
+                send_amount: 500000,
+                maker_count: 2,
+                tx_count: 3,
+                required_confirms: 1,
+                fee_rate: 1000,
+                ..SwapParams::default()
+            },
         );
     });
 
@@ -268,7 +282,7 @@ async fn test_standard_coinswap() {
     assert_eq!(maker1_wallet.get_swapcoins_count(), 6);
     assert_eq!(maker2_wallet.get_swapcoins_count(), 6);
 
-    let (rpc, network) = teleport::get_bitcoin_rpc().unwrap();
+    let (rpc, network) = teleport::get_bitcoin_rpc(&RpcConfig::default()).unwrap();
     assert_eq!(network, Network::Regtest);
 
     let utxos = taker_wallet